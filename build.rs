@@ -1,6 +1,17 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+  let git_commit = std::process::Command::new("git")
+    .args(["rev-parse", "--short", "HEAD"])
+    .output()
+    .ok()
+    .filter(|output| output.status.success())
+    .and_then(|output| String::from_utf8(output.stdout).ok())
+    .map(|commit| commit.trim().to_string())
+    .unwrap_or_else(|| "unknown".to_string());
+  println!("cargo:rustc-env=P2PIM_GIT_COMMIT={}", git_commit);
+  println!("cargo:rerun-if-changed=.git/HEAD");
+  println!("cargo:rerun-if-changed=.git/refs");
   tonic_build::compile_protos("proto/solidity.proto")?;
   tonic_build::compile_protos("proto/api.proto")?;
-  prost_build::compile_protos(&["proto/p2p.proto"], &["proto/"])?;
+  prost_build::compile_protos(&["proto/p2p.proto", "proto/persistence.proto"], &["proto/"])?;
   Ok(())
 }
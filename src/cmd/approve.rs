@@ -1,4 +1,4 @@
-use crate::cmd::{arg_token, arg_url, ARG_TOKEN, ARG_URL};
+use crate::cmd::{arg_network, arg_token, arg_url, network_of, ARG_TOKEN, ARG_URL};
 use clap::{ArgMatches, Command};
 use ethcontract::U256;
 use p2pim::proto::api::p2pim_client::P2pimClient;
@@ -10,23 +10,30 @@ pub fn command<'a>() -> Command<'a> {
     .about("approve to use tokens by the adjudicator")
     .arg(arg_url())
     .arg(arg_token())
+    .arg(arg_network())
 }
 
 pub fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
   let rpc_url = matches.value_of_t(ARG_URL)?;
   let token_addr = matches.value_of_t(ARG_TOKEN)?;
+  let network = network_of(matches);
   tokio::runtime::Builder::new_multi_thread()
     .enable_all()
     .build()
     .unwrap()
-    .block_on(run_approve(rpc_url, token_addr))
+    .block_on(run_approve(rpc_url, token_addr, network))
 }
 
-async fn run_approve(rpc_url: String, token_addr: web3::types::Address) -> Result<(), Box<dyn std::error::Error>> {
+async fn run_approve(
+  rpc_url: String,
+  token_addr: web3::types::Address,
+  network: String,
+) -> Result<(), Box<dyn std::error::Error>> {
   let mut client = P2pimClient::connect(rpc_url).await?;
   let req = ApproveRequest {
     token_address: Some(From::from(token_addr)),
     amount: Some(From::from(U256::max_value())),
+    network,
   };
   let response = client.approve(req).await?;
   let trans_hash: H256 = response
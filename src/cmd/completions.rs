@@ -0,0 +1,28 @@
+use clap::{Arg, ArgMatches, Command};
+use clap_complete::Shell;
+use std::io::Write;
+
+pub const CMD_NAME: &str = "completions";
+
+const ARG_SHELL: &str = "shell";
+
+pub fn command<'a>() -> Command<'a> {
+  Command::new(CMD_NAME).about("generate shell completions").hide(true).arg(
+    Arg::new(ARG_SHELL)
+      .takes_value(true)
+      .required(true)
+      .possible_values(["bash", "elvish", "fish", "powershell", "zsh"])
+      .help("shell to generate the completions for"),
+  )
+}
+
+pub fn run(matches: &ArgMatches, cli: &mut Command) -> Result<(), Box<dyn std::error::Error>> {
+  let shell: Shell = matches.value_of_t(ARG_SHELL)?;
+  generate(shell, cli, &mut std::io::stdout());
+  Ok(())
+}
+
+pub fn generate(shell: Shell, cli: &mut Command, out: &mut dyn Write) {
+  let bin_name = cli.get_name().to_string();
+  clap_complete::generate(shell, cli, bin_name, out);
+}
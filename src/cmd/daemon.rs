@@ -1,30 +1,143 @@
 use bigdecimal::BigDecimal;
 use std::collections::HashMap;
+use std::fmt::Display;
+use std::num::NonZeroUsize;
 use std::ops::Range;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 use clap::{Arg, ArgMatches, Command};
-use p2pim::daemon::{DaemonOpts, EthOpts, LessorOpts, MdnsOpts, S3Opts, TokenLeaseAsk};
+use libp2p::Multiaddr;
+use p2pim::daemon::{
+  ConnectionOpts, DaemonOpts, EthOpts, LessorOpts, MdnsOpts, PingOpts, ReactorOpts, S3Opts, TokenLeaseAsk,
+};
+use serde::{Deserialize, Deserializer};
+use tokio::runtime::Runtime;
 use typed_arena::Arena;
+use url::Url;
 
 pub const CMD_NAME: &str = "daemon";
 
+const ARG_CONFIG: &str = "config";
+
 const ARG_ETH_URL: &str = "eth.url";
 const ARG_ETH_MASTER: &str = "eth.master";
+const ARG_ETH_POLL_INTERVAL: &str = "eth.poll-interval";
+const ARG_ETH_POLL_INTERVAL_DEFAULT: &str = "1s";
+const ARG_ETH_NETWORK: &str = "eth.network";
+const ARG_ETH_BALANCE_MAX_AGE: &str = "eth.balance-max-age";
 
 const ARG_RPC_ADDRESS: &str = "rpc.address";
 const ARG_RPC_ADDRESS_DEFAULT: &str = "127.0.0.1:8122";
+const ARG_RPC_MAX_UPLOAD_SIZE: &str = "rpc.max-upload-size";
+const ARG_RPC_MAX_UPLOAD_SIZE_DEFAULT: &str = "268435456";
+
+const ARG_DEBUG_RPC: &str = "debug-rpc";
+
+const ARG_BLOCK_SIZE: &str = "block-size";
+
+const ARG_WORKER_THREADS: &str = "worker-threads";
+
+const ARG_DATA_COMPRESSION: &str = "data-compression";
+const ARG_DATA_COMPRESSION_DEFAULT: &str = "none";
+
+const ARG_DATA_STRICT_PERMISSIONS: &str = "data-strict-permissions";
 
 const ARG_LESSOR_ASK: &str = "lessor.ask";
 
+const ARG_LESSOR_STORAGE_QUOTA: &str = "lessor.storage-quota";
+
 const ARG_MDNS: &str = "mdns";
 
+const ARG_LISTEN_ADDRESS: &str = "listen-address";
+
+const ARG_MAX_CONNECTIONS: &str = "max-connections";
+const ARG_MAX_CONNECTIONS_DEFAULT: &str = "128";
+
+const ARG_MAX_PENDING_CONNECTIONS: &str = "max-pending-connections";
+const ARG_MAX_PENDING_CONNECTIONS_DEFAULT: &str = "32";
+
+const ARG_MAX_CONNECTIONS_PER_PEER: &str = "max-connections-per-peer";
+const ARG_MAX_CONNECTIONS_PER_PEER_DEFAULT: &str = "4";
+
+const ARG_PING_KEEPALIVE: &str = "ping-keepalive";
+const ARG_PING_KEEPALIVE_DEFAULT: &str = "true";
+
+const ARG_PING_INTERVAL: &str = "ping-interval";
+const ARG_PING_INTERVAL_DEFAULT: &str = "15s";
+
+const ARG_SUBSTREAM_IDLE_TIMEOUT: &str = "substream-idle-timeout";
+const ARG_SUBSTREAM_IDLE_TIMEOUT_DEFAULT: &str = "10s";
+
+const ARG_MAX_QUEUED_MESSAGES: &str = "max-queued-messages";
+const ARG_MAX_QUEUED_MESSAGES_DEFAULT: &str = "1024";
+
+const ARG_CHALLENGE_TIMEOUT: &str = "challenge-timeout";
+const ARG_CHALLENGE_TIMEOUT_DEFAULT: &str = "60s";
+
+const ARG_RETRIEVE_TIMEOUT: &str = "retrieve-timeout";
+const ARG_RETRIEVE_TIMEOUT_DEFAULT: &str = "5m";
+
+const ARG_RETRIEVE_RETRIES: &str = "retrieve-retries";
+const ARG_RETRIEVE_RETRIES_DEFAULT: &str = "1";
+
+const ARG_NETWORK_ID: &str = "network-id";
+
+const ARG_AGENT_VERSION: &str = "agent-version";
+const ARG_AGENT_VERSION_DEFAULT: &str = p2pim::p2p::behaviour::AGENT_VERSION;
+
+const ARG_KNOWN_PEERS_CAPACITY: &str = "known-peers-capacity";
+const ARG_KNOWN_PEERS_CAPACITY_DEFAULT: &str = "1024";
+
+const ARG_PROPOSAL_TIMEOUT: &str = "proposal-timeout";
+const ARG_PROPOSAL_TIMEOUT_DEFAULT: &str = "2m";
+
+const ARG_INTEGRITY_CHECK_INTERVAL: &str = "integrity-check-interval";
+
+const ARG_CHALLENGE_WATCHDOG_INTERVAL: &str = "challenge-watchdog-interval";
+
+const ARG_EXPIRY_CHECK_INTERVAL: &str = "expiry-check-interval";
+
+const ARG_LEASE_KEEPALIVE_INTERVAL: &str = "lease-keepalive-interval";
+
+const ARG_LET_DATA_GRACE_PERIOD: &str = "let-data-grace-period";
+const ARG_LET_DATA_GRACE_PERIOD_DEFAULT: &str = "24h";
+
+const ARG_CONFIRMATION_DEPTH: &str = "confirmation-depth";
+const ARG_CONFIRMATION_DEPTH_DEFAULT: &str = "0";
+
+const ARG_MAX_CONCURRENT_SEALS: &str = "max-concurrent-seals";
+const ARG_MAX_CONCURRENT_SEALS_DEFAULT: &str = "16";
+
+const ARG_MAX_PROPOSAL_RATE: &str = "max-proposal-rate";
+
+const ARG_SEAL_GAS_PRECHECK: &str = "seal-gas-precheck";
+
+const ARG_MIN_GAS_BALANCE: &str = "min-gas-balance";
+const ARG_MIN_GAS_BALANCE_DEFAULT: &str = "0";
+
+const ARG_MAX_RETRIEVE_SIZE: &str = "max-retrieve-size";
+const ARG_MAX_RETRIEVE_SIZE_DEFAULT: &str = "268435456";
+
+const ARG_RETRIEVE_MIN_INTERVAL: &str = "retrieve-min-interval";
+const ARG_RETRIEVE_MIN_INTERVAL_DEFAULT: &str = "1s";
+
+const ARG_WITHDRAW_EXPIRED: &str = "withdraw-expired";
+
 const ARG_S3: &str = "s3";
 
 const ARG_S3_ADDRESS: &str = "s3.address";
 const ARG_S3_ADDRESS_DEFAULT: &str = "127.0.0.1:8123";
 
-fn arg_eth_url(buf: &mut Arena<String>) -> Arg {
+fn arg_config<'a>() -> Arg<'a> {
+  Arg::new(ARG_CONFIG)
+    .long(ARG_CONFIG)
+    .takes_value(true)
+    .value_name("FILE")
+    .help("TOML file with the daemon options, a dotted flag goes in the table of its prefix and s3 as s3.enabled; flags given in the command line take precedence")
+}
+
+fn arg_eth_url(buf: &Arena<String>) -> Arg {
   let default_value = buf.alloc(format!(
     "file://{}/.ethereum/geth.ipc",
     dirs::home_dir().expect("TODO").to_str().expect("TODO")
@@ -34,7 +147,8 @@ fn arg_eth_url(buf: &mut Arena<String>) -> Arg {
     .takes_value(true)
     .value_name("ADDRESS")
     .default_value(default_value)
-    .help("ethereum JSON-RPC address")
+    // Parsed when the daemon starts, clap validates the default too and fails when there is no local node
+    .help("ethereum JSON-RPC address, a ws(s) or http(s) url or the path to the IPC socket")
 }
 
 fn arg_eth_master<'a>() -> Arg<'a> {
@@ -47,6 +161,85 @@ fn arg_eth_master<'a>() -> Arg<'a> {
     .help("ethereum address of the master record contract")
 }
 
+fn arg_eth_poll_interval<'a>() -> Arg<'a> {
+  Arg::new(ARG_ETH_POLL_INTERVAL)
+    .long(ARG_ETH_POLL_INTERVAL)
+    .takes_value(true)
+    .value_name("DURATION")
+    .validator(parse_duration::parse)
+    .default_value(ARG_ETH_POLL_INTERVAL_DEFAULT)
+    .help("interval between polls for contract events and, over http, new blocks; lower is faster but more requests")
+}
+
+fn arg_eth_balance_max_age<'a>() -> Arg<'a> {
+  Arg::new(ARG_ETH_BALANCE_MAX_AGE)
+    .long(ARG_ETH_BALANCE_MAX_AGE)
+    .takes_value(true)
+    .value_name("DURATION")
+    .validator(parse_duration::parse)
+    .required(false)
+    .help("maximum age of the token balances served from the cache, read on every request if not present")
+}
+
+fn arg_eth_network<'a>() -> Arg<'a> {
+  Arg::new(ARG_ETH_NETWORK)
+    .long(ARG_ETH_NETWORK)
+    .takes_value(true)
+    .value_name("ADDRESS[,MASTER]")
+    .multiple_occurrences(true)
+    .validator(parse_eth_network)
+    .help(
+      "additional ethereum network whose balances are served, leases are sealed on the one of eth.url; repeat for several",
+    )
+}
+
+fn arg_debug_rpc<'a>() -> Arg<'a> {
+  Arg::new(ARG_DEBUG_RPC)
+    .long(ARG_DEBUG_RPC)
+    .required(false)
+    .takes_value(false)
+    .help("Enable the debug rpcs, which expose the pending operations of the daemon")
+}
+
+fn arg_block_size(buf: &Arena<String>) -> Arg<'_> {
+  let default_value = buf.alloc(p2pim::cryptography::BLOCK_SIZE_BYTES.to_string());
+  Arg::new(ARG_BLOCK_SIZE)
+    .long(ARG_BLOCK_SIZE)
+    .takes_value(true)
+    .value_name("BYTES")
+    .validator(str::parse::<usize>)
+    .default_value(default_value)
+    .hide(true)
+    .help("size of the merkle tree blocks, must match the adjudicator contract")
+}
+
+fn arg_worker_threads<'a>() -> Arg<'a> {
+  Arg::new(ARG_WORKER_THREADS)
+    .long(ARG_WORKER_THREADS)
+    .takes_value(true)
+    .value_name("NUMBER")
+    .validator(str::parse::<NonZeroUsize>)
+    .help("number of worker threads of the runtime, one per cpu core if not present")
+}
+
+fn arg_data_compression<'a>() -> Arg<'a> {
+  Arg::new(ARG_DATA_COMPRESSION)
+    .long(ARG_DATA_COMPRESSION)
+    .takes_value(true)
+    .value_name("CODEC")
+    .possible_values(["none", "zstd"])
+    .default_value(ARG_DATA_COMPRESSION_DEFAULT)
+    .help("compression of the let data stored on disk, data already stored keeps its codec")
+}
+
+fn arg_data_strict_permissions<'a>() -> Arg<'a> {
+  Arg::new(ARG_DATA_STRICT_PERMISSIONS)
+    .long(ARG_DATA_STRICT_PERMISSIONS)
+    .required(false)
+    .takes_value(false)
+    .help("Refuse to start when the data folder is readable by other users, instead of warning")
+}
+
 fn arg_rpc_address<'a>() -> Arg<'a> {
   Arg::new(ARG_RPC_ADDRESS)
     .long(ARG_RPC_ADDRESS)
@@ -56,6 +249,16 @@ fn arg_rpc_address<'a>() -> Arg<'a> {
     .help("gRPC server listening address")
 }
 
+fn arg_rpc_max_upload_size<'a>() -> Arg<'a> {
+  Arg::new(ARG_RPC_MAX_UPLOAD_SIZE)
+    .long(ARG_RPC_MAX_UPLOAD_SIZE)
+    .takes_value(true)
+    .value_name("BYTES")
+    .validator(str::parse::<usize>)
+    .default_value(ARG_RPC_MAX_UPLOAD_SIZE_DEFAULT)
+    .help("maximum size of the data uploaded with a streamed store, larger uploads are rejected")
+}
+
 fn arg_s3<'a>() -> Arg<'a> {
   Arg::new(ARG_S3)
     .long(ARG_S3)
@@ -64,6 +267,16 @@ fn arg_s3<'a>() -> Arg<'a> {
     .help("Enable the S3 compatible server")
 }
 
+fn arg_listen_address<'a>() -> Arg<'a> {
+  Arg::new(ARG_LISTEN_ADDRESS)
+    .long(ARG_LISTEN_ADDRESS)
+    .takes_value(true)
+    .value_name("MULTIADDR")
+    .multiple_occurrences(true)
+    .validator(Multiaddr::from_str)
+    .help("p2p listening address, repeat for several; any IPv4 and IPv6 interface if not present")
+}
+
 fn arg_mdns<'a>() -> Arg<'a> {
   Arg::new(ARG_MDNS)
     .long(ARG_MDNS)
@@ -72,6 +285,270 @@ fn arg_mdns<'a>() -> Arg<'a> {
     .help("Enable bootstraping using mdns")
 }
 
+fn arg_max_connections<'a>() -> Arg<'a> {
+  Arg::new(ARG_MAX_CONNECTIONS)
+    .long(ARG_MAX_CONNECTIONS)
+    .takes_value(true)
+    .value_name("NUMBER")
+    .validator(str::parse::<u32>)
+    .default_value(ARG_MAX_CONNECTIONS_DEFAULT)
+    .help("maximum number of established connections")
+}
+
+fn arg_max_pending_connections<'a>() -> Arg<'a> {
+  Arg::new(ARG_MAX_PENDING_CONNECTIONS)
+    .long(ARG_MAX_PENDING_CONNECTIONS)
+    .takes_value(true)
+    .value_name("NUMBER")
+    .validator(str::parse::<u32>)
+    .default_value(ARG_MAX_PENDING_CONNECTIONS_DEFAULT)
+    .help("maximum number of pending incoming and outgoing connections")
+}
+
+fn arg_max_connections_per_peer<'a>() -> Arg<'a> {
+  Arg::new(ARG_MAX_CONNECTIONS_PER_PEER)
+    .long(ARG_MAX_CONNECTIONS_PER_PEER)
+    .takes_value(true)
+    .value_name("NUMBER")
+    .validator(str::parse::<u32>)
+    .default_value(ARG_MAX_CONNECTIONS_PER_PEER_DEFAULT)
+    .help("maximum number of established connections with the same peer")
+}
+
+fn arg_ping_keepalive<'a>() -> Arg<'a> {
+  Arg::new(ARG_PING_KEEPALIVE)
+    .long(ARG_PING_KEEPALIVE)
+    .takes_value(true)
+    .value_name("BOOL")
+    .validator(bool::from_str)
+    .default_value(ARG_PING_KEEPALIVE_DEFAULT)
+    .help("keep connections alive with ping, when disabled connections rely on the p2pim protocol keep-alive")
+}
+
+fn arg_ping_interval<'a>() -> Arg<'a> {
+  Arg::new(ARG_PING_INTERVAL)
+    .long(ARG_PING_INTERVAL)
+    .takes_value(true)
+    .value_name("DURATION")
+    .validator(parse_duration::parse)
+    .default_value(ARG_PING_INTERVAL_DEFAULT)
+    .help("interval between pings to connected peers")
+}
+
+fn arg_max_queued_messages<'a>() -> Arg<'a> {
+  Arg::new(ARG_MAX_QUEUED_MESSAGES)
+    .long(ARG_MAX_QUEUED_MESSAGES)
+    .takes_value(true)
+    .value_name("NUMBER")
+    .validator(str::parse::<usize>)
+    .default_value(ARG_MAX_QUEUED_MESSAGES_DEFAULT)
+    .help("maximum number of p2pim messages waiting to be sent, further sends fail until the queue drains")
+}
+
+fn arg_substream_idle_timeout<'a>() -> Arg<'a> {
+  Arg::new(ARG_SUBSTREAM_IDLE_TIMEOUT)
+    .long(ARG_SUBSTREAM_IDLE_TIMEOUT)
+    .takes_value(true)
+    .value_name("DURATION")
+    .validator(parse_duration::parse)
+    .default_value(ARG_SUBSTREAM_IDLE_TIMEOUT_DEFAULT)
+    .help("close p2pim protocol substreams after this time without messages, the connection is kept by ping")
+}
+
+fn arg_challenge_timeout<'a>() -> Arg<'a> {
+  Arg::new(ARG_CHALLENGE_TIMEOUT)
+    .long(ARG_CHALLENGE_TIMEOUT)
+    .takes_value(true)
+    .value_name("DURATION")
+    .validator(parse_duration::parse)
+    .default_value(ARG_CHALLENGE_TIMEOUT_DEFAULT)
+    .help("time to wait for the proof of a challenge, the watchdog records challenges not answered as timed out")
+}
+
+fn arg_retrieve_timeout<'a>() -> Arg<'a> {
+  Arg::new(ARG_RETRIEVE_TIMEOUT)
+    .long(ARG_RETRIEVE_TIMEOUT)
+    .takes_value(true)
+    .value_name("DURATION")
+    .validator(parse_duration::parse)
+    .default_value(ARG_RETRIEVE_TIMEOUT_DEFAULT)
+    .help("time to wait for a peer to deliver the data of a lease")
+}
+
+fn arg_retrieve_retries<'a>() -> Arg<'a> {
+  Arg::new(ARG_RETRIEVE_RETRIES)
+    .long(ARG_RETRIEVE_RETRIES)
+    .takes_value(true)
+    .value_name("COUNT")
+    .validator(str::parse::<u32>)
+    .default_value(ARG_RETRIEVE_RETRIES_DEFAULT)
+    .help("times the data of a lease is requested again when the delivery does not arrive within the retrieve timeout")
+}
+
+fn arg_network_id<'a>() -> Arg<'a> {
+  Arg::new(ARG_NETWORK_ID)
+    .long(ARG_NETWORK_ID)
+    .takes_value(true)
+    .value_name("ID")
+    .validator(parse_network_id)
+    .help("network id appended to the protocol version, only nodes with the same one connect to each other")
+}
+
+fn arg_agent_version<'a>() -> Arg<'a> {
+  Arg::new(ARG_AGENT_VERSION)
+    .long(ARG_AGENT_VERSION)
+    .takes_value(true)
+    .value_name("AGENT")
+    .default_value(ARG_AGENT_VERSION_DEFAULT)
+    .help("agent version announced to the peers")
+}
+
+fn arg_known_peers_capacity<'a>() -> Arg<'a> {
+  Arg::new(ARG_KNOWN_PEERS_CAPACITY)
+    .long(ARG_KNOWN_PEERS_CAPACITY)
+    .takes_value(true)
+    .value_name("COUNT")
+    .validator(str::parse::<usize>)
+    .default_value(ARG_KNOWN_PEERS_CAPACITY_DEFAULT)
+    .help("identified peers kept in memory, the least recently seen disconnected ones are evicted first")
+}
+
+fn arg_proposal_timeout<'a>() -> Arg<'a> {
+  Arg::new(ARG_PROPOSAL_TIMEOUT)
+    .long(ARG_PROPOSAL_TIMEOUT)
+    .takes_value(true)
+    .value_name("DURATION")
+    .validator(parse_duration::parse)
+    .default_value(ARG_PROPOSAL_TIMEOUT_DEFAULT)
+    .help("time to wait for a peer to answer a lease proposal, the lease can still be sealed on chain afterwards")
+}
+
+fn arg_integrity_check_interval<'a>() -> Arg<'a> {
+  Arg::new(ARG_INTEGRITY_CHECK_INTERVAL)
+    .long(ARG_INTEGRITY_CHECK_INTERVAL)
+    .takes_value(true)
+    .value_name("DURATION")
+    .required(false)
+    .validator(parse_duration::parse)
+    .help("interval between integrity checks of the stored data, disabled if not present")
+}
+
+fn arg_lease_keepalive_interval<'a>() -> Arg<'a> {
+  Arg::new(ARG_LEASE_KEEPALIVE_INTERVAL)
+    .long(ARG_LEASE_KEEPALIVE_INTERVAL)
+    .takes_value(true)
+    .value_name("DURATION")
+    .required(false)
+    .validator(parse_duration::parse)
+    .help("interval between dials of the disconnected peers with active leases, disabled if not present")
+}
+
+fn arg_challenge_watchdog_interval<'a>() -> Arg<'a> {
+  Arg::new(ARG_CHALLENGE_WATCHDOG_INTERVAL)
+    .long(ARG_CHALLENGE_WATCHDOG_INTERVAL)
+    .takes_value(true)
+    .value_name("DURATION")
+    .required(false)
+    .validator(parse_duration::parse)
+    .help("interval between automatic challenges of the rented storage, disabled if not present")
+}
+
+fn arg_expiry_check_interval<'a>() -> Arg<'a> {
+  Arg::new(ARG_EXPIRY_CHECK_INTERVAL)
+    .long(ARG_EXPIRY_CHECK_INTERVAL)
+    .takes_value(true)
+    .value_name("DURATION")
+    .required(false)
+    .validator(parse_duration::parse)
+    .help("interval between checks for expired leases, disabled if not present")
+}
+
+fn arg_let_data_grace_period<'a>() -> Arg<'a> {
+  Arg::new(ARG_LET_DATA_GRACE_PERIOD)
+    .long(ARG_LET_DATA_GRACE_PERIOD)
+    .takes_value(true)
+    .value_name("DURATION")
+    .validator(parse_duration::parse)
+    .default_value(ARG_LET_DATA_GRACE_PERIOD_DEFAULT)
+    .help("time to keep the data of an expired let lease before removing it")
+}
+
+fn arg_confirmation_depth<'a>() -> Arg<'a> {
+  Arg::new(ARG_CONFIRMATION_DEPTH)
+    .long(ARG_CONFIRMATION_DEPTH)
+    .takes_value(true)
+    .value_name("BLOCKS")
+    .validator(str::parse::<u64>)
+    .default_value(ARG_CONFIRMATION_DEPTH_DEFAULT)
+    .help("number of blocks built on top of a sealed lease before it is considered confirmed")
+}
+
+fn arg_max_concurrent_seals<'a>() -> Arg<'a> {
+  Arg::new(ARG_MAX_CONCURRENT_SEALS)
+    .long(ARG_MAX_CONCURRENT_SEALS)
+    .takes_value(true)
+    .value_name("NUMBER")
+    .validator(str::parse::<usize>)
+    .default_value(ARG_MAX_CONCURRENT_SEALS_DEFAULT)
+    .help("maximum number of lease proposals sealed concurrently, proposals beyond are rejected as busy")
+}
+
+fn arg_max_proposal_rate<'a>() -> Arg<'a> {
+  Arg::new(ARG_MAX_PROPOSAL_RATE)
+    .long(ARG_MAX_PROPOSAL_RATE)
+    .takes_value(true)
+    .value_name("NUMBER")
+    .validator(str::parse::<NonZeroUsize>)
+    .required(false)
+    .help("maximum lease proposals received and quotes answered for a peer per minute, faster ones are rejected, disabled if not present")
+}
+
+fn arg_seal_gas_precheck<'a>() -> Arg<'a> {
+  Arg::new(ARG_SEAL_GAS_PRECHECK)
+    .long(ARG_SEAL_GAS_PRECHECK)
+    .required(false)
+    .takes_value(false)
+    .help("Estimate the gas of each seal before sending it, proposals that would revert are rejected")
+}
+
+fn arg_min_gas_balance<'a>() -> Arg<'a> {
+  Arg::new(ARG_MIN_GAS_BALANCE)
+    .long(ARG_MIN_GAS_BALANCE)
+    .takes_value(true)
+    .value_name("ETH")
+    .validator(BigDecimal::from_str)
+    .default_value(ARG_MIN_GAS_BALANCE_DEFAULT)
+    .help("native balance the wallet keeps, proposals are rejected below it or below the gas cost of the seal")
+}
+
+fn arg_max_retrieve_size<'a>() -> Arg<'a> {
+  Arg::new(ARG_MAX_RETRIEVE_SIZE)
+    .long(ARG_MAX_RETRIEVE_SIZE)
+    .takes_value(true)
+    .value_name("BYTES")
+    .validator(str::parse::<usize>)
+    .default_value(ARG_MAX_RETRIEVE_SIZE_DEFAULT)
+    .help("maximum size of the data delivered to a peer on a retrieve request, larger requests are ignored")
+}
+
+fn arg_retrieve_min_interval<'a>() -> Arg<'a> {
+  Arg::new(ARG_RETRIEVE_MIN_INTERVAL)
+    .long(ARG_RETRIEVE_MIN_INTERVAL)
+    .takes_value(true)
+    .value_name("DURATION")
+    .validator(parse_duration::parse)
+    .default_value(ARG_RETRIEVE_MIN_INTERVAL_DEFAULT)
+    .help("minimum time between two retrieve requests served to the same peer, faster requests are ignored")
+}
+
+fn arg_withdraw_expired<'a>() -> Arg<'a> {
+  Arg::new(ARG_WITHDRAW_EXPIRED)
+    .long(ARG_WITHDRAW_EXPIRED)
+    .required(false)
+    .takes_value(false)
+    .help("Withdraw the available storage balance of a token to the wallet once some of its leases expire")
+}
+
 fn arg_s3_address<'a>() -> Arg<'a> {
   Arg::new(ARG_S3_ADDRESS)
     .long(ARG_S3_ADDRESS)
@@ -90,51 +567,481 @@ fn arg_lessor_ask<'a>() -> Arg<'a> {
     .help("lease ask in form TOKEN:min_duration:max_duration:min_size:max_size:min_tokens_total:min_tokens_gb_hour:max_penalty_rate")
 }
 
+fn arg_lessor_storage_quota<'a>() -> Arg<'a> {
+  Arg::new(ARG_LESSOR_STORAGE_QUOTA)
+    .long(ARG_LESSOR_STORAGE_QUOTA)
+    .takes_value(true)
+    .value_name("BYTES")
+    .validator(str::parse::<u64>)
+    .help("maximum bytes stored for let leases, proposals beyond are rejected; unlimited if not present")
+}
+
 pub fn command(buf: &mut Arena<String>) -> Command {
   Command::new("daemon")
     .about("run daemon")
+    .arg(arg_config())
     .arg(arg_eth_url(buf))
     .arg(arg_eth_master())
+    .arg(arg_eth_poll_interval())
+    .arg(arg_eth_balance_max_age())
+    .arg(arg_eth_network())
     .arg(arg_rpc_address())
+    .arg(arg_rpc_max_upload_size())
+    .arg(arg_debug_rpc())
+    .arg(arg_block_size(buf))
+    .arg(arg_data_compression())
+    .arg(arg_data_strict_permissions())
+    .arg(arg_worker_threads())
     .arg(arg_s3())
     .arg(arg_s3_address())
     .arg(arg_lessor_ask())
+    .arg(arg_lessor_storage_quota())
+    .arg(arg_listen_address())
     .arg(arg_mdns())
+    .arg(arg_max_connections())
+    .arg(arg_max_pending_connections())
+    .arg(arg_max_connections_per_peer())
+    .arg(arg_substream_idle_timeout())
+    .arg(arg_max_queued_messages())
+    .arg(arg_challenge_timeout())
+    .arg(arg_retrieve_timeout())
+    .arg(arg_retrieve_retries())
+    .arg(arg_network_id())
+    .arg(arg_agent_version())
+    .arg(arg_known_peers_capacity())
+    .arg(arg_proposal_timeout())
+    .arg(arg_ping_keepalive())
+    .arg(arg_ping_interval())
+    .arg(arg_integrity_check_interval())
+    .arg(arg_challenge_watchdog_interval())
+    .arg(arg_expiry_check_interval())
+    .arg(arg_lease_keepalive_interval())
+    .arg(arg_let_data_grace_period())
+    .arg(arg_confirmation_depth())
+    .arg(arg_max_concurrent_seals())
+    .arg(arg_max_proposal_rate())
+    .arg(arg_seal_gas_precheck())
+    .arg(arg_min_gas_balance())
+    .arg(arg_max_retrieve_size())
+    .arg(arg_retrieve_min_interval())
+    .arg(arg_withdraw_expired())
 }
 
 pub fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
-  let daemon_opts = DaemonOpts {
-    rpc_addr: matches.value_of_t(ARG_RPC_ADDRESS)?,
+  let values = Values::load(matches)?;
+  let daemon_opts = daemon_opts(&values)?;
+  let worker_threads = values
+    .value_of(ARG_WORKER_THREADS)?
+    .as_deref()
+    .map(str::parse::<NonZeroUsize>)
+    .transpose()?;
+  build_runtime(worker_threads)?.block_on(p2pim::daemon::listen_and_serve(&daemon_opts))
+}
+
+fn daemon_opts(values: &Values) -> Result<DaemonOpts, Box<dyn std::error::Error>> {
+  let poll_interval = parse_duration::parse(values.value_of_t::<String>(ARG_ETH_POLL_INTERVAL)?.as_str())?;
+  let balance_max_age = values
+    .value_of(ARG_ETH_BALANCE_MAX_AGE)?
+    .as_deref()
+    .map(parse_duration::parse)
+    .transpose()?;
+  Ok(DaemonOpts {
+    rpc_addr: values.value_of_t(ARG_RPC_ADDRESS)?,
+    rpc_max_upload_size: values.value_of_t(ARG_RPC_MAX_UPLOAD_SIZE)?,
+    debug_rpc: values.is_present(ARG_DEBUG_RPC)?,
+    block_size: values.value_of_t(ARG_BLOCK_SIZE)?,
+    data_compression: values.value_of_t(ARG_DATA_COMPRESSION)?,
+    data_strict_permissions: values.is_present(ARG_DATA_STRICT_PERMISSIONS)?,
     eth_opts: EthOpts {
-      master_addr: matches
-        .value_of(ARG_ETH_MASTER)
+      master_addr: values
+        .value_of(ARG_ETH_MASTER)?
+        .as_deref()
         .map(web3::types::Address::from_str)
         .transpose()?,
-      url: matches.value_of_t(ARG_ETH_URL)?,
+      poll_interval,
+      balance_max_age,
+      url: parse_eth_url(values.value_of_t::<String>(ARG_ETH_URL)?.as_str())?,
     },
-    lessor_opts: LessorOpts {
-      token_lease_terms: matches
-        .values_of(ARG_LESSOR_ASK)
-        .map(|values| {
-          values
-            .map(parse_lessor_ask)
-            .collect::<Result<HashMap<web3::types::Address, TokenLeaseAsk>, Box<dyn std::error::Error>>>()
+    eth_networks: values
+      .values_of(ARG_ETH_NETWORK)?
+      .iter()
+      .map(|v| {
+        parse_eth_network(v).map(|(url, master_addr)| EthOpts {
+          url,
+          master_addr,
+          poll_interval,
+          balance_max_age,
         })
-        .unwrap_or_else(|| Ok(Default::default()))?,
+      })
+      .collect::<Result<Vec<EthOpts>, _>>()?,
+    lessor_opts: LessorOpts {
+      token_lease_terms: values
+        .values_of(ARG_LESSOR_ASK)?
+        .iter()
+        .map(|v| parse_lessor_ask(v))
+        .collect::<Result<HashMap<web3::types::Address, TokenLeaseAsk>, Box<dyn std::error::Error>>>()?,
+      storage_quota: values
+        .value_of(ARG_LESSOR_STORAGE_QUOTA)?
+        .as_deref()
+        .map(str::parse::<u64>)
+        .transpose()?,
+    },
+    connection_opts: ConnectionOpts {
+      listen_addresses: values
+        .values_of(ARG_LISTEN_ADDRESS)?
+        .iter()
+        .map(|v| Multiaddr::from_str(v))
+        .collect::<Result<Vec<Multiaddr>, _>>()?,
+      max_established: values.value_of_t(ARG_MAX_CONNECTIONS)?,
+      max_pending: values.value_of_t(ARG_MAX_PENDING_CONNECTIONS)?,
+      max_established_per_peer: values.value_of_t(ARG_MAX_CONNECTIONS_PER_PEER)?,
+      substream_idle_timeout: parse_duration::parse(values.value_of_t::<String>(ARG_SUBSTREAM_IDLE_TIMEOUT)?.as_str())?,
+      max_queued_messages: values.value_of_t(ARG_MAX_QUEUED_MESSAGES)?,
+      challenge_timeout: parse_duration::parse(values.value_of_t::<String>(ARG_CHALLENGE_TIMEOUT)?.as_str())?,
+      retrieve_timeout: parse_duration::parse(values.value_of_t::<String>(ARG_RETRIEVE_TIMEOUT)?.as_str())?,
+      proposal_timeout: parse_duration::parse(values.value_of_t::<String>(ARG_PROPOSAL_TIMEOUT)?.as_str())?,
+      retrieve_retries: values.value_of_t(ARG_RETRIEVE_RETRIES)?,
+      network_id: values
+        .value_of(ARG_NETWORK_ID)?
+        .as_deref()
+        .map(parse_network_id)
+        .transpose()?,
+      agent_version: values.value_of_t(ARG_AGENT_VERSION)?,
+      known_peers_capacity: values.value_of_t(ARG_KNOWN_PEERS_CAPACITY)?,
     },
     mdns_opts: MdnsOpts {
-      enabled: matches.is_present(ARG_MDNS),
+      enabled: values.is_present(ARG_MDNS)?,
+    },
+    ping_opts: PingOpts {
+      keep_alive: values.value_of_t(ARG_PING_KEEPALIVE)?,
+      interval: parse_duration::parse(values.value_of_t::<String>(ARG_PING_INTERVAL)?.as_str())?,
+    },
+    reactor_opts: ReactorOpts {
+      integrity_check_interval: values
+        .value_of(ARG_INTEGRITY_CHECK_INTERVAL)?
+        .as_deref()
+        .map(parse_duration::parse)
+        .transpose()?,
+      challenge_watchdog_interval: values
+        .value_of(ARG_CHALLENGE_WATCHDOG_INTERVAL)?
+        .as_deref()
+        .map(parse_duration::parse)
+        .transpose()?,
+      expiry_check_interval: values
+        .value_of(ARG_EXPIRY_CHECK_INTERVAL)?
+        .as_deref()
+        .map(parse_duration::parse)
+        .transpose()?,
+      lease_keepalive_interval: values
+        .value_of(ARG_LEASE_KEEPALIVE_INTERVAL)?
+        .as_deref()
+        .map(parse_duration::parse)
+        .transpose()?,
+      confirmation_depth: values.value_of_t(ARG_CONFIRMATION_DEPTH)?,
+      max_concurrent_seals: values.value_of_t(ARG_MAX_CONCURRENT_SEALS)?,
+      max_proposal_rate: values
+        .value_of(ARG_MAX_PROPOSAL_RATE)?
+        .as_deref()
+        .map(str::parse::<NonZeroUsize>)
+        .transpose()?
+        .map(NonZeroUsize::get),
+      seal_gas_precheck: values.is_present(ARG_SEAL_GAS_PRECHECK)?,
+      min_gas_balance: values.value_of_t(ARG_MIN_GAS_BALANCE)?,
+      max_retrieve_size: values.value_of_t(ARG_MAX_RETRIEVE_SIZE)?,
+      retrieve_min_interval: parse_duration::parse(values.value_of_t::<String>(ARG_RETRIEVE_MIN_INTERVAL)?.as_str())?,
+      let_data_grace_period: parse_duration::parse(values.value_of_t::<String>(ARG_LET_DATA_GRACE_PERIOD)?.as_str())?,
+      withdraw_expired: values.is_present(ARG_WITHDRAW_EXPIRED)?,
     },
     s3_opts: S3Opts {
-      enabled: matches.is_present(ARG_S3),
-      s3_addr: matches.value_of_t(ARG_S3_ADDRESS)?,
+      enabled: values.is_present(ARG_S3)?,
+      s3_addr: values.value_of_t(ARG_S3_ADDRESS)?,
     },
+  })
+}
+
+fn build_runtime(worker_threads: Option<NonZeroUsize>) -> std::io::Result<Runtime> {
+  let mut builder = tokio::runtime::Builder::new_multi_thread();
+  if let Some(worker_threads) = worker_threads {
+    builder.worker_threads(worker_threads.get());
+  }
+  builder.enable_all().build()
+}
+
+/// Daemon option values, a flag given in the command line takes precedence over the config file, which takes precedence
+/// over the flag default.
+struct Values<'a> {
+  matches: &'a ArgMatches,
+  config: HashMap<&'static str, Vec<String>>,
+}
+
+impl<'a> Values<'a> {
+  fn load(matches: &'a ArgMatches) -> Result<Self, Box<dyn std::error::Error>> {
+    let config = match matches.value_of(ARG_CONFIG) {
+      Some(path) => {
+        let content = std::fs::read_to_string(path).map_err(|e| format!("cannot read config file {}: {}", path, e))?;
+        let config: Config = toml::from_str(content.as_str()).map_err(|e| format!("invalid config file {}: {}", path, e))?;
+        config.values()
+      }
+      None => Default::default(),
+    };
+    Ok(Values { matches, config })
+  }
+
+  fn config_override(&self, name: &str) -> Option<&Vec<String>> {
+    if self.matches.occurrences_of(name) > 0 {
+      None
+    } else {
+      self.config.get(name)
+    }
+  }
+
+  fn value_of(&self, name: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    match self.config_override(name) {
+      Some(values) => Ok(values.first().cloned()),
+      None => Ok(self.matches.value_of(name).map(str::to_string)),
+    }
+  }
+
+  fn values_of(&self, name: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    match self.config_override(name) {
+      Some(values) => Ok(values.clone()),
+      None => Ok(
+        self
+          .matches
+          .values_of(name)
+          .map(|values| values.map(str::to_string).collect())
+          .unwrap_or_default(),
+      ),
+    }
+  }
+
+  fn value_of_t<T>(&self, name: &str) -> Result<T, Box<dyn std::error::Error>>
+  where
+    T: FromStr,
+    T::Err: Display,
+  {
+    let value = self.value_of(name)?.ok_or_else(|| format!("missing value for {}", name))?;
+    value
+      .parse::<T>()
+      .map_err(|e| format!("invalid value for {}: {}", name, e).into())
+  }
+
+  fn is_present(&self, name: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    if self.matches.is_present(name) {
+      return Ok(true);
+    }
+    match self.value_of(name)? {
+      Some(value) => value
+        .parse::<bool>()
+        .map_err(|e| format!("invalid value for {}: {}", name, e).into()),
+      None => Ok(false),
+    }
+  }
+}
+
+/// Layout of the config file, the options are the flags without their prefix, in the table named by it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+  eth: EthConfig,
+  rpc: RpcConfig,
+  lessor: LessorConfig,
+  s3: S3Config,
+  debug_rpc: Option<bool>,
+  block_size: Option<usize>,
+  worker_threads: Option<NonZeroUsize>,
+  data_compression: Option<String>,
+  data_strict_permissions: Option<bool>,
+  listen_address: Option<Vec<String>>,
+  mdns: Option<bool>,
+  max_connections: Option<u32>,
+  max_pending_connections: Option<u32>,
+  max_connections_per_peer: Option<u32>,
+  substream_idle_timeout: Option<String>,
+  max_queued_messages: Option<usize>,
+  challenge_timeout: Option<String>,
+  retrieve_timeout: Option<String>,
+  retrieve_retries: Option<u32>,
+  network_id: Option<String>,
+  agent_version: Option<String>,
+  known_peers_capacity: Option<usize>,
+  proposal_timeout: Option<String>,
+  ping_keepalive: Option<bool>,
+  ping_interval: Option<String>,
+  integrity_check_interval: Option<String>,
+  challenge_watchdog_interval: Option<String>,
+  expiry_check_interval: Option<String>,
+  lease_keepalive_interval: Option<String>,
+  let_data_grace_period: Option<String>,
+  confirmation_depth: Option<u64>,
+  max_concurrent_seals: Option<usize>,
+  max_proposal_rate: Option<usize>,
+  seal_gas_precheck: Option<bool>,
+  min_gas_balance: Option<String>,
+  max_retrieve_size: Option<usize>,
+  retrieve_min_interval: Option<String>,
+  withdraw_expired: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct EthConfig {
+  url: Option<String>,
+  master: Option<String>,
+  poll_interval: Option<String>,
+  network: Option<Vec<String>>,
+  balance_max_age: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct RpcConfig {
+  address: Option<String>,
+  max_upload_size: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct LessorConfig {
+  #[serde(deserialize_with = "deserialize_lessor_asks")]
+  ask: Option<Vec<String>>,
+  storage_quota: Option<u64>,
+}
+
+/// The `s3` flag is `enabled`, so the table holds the address too.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct S3Config {
+  enabled: Option<bool>,
+  address: Option<String>,
+}
+
+impl Config {
+  /// Values of the config keyed by the flag they set.
+  fn values(self) -> HashMap<&'static str, Vec<String>> {
+    fn one<T: ToString>(value: Option<T>) -> Option<Vec<String>> {
+      value.map(|v| vec![v.to_string()])
+    }
+    vec![
+      (ARG_ETH_URL, one(self.eth.url)),
+      (ARG_ETH_MASTER, one(self.eth.master)),
+      (ARG_ETH_POLL_INTERVAL, one(self.eth.poll_interval)),
+      (ARG_ETH_NETWORK, self.eth.network),
+      (ARG_ETH_BALANCE_MAX_AGE, one(self.eth.balance_max_age)),
+      (ARG_RPC_ADDRESS, one(self.rpc.address)),
+      (ARG_RPC_MAX_UPLOAD_SIZE, one(self.rpc.max_upload_size)),
+      (ARG_LESSOR_ASK, self.lessor.ask),
+      (ARG_LESSOR_STORAGE_QUOTA, one(self.lessor.storage_quota)),
+      (ARG_S3, one(self.s3.enabled)),
+      (ARG_S3_ADDRESS, one(self.s3.address)),
+      (ARG_DEBUG_RPC, one(self.debug_rpc)),
+      (ARG_BLOCK_SIZE, one(self.block_size)),
+      (ARG_WORKER_THREADS, one(self.worker_threads)),
+      (ARG_DATA_COMPRESSION, one(self.data_compression)),
+      (ARG_DATA_STRICT_PERMISSIONS, one(self.data_strict_permissions)),
+      (ARG_LISTEN_ADDRESS, self.listen_address),
+      (ARG_MDNS, one(self.mdns)),
+      (ARG_MAX_CONNECTIONS, one(self.max_connections)),
+      (ARG_MAX_PENDING_CONNECTIONS, one(self.max_pending_connections)),
+      (ARG_MAX_CONNECTIONS_PER_PEER, one(self.max_connections_per_peer)),
+      (ARG_SUBSTREAM_IDLE_TIMEOUT, one(self.substream_idle_timeout)),
+      (ARG_MAX_QUEUED_MESSAGES, one(self.max_queued_messages)),
+      (ARG_CHALLENGE_TIMEOUT, one(self.challenge_timeout)),
+      (ARG_RETRIEVE_TIMEOUT, one(self.retrieve_timeout)),
+      (ARG_RETRIEVE_RETRIES, one(self.retrieve_retries)),
+      (ARG_NETWORK_ID, one(self.network_id)),
+      (ARG_AGENT_VERSION, one(self.agent_version)),
+      (ARG_KNOWN_PEERS_CAPACITY, one(self.known_peers_capacity)),
+      (ARG_PROPOSAL_TIMEOUT, one(self.proposal_timeout)),
+      (ARG_PING_KEEPALIVE, one(self.ping_keepalive)),
+      (ARG_PING_INTERVAL, one(self.ping_interval)),
+      (ARG_INTEGRITY_CHECK_INTERVAL, one(self.integrity_check_interval)),
+      (ARG_CHALLENGE_WATCHDOG_INTERVAL, one(self.challenge_watchdog_interval)),
+      (ARG_EXPIRY_CHECK_INTERVAL, one(self.expiry_check_interval)),
+      (ARG_LEASE_KEEPALIVE_INTERVAL, one(self.lease_keepalive_interval)),
+      (ARG_LET_DATA_GRACE_PERIOD, one(self.let_data_grace_period)),
+      (ARG_CONFIRMATION_DEPTH, one(self.confirmation_depth)),
+      (ARG_MAX_CONCURRENT_SEALS, one(self.max_concurrent_seals)),
+      (ARG_MAX_PROPOSAL_RATE, one(self.max_proposal_rate)),
+      (ARG_SEAL_GAS_PRECHECK, one(self.seal_gas_precheck)),
+      (ARG_MIN_GAS_BALANCE, one(self.min_gas_balance)),
+      (ARG_MAX_RETRIEVE_SIZE, one(self.max_retrieve_size)),
+      (ARG_RETRIEVE_MIN_INTERVAL, one(self.retrieve_min_interval)),
+      (ARG_WITHDRAW_EXPIRED, one(self.withdraw_expired)),
+    ]
+    .into_iter()
+    .filter_map(|(name, values)| Some((name, values?)))
+    .collect()
+  }
+}
+
+fn deserialize_lessor_asks<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  let asks = Vec::<String>::deserialize(deserializer)?;
+  for ask in &asks {
+    parse_lessor_ask(ask).map_err(|e| serde::de::Error::custom(format!("invalid ask {}: {}", ask, e)))?;
+  }
+  Ok(Some(asks))
+}
+
+pub fn parse_eth_url(value: &str) -> Result<Url, String> {
+  let ipc_path = match value.split_once("://") {
+    None => value,
+    Some(("file", path)) => path,
+    Some(("ws", _)) | Some(("wss", _)) | Some(("http", _)) | Some(("https", _)) => {
+      return Url::parse(value).map_err(|e| format!("invalid url {}: {}", value, e));
+    }
+    Some((scheme, _)) => {
+      return Err(format!(
+        "unsupported scheme {}, expected ws, wss, http, https or file",
+        scheme
+      ))
+    }
   };
-  tokio::runtime::Builder::new_multi_thread()
-    .enable_all()
-    .build()
-    .unwrap()
-    .block_on(p2pim::daemon::listen_and_serve(&daemon_opts))
+
+  let mut path = match ipc_path.strip_prefix('~') {
+    Some(rest) => {
+      let mut home = dirs::home_dir().ok_or("no home dir found")?;
+      home.push(rest.trim_start_matches('/'));
+      home
+    }
+    None => PathBuf::from(ipc_path),
+  };
+  if path.is_relative() {
+    path = std::env::current_dir().map_err(|e| e.to_string())?.join(path);
+  }
+  if !path.exists() {
+    return Err(format!("IPC socket not found: {}", path.display()));
+  }
+  Url::from_file_path(&path).map_err(|_| format!("invalid IPC path: {}", path.display()))
+}
+
+/// Network in form `ADDRESS[,MASTER]`, the master record is looked up in the network deployments when not present.
+fn parse_eth_network(value: &str) -> Result<(Url, Option<web3::types::Address>), String> {
+  match value.rsplit_once(',') {
+    Some((url, master)) => {
+      let master_addr =
+        web3::types::Address::from_str(master).map_err(|e| format!("invalid master record address {}: {}", master, e))?;
+      Ok((parse_eth_url(url)?, Some(master_addr)))
+    }
+    None => Ok((parse_eth_url(value)?, None)),
+  }
+}
+
+// Part of the protocol version, so it is kept to characters that cannot be confused with its separators
+fn parse_network_id(value: &str) -> Result<String, String> {
+  if value.is_empty() {
+    return Err("network id should not be empty".to_string());
+  }
+  match value
+    .chars()
+    .find(|c| !(c.is_ascii_alphanumeric() || *c == '-' || *c == '_' || *c == '.'))
+  {
+    Some(c) => Err(format!("invalid character '{}' in network id", c)),
+    None => Ok(value.to_string()),
+  }
 }
 
 pub fn parse_lessor_ask(terms: &str) -> Result<(web3::types::Address, TokenLeaseAsk), Box<dyn std::error::Error>> {
@@ -192,3 +1099,163 @@ pub fn parse_lessor_ask(terms: &str) -> Result<(web3::types::Address, TokenLease
     },
   ))
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::cmd::testutil::TempDir;
+  use std::collections::HashSet;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::{Arc, Barrier, Mutex};
+
+  const CONFIG: &str = r#"
+block-size = 64
+mdns = true
+max-connections = 10
+listen-address = ["/ip4/127.0.0.1/tcp/4001", "/ip4/127.0.0.1/tcp/4002"]
+max-proposal-rate = 5
+
+[eth]
+url = "http://localhost:8545"
+poll-interval = "5s"
+
+[rpc]
+address = "127.0.0.1:9000"
+
+[lessor]
+ask = ["0000000000000000000000000000000000000001:1h:2h:1KB:1MB:1:0.5:0.1"]
+storage-quota = 1000
+
+[s3]
+enabled = true
+address = "127.0.0.1:9001"
+"#;
+
+  /// Options of the daemon from the config file and the flags.
+  fn load(config: &str, args: &[&str]) -> Result<DaemonOpts, Box<dyn std::error::Error>> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+      "p2pim-config-{}-{}.toml",
+      std::process::id(),
+      COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    std::fs::write(&path, config)?;
+    let mut buf = Arena::new();
+    let result = command(&mut buf)
+      .try_get_matches_from(["daemon", "--config", path.to_str().unwrap()].iter().chain(args.iter()))
+      .map_err(|e| e.into())
+      .and_then(|matches| Values::load(&matches).and_then(|values| daemon_opts(&values)));
+    // Removed whatever the outcome, so flags refused by clap leave nothing behind
+    std::fs::remove_file(&path)?;
+    result
+  }
+
+  #[test]
+  fn config_loaded() {
+    let opts = load(CONFIG, &[]).unwrap();
+    assert_eq!(opts.block_size, 64);
+    assert_eq!(opts.rpc_addr, "127.0.0.1:9000".parse().unwrap());
+    assert_eq!(opts.eth_opts.url.as_str(), "http://localhost:8545/");
+    assert_eq!(opts.eth_opts.poll_interval, std::time::Duration::from_secs(5));
+    assert!(opts.mdns_opts.enabled);
+    assert_eq!(opts.connection_opts.max_established, 10);
+    assert_eq!(opts.connection_opts.listen_addresses.len(), 2);
+    assert_eq!(opts.reactor_opts.max_proposal_rate, Some(5));
+    assert_eq!(opts.lessor_opts.token_lease_terms.len(), 1);
+    assert_eq!(opts.lessor_opts.storage_quota, Some(1000));
+    assert!(opts.s3_opts.enabled);
+    assert_eq!(opts.s3_opts.s3_addr, "127.0.0.1:9001".parse().unwrap());
+    // Flags absent from the file keep their default
+    assert_eq!(opts.connection_opts.max_pending, 32);
+  }
+
+  #[test]
+  fn flags_override_the_config() {
+    let opts = load(
+      CONFIG,
+      &["--max-connections", "20", "--listen-address", "/ip4/127.0.0.1/tcp/5001"],
+    )
+    .unwrap();
+    assert_eq!(opts.connection_opts.max_established, 20);
+    assert_eq!(
+      opts.connection_opts.listen_addresses,
+      vec!["/ip4/127.0.0.1/tcp/5001".parse().unwrap()]
+    );
+    assert_eq!(opts.block_size, 64);
+  }
+
+  #[test]
+  fn unknown_config_keys_rejected() {
+    let config = format!("{}unknown = 1\n", CONFIG);
+    assert!(load(&config, &[]).is_err());
+    let config = CONFIG.replace("[s3]", "[s3]\nport = 9001");
+    assert!(load(&config, &[]).is_err());
+    // The s3 flag is set in its table
+    let config = CONFIG.replace("[s3]\nenabled = true", "s3 = true\n[s3]");
+    assert!(load(&config, &[]).is_err());
+  }
+
+  #[test]
+  fn invalid_lessor_ask_rejected() {
+    let config = CONFIG.replace(":1h:2h:", ":2h:1h:");
+    let error = load(&config, &[]).err().unwrap();
+    assert!(error.to_string().contains("invalid ask"), "{}", error);
+  }
+
+  #[test]
+  fn zero_proposal_rate_rejected() {
+    assert!(load(CONFIG, &["--max-proposal-rate", "0"]).is_err());
+    assert!(load(&CONFIG.replace("max-proposal-rate = 5", "max-proposal-rate = 0"), &[]).is_err());
+  }
+
+  #[test]
+  fn missing_ipc_socket_rejected() {
+    let folder = TempDir::new();
+    let socket = folder.path().join("geth.ipc");
+
+    let error = parse_eth_url(&format!("file://{}", socket.display())).unwrap_err();
+
+    assert!(error.contains("IPC socket not found"), "{}", error);
+    std::fs::write(&socket, b"").unwrap();
+    assert_eq!(
+      parse_eth_url(&format!("file://{}", socket.display())),
+      Ok(Url::from_file_path(&socket).unwrap())
+    );
+    assert_eq!(
+      parse_eth_url(socket.to_str().unwrap()),
+      Ok(Url::from_file_path(&socket).unwrap())
+    );
+  }
+
+  #[test]
+  fn unsupported_eth_scheme_rejected() {
+    let error = parse_eth_url("ftp://localhost:8545").unwrap_err();
+    assert!(error.contains("unsupported scheme ftp"), "{}", error);
+    assert!(parse_eth_url("http://localhost:8545").is_ok());
+    assert!(parse_eth_url("wss://localhost:8546").is_ok());
+  }
+
+  #[test]
+  fn worker_threads_applied_to_the_runtime() {
+    let runtime = build_runtime(Some(NonZeroUsize::new(3).unwrap())).unwrap();
+    let threads = Arc::new(Mutex::new(HashSet::new()));
+    // Tasks blocked on the barrier only all get through with a worker each
+    let barrier = Arc::new(Barrier::new(3));
+    let tasks = (0..23)
+      .map(|i| {
+        let threads = threads.clone();
+        let barrier = barrier.clone();
+        runtime.spawn(async move {
+          if i < 3 {
+            barrier.wait();
+          }
+          threads.lock().unwrap().insert(std::thread::current().id());
+        })
+      })
+      .collect::<Vec<_>>();
+
+    runtime.block_on(futures::future::try_join_all(tasks)).unwrap();
+
+    assert_eq!(threads.lock().unwrap().len(), 3);
+  }
+}
@@ -0,0 +1,121 @@
+use crate::cmd::{arg_format, arg_url, OutputFormat, ARG_FORMAT, ARG_URL};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use clap::{ArgMatches, Command};
+use p2pim::onchain;
+use p2pim::proto::api::p2pim_client::P2pimClient;
+use p2pim::proto::api::{AuditRole, GetAuditLogRequest};
+use p2pim::types::Signature;
+use std::convert::TryFrom;
+
+pub const CMD_NAME: &str = "audit";
+
+pub fn command<'a>() -> Command<'a> {
+  Command::new(CMD_NAME)
+    .about("dump the log of challenges answered and verified")
+    .arg(arg_url())
+    .arg(arg_format())
+}
+
+pub fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+  let rpc_url = matches.value_of_t(ARG_URL)?;
+  let format = matches.value_of_t(ARG_FORMAT)?;
+  tokio::runtime::Builder::new_multi_thread()
+    .enable_all()
+    .build()
+    .unwrap()
+    .block_on(run_audit(rpc_url, format))
+}
+
+async fn run_audit(rpc_url: String, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+  let mut client = P2pimClient::connect(rpc_url).await?;
+  let response = client.get_audit_log(GetAuditLogRequest {}).await?.into_inner();
+  let mut json_entries = Vec::new();
+  for (i, entry) in response.entries.iter().enumerate() {
+    let peer_id = entry
+      .peer_id
+      .as_ref()
+      .map(libp2p::PeerId::try_from)
+      .ok_or("empty peer_id")??;
+    let timestamp = entry
+      .timestamp
+      .clone()
+      .map(|ts| DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(ts.seconds, ts.nanos as u32), Utc))
+      .ok_or("empty timestamp")?;
+    let role = match AuditRole::from_i32(entry.role) {
+      Some(AuditRole::Challenger) => "challenger",
+      Some(AuditRole::Prover) => "prover",
+      _ => "unknown",
+    };
+    let proof_hash = entry
+      .proof_hash
+      .as_ref()
+      .map(web3::types::H256::from)
+      .ok_or("empty proof_hash")?;
+    let hash = entry.hash.as_ref().map(web3::types::H256::from).ok_or("empty hash")?;
+
+    match format {
+      OutputFormat::Text => {
+        println!("{}: {} - {}", i, peer_id, entry.nonce);
+        println!("  Timestamp   : {}", timestamp);
+        println!("  Role        : {}", role);
+        println!("  Block Number: {}", entry.block_number);
+        println!("  Verified    : {}", if entry.verified { "yes" } else { "no" });
+        println!("  Proof Hash  : 0x{:x}", proof_hash);
+        println!("  Entry Hash  : 0x{:x}", hash);
+      }
+      OutputFormat::Json => json_entries.push(serde_json::json!({
+        "peer_id": peer_id.to_base58(),
+        "nonce": entry.nonce,
+        "timestamp": timestamp.to_rfc3339(),
+        "role": role,
+        "block_number": entry.block_number,
+        "verified": entry.verified,
+        "proof_hash": format!("0x{:x}", proof_hash),
+        "hash": format!("0x{:x}", hash),
+      })),
+    }
+  }
+
+  let signer_address: web3::types::Address = response
+    .signer_address
+    .as_ref()
+    .ok_or("invalid response: signer address empty")?
+    .into();
+  // Checked locally, the daemon only signs the head it serves
+  let signature_valid = match response.entries.last().and_then(|e| e.hash.as_ref()) {
+    Some(head) => {
+      let signature = Signature::deserialize(response.head_signature.as_slice())?;
+      onchain::recover_message_signer(web3::types::H256::from(head).as_bytes(), &signature)? == signer_address
+    }
+    None => true,
+  };
+
+  match format {
+    OutputFormat::Text => {
+      println!("Hash chain: {}", if response.consistent { "consistent" } else { "BROKEN" });
+      println!(
+        "Signed by : 0x{:x} ({})",
+        signer_address,
+        if signature_valid { "valid" } else { "INVALID" }
+      );
+    }
+    OutputFormat::Json => println!(
+      "{}",
+      serde_json::json!({
+        "entries": json_entries,
+        "consistent": response.consistent,
+        "signer_address": format!("0x{:x}", signer_address),
+        "head_signature": format!("0x{}", hex::encode(&response.head_signature)),
+        "signature_valid": signature_valid,
+      })
+    ),
+  }
+
+  if !response.consistent {
+    Err("audit log hash chain is not consistent".into())
+  } else if !signature_valid {
+    Err("audit log head is not signed by the node".into())
+  } else {
+    Ok(())
+  }
+}
@@ -1,8 +1,10 @@
-use crate::cmd::{arg_url, ARG_URL};
+use crate::cmd::{
+  arg_format, arg_timeout, arg_url, parse_peer_id, peer_id_of, timeout_of, with_timeout, OutputFormat, ARG_FORMAT, ARG_URL,
+};
 use clap::{Arg, ArgMatches, Command};
 use libp2p::PeerId;
 use p2pim::proto::api::p2pim_client::P2pimClient;
-use p2pim::proto::api::ChallengeRequest;
+use p2pim::proto::api::{ChallengeRandomRequest, ChallengeRequest, ChallengeResponse};
 
 pub const CMD_NAME: &str = "challenge";
 
@@ -14,6 +16,8 @@ pub fn command<'a>() -> Command<'a> {
   Command::new(CMD_NAME)
     .about("challenge lease to peer")
     .arg(arg_url())
+    .arg(arg_format())
+    .arg(arg_timeout())
     .arg(arg_peer_id())
     .arg(arg_nonce())
     .arg(arg_block())
@@ -30,6 +34,7 @@ fn arg_nonce<'a>() -> Arg<'a> {
 fn arg_peer_id<'a>() -> Arg<'a> {
   Arg::new(ARG_PEER_ID)
     .takes_value(true)
+    .validator(parse_peer_id)
     .required(true)
     .help("peer of the lease")
 }
@@ -37,36 +42,102 @@ fn arg_peer_id<'a>() -> Arg<'a> {
 fn arg_block<'a>() -> Arg<'a> {
   Arg::new(ARG_BLOCK_NUMBER)
     .takes_value(true)
-    .required(true)
+    .required(false)
     .validator(str::parse::<u32>)
-    .help("block to request")
+    .help("block to request, derived from the hash of the latest confirmed chain block if not present")
 }
 
 pub fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
   let rpc_url = matches.value_of_t(ARG_URL)?;
-  let peer_id = matches.value_of_t(ARG_PEER_ID)?;
+  let format = matches.value_of_t(ARG_FORMAT)?;
+  let peer_id = peer_id_of(matches, ARG_PEER_ID)?;
   let nonce = matches.value_of_t(ARG_NONCE)?;
-  let block_number = matches.value_of_t(ARG_BLOCK_NUMBER)?;
+  let block_number = matches.value_of(ARG_BLOCK_NUMBER).map(str::parse::<u32>).transpose()?;
+  let timeout = timeout_of(matches)?;
   tokio::runtime::Builder::new_multi_thread()
     .enable_all()
     .build()
     .unwrap()
-    .block_on(run_challenge(rpc_url, peer_id, nonce, block_number))
+    .block_on(with_timeout(
+      timeout,
+      run_challenge(rpc_url, format, peer_id, nonce, block_number),
+    ))
 }
 
 async fn run_challenge(
   rpc_url: String,
+  format: OutputFormat,
   peer_id: PeerId,
   nonce: u64,
-  block_number: u32,
+  block_number: Option<u32>,
 ) -> Result<(), Box<dyn std::error::Error>> {
   let mut client = P2pimClient::connect(rpc_url).await?;
-  let challenge_request = ChallengeRequest {
-    peer_id: Some(peer_id.into()),
-    nonce,
-    block_number,
+  let (block_number, seed, response_dto) = match block_number {
+    Some(block_number) => {
+      let challenge_request = ChallengeRequest {
+        peer_id: Some(peer_id.into()),
+        nonce,
+        block_number,
+      };
+      let response = client.challenge(challenge_request).await?.into_inner();
+      (block_number, None, response)
+    }
+    None => {
+      let challenge_request = ChallengeRandomRequest {
+        peer_id: Some(peer_id.into()),
+        nonce,
+      };
+      let response = client.challenge_random(challenge_request).await?.into_inner();
+      let seed_block_hash = response
+        .seed_block_hash
+        .as_ref()
+        .map(web3::types::H256::from)
+        .ok_or("invalid response: seed block hash empty")?;
+      let seed = (response.seed_block_number, seed_block_hash);
+      let challenge_response = ChallengeResponse {
+        block_data: response.block_data,
+        proof: response.proof,
+        verified: response.verified,
+      };
+      (response.block_number, Some(seed), challenge_response)
+    }
   };
-  let _ = client.challenge(challenge_request).await?;
-  println!("Challenge Ok");
-  Ok(())
+  let proof = response_dto
+    .proof
+    .iter()
+    .map(|p| format!("0x{:x}", web3::types::H256::from(p)))
+    .collect::<Vec<String>>();
+  let verified = response_dto.verified;
+  match format {
+    OutputFormat::Text => {
+      if let Some((seed_block_number, seed_block_hash)) = seed {
+        println!("Seed Block: {} (0x{:x})", seed_block_number, seed_block_hash);
+      }
+      println!("Block     : {}", block_number);
+      println!("Verified  : {}", if verified { "yes" } else { "no" });
+      println!("Block Data: {} bytes", response_dto.block_data.len());
+      println!("Proof     :");
+      proof.iter().for_each(|p| println!("  {}", p));
+    }
+    OutputFormat::Json => {
+      let mut json = serde_json::json!({
+        "peer_id": peer_id.to_base58(),
+        "nonce": nonce,
+        "block_number": block_number,
+        "verified": verified,
+        "block_data": hex::encode(&response_dto.block_data),
+        "proof": proof,
+      });
+      if let Some((seed_block_number, seed_block_hash)) = seed {
+        json["seed_block_number"] = seed_block_number.into();
+        json["seed_block_hash"] = format!("0x{:x}", seed_block_hash).into();
+      }
+      println!("{}", json);
+    }
+  }
+  if verified {
+    Ok(())
+  } else {
+    Err("challenge proof not valid".into())
+  }
 }
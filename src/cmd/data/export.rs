@@ -0,0 +1,207 @@
+use crate::cmd::data::retrieve::merkle_root_of;
+use crate::cmd::{arg_url, ARG_URL};
+use clap::{Arg, ArgMatches, Command};
+use futures::StreamExt;
+use libp2p::PeerId;
+use p2pim::cryptography;
+use p2pim::proto::api::p2pim_client::P2pimClient;
+use p2pim::proto::api::{GetLeaseRequest, ListStorageRentedRequest, RetrieveRequest};
+use std::convert::TryFrom;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tonic::transport::Channel;
+
+pub const CMD_NAME: &str = "export";
+
+const ARG_CONCURRENCY: &str = "concurrency";
+const ARG_CONCURRENCY_DEFAULT: &str = "4";
+const ARG_DIR: &str = "dir";
+
+pub fn command<'a>() -> Command<'a> {
+  Command::new(CMD_NAME)
+    .about("retrieve all the rented data into a directory")
+    .arg(arg_url())
+    .arg(arg_dir())
+    .arg(arg_concurrency())
+}
+
+fn arg_concurrency<'a>() -> Arg<'a> {
+  Arg::new(ARG_CONCURRENCY)
+    .long(ARG_CONCURRENCY)
+    .takes_value(true)
+    .value_name("N")
+    .default_value(ARG_CONCURRENCY_DEFAULT)
+    .validator(str::parse::<NonZeroUsize>)
+    .help("maximum number of leases retrieved at the same time")
+}
+
+fn arg_dir<'a>() -> Arg<'a> {
+  Arg::new(ARG_DIR)
+    .takes_value(true)
+    .required(true)
+    .value_name("DIR")
+    .help("directory where to write the data, as <dir>/<peer>/<nonce>")
+}
+
+pub fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+  let rpc_url = matches.value_of_t(ARG_URL)?;
+  let dir = PathBuf::from(matches.value_of(ARG_DIR).unwrap());
+  let concurrency: NonZeroUsize = matches.value_of_t(ARG_CONCURRENCY)?;
+  tokio::runtime::Builder::new_multi_thread()
+    .enable_all()
+    .build()
+    .unwrap()
+    .block_on(run_export(rpc_url, dir, concurrency.get()))
+}
+
+async fn run_export(rpc_url: String, dir: PathBuf, concurrency: usize) -> Result<(), Box<dyn std::error::Error>> {
+  let mut client = P2pimClient::connect(rpc_url).await?;
+  let response = client.list_storage_rented(ListStorageRentedRequest {}).await?;
+  let cryptography = cryptography::new_service(cryptography::BLOCK_SIZE_BYTES)?;
+
+  let mut exported = 0;
+  let mut failed = Vec::new();
+  let mut leases = Vec::new();
+  for data in response.into_inner().storage_rented_data {
+    match data.peer_id.as_ref().map(PeerId::try_from) {
+      Some(Ok(peer_id)) => leases.push((peer_id, data.nonce)),
+      _ => failed.push(format!("<invalid peer> - {}: empty or invalid peer_id", data.nonce)),
+    }
+  }
+
+  // Each retrieve holds the whole lease data in memory, so only a few of them run at the same time
+  let mut results = futures::stream::iter(leases)
+    .map(|(peer_id, nonce)| {
+      let mut client = client.clone();
+      let cryptography = &cryptography;
+      let dir = dir.as_path();
+      async move {
+        let result = export_lease(&mut client, cryptography, dir, peer_id, nonce).await;
+        (peer_id, nonce, result)
+      }
+    })
+    .buffer_unordered(concurrency);
+  while let Some((peer_id, nonce, result)) = results.next().await {
+    match result {
+      Ok(path) => {
+        println!("exported {} - {} to {}", peer_id, nonce, path.display());
+        exported += 1;
+      }
+      Err(e) => failed.push(format!("{} - {}: {}", peer_id, nonce, e)),
+    }
+  }
+
+  println!("Exported: {}", exported);
+  println!("Failed  : {}", failed.len());
+  for failure in failed.iter() {
+    println!("  {}", failure);
+  }
+
+  if failed.is_empty() {
+    Ok(())
+  } else {
+    Err(format!("{} leases could not be exported", failed.len()).into())
+  }
+}
+
+async fn export_lease<C: cryptography::Service>(
+  client: &mut P2pimClient<Channel>,
+  cryptography: &C,
+  dir: &Path,
+  peer_id: PeerId,
+  nonce: u64,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+  let lease = client
+    .get_lease(GetLeaseRequest {
+      peer_id: Some(peer_id.into()),
+      nonce,
+    })
+    .await?
+    .into_inner();
+  let data = client
+    .retrieve(RetrieveRequest {
+      peer_id: Some(peer_id.into()),
+      nonce,
+    })
+    .await?
+    .into_inner()
+    .data;
+
+  if data.len() as u64 != lease.size {
+    return Err(format!("unexpected data size, expected={}, received={}", lease.size, data.len()).into());
+  }
+  if merkle_root_of(cryptography, data.as_slice()).as_slice() != lease.merkle_root.as_slice() {
+    return Err("received data does not match with the merkle root".into());
+  }
+
+  let peer_dir = dir.join(peer_id.to_base58());
+  tokio::fs::create_dir_all(&peer_dir).await?;
+  let path = peer_dir.join(nonce.to_string());
+  let mut file = tokio::fs::File::create(&path).await?;
+  file.write_all(data.as_slice()).await?;
+  file.flush().await?;
+  Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::cmd::testutil::{sample_data, FakeDaemon, TempDir};
+
+  #[tokio::test]
+  async fn leases_exported() {
+    let daemon = FakeDaemon::default();
+    let (peer_a, peer_b) = (PeerId::random(), PeerId::random());
+    daemon.add_lease(peer_a, 1, sample_data(2));
+    daemon.add_lease(peer_b, 2, sample_data(3));
+    let url = daemon.serve().await;
+    let folder = TempDir::new();
+
+    run_export(url, folder.path().to_path_buf(), 2).await.unwrap();
+
+    let exported =
+      |peer_id: PeerId, nonce: u64| std::fs::read(folder.path().join(peer_id.to_base58()).join(nonce.to_string())).unwrap();
+    assert_eq!(exported(peer_a, 1), sample_data(2));
+    assert_eq!(exported(peer_b, 2), sample_data(3));
+  }
+
+  #[tokio::test]
+  async fn retrieves_bounded_by_the_concurrency() {
+    let daemon = FakeDaemon::default();
+    let leases = (1..=6).map(|nonce| (PeerId::random(), nonce)).collect::<Vec<_>>();
+    for (peer_id, nonce) in leases.iter() {
+      daemon.add_lease(*peer_id, *nonce, sample_data(*nonce as usize));
+    }
+    let url = daemon.serve().await;
+    let folder = TempDir::new();
+
+    run_export(url, folder.path().to_path_buf(), 2).await.unwrap();
+
+    assert_eq!(daemon.state.lock().unwrap().max_retrieving, 2);
+    for (peer_id, nonce) in leases {
+      let path = folder.path().join(peer_id.to_base58()).join(nonce.to_string());
+      assert_eq!(std::fs::read(path).unwrap(), sample_data(nonce as usize));
+    }
+  }
+
+  #[tokio::test]
+  async fn export_continues_past_a_failed_lease() {
+    let daemon = FakeDaemon::default();
+    let (peer_a, peer_b) = (PeerId::random(), PeerId::random());
+    daemon.add_lease(peer_a, 1, sample_data(2));
+    daemon.add_lease(peer_b, 2, sample_data(3));
+    let mut tampered = sample_data(3);
+    tampered[5] ^= 1;
+    daemon.state.lock().unwrap().tampered.insert((peer_b, 2), tampered);
+    let url = daemon.serve().await;
+    let folder = TempDir::new();
+
+    let result = run_export(url, folder.path().to_path_buf(), 1).await;
+
+    assert_eq!(result.unwrap_err().to_string(), "1 leases could not be exported");
+    let path = |peer_id: PeerId, nonce: u64| folder.path().join(peer_id.to_base58()).join(nonce.to_string());
+    assert_eq!(std::fs::read(path(peer_a, 1)).unwrap(), sample_data(2));
+    assert!(!path(peer_b, 2).exists());
+  }
+}
@@ -1,33 +1,78 @@
-use crate::cmd::{arg_url, ARG_URL};
+use crate::cmd::{
+  arg_decimals, arg_format, arg_url, format_amount, token_decimals, OutputFormat, ARG_DECIMALS, ARG_FORMAT, ARG_URL,
+};
+use bigdecimal::BigDecimal;
 use chrono::{DateTime, NaiveDateTime, Utc};
 use clap::{ArgMatches, Command};
+use p2pim::proto::api::list_storage_rented_response::StorageRentedData;
 use p2pim::proto::api::p2pim_client::P2pimClient;
-use p2pim::proto::api::ListStorageRentedRequest;
+use p2pim::proto::api::{GetBalanceRequest, ListStorageRentedRequest};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::fmt::Write;
 
 pub const LIST_CMD: &str = "list";
 
 pub fn command<'a>() -> Command<'a> {
-  Command::new(LIST_CMD).about("list rented storage").arg(arg_url())
+  Command::new(LIST_CMD)
+    .about("list rented storage")
+    .arg(arg_url())
+    .arg(arg_format())
+    .arg(arg_decimals())
 }
 
 pub fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
   let rpc_url = matches.value_of_t(ARG_URL)?;
+  let format = matches.value_of_t(ARG_FORMAT)?;
+  let decimals = matches.value_of_t(ARG_DECIMALS)?;
   tokio::runtime::Builder::new_multi_thread()
     .enable_all()
     .build()
     .unwrap()
-    .block_on(run_list(rpc_url))
+    .block_on(run_list(rpc_url, format, decimals))
 }
 
-async fn run_list(rpc_url: String) -> Result<(), Box<dyn std::error::Error>> {
+async fn run_list(rpc_url: String, format: OutputFormat, decimals: u32) -> Result<(), Box<dyn std::error::Error>> {
   let mut client = P2pimClient::connect(rpc_url).await?;
   let list_storage_request = ListStorageRentedRequest {};
   let response = client.list_storage_rented(list_storage_request).await?;
-  for (i, data) in response.get_ref().storage_rented_data.iter().enumerate() {
+  let mut tokens_decimals = HashMap::new();
+  for data in response.get_ref().storage_rented_data.iter() {
+    let token_address: web3::types::Address = data.token_address.as_ref().ok_or("empty token_address")?.into();
+    if let Entry::Vacant(entry) = tokens_decimals.entry(token_address) {
+      let get_balance_request = GetBalanceRequest {
+        token_address: Some(token_address.into()),
+        network: String::new(),
+        refresh: false,
+      };
+      let response = client.get_balance(get_balance_request).await?;
+      entry.insert(token_decimals(response.get_ref())?);
+    }
+  }
+  let output = format_leases(&response.get_ref().storage_rented_data, &tokens_decimals, format, decimals)?;
+  if !output.is_empty() {
+    println!("{}", output);
+  }
+  Ok(())
+}
+
+fn format_leases(
+  leases: &[StorageRentedData],
+  tokens_decimals: &HashMap<web3::types::Address, i64>,
+  format: OutputFormat,
+  decimals: u32,
+) -> Result<String, Box<dyn std::error::Error>> {
+  let mut result = String::new();
+  let mut json_entries = Vec::new();
+  for (i, data) in leases.iter().enumerate() {
     let peer_id = data.peer_id.as_ref().map(libp2p::PeerId::try_from).ok_or("empty peer_id")??;
     let nonce = data.nonce;
-    println!("{}: {} - {}", i, peer_id, nonce);
+
+    let token_address: web3::types::Address = data.token_address.as_ref().ok_or("empty token_address")?.into();
+    let token_scale = *tokens_decimals.get(&token_address).ok_or("unknown token decimals")?;
+    let price = BigDecimal::new(data.price.as_ref().ok_or("empty price")?.into(), token_scale);
+    let penalty = BigDecimal::new(data.penalty.as_ref().ok_or("empty penalty")?.into(), token_scale);
 
     let duration = data
       .lease_duration
@@ -37,20 +82,104 @@ async fn run_list(rpc_url: String) -> Result<(), Box<dyn std::error::Error>> {
       .map_err(|_| "negative lease_duration")?;
 
     let tx_hash = data.transaction_hash.as_ref().map(web3::types::H256::from);
-    let tx_ts = data.lease_started.clone();
-    println!("  Lease Duration  : {:?}", duration);
-    if let (Some(hash), Some(ts)) = (tx_hash, tx_ts) {
-      let ts2 = DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(ts.seconds, 0), Utc);
-      println!("  Transaction Hash : 0x{:x}", hash);
-      println!("  Transaction Start: {}", ts2);
-      println!(
-        "  Lease Ends       : {}",
-        ts2 + chrono::Duration::seconds(duration.as_secs() as i64)
-      );
-    } else {
-      println!("  Transaction Hash: Not confirmed",);
+    let tx_ts = data
+      .lease_started
+      .clone()
+      .map(|ts| DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(ts.seconds, 0), Utc));
+    let lease_ends = tx_ts.map(|ts| ts + chrono::Duration::seconds(duration.as_secs() as i64));
+
+    match format {
+      OutputFormat::Text => {
+        writeln!(result, "{}: {} - {}", i, peer_id, nonce)?;
+        writeln!(result, "  Token           : 0x{:x}", token_address)?;
+        writeln!(result, "  Price           : {}", format_amount(&price, decimals))?;
+        writeln!(result, "  Penalty         : {}", format_amount(&penalty, decimals))?;
+        writeln!(result, "  Lease Duration  : {:?}", duration)?;
+        if let (Some(hash), Some(ts), Some(ends)) = (tx_hash, tx_ts, lease_ends) {
+          writeln!(result, "  Transaction Hash : 0x{:x}", hash)?;
+          writeln!(result, "  Transaction Start: {}", ts)?;
+          writeln!(result, "  Lease Ends       : {}", ends)?;
+        } else {
+          writeln!(result, "  Transaction Hash: Not confirmed",)?;
+        }
+      }
+      OutputFormat::Json => json_entries.push(serde_json::json!({
+        "peer_id": peer_id.to_base58(),
+        "nonce": nonce,
+        "token_address": format!("0x{:x}", token_address),
+        "price": price.to_string(),
+        "penalty": penalty.to_string(),
+        "lease_duration_secs": duration.as_secs(),
+        "transaction_hash": tx_hash.map(|h| format!("0x{:x}", h)),
+        "lease_started": tx_ts.map(|ts| ts.to_rfc3339()),
+        "lease_ends": lease_ends.map(|ts| ts.to_rfc3339()),
+      })),
     }
   }
 
-  Ok(())
+  if format == OutputFormat::Json {
+    write!(result, "{}", serde_json::Value::Array(json_entries))?;
+  } else {
+    // The last line break is added when printed
+    result.pop();
+  }
+
+  Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use web3::types::{Address, H256, U256};
+
+  #[test]
+  fn json_parsed_back() {
+    let peer_id = libp2p::PeerId::random();
+    let token_address = Address::repeat_byte(1);
+    let leases = vec![
+      StorageRentedData {
+        peer_id: Some(peer_id.into()),
+        nonce: 3,
+        token_address: Some(token_address.into()),
+        price: Some(U256::from(1250).into()),
+        penalty: Some(U256::from(300).into()),
+        proposal_expiration: None,
+        lease_duration: Some(prost_types::Duration { seconds: 3600, nanos: 0 }),
+        transaction_hash: Some(H256::repeat_byte(2).into()),
+        lease_started: Some(prost_types::Timestamp {
+          seconds: 1_600_000_000,
+          nanos: 0,
+        }),
+      },
+      StorageRentedData {
+        peer_id: Some(peer_id.into()),
+        nonce: 4,
+        token_address: Some(token_address.into()),
+        price: Some(U256::from(1).into()),
+        penalty: Some(U256::from(1).into()),
+        proposal_expiration: None,
+        lease_duration: Some(prost_types::Duration { seconds: 60, nanos: 0 }),
+        transaction_hash: None,
+        lease_started: None,
+      },
+    ];
+    let tokens_decimals = vec![(token_address, 2)].into_iter().collect();
+
+    let output = format_leases(&leases, &tokens_decimals, OutputFormat::Json, 4).unwrap();
+    let json: serde_json::Value = serde_json::from_str(output.as_str()).unwrap();
+
+    assert_eq!(json.as_array().unwrap().len(), 2);
+    let sealed = &json[0];
+    assert_eq!(sealed["peer_id"], peer_id.to_base58());
+    assert_eq!(sealed["nonce"], 3);
+    assert_eq!(sealed["token_address"], format!("0x{:x}", token_address));
+    assert_eq!(sealed["price"], "12.50");
+    assert_eq!(sealed["penalty"], "3.00");
+    assert_eq!(sealed["lease_duration_secs"], 3600);
+    assert_eq!(sealed["transaction_hash"], format!("0x{:x}", H256::repeat_byte(2)));
+    assert_eq!(sealed["lease_started"], "2020-09-13T12:26:40+00:00");
+    assert_eq!(sealed["lease_ends"], "2020-09-13T13:26:40+00:00");
+    assert!(json[1]["transaction_hash"].is_null());
+    assert!(json[1]["lease_ends"].is_null());
+  }
 }
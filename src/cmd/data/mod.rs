@@ -1,9 +1,17 @@
 use clap::{ArgMatches, Command};
 
+pub mod audit;
 pub mod challenge;
+pub mod export;
 pub mod list;
+pub mod params;
+pub mod providers;
+pub mod reindex;
 pub mod retrieve;
+pub mod retrieve_block;
+pub mod retrieve_blocks;
 pub mod store;
+pub mod verify_signature;
 
 pub const DATA_CMD: &str = "data";
 
@@ -12,18 +20,34 @@ pub fn command<'a>() -> Command<'a> {
     .about("data related commands")
     .subcommand_required(true)
     .arg_required_else_help(true)
+    .subcommand(audit::command())
     .subcommand(challenge::command())
+    .subcommand(export::command())
     .subcommand(list::command())
+    .subcommand(params::command())
+    .subcommand(providers::command())
+    .subcommand(reindex::command())
     .subcommand(retrieve::command())
+    .subcommand(retrieve_block::command())
+    .subcommand(retrieve_blocks::command())
     .subcommand(store::command())
+    .subcommand(verify_signature::command())
 }
 
 pub fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
   match matches.subcommand() {
+    Some((audit::CMD_NAME, m)) => audit::run(m),
     Some((challenge::CMD_NAME, m)) => challenge::run(m),
+    Some((export::CMD_NAME, m)) => export::run(m),
     Some((list::LIST_CMD, m)) => list::run(m),
+    Some((params::CMD_NAME, m)) => params::run(m),
+    Some((providers::CMD_NAME, m)) => providers::run(m),
+    Some((reindex::CMD_NAME, m)) => reindex::run(m),
     Some((retrieve::CMD_NAME, m)) => retrieve::run(m),
+    Some((retrieve_block::CMD_NAME, m)) => retrieve_block::run(m),
+    Some((retrieve_blocks::CMD_NAME, m)) => retrieve_blocks::run(m),
     Some((store::STORE_CMD, m)) => store::run(m),
+    Some((verify_signature::CMD_NAME, m)) => verify_signature::run(m),
     _ => unreachable!("this should not happen if we have all the cases covered"),
   }
 }
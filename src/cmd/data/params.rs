@@ -0,0 +1,55 @@
+use crate::cmd::{arg_format, arg_url, OutputFormat, ARG_FORMAT, ARG_URL};
+use clap::{Arg, ArgMatches, Command};
+use p2pim::proto::api::p2pim_client::P2pimClient;
+use p2pim::proto::api::ComputeParametersRequest;
+
+pub const CMD_NAME: &str = "params";
+
+const ARG_DATA_FILE: &str = "data_file";
+
+pub fn command<'a>() -> Command<'a> {
+  Command::new(CMD_NAME)
+    .about("compute the merkle root and size of data without leasing it")
+    .arg(arg_url())
+    .arg(arg_format())
+    .arg(arg_data_file())
+}
+
+fn arg_data_file<'a>() -> Arg<'a> {
+  Arg::new(ARG_DATA_FILE)
+    .takes_value(true)
+    .required(true)
+    .help("file to compute the parameters of")
+}
+
+pub fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+  let rpc_url = matches.value_of_t(ARG_URL)?;
+  let format = matches.value_of_t(ARG_FORMAT)?;
+  let data_file: String = matches.value_of_t(ARG_DATA_FILE)?;
+  tokio::runtime::Builder::new_multi_thread()
+    .enable_all()
+    .build()
+    .unwrap()
+    .block_on(run_params(rpc_url, format, data_file))
+}
+
+async fn run_params(rpc_url: String, format: OutputFormat, data_file: String) -> Result<(), Box<dyn std::error::Error>> {
+  let data = tokio::fs::read(data_file).await?;
+  let mut client = P2pimClient::connect(rpc_url).await?;
+  let response = client.compute_parameters(ComputeParametersRequest { data }).await?;
+  let response_dto = response.get_ref();
+  match format {
+    OutputFormat::Text => {
+      println!("Merkle Root: 0x{}", hex::encode(&response_dto.merkle_root));
+      println!("Size       : {} bytes", response_dto.size);
+    }
+    OutputFormat::Json => {
+      let json = serde_json::json!({
+        "merkle_root": format!("0x{}", hex::encode(&response_dto.merkle_root)),
+        "size": response_dto.size,
+      });
+      println!("{}", json);
+    }
+  }
+  Ok(())
+}
@@ -0,0 +1,141 @@
+use crate::cmd::data::store::convert_amount;
+use crate::cmd::{
+  arg_format, arg_timeout, arg_token, arg_url, timeout_of, token_decimals, with_timeout, OutputFormat, ARG_FORMAT, ARG_TOKEN,
+  ARG_URL,
+};
+use bigdecimal::BigDecimal;
+use clap::{Arg, ArgMatches, Command};
+use libp2p::PeerId;
+use p2pim::proto::api::p2pim_client::P2pimClient;
+use p2pim::proto::api::{FindProvidersRequest, GetBalanceRequest};
+use std::convert::TryInto;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const CMD_NAME: &str = "providers";
+
+const ARG_DURATION: &str = "duration";
+const ARG_PENALTY: &str = "penalty";
+const ARG_PRICE: &str = "price";
+const ARG_SIZE: &str = "size";
+
+pub fn command<'a>() -> Command<'a> {
+  Command::new(CMD_NAME)
+    .about("list known peers that would accept a lease with the given terms")
+    .arg(arg_url())
+    .arg(arg_format())
+    .arg(arg_timeout())
+    .arg(arg_token().long(ARG_TOKEN))
+    .arg(arg_price())
+    .arg(arg_penalty())
+    .arg(arg_duration())
+    .arg(arg_size())
+}
+
+fn arg_duration<'a>() -> Arg<'a> {
+  Arg::new(ARG_DURATION)
+    .long(ARG_DURATION)
+    .takes_value(true)
+    .required(true)
+    .validator(parse_duration::parse)
+    .help("duration of the lease")
+}
+
+fn arg_penalty<'a>() -> Arg<'a> {
+  Arg::new(ARG_PENALTY)
+    .long(ARG_PENALTY)
+    .takes_value(true)
+    .required(true)
+    .validator(bigdecimal::BigDecimal::from_str)
+    .help("penalty applied to the lessor in case storage lost")
+}
+
+fn arg_price<'a>() -> Arg<'a> {
+  Arg::new(ARG_PRICE)
+    .long(ARG_PRICE)
+    .takes_value(true)
+    .required(true)
+    .validator(bigdecimal::BigDecimal::from_str)
+    .help("price for the lease")
+}
+
+fn arg_size<'a>() -> Arg<'a> {
+  Arg::new(ARG_SIZE)
+    .long(ARG_SIZE)
+    .takes_value(true)
+    .required(true)
+    .validator(str::parse::<u64>)
+    .help("size in bytes of the data to store")
+}
+
+pub fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+  let rpc_url = matches.value_of_t(ARG_URL)?;
+  let format = matches.value_of_t(ARG_FORMAT)?;
+  let token_addr = matches.value_of_t(ARG_TOKEN)?;
+  let price = matches.value_of_t(ARG_PRICE)?;
+  let penalty = matches.value_of_t(ARG_PENALTY)?;
+  let duration = parse_duration::parse(matches.value_of_t::<String>(ARG_DURATION)?.as_str())?;
+  let size = matches.value_of_t(ARG_SIZE)?;
+  let timeout = timeout_of(matches)?;
+  tokio::runtime::Builder::new_multi_thread()
+    .enable_all()
+    .build()
+    .unwrap()
+    .block_on(with_timeout(
+      timeout,
+      run_providers(rpc_url, format, token_addr, price, penalty, duration, size),
+    ))
+}
+
+async fn run_providers(
+  rpc_url: String,
+  format: OutputFormat,
+  token_addr: web3::types::Address,
+  price: BigDecimal,
+  penalty: BigDecimal,
+  duration: Duration,
+  size: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+  let mut client = P2pimClient::connect(rpc_url).await?;
+  let get_balance_request = GetBalanceRequest {
+    token_address: Some(token_addr.into()),
+    network: String::new(),
+    refresh: false,
+  };
+  let response = client.get_balance(get_balance_request).await?;
+  let decimals = token_decimals(response.get_ref())?;
+
+  let request = FindProvidersRequest {
+    token_address: Some(token_addr.into()),
+    price: Some(convert_amount(price, decimals, "price")?.try_into()?),
+    penalty: Some(convert_amount(penalty, decimals, "penalty")?.try_into()?),
+    lease_duration: Some(prost_types::Duration {
+      seconds: duration.as_secs() as i64,
+      nanos: 0,
+    }),
+    size,
+  };
+  let response = client.find_providers(request).await?;
+  let providers = response
+    .get_ref()
+    .peer_list
+    .iter()
+    .map(|p| PeerId::from_bytes(p.data.as_slice()))
+    .collect::<Result<Vec<PeerId>, _>>()?;
+  match format {
+    OutputFormat::Text => {
+      if providers.is_empty() {
+        println!("no providers")
+      } else {
+        providers.iter().for_each(|p| println!("{}", p));
+      }
+    }
+    OutputFormat::Json => {
+      let json = serde_json::json!({
+        "providers": providers.iter().map(PeerId::to_base58).collect::<Vec<String>>(),
+      });
+      println!("{}", json);
+    }
+  }
+  Ok(())
+}
@@ -0,0 +1,80 @@
+use crate::cmd::{arg_format, arg_url, OutputFormat, ARG_FORMAT, ARG_URL};
+use clap::{ArgMatches, Command};
+use p2pim::proto::api::p2pim_client::P2pimClient;
+use p2pim::proto::api::{ReindexOutcome, ReindexRequest};
+use std::convert::TryFrom;
+
+pub const CMD_NAME: &str = "reindex";
+
+pub fn command<'a>() -> Command<'a> {
+  Command::new(CMD_NAME)
+    .about("recompute the parameters of the stored leases and write the missing parameters files")
+    .arg(arg_url())
+    .arg(arg_format())
+}
+
+pub fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+  let rpc_url = matches.value_of_t(ARG_URL)?;
+  let format = matches.value_of_t(ARG_FORMAT)?;
+  tokio::runtime::Builder::new_multi_thread()
+    .enable_all()
+    .build()
+    .unwrap()
+    .block_on(run_reindex(rpc_url, format))
+}
+
+async fn run_reindex(rpc_url: String, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+  let mut client = P2pimClient::connect(rpc_url).await?;
+  let response = client.reindex(ReindexRequest {}).await?.into_inner();
+  let mut json_entries = Vec::new();
+  let mut failed = 0;
+  for entry in response.entries.iter() {
+    let peer_id = entry
+      .peer_id
+      .as_ref()
+      .map(libp2p::PeerId::try_from)
+      .ok_or("empty peer_id")??;
+    let outcome = match ReindexOutcome::from_i32(entry.outcome) {
+      Some(ReindexOutcome::Consistent) => "consistent",
+      Some(ReindexOutcome::Regenerated) => "regenerated",
+      Some(ReindexOutcome::Mismatch) => "mismatch",
+      Some(ReindexOutcome::Unreadable) => "unreadable",
+      _ => "unknown",
+    };
+    if matches!(
+      ReindexOutcome::from_i32(entry.outcome),
+      Some(ReindexOutcome::Mismatch) | Some(ReindexOutcome::Unreadable)
+    ) {
+      failed += 1;
+    }
+
+    match format {
+      OutputFormat::Text => {
+        if entry.detail.is_empty() {
+          println!("{} - {}: {}", peer_id, entry.nonce, outcome);
+        } else {
+          println!("{} - {}: {} ({})", peer_id, entry.nonce, outcome, entry.detail);
+        }
+      }
+      OutputFormat::Json => json_entries.push(serde_json::json!({
+        "peer_id": peer_id.to_base58(),
+        "nonce": entry.nonce,
+        "outcome": outcome,
+        "merkle_root": format!("0x{}", hex::encode(&entry.merkle_root)),
+        "size": entry.size,
+        "detail": entry.detail,
+      })),
+    }
+  }
+
+  match format {
+    OutputFormat::Text => println!("Leases: {}, failed: {}", response.entries.len(), failed),
+    OutputFormat::Json => println!("{}", serde_json::json!({ "entries": json_entries })),
+  }
+
+  if failed == 0 {
+    Ok(())
+  } else {
+    Err(format!("{} leases do not match their parameters", failed).into())
+  }
+}
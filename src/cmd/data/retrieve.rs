@@ -1,21 +1,29 @@
-use crate::cmd::{arg_url, ARG_URL};
+use crate::cmd::{arg_timeout, arg_url, parse_peer_id, peer_id_of, timeout_of, with_timeout, ARG_URL};
 use clap::{Arg, ArgMatches, Command};
 use libp2p::PeerId;
+use p2pim::cryptography::{self, MerkleTree};
 use p2pim::proto::api::p2pim_client::P2pimClient;
-use p2pim::proto::api::RetrieveRequest;
+use p2pim::proto::api::{GetLeaseRequest, RetrieveRequest};
+use std::path::PathBuf;
+use std::time::Duration;
 use tokio::io::AsyncWriteExt;
 
 pub const CMD_NAME: &str = "retrieve";
 
 const ARG_PEER_ID: &str = "peer";
 const ARG_NONCE: &str = "nonce";
+const ARG_OUTPUT: &str = "output";
+const ARG_NO_VERIFY: &str = "no-verify";
 
 pub fn command<'a>() -> Command<'a> {
   Command::new(CMD_NAME)
     .about("retrieve data from peer")
     .arg(arg_url())
+    .arg(arg_timeout())
     .arg(arg_peer_id())
     .arg(arg_nonce())
+    .arg(arg_output())
+    .arg(arg_no_verify())
 }
 
 fn arg_nonce<'a>() -> Arg<'a> {
@@ -29,30 +37,163 @@ fn arg_nonce<'a>() -> Arg<'a> {
 fn arg_peer_id<'a>() -> Arg<'a> {
   Arg::new(ARG_PEER_ID)
     .takes_value(true)
+    .validator(parse_peer_id)
     .required(true)
     .help("peer of the lease")
 }
 
+fn arg_output<'a>() -> Arg<'a> {
+  Arg::new(ARG_OUTPUT)
+    .long(ARG_OUTPUT)
+    .short('o')
+    .takes_value(true)
+    .value_name("PATH")
+    .required(false)
+    .help("file where to write the data, stdout if not present")
+}
+
+fn arg_no_verify<'a>() -> Arg<'a> {
+  Arg::new(ARG_NO_VERIFY)
+    .long(ARG_NO_VERIFY)
+    .takes_value(false)
+    .help("do not check the received data against the merkle root of the lease")
+}
+
 pub fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
   let rpc_url = matches.value_of_t(ARG_URL)?;
-  let peer_id = matches.value_of_t(ARG_PEER_ID)?;
+  let peer_id = peer_id_of(matches, ARG_PEER_ID)?;
   let nonce = matches.value_of_t(ARG_NONCE)?;
+  let output = matches.value_of(ARG_OUTPUT).map(PathBuf::from);
+  let verify = !matches.is_present(ARG_NO_VERIFY);
+  let timeout = timeout_of(matches)?;
   tokio::runtime::Builder::new_multi_thread()
     .enable_all()
     .build()
     .unwrap()
-    .block_on(run_retrieve(rpc_url, peer_id, nonce))
+    .block_on(with_timeout(
+      timeout,
+      run_retrieve(rpc_url, peer_id, nonce, output, verify, timeout),
+    ))
 }
 
-async fn run_retrieve(rpc_url: String, peer_id: PeerId, nonce: u64) -> Result<(), Box<dyn std::error::Error>> {
+async fn run_retrieve(
+  rpc_url: String,
+  peer_id: PeerId,
+  nonce: u64,
+  output: Option<PathBuf>,
+  verify: bool,
+  timeout: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
   let mut client = P2pimClient::connect(rpc_url).await?;
-  let retrieve_request = RetrieveRequest {
+  let expected = if verify {
+    let lease = client
+      .get_lease(GetLeaseRequest {
+        peer_id: Some(peer_id.into()),
+        nonce,
+      })
+      .await?
+      .into_inner();
+    Some((lease.merkle_root, lease.size))
+  } else {
+    None
+  };
+  let mut retrieve_request = tonic::Request::new(RetrieveRequest {
     peer_id: Some(peer_id.into()),
     nonce,
-  };
+  });
+  retrieve_request.set_timeout(timeout);
   let response = client.retrieve(retrieve_request).await?;
   let data = response.into_inner().data;
-  let mut stdout = tokio::io::stdout();
-  stdout.write_all(data.as_slice()).await?;
+  if let Some((merkle_root, size)) = expected {
+    if data.len() as u64 != size {
+      return Err(format!("unexpected data size, expected={}, received={}", size, data.len()).into());
+    }
+    if merkle_root_of(&cryptography::new_service(cryptography::BLOCK_SIZE_BYTES)?, data.as_slice()).as_slice()
+      != merkle_root.as_slice()
+    {
+      return Err("received data does not match with the merkle root".into());
+    }
+  }
+  if let Some(path) = output {
+    if let Some(parent) = path.parent() {
+      tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut file = tokio::fs::File::create(path).await?;
+    file.write_all(data.as_slice()).await?;
+    file.flush().await?;
+  } else {
+    let mut stdout = tokio::io::stdout();
+    stdout.write_all(data.as_slice()).await?;
+  }
   Ok(())
 }
+
+pub fn merkle_root_of<C: cryptography::Service>(cryptography: &C, data: &[u8]) -> [u8; 32] {
+  let mut merkle = cryptography.new_merkle_tree();
+  merkle.append_data(data);
+  merkle.root()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::cmd::testutil::{sample_data, FakeDaemon, TempDir};
+
+  #[tokio::test]
+  async fn retrieved_to_a_file() {
+    let daemon = FakeDaemon::default();
+    let peer_id = PeerId::random();
+    let data = sample_data(3);
+    daemon.add_lease(peer_id, 7, data.clone());
+    let url = daemon.serve().await;
+    let folder = TempDir::new();
+    let output = folder.path().join("nested").join("data");
+
+    run_retrieve(url, peer_id, 7, Some(output.clone()), true, Duration::from_secs(5))
+      .await
+      .unwrap();
+
+    assert_eq!(std::fs::read(output).unwrap(), data);
+  }
+
+  #[tokio::test]
+  async fn tampered_data_rejected() {
+    let daemon = FakeDaemon::default();
+    let peer_id = PeerId::random();
+    let mut tampered = sample_data(3);
+    daemon.add_lease(peer_id, 7, tampered.clone());
+    tampered[5] ^= 1;
+    daemon.state.lock().unwrap().tampered.insert((peer_id, 7), tampered.clone());
+    let url = daemon.serve().await;
+    let folder = TempDir::new();
+    let output = folder.path().join("data");
+
+    let result = run_retrieve(url.clone(), peer_id, 7, Some(output.clone()), true, Duration::from_secs(5)).await;
+
+    assert!(result.is_err());
+    assert!(!output.exists());
+
+    run_retrieve(url, peer_id, 7, Some(output.clone()), false, Duration::from_secs(5))
+      .await
+      .unwrap();
+
+    assert_eq!(std::fs::read(output).unwrap(), tampered);
+  }
+
+  #[tokio::test]
+  async fn stalled_retrieve_timed_out() {
+    let daemon = FakeDaemon::default();
+    let peer_id = PeerId::random();
+    daemon.add_lease(peer_id, 7, sample_data(3));
+    daemon.state.lock().unwrap().stalled = true;
+    let url = daemon.serve().await;
+    let timeout = Duration::from_millis(200);
+
+    let retrieve = with_timeout(timeout, run_retrieve(url, peer_id, 7, None, true, timeout));
+    let result = tokio::time::timeout(Duration::from_secs(5), retrieve)
+      .await
+      .expect("retrieve kept waiting");
+
+    assert_eq!(result.unwrap_err().to_string(), "operation timed out after 0.2s");
+  }
+}
@@ -0,0 +1,122 @@
+use crate::cmd::{
+  arg_format, arg_timeout, arg_url, parse_peer_id, peer_id_of, timeout_of, with_timeout, OutputFormat, ARG_FORMAT, ARG_URL,
+};
+use clap::{Arg, ArgMatches, Command};
+use libp2p::PeerId;
+use p2pim::cryptography::{self, Service};
+use p2pim::proto::api::p2pim_client::P2pimClient;
+use p2pim::proto::api::RetrieveBlockRequest;
+
+pub const CMD_NAME: &str = "retrieve-block";
+
+const ARG_PEER_ID: &str = "peer";
+const ARG_NONCE: &str = "nonce";
+const ARG_BLOCK_NUMBER: &str = "block.number";
+
+pub fn command<'a>() -> Command<'a> {
+  Command::new(CMD_NAME)
+    .about("retrieve a single block of a lease stored by the daemon and verify its proof")
+    .arg(arg_url())
+    .arg(arg_format())
+    .arg(arg_timeout())
+    .arg(arg_peer_id())
+    .arg(arg_nonce())
+    .arg(arg_block())
+}
+
+fn arg_nonce<'a>() -> Arg<'a> {
+  Arg::new(ARG_NONCE)
+    .takes_value(true)
+    .required(true)
+    .validator(str::parse::<u64>)
+    .help("nonce of the lease")
+}
+
+fn arg_peer_id<'a>() -> Arg<'a> {
+  Arg::new(ARG_PEER_ID)
+    .takes_value(true)
+    .validator(parse_peer_id)
+    .required(true)
+    .help("peer owning the data")
+}
+
+fn arg_block<'a>() -> Arg<'a> {
+  Arg::new(ARG_BLOCK_NUMBER)
+    .takes_value(true)
+    .required(true)
+    .validator(str::parse::<u32>)
+    .help("block to retrieve")
+}
+
+pub fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+  let rpc_url = matches.value_of_t(ARG_URL)?;
+  let format = matches.value_of_t(ARG_FORMAT)?;
+  let peer_id = peer_id_of(matches, ARG_PEER_ID)?;
+  let nonce = matches.value_of_t(ARG_NONCE)?;
+  let block_number = matches.value_of_t(ARG_BLOCK_NUMBER)?;
+  let timeout = timeout_of(matches)?;
+  tokio::runtime::Builder::new_multi_thread()
+    .enable_all()
+    .build()
+    .unwrap()
+    .block_on(with_timeout(
+      timeout,
+      run_retrieve_block(rpc_url, format, peer_id, nonce, block_number),
+    ))
+}
+
+async fn run_retrieve_block(
+  rpc_url: String,
+  format: OutputFormat,
+  peer_id: PeerId,
+  nonce: u64,
+  block_number: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+  let mut client = P2pimClient::connect(rpc_url).await?;
+  let request = RetrieveBlockRequest {
+    peer_id: Some(peer_id.into()),
+    nonce,
+    block_number,
+  };
+  let response = client.retrieve_block(request).await?.into_inner();
+  let proof = response
+    .proof
+    .iter()
+    .map(|p| web3::types::H256::from(p).0)
+    .collect::<Vec<[u8; 32]>>();
+  let verified = cryptography::new_service(cryptography::BLOCK_SIZE_BYTES)?.verify(
+    block_number as usize,
+    response.block_data.as_slice(),
+    proof.clone(),
+    response.merkle_root.as_slice(),
+    response.size as usize,
+  )?;
+  let proof = proof.iter().map(|p| format!("0x{}", hex::encode(p))).collect::<Vec<String>>();
+  match format {
+    OutputFormat::Text => {
+      println!("Verified   : {}", if verified { "yes" } else { "no" });
+      println!("Merkle Root: 0x{}", hex::encode(&response.merkle_root));
+      println!("Block Data : {}", hex::encode(&response.block_data));
+      println!("Proof      :");
+      proof.iter().for_each(|p| println!("  {}", p));
+    }
+    OutputFormat::Json => {
+      let json = serde_json::json!({
+        "peer_id": peer_id.to_base58(),
+        "nonce": nonce,
+        "block_number": block_number,
+        "verified": verified,
+        "merkle_root": format!("0x{}", hex::encode(&response.merkle_root)),
+        "size": response.size,
+        "block_data": hex::encode(&response.block_data),
+        "proof": proof,
+      });
+      println!("{}", json);
+    }
+  }
+  if verified {
+    Ok(())
+  } else {
+    Err("block proof does not match the merkle root".into())
+  }
+}
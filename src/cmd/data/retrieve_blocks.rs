@@ -0,0 +1,360 @@
+use crate::cmd::{arg_timeout, arg_url, parse_peer_id, peer_id_of, timeout_of, with_timeout, ARG_URL};
+use clap::{Arg, ArgMatches, Command};
+use libp2p::PeerId;
+use p2pim::cryptography::{self, Service};
+use p2pim::proto::api::p2pim_client::P2pimClient;
+use p2pim::proto::api::{GetLeaseRequest, RetrieveBlockRequest};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+pub const CMD_NAME: &str = "retrieve-blocks";
+
+const ARG_PEER_ID: &str = "peer";
+const ARG_NONCE: &str = "nonce";
+const ARG_OUTPUT: &str = "output";
+const ARG_MERKLE_ROOT: &str = "merkle-root";
+
+// The data is written next to the output until complete, along with the progress of the retrieve
+const PART_SUFFIX: &str = ".part";
+const STATE_SUFFIX: &str = ".part.state";
+
+pub fn command<'a>() -> Command<'a> {
+  Command::new(CMD_NAME)
+    .about("retrieve a lease stored by the daemon block by block, verifying each proof and resuming an interrupted retrieve")
+    .arg(arg_url())
+    .arg(arg_timeout())
+    .arg(arg_peer_id())
+    .arg(arg_nonce())
+    .arg(arg_output())
+    .arg(arg_merkle_root())
+}
+
+fn arg_nonce<'a>() -> Arg<'a> {
+  Arg::new(ARG_NONCE)
+    .takes_value(true)
+    .required(true)
+    .validator(str::parse::<u64>)
+    .help("nonce of the lease")
+}
+
+fn arg_peer_id<'a>() -> Arg<'a> {
+  Arg::new(ARG_PEER_ID)
+    .takes_value(true)
+    .validator(parse_peer_id)
+    .required(true)
+    .help("peer owning the data")
+}
+
+fn arg_output<'a>() -> Arg<'a> {
+  Arg::new(ARG_OUTPUT)
+    .long(ARG_OUTPUT)
+    .short('o')
+    .takes_value(true)
+    .value_name("PATH")
+    .required(true)
+    .help("file where to write the data, a retrieve of the same lease to it is resumed")
+}
+
+fn arg_merkle_root<'a>() -> Arg<'a> {
+  Arg::new(ARG_MERKLE_ROOT)
+    .long(ARG_MERKLE_ROOT)
+    .takes_value(true)
+    .required(false)
+    .validator(decode_hex)
+    .help("merkle root in hex the blocks are verified against, the one reported by the daemon if not present")
+}
+
+fn decode_hex(value: &str) -> Result<Vec<u8>, hex::FromHexError> {
+  hex::decode(value.strip_prefix("0x").unwrap_or(value))
+}
+
+pub fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+  let rpc_url = matches.value_of_t(ARG_URL)?;
+  let peer_id = peer_id_of(matches, ARG_PEER_ID)?;
+  let nonce = matches.value_of_t(ARG_NONCE)?;
+  let output = PathBuf::from(matches.value_of_t::<String>(ARG_OUTPUT)?);
+  let merkle_root = matches.value_of(ARG_MERKLE_ROOT).map(decode_hex).transpose()?;
+  let timeout = timeout_of(matches)?;
+  tokio::runtime::Builder::new_multi_thread()
+    .enable_all()
+    .build()
+    .unwrap()
+    .block_on(with_timeout(
+      timeout,
+      run_retrieve_blocks(rpc_url, peer_id, nonce, output, merkle_root),
+    ))
+}
+
+/// Progress of a retrieve, saved after each verified block.
+struct RetrieveState {
+  peer_id: PeerId,
+  nonce: u64,
+  merkle_root: Vec<u8>,
+  size: u64,
+  next_block: u32,
+}
+
+impl RetrieveState {
+  async fn load(path: &Path) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+    let raw = match tokio::fs::read(path).await {
+      Ok(raw) => raw,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e.into()),
+    };
+    let json: serde_json::Value = serde_json::from_slice(raw.as_slice())?;
+    let invalid = || format!("invalid state file {}", path.display());
+    Ok(Some(RetrieveState {
+      peer_id: parse_peer_id(json["peer_id"].as_str().ok_or_else(invalid)?)?,
+      nonce: json["nonce"].as_u64().ok_or_else(invalid)?,
+      merkle_root: decode_hex(json["merkle_root"].as_str().ok_or_else(invalid)?)?,
+      size: json["size"].as_u64().ok_or_else(invalid)?,
+      next_block: json["next_block"].as_u64().ok_or_else(invalid)? as u32,
+    }))
+  }
+
+  // Replaced with a rename, so an interruption leaves either the previous or the new progress
+  async fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let json = serde_json::json!({
+      "peer_id": self.peer_id.to_base58(),
+      "nonce": self.nonce,
+      "merkle_root": format!("0x{}", hex::encode(&self.merkle_root)),
+      "size": self.size,
+      "next_block": self.next_block,
+    });
+    let tmp = with_suffix(path, ".tmp");
+    tokio::fs::write(&tmp, json.to_string()).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+  }
+
+  fn block_count(&self) -> u32 {
+    let block_size = cryptography::BLOCK_SIZE_BYTES as u64;
+    self.size.div_ceil(block_size) as u32
+  }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+  let mut name = path.as_os_str().to_owned();
+  name.push(suffix);
+  PathBuf::from(name)
+}
+
+async fn run_retrieve_blocks(
+  rpc_url: String,
+  peer_id: PeerId,
+  nonce: u64,
+  output: PathBuf,
+  merkle_root: Option<Vec<u8>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+  let part_path = with_suffix(&output, PART_SUFFIX);
+  let state_path = with_suffix(&output, STATE_SUFFIX);
+  if let Some(parent) = output.parent() {
+    tokio::fs::create_dir_all(parent).await?;
+  }
+
+  let mut state = RetrieveState::load(&state_path).await?;
+  if let Some(s) = state.as_ref() {
+    let same_root = merkle_root.as_ref().map(|r| *r == s.merkle_root).unwrap_or(true);
+    if s.peer_id != peer_id || s.nonce != nonce || !same_root {
+      return Err(
+        format!(
+          "{} belongs to another retrieve, remove it to start over",
+          state_path.display()
+        )
+        .into(),
+      );
+    }
+  }
+  let resumed_from = state.as_ref().map(|s| s.next_block).unwrap_or(0);
+  let mut file = tokio::fs::OpenOptions::new()
+    .create(true)
+    .write(true)
+    .truncate(false)
+    .open(&part_path)
+    .await?;
+  // Only the verified blocks are kept, bytes written after the last saved progress are discarded
+  let offset = resumed_from as u64 * cryptography::BLOCK_SIZE_BYTES as u64;
+  file.set_len(offset).await?;
+  file.seek(SeekFrom::Start(offset)).await?;
+
+  let cryptography = cryptography::new_service(cryptography::BLOCK_SIZE_BYTES)?;
+  let mut client = P2pimClient::connect(rpc_url).await?;
+  if state.is_none() {
+    // An empty lease has no block to retrieve, block 0 is already out of range
+    let lease = client
+      .get_lease(GetLeaseRequest {
+        peer_id: Some(peer_id.into()),
+        nonce,
+      })
+      .await?
+      .into_inner();
+    if lease.size == 0 {
+      if merkle_root.as_ref().map(|r| *r != lease.merkle_root).unwrap_or(false) {
+        return Err("the merkle root of the lease does not match the expected one".into());
+      }
+      let empty = RetrieveState {
+        peer_id,
+        nonce,
+        merkle_root: lease.merkle_root,
+        size: 0,
+        next_block: 0,
+      };
+      empty.save(&state_path).await?;
+      state = Some(empty);
+    }
+  }
+  let mut block_number = resumed_from;
+  while state.as_ref().map(|s| block_number < s.block_count()).unwrap_or(true) {
+    let response = client
+      .retrieve_block(RetrieveBlockRequest {
+        peer_id: Some(peer_id.into()),
+        nonce,
+        block_number,
+      })
+      .await?
+      .into_inner();
+    let current = match state.take() {
+      Some(s) if s.merkle_root != response.merkle_root || s.size != response.size => {
+        return Err(
+          format!(
+            "lease changed while retrieving it, remove {} to start over",
+            state_path.display()
+          )
+          .into(),
+        );
+      }
+      Some(s) => s,
+      None => {
+        if merkle_root.as_ref().map(|r| *r != response.merkle_root).unwrap_or(false) {
+          return Err("the merkle root of the lease does not match the expected one".into());
+        }
+        RetrieveState {
+          peer_id,
+          nonce,
+          merkle_root: response.merkle_root.clone(),
+          size: response.size,
+          next_block: 0,
+        }
+      }
+    };
+    let proof = response
+      .proof
+      .iter()
+      .map(|p| web3::types::H256::from(p).0)
+      .collect::<Vec<[u8; 32]>>();
+    let verified = cryptography.verify(
+      block_number as usize,
+      response.block_data.as_slice(),
+      proof,
+      current.merkle_root.as_slice(),
+      current.size as usize,
+    )?;
+    if !verified {
+      return Err(format!("block {} proof does not match the merkle root", block_number).into());
+    }
+    let block_offset = block_number as u64 * cryptography::BLOCK_SIZE_BYTES as u64;
+    let length = std::cmp::min(cryptography::BLOCK_SIZE_BYTES as u64, current.size - block_offset) as usize;
+    if response.block_data.len() < length {
+      return Err(format!("block {} is shorter than expected", block_number).into());
+    }
+    file.write_all(&response.block_data[..length]).await?;
+    // The block is on disk before the progress says so
+    file.sync_data().await?;
+    block_number += 1;
+    let next = RetrieveState {
+      next_block: block_number,
+      ..current
+    };
+    next.save(&state_path).await?;
+    state = Some(next);
+  }
+
+  file.flush().await?;
+  drop(file);
+  tokio::fs::rename(&part_path, &output).await?;
+  tokio::fs::remove_file(&state_path).await?;
+  if resumed_from > 0 {
+    println!("Retrieved {} blocks, resumed from block {}", block_number, resumed_from);
+  } else {
+    println!("Retrieved {} blocks", block_number);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::cmd::testutil::{sample_data, FakeDaemon, TempDir};
+
+  #[tokio::test]
+  async fn interrupted_retrieve_resumed() {
+    let daemon = FakeDaemon::default();
+    let peer_id = PeerId::random();
+    let data = sample_data(5);
+    daemon.add_lease(peer_id, 7, data.clone());
+    daemon.state.lock().unwrap().blocks_limit = Some(2);
+    let url = daemon.serve().await;
+    let folder = TempDir::new();
+    let output = folder.path().join("data");
+    let state_path = with_suffix(&output, STATE_SUFFIX);
+
+    let interrupted = run_retrieve_blocks(url.clone(), peer_id, 7, output.clone(), None).await;
+
+    assert!(interrupted.is_err());
+    assert!(!output.exists());
+    assert_eq!(RetrieveState::load(&state_path).await.unwrap().unwrap().next_block, 2);
+    let part = std::fs::read(with_suffix(&output, PART_SUFFIX)).unwrap();
+    assert_eq!(part, data[..2 * cryptography::BLOCK_SIZE_BYTES]);
+
+    daemon.state.lock().unwrap().blocks_limit = None;
+    run_retrieve_blocks(url, peer_id, 7, output.clone(), None).await.unwrap();
+
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+    assert!(!state_path.exists());
+    // Only the missing blocks were retrieved again
+    assert_eq!(daemon.state.lock().unwrap().blocks_served, 5);
+  }
+
+  #[tokio::test]
+  async fn empty_lease_retrieved() {
+    let daemon = FakeDaemon::default();
+    let peer_id = PeerId::random();
+    daemon.add_lease(peer_id, 7, Vec::new());
+    let url = daemon.serve().await;
+    let folder = TempDir::new();
+    let output = folder.path().join("data");
+
+    run_retrieve_blocks(url, peer_id, 7, output.clone(), None).await.unwrap();
+
+    assert_eq!(std::fs::read(&output).unwrap(), Vec::<u8>::new());
+    assert!(!with_suffix(&output, STATE_SUFFIX).exists());
+    assert_eq!(daemon.state.lock().unwrap().blocks_served, 0);
+  }
+
+  #[tokio::test]
+  async fn state_of_another_retrieve_refused() {
+    let daemon = FakeDaemon::default();
+    let (peer_a, peer_b) = (PeerId::random(), PeerId::random());
+    daemon.add_lease(peer_a, 7, sample_data(3));
+    daemon.add_lease(peer_b, 7, sample_data(3));
+    daemon.state.lock().unwrap().blocks_limit = Some(1);
+    let url = daemon.serve().await;
+    let folder = TempDir::new();
+    let output = folder.path().join("data");
+    assert!(run_retrieve_blocks(url.clone(), peer_a, 7, output.clone(), None)
+      .await
+      .is_err());
+    daemon.state.lock().unwrap().blocks_limit = None;
+
+    let error = run_retrieve_blocks(url, peer_b, 7, output.clone(), None).await.unwrap_err();
+
+    assert!(
+      error
+        .to_string()
+        .ends_with("belongs to another retrieve, remove it to start over"),
+      "{}",
+      error
+    );
+  }
+}
@@ -1,13 +1,20 @@
-use crate::cmd::{arg_token, arg_url, ARG_TOKEN, ARG_URL};
+use crate::cmd::{
+  arg_timeout, arg_token, arg_url, parse_peer_id, peer_id_of, timeout_of, token_decimals, with_timeout, ARG_TOKEN, ARG_URL,
+};
 use bigdecimal::BigDecimal;
 use clap::{Arg, ArgMatches, Command};
 use libp2p::PeerId;
 use num_bigint::{BigInt, Sign, ToBigInt};
+use p2pim::lessor::tokens_gb_hour;
 use p2pim::proto::api::p2pim_client::P2pimClient;
-use p2pim::proto::api::{GetBalanceRequest, StoreRequest};
+use p2pim::proto::api::store_stream_request::{Header, Request};
+use p2pim::proto::api::{GetBalanceRequest, StoreStreamRequest};
 use std::convert::TryInto;
+use std::io::{IsTerminal, Write};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
 use web3::types::H256;
 
 pub const STORE_CMD: &str = "store";
@@ -17,21 +24,39 @@ const ARG_DURATION: &str = "duration";
 const ARG_PEER_ID: &str = "peer";
 const ARG_PENALTY: &str = "penalty";
 const ARG_PRICE: &str = "price";
+const ARG_YES: &str = "yes";
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const STDIN_DATA_FILE: &str = "-";
 
 pub fn command<'a>() -> Command<'a> {
   Command::new(STORE_CMD)
     .about("store data in a peer")
     .arg(arg_url())
+    .arg(arg_timeout())
     .arg(arg_peer_id())
     .arg(arg_token().long(ARG_TOKEN))
     .arg(arg_price())
     .arg(arg_penalty())
     .arg(arg_duration())
     .arg(arg_data_file())
+    .arg(arg_yes())
+}
+
+fn arg_yes<'a>() -> Arg<'a> {
+  Arg::new(ARG_YES)
+    .long(ARG_YES)
+    .short('y')
+    .required(false)
+    .takes_value(false)
+    .help("do not ask for confirmation of the price per GB per hour")
 }
 
 fn arg_data_file<'a>() -> Arg<'a> {
-  Arg::new(ARG_DATA_FILE).takes_value(true).required(true).help("file to store")
+  Arg::new(ARG_DATA_FILE)
+    .takes_value(true)
+    .required(true)
+    .help("file to store, - reads the data from stdin")
 }
 
 fn arg_duration<'a>() -> Arg<'a> {
@@ -47,6 +72,7 @@ fn arg_peer_id<'a>() -> Arg<'a> {
   Arg::new(ARG_PEER_ID)
     .long(ARG_PEER_ID)
     .takes_value(true)
+    .validator(parse_peer_id)
     .required(true)
     .help("peer where store the data")
 }
@@ -71,60 +97,143 @@ fn arg_price<'a>() -> Arg<'a> {
 
 pub fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
   let rpc_url = matches.value_of_t(ARG_URL)?;
-  let peer_id = matches.value_of_t(ARG_PEER_ID)?;
+  let peer_id = peer_id_of(matches, ARG_PEER_ID)?;
   let token_addr = matches.value_of_t(ARG_TOKEN)?;
   let price = matches.value_of_t(ARG_PRICE)?;
   let penalty = matches.value_of_t(ARG_PENALTY)?;
   let duration = parse_duration::parse(matches.value_of_t::<String>(ARG_DURATION)?.as_str())?;
   let data_file = matches.value_of_t(ARG_DATA_FILE)?;
+  let confirm = !matches.is_present(ARG_YES);
+  let timeout = timeout_of(matches)?;
   tokio::runtime::Builder::new_multi_thread()
     .enable_all()
     .build()
     .unwrap()
-    .block_on(run_store(rpc_url, peer_id, token_addr, price, penalty, duration, data_file))
+    .block_on(with_timeout(
+      timeout,
+      run_store(
+        rpc_url,
+        peer_id,
+        LeaseArgs {
+          token_addr,
+          price,
+          penalty,
+          duration,
+        },
+        data_file,
+        tokio::io::stdin(),
+        confirm,
+        timeout,
+      ),
+    ))
 }
 
-async fn run_store(
-  rpc_url: String,
-  peer_id: PeerId,
+// Terms of the lease as given in the command line, the amounts in token units
+struct LeaseArgs {
   token_addr: web3::types::Address,
   price: BigDecimal,
   penalty: BigDecimal,
   duration: Duration,
+}
+
+async fn run_store(
+  rpc_url: String,
+  peer_id: PeerId,
+  lease: LeaseArgs,
   data_file: String,
+  mut stdin: impl AsyncRead + Unpin + Send + 'static,
+  confirm: bool,
+  timeout: Duration,
 ) -> Result<(), Box<dyn std::error::Error>> {
+  let LeaseArgs {
+    token_addr,
+    price,
+    penalty,
+    duration,
+  } = lease;
   let mut client = P2pimClient::connect(rpc_url).await?;
   let get_balance_request = GetBalanceRequest {
     token_address: Some(token_addr.into()),
+    network: String::new(),
+    refresh: false,
   };
   let response = client.get_balance(get_balance_request).await?;
-  let decimals = response
-    .get_ref()
-    .balance
-    .as_ref()
-    .and_then(|v| v.token_metadata.as_ref())
-    .map(|v| v.decimals)
-    .ok_or("TODO: invalid response")? as i64;
+  let decimals = token_decimals(response.get_ref())?;
 
   let abs_price = convert_amount(price, decimals, "price")?;
   let abs_penalty = convert_amount(penalty, decimals, "penalty")?;
 
-  // TODO This does not have any limit or check or anything
-  let data = tokio::fs::read(data_file).await?;
+  let (reader, size): (Box<dyn AsyncRead + Unpin + Send>, Option<u64>) = if data_file == STDIN_DATA_FILE {
+    // The first chunk is read before proposing the lease, so an empty input fails early
+    let mut first_chunk = vec![0u8; CHUNK_SIZE];
+    let n = stdin.read(first_chunk.as_mut_slice()).await?;
+    if n == 0 {
+      return Err("no data received from stdin".into());
+    }
+    first_chunk.truncate(n);
+    (Box::new(std::io::Cursor::new(first_chunk).chain(stdin)), None)
+  } else {
+    let file = tokio::fs::File::open(data_file).await?;
+    let size = file.metadata().await?.len();
+    (Box::new(file), Some(size))
+  };
 
-  let store_request = StoreRequest {
-    peer_id: Some(peer_id.into()),
-    token_address: Some(token_addr.into()),
-    price: Some(abs_price.try_into()?),
-    penalty: Some(abs_penalty.try_into()?),
-    lease_duration: Some(prost_types::Duration {
-      seconds: duration.as_secs() as i64,
-      nanos: 0,
-    }),
-    data,
+  eprintln!("{}", price_per_gb_hour(&abs_price, duration, size, decimals));
+  // Data read from stdin leaves no input to answer with
+  if confirm && size.is_some() && std::io::stdin().is_terminal() && !confirmed()? {
+    return Err("store aborted".into());
+  }
+
+  let header = StoreStreamRequest {
+    request: Some(Request::Header(Header {
+      peer_id: Some(peer_id.into()),
+      token_address: Some(token_addr.into()),
+      price: Some(abs_price.try_into()?),
+      penalty: Some(abs_penalty.try_into()?),
+      lease_duration: Some(prost_types::Duration {
+        seconds: duration.as_secs() as i64,
+        nanos: 0,
+      }),
+      size: size.unwrap_or(0),
+    })),
   };
 
-  let response = client.store(store_request).await?;
+  let read_error: Arc<Mutex<Option<std::io::Error>>> = Default::default();
+  let progress = Progress::new(size);
+  let chunks = futures::stream::unfold(
+    (reader, progress, read_error.clone()),
+    |(mut reader, mut progress, read_error)| async move {
+      let mut buf = vec![0u8; CHUNK_SIZE];
+      match read_chunk(&mut reader, buf.as_mut_slice()).await {
+        Ok(0) => {
+          progress.finish();
+          None
+        }
+        Ok(n) => {
+          buf.truncate(n);
+          progress.advance(n);
+          let request = StoreStreamRequest {
+            request: Some(Request::Data(buf)),
+          };
+          Some((request, (reader, progress, read_error)))
+        }
+        Err(e) => {
+          progress.finish();
+          *read_error.lock().unwrap() = Some(e);
+          None
+        }
+      }
+    },
+  );
+
+  // Sent as the deadline of the request, so the daemon stops storing when we stop waiting
+  let mut request = tonic::Request::new(futures::StreamExt::chain(futures::stream::iter(vec![header]), chunks));
+  request.set_timeout(timeout);
+  let result = client.store_stream(request).await;
+  if let Some(e) = read_error.lock().unwrap().take() {
+    return Err(e.into());
+  }
+  let response = result?;
   let hash: H256 = response
     .get_ref()
     .transaction_hash
@@ -136,7 +245,79 @@ async fn run_store(
   Ok(())
 }
 
-fn convert_amount(original: BigDecimal, decimals: i64, name: &str) -> Result<BigInt, Box<dyn std::error::Error>> {
+fn price_per_gb_hour(price: &BigInt, duration: Duration, size: Option<u64>, decimals: i64) -> String {
+  let size = match size {
+    Some(size) => size,
+    None => return "price per GB per hour: unknown, the size of the data is not known yet".to_string(),
+  };
+  match tokens_gb_hour(price, duration, size as usize) {
+    // The rate the lessor compares with its ask, so a proposal below it is rejected
+    Some(rate) => format!("price per GB per hour: {}", BigDecimal::new(rate, decimals)),
+    // The lessor only checks the total price of empty data
+    None if size == 0 => "price per GB per hour: none, the data is empty".to_string(),
+    None => "price per GB per hour: none, the lease is shorter than a second".to_string(),
+  }
+}
+
+// Files are read in pieces smaller than a chunk, so it reads until the chunk is full or the data ends
+async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+  let mut filled = 0;
+  while filled < buf.len() {
+    match reader.read(&mut buf[filled..]).await? {
+      0 => break,
+      n => filled += n,
+    }
+  }
+  Ok(filled)
+}
+
+fn confirmed() -> Result<bool, std::io::Error> {
+  eprint!("propose the lease? [y/N] ");
+  std::io::stderr().flush()?;
+  let mut answer = String::new();
+  std::io::stdin().read_line(&mut answer)?;
+  Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+struct Progress {
+  // Unknown when reading from stdin
+  total: Option<u64>,
+  sent: u64,
+  enabled: bool,
+}
+
+impl Progress {
+  fn new(total: Option<u64>) -> Self {
+    Progress {
+      total,
+      sent: 0,
+      enabled: std::io::stderr().is_terminal(),
+    }
+  }
+
+  fn advance(&mut self, bytes: usize) {
+    self.sent += bytes as u64;
+    if self.enabled {
+      match self.total {
+        Some(total) => {
+          let percent = (self.sent * 100).checked_div(total).unwrap_or(100);
+          eprint!("\rsending {}/{} bytes ({}%)", self.sent, total, percent);
+        }
+        None => eprint!("\rsending {} bytes", self.sent),
+      }
+      let _ = std::io::stderr().flush();
+    }
+  }
+
+  fn finish(&mut self) {
+    if self.enabled && self.sent > 0 {
+      eprintln!();
+    }
+    self.enabled = false;
+  }
+}
+
+pub fn convert_amount(original: BigDecimal, decimals: i64, name: &str) -> Result<BigInt, Box<dyn std::error::Error>> {
   let abs_amount: BigDecimal = original * BigDecimal::new(1.into(), -decimals);
   if !abs_amount.is_integer() {
     Err(format!("TODO(formatting): the amount for {} has too many decimals", name).into())
@@ -146,3 +327,92 @@ fn convert_amount(original: BigDecimal, decimals: i64, name: &str) -> Result<Big
     Ok(abs_amount.to_bigint().expect("this will never happens"))
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::cmd::testutil::{FakeDaemon, TempDir};
+
+  fn lease_args() -> LeaseArgs {
+    LeaseArgs {
+      token_addr: web3::types::Address::repeat_byte(1),
+      price: BigDecimal::from(1),
+      penalty: BigDecimal::from(1),
+      duration: Duration::from_secs(3600),
+    }
+  }
+
+  #[tokio::test]
+  async fn file_sent_in_chunks() {
+    let daemon = FakeDaemon::default();
+    let url = daemon.serve().await;
+    let folder = TempDir::new();
+    let data: Vec<u8> = (0..CHUNK_SIZE * 5 / 2).map(|i| (i % 251) as u8).collect();
+    let data_file = folder.path().join("data");
+    std::fs::write(&data_file, &data).unwrap();
+
+    run_store(
+      url,
+      PeerId::random(),
+      lease_args(),
+      data_file.to_str().unwrap().to_string(),
+      tokio::io::empty(),
+      false,
+      Duration::from_secs(5),
+    )
+    .await
+    .unwrap();
+
+    let chunks = daemon.state.lock().unwrap().stored_chunks.clone();
+    assert_eq!(chunks.len(), 3);
+    assert_eq!(chunks.concat(), data);
+  }
+
+  #[tokio::test]
+  async fn stdin_data_sent() {
+    let daemon = FakeDaemon::default();
+    let url = daemon.serve().await;
+    let data: Vec<u8> = (0..CHUNK_SIZE * 3 / 2).map(|i| (i % 251) as u8).collect();
+    let store = |stdin| {
+      run_store(
+        url.clone(),
+        PeerId::random(),
+        lease_args(),
+        STDIN_DATA_FILE.to_string(),
+        stdin,
+        true,
+        Duration::from_secs(5),
+      )
+    };
+
+    store(std::io::Cursor::new(data.clone())).await.unwrap();
+    let empty = store(std::io::Cursor::new(Vec::new())).await.unwrap_err();
+
+    assert_eq!(daemon.state.lock().unwrap().stored_chunks.concat(), data);
+    assert_eq!(empty.to_string(), "no data received from stdin");
+  }
+
+  #[test]
+  fn price_shown_as_the_lessor_rate() {
+    const GB: u64 = 1024 * 1024 * 1024;
+    let hour = Duration::from_secs(3600);
+    let price = BigInt::from(3) * BigInt::from(10).pow(18);
+
+    assert_eq!(
+      price_per_gb_hour(&price, hour, Some(GB), 18),
+      "price per GB per hour: 3.000000000000000000"
+    );
+    assert_eq!(
+      price_per_gb_hour(&price, 2 * hour, Some(2 * GB), 18),
+      "price per GB per hour: 0.750000000000000000"
+    );
+    assert_eq!(
+      price_per_gb_hour(&price, hour, None, 18),
+      "price per GB per hour: unknown, the size of the data is not known yet"
+    );
+    assert_eq!(
+      price_per_gb_hour(&price, hour, Some(0), 18),
+      "price per GB per hour: none, the data is empty"
+    );
+  }
+}
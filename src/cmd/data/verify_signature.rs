@@ -0,0 +1,175 @@
+use crate::cmd::{arg_format, arg_token, arg_url, OutputFormat, ARG_FORMAT, ARG_TOKEN, ARG_URL};
+use clap::{Arg, ArgMatches, Command};
+use p2pim::proto::api::p2pim_client::P2pimClient;
+use p2pim::proto::api::VerifySignatureRequest;
+use std::str::FromStr;
+use std::time::{Duration, UNIX_EPOCH};
+use web3::types::{Address, U256};
+
+pub const CMD_NAME: &str = "verify-signature";
+
+const ARG_DURATION: &str = "duration";
+const ARG_EXPIRATION: &str = "expiration";
+const ARG_LESSEE: &str = "lessee";
+const ARG_LESSOR: &str = "lessor";
+const ARG_MERKLE_ROOT: &str = "merkle-root";
+const ARG_NONCE: &str = "nonce";
+const ARG_PENALTY: &str = "penalty";
+const ARG_PRICE: &str = "price";
+const ARG_SIGNATURE: &str = "signature";
+const ARG_SIZE: &str = "size";
+
+pub fn command<'a>() -> Command<'a> {
+  Command::new(CMD_NAME)
+    .about("recover the signer of a lease signature and check it against the lessee and lessor")
+    .arg(arg_url())
+    .arg(arg_format())
+    .arg(arg_token().long(ARG_TOKEN).help("token of the lease"))
+    .arg(arg_address(ARG_LESSEE, "address of the lessee"))
+    .arg(arg_address(ARG_LESSOR, "address of the lessor"))
+    .arg(arg_nonce())
+    .arg(arg_hex(ARG_MERKLE_ROOT, "merkle root of the data in hex"))
+    .arg(arg_size())
+    .arg(arg_amount(ARG_PRICE, "price of the lease in token base units"))
+    .arg(arg_amount(ARG_PENALTY, "penalty of the lease in token base units"))
+    .arg(arg_duration())
+    .arg(arg_expiration())
+    .arg(arg_hex(ARG_SIGNATURE, "signature in hex"))
+}
+
+fn arg_address<'a>(name: &'a str, help: &'a str) -> Arg<'a> {
+  Arg::new(name)
+    .long(name)
+    .takes_value(true)
+    .required(true)
+    .validator(Address::from_str)
+    .help(help)
+}
+
+fn arg_amount<'a>(name: &'a str, help: &'a str) -> Arg<'a> {
+  Arg::new(name)
+    .long(name)
+    .takes_value(true)
+    .required(true)
+    .validator(U256::from_dec_str)
+    .help(help)
+}
+
+fn arg_duration<'a>() -> Arg<'a> {
+  Arg::new(ARG_DURATION)
+    .long(ARG_DURATION)
+    .takes_value(true)
+    .required(true)
+    .validator(parse_duration::parse)
+    .help("duration of the lease")
+}
+
+fn arg_expiration<'a>() -> Arg<'a> {
+  Arg::new(ARG_EXPIRATION)
+    .long(ARG_EXPIRATION)
+    .takes_value(true)
+    .required(true)
+    .validator(str::parse::<u64>)
+    .help("proposal expiration in seconds since the unix epoch")
+}
+
+fn arg_hex<'a>(name: &'a str, help: &'a str) -> Arg<'a> {
+  Arg::new(name)
+    .long(name)
+    .takes_value(true)
+    .required(true)
+    .validator(decode_hex)
+    .help(help)
+}
+
+fn arg_nonce<'a>() -> Arg<'a> {
+  Arg::new(ARG_NONCE)
+    .long(ARG_NONCE)
+    .takes_value(true)
+    .required(true)
+    .validator(str::parse::<u64>)
+    .help("nonce of the lease")
+}
+
+fn arg_size<'a>() -> Arg<'a> {
+  Arg::new(ARG_SIZE)
+    .long(ARG_SIZE)
+    .takes_value(true)
+    .required(true)
+    .validator(str::parse::<u64>)
+    .help("size in bytes of the data")
+}
+
+fn decode_hex(value: &str) -> Result<Vec<u8>, hex::FromHexError> {
+  hex::decode(value.strip_prefix("0x").unwrap_or(value))
+}
+
+pub fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+  let rpc_url = matches.value_of_t(ARG_URL)?;
+  let format = matches.value_of_t(ARG_FORMAT)?;
+  let token_address: Address = matches.value_of_t(ARG_TOKEN)?;
+  let lessee_address: Address = matches.value_of_t(ARG_LESSEE)?;
+  let lessor_address: Address = matches.value_of_t(ARG_LESSOR)?;
+  let price = U256::from_dec_str(matches.value_of_t::<String>(ARG_PRICE)?.as_str())?;
+  let penalty = U256::from_dec_str(matches.value_of_t::<String>(ARG_PENALTY)?.as_str())?;
+  let duration = parse_duration::parse(matches.value_of_t::<String>(ARG_DURATION)?.as_str())?;
+  let expiration: u64 = matches.value_of_t(ARG_EXPIRATION)?;
+  let request = VerifySignatureRequest {
+    token_address: Some(token_address.into()),
+    lessee_address: Some(lessee_address.into()),
+    lessor_address: Some(lessor_address.into()),
+    nonce: matches.value_of_t(ARG_NONCE)?,
+    merkle_root: decode_hex(matches.value_of_t::<String>(ARG_MERKLE_ROOT)?.as_str())?,
+    size: matches.value_of_t(ARG_SIZE)?,
+    price: Some(price.into()),
+    penalty: Some(penalty.into()),
+    lease_duration: Some(prost_types::Duration {
+      seconds: duration.as_secs() as i64,
+      nanos: 0,
+    }),
+    proposal_expiration: Some((UNIX_EPOCH + Duration::from_secs(expiration)).into()),
+    signature: decode_hex(matches.value_of_t::<String>(ARG_SIGNATURE)?.as_str())?,
+  };
+  tokio::runtime::Builder::new_multi_thread()
+    .enable_all()
+    .build()
+    .unwrap()
+    .block_on(run_verify_signature(rpc_url, format, request))
+}
+
+async fn run_verify_signature(
+  rpc_url: String,
+  format: OutputFormat,
+  request: VerifySignatureRequest,
+) -> Result<(), Box<dyn std::error::Error>> {
+  let mut client = P2pimClient::connect(rpc_url).await?;
+  let signature = hex::encode(&request.signature);
+  let response = client.verify_signature(request).await?.into_inner();
+  let signer_address: Address = response
+    .signer_address
+    .as_ref()
+    .ok_or("invalid response: signer address empty")?
+    .into();
+  match format {
+    OutputFormat::Text => {
+      println!("Signature       : 0x{}", signature);
+      println!("Signer          : 0x{:x}", signer_address);
+      println!("Signed by lessee: {}", if response.signed_by_lessee { "yes" } else { "no" });
+      println!("Signed by lessor: {}", if response.signed_by_lessor { "yes" } else { "no" });
+    }
+    OutputFormat::Json => {
+      let json = serde_json::json!({
+        "signature": format!("0x{}", signature),
+        "signer_address": format!("0x{:x}", signer_address),
+        "signed_by_lessee": response.signed_by_lessee,
+        "signed_by_lessor": response.signed_by_lessor,
+      });
+      println!("{}", json);
+    }
+  }
+  if response.signed_by_lessee || response.signed_by_lessor {
+    Ok(())
+  } else {
+    Err("signature was not made by the lessee nor the lessor".into())
+  }
+}
@@ -1,4 +1,4 @@
-use crate::cmd::{arg_amount, arg_token, arg_url, ARG_AMOUNT, ARG_TOKEN, ARG_URL};
+use crate::cmd::{arg_amount, arg_network, arg_token, arg_url, network_of, token_decimals, ARG_AMOUNT, ARG_TOKEN, ARG_URL};
 use bigdecimal::BigDecimal;
 use clap::{ArgMatches, Command};
 use num_bigint::{Sign, ToBigInt};
@@ -13,36 +13,35 @@ pub fn command<'a>() -> Command<'a> {
     .arg(arg_url())
     .arg(arg_token())
     .arg(arg_amount())
+    .arg(arg_network())
 }
 
 pub fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
   let rpc_url = matches.value_of_t(ARG_URL)?;
   let token_addr = matches.value_of_t(ARG_TOKEN)?;
   let amount = matches.value_of_t(ARG_AMOUNT)?;
+  let network = network_of(matches);
   tokio::runtime::Builder::new_multi_thread()
     .enable_all()
     .build()
     .unwrap()
-    .block_on(run_deposit(rpc_url, token_addr, amount))
+    .block_on(run_deposit(rpc_url, token_addr, amount, network))
 }
 
 async fn run_deposit(
   rpc_url: String,
   token_addr: web3::types::Address,
   amount: BigDecimal,
+  network: String,
 ) -> Result<(), Box<dyn std::error::Error>> {
   let mut client = P2pimClient::connect(rpc_url).await?;
   let get_balance_request = GetBalanceRequest {
     token_address: Some(token_addr.into()),
+    network: network.clone(),
+    refresh: false,
   };
   let response = client.get_balance(get_balance_request).await?;
-  let decimals = response
-    .get_ref()
-    .balance
-    .as_ref()
-    .and_then(|v| v.token_metadata.as_ref())
-    .map(|v| v.decimals)
-    .ok_or("TODO: invalid response")? as i64;
+  let decimals = token_decimals(response.get_ref())?;
   let abs_amount: BigDecimal = amount * BigDecimal::new(1.into(), -decimals);
   if !abs_amount.is_integer() {
     Err("TODO(formatting): the amount has too many decimals".into())
@@ -54,6 +53,7 @@ async fn run_deposit(
       .deposit(DepositRequest {
         token_address: Some(token_addr.into()),
         amount: Some(conv_amount),
+        network,
       })
       .await?;
     let trans_hash: H256 = response
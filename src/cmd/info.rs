@@ -1,64 +1,151 @@
 use bigdecimal::BigDecimal;
+use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt::Write;
 
-use crate::cmd::{arg_url, ARG_URL};
-use clap::{ArgMatches, Command};
+use crate::cmd::{
+  arg_decimals, arg_format, arg_network, arg_url, format_amount, network_of, OutputFormat, ARG_DECIMALS, ARG_FORMAT, ARG_URL,
+};
+use clap::{Arg, ArgMatches, Command};
 use p2pim::proto::api::p2pim_client::P2pimClient;
-use p2pim::proto::api::{BalanceEntry, GetInfoRequest};
+use p2pim::proto::api::{BalanceEntry, GetInfoRequest, GetInfoResponse};
+
+const NATIVE_DECIMALS: i64 = 18;
+
+const ARG_REFRESH: &str = "refresh";
 
 pub fn command<'a>() -> Command<'a> {
-  Command::new("info").about("show p2pim account info").arg(arg_url())
+  Command::new("info")
+    .about("show p2pim account info")
+    .arg(arg_url())
+    .arg(arg_format())
+    .arg(arg_decimals())
+    .arg(arg_network())
+    .arg(arg_refresh())
+}
+
+fn arg_refresh<'a>() -> Arg<'a> {
+  Arg::new(ARG_REFRESH)
+    .long(ARG_REFRESH)
+    .takes_value(false)
+    .help("read the balances from the chain instead of the daemon cache")
 }
 
 pub fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
   let rpc_url = matches.value_of_t(ARG_URL)?;
+  let format = matches.value_of_t(ARG_FORMAT)?;
+  let decimals = matches.value_of_t(ARG_DECIMALS)?;
+  let network = network_of(matches);
+  let refresh = matches.is_present(ARG_REFRESH);
   tokio::runtime::Builder::new_multi_thread()
     .enable_all()
     .build()
     .unwrap()
-    .block_on(run_info(rpc_url))
+    .block_on(run_info(rpc_url, format, decimals, network, refresh))
 }
 
-async fn run_info(rpc_url: String) -> Result<(), Box<dyn std::error::Error>> {
+async fn run_info(
+  rpc_url: String,
+  format: OutputFormat,
+  decimals: u32,
+  network: String,
+  refresh: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
   let mut client = P2pimClient::connect(rpc_url).await?;
-  let get_info_req: GetInfoRequest = Default::default();
+  let get_info_req = GetInfoRequest { network, refresh };
   let response = client.get_info(get_info_req).await?;
-  let response_dto = response.get_ref();
+  println!("{}", format_info(response.get_ref(), format, decimals)?);
+  Ok(())
+}
+
+fn format_info(response_dto: &GetInfoResponse, format: OutputFormat, decimals: u32) -> Result<String, Box<dyn Error>> {
+  let peer_id = response_dto
+    .peer_id
+    .as_ref()
+    .map(libp2p::PeerId::try_from)
+    .ok_or("empty peer id")??;
   let address_wallet: web3::types::Address = convert_or_err(response_dto.address_wallet.as_ref(), "empty address wallet")?;
   let address_storage: web3::types::Address = convert_or_err(response_dto.address_storage.as_ref(), "empty address storage")?;
-  let balance = response_dto
+  let address_master_record: web3::types::Address =
+    convert_or_err(response_dto.address_master_record.as_ref(), "empty address master record")?;
+  let to_native = |v| BigDecimal::new(v, NATIVE_DECIMALS);
+  let native_balance_wallet =
+    convert_or_err(response_dto.native_balance_wallet.as_ref(), "empty native balance wallet").map(to_native)?;
+  let native_balance_storage =
+    convert_or_err(response_dto.native_balance_storage.as_ref(), "empty native balance storage").map(to_native)?;
+  let pending_transactions_wallet = response_dto.pending_transactions_wallet;
+  let pending_transactions_storage = response_dto.pending_transactions_storage;
+  let balances = response_dto
     .balance
     .iter()
-    .map(format_balance)
-    .collect::<Result<Vec<String>, _>>()
-    .map(|bal| bal.join("\n"))?;
-  println!("Wallet  Address: 0x{:x}", address_wallet);
-  println!("Storage Address: 0x{:x}", address_storage);
-  println!("Balances:");
-  println!("{}", balance);
-  Ok(())
+    .map(parse_balance)
+    .collect::<Result<Vec<BalanceView>, _>>()?;
+  let mut result = String::new();
+  match format {
+    OutputFormat::Text => {
+      let balance = balances
+        .iter()
+        .map(|b| format_balance(b, decimals))
+        .collect::<Result<Vec<String>, _>>()
+        .map(|bal| bal.join("\n"))?;
+      writeln!(result, "Peer Id        : {}", peer_id)?;
+      writeln!(result, "Network        : {}", response_dto.network)?;
+      writeln!(result, "Wallet  Address: 0x{:x}", address_wallet)?;
+      writeln!(
+        result,
+        "  Native Balance      : {} ETH",
+        format_amount(&native_balance_wallet, decimals)
+      )?;
+      writeln!(result, "  Pending Transactions: {}", pending_transactions_wallet)?;
+      writeln!(result, "Storage Address: 0x{:x}", address_storage)?;
+      writeln!(
+        result,
+        "  Native Balance      : {} ETH",
+        format_amount(&native_balance_storage, decimals)
+      )?;
+      writeln!(result, "  Pending Transactions: {}", pending_transactions_storage)?;
+      writeln!(result, "Master Record  : 0x{:x}", address_master_record)?;
+      writeln!(result, "Balances:")?;
+      write!(result, "{}", balance)?;
+    }
+    OutputFormat::Json => {
+      let json = serde_json::json!({
+        "peer_id": peer_id.to_base58(),
+        "network": response_dto.network,
+        "address_wallet": format!("0x{:x}", address_wallet),
+        "address_storage": format!("0x{:x}", address_storage),
+        "address_master_record": format!("0x{:x}", address_master_record),
+        "native_balance_wallet": native_balance_wallet.to_string(),
+        "native_balance_storage": native_balance_storage.to_string(),
+        "pending_transactions_wallet": pending_transactions_wallet,
+        "pending_transactions_storage": pending_transactions_storage,
+        "balances": balances.iter().map(json_balance).collect::<Vec<_>>(),
+      });
+      write!(result, "{}", json)?;
+    }
+  }
+  Ok(result)
+}
+
+struct BalanceView {
+  token_address: web3::types::Address,
+  adjudicator_address: web3::types::Address,
+  token_name: String,
+  token_symbol: String,
+  token_decimals: u32,
+  available_account: BigDecimal,
+  allowed_account: BigDecimal,
+  available_p2pim: BigDecimal,
+  locked_rents: BigDecimal,
+  locked_lets: BigDecimal,
 }
 
-fn format_balance(entry: &BalanceEntry) -> Result<String, Box<dyn Error>> {
+fn parse_balance(entry: &BalanceEntry) -> Result<BalanceView, Box<dyn Error>> {
   let token = entry.token_metadata.as_ref().ok_or("missing token info")?;
 
   let token_address: web3::types::Address = convert_or_err(entry.token_address.as_ref(), "missing token address")?;
-  let token_name = &token.name;
-  let token_symbol = &token.symbol;
-
-  let mut result = {
-    if token_name.is_empty() {
-      format!("  Token at 0x{:x} :\n", token_address)
-    } else {
-      let symbol = if token_symbol.is_empty() {
-        Default::default()
-      } else {
-        format!(" ({})", token_symbol)
-      };
-      format!("  {}{} at 0x{:x} :\n", token_name, symbol, token_address)
-    }
-  };
+  let adjudicator_address: web3::types::Address =
+    convert_or_err(entry.adjudicator_address.as_ref(), "missing adjudicator address")?;
 
   let token_decimals = From::from(token.decimals);
 
@@ -89,14 +176,133 @@ fn format_balance(entry: &BalanceEntry) -> Result<String, Box<dyn Error>> {
   )
   .map(to_big_decimal)?;
 
-  writeln!(result, "    Available Account: {}", available_account)?;
-  writeln!(result, "    Allowed Account  : {}", allowed_account)?;
-  writeln!(result, "    Available P2pim  : {}", available_p2pim)?;
-  writeln!(result, "    Locked Rents     : {}", locked_rents)?;
-  writeln!(result, "    Locked Lets      : {}", locked_lets)?;
+  Ok(BalanceView {
+    token_address,
+    adjudicator_address,
+    token_name: token.name.clone(),
+    token_symbol: token.symbol.clone(),
+    token_decimals: token.decimals,
+    available_account,
+    allowed_account,
+    available_p2pim,
+    locked_rents,
+    locked_lets,
+  })
+}
+
+fn format_balance(balance: &BalanceView, decimals: u32) -> Result<String, Box<dyn Error>> {
+  let token_address = balance.token_address;
+  let token_name = &balance.token_name;
+  let token_symbol = &balance.token_symbol;
+
+  let mut result = {
+    if token_name.is_empty() {
+      format!("  Token at 0x{:x} :\n", token_address)
+    } else {
+      let symbol = if token_symbol.is_empty() {
+        Default::default()
+      } else {
+        format!(" ({})", token_symbol)
+      };
+      format!("  {}{} at 0x{:x} :\n", token_name, symbol, token_address)
+    }
+  };
+
+  writeln!(result, "    Adjudicator      : 0x{:x}", balance.adjudicator_address)?;
+  let amount = |v| format_amount(v, decimals);
+  writeln!(result, "    Available Account: {}", amount(&balance.available_account))?;
+  writeln!(result, "    Allowed Account  : {}", amount(&balance.allowed_account))?;
+  writeln!(result, "    Available P2pim  : {}", amount(&balance.available_p2pim))?;
+  writeln!(result, "    Locked Rents     : {}", amount(&balance.locked_rents))?;
+  writeln!(result, "    Locked Lets      : {}", amount(&balance.locked_lets))?;
   Ok(result)
 }
 
+fn json_balance(balance: &BalanceView) -> serde_json::Value {
+  serde_json::json!({
+    "token_address": format!("0x{:x}", balance.token_address),
+    "adjudicator_address": format!("0x{:x}", balance.adjudicator_address),
+    "token_name": balance.token_name,
+    "token_symbol": balance.token_symbol,
+    "token_decimals": balance.token_decimals,
+    "available_account": balance.available_account.to_string(),
+    "allowed_account": balance.allowed_account.to_string(),
+    "available_p2pim": balance.available_p2pim.to_string(),
+    "locked_rents": balance.locked_rents.to_string(),
+    "locked_lets": balance.locked_lets.to_string(),
+  })
+}
+
 fn convert_or_err<I, O: From<I>, E>(input: Option<I>, err: E) -> Result<O, E> {
   input.map(Into::<O>::into).ok_or(err)
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use p2pim::proto::api::balance_entry::{StorageBalance, TokenMetadata, WalletBalance};
+  use web3::types::{Address, U256};
+
+  fn info_response(peer_id: libp2p::PeerId) -> GetInfoResponse {
+    let amount = |v: u64| Some(U256::from(v).into());
+    GetInfoResponse {
+      address_wallet: Some(Address::repeat_byte(1).into()),
+      address_storage: Some(Address::repeat_byte(2).into()),
+      balance: vec![BalanceEntry {
+        token_address: Some(Address::repeat_byte(3).into()),
+        token_metadata: Some(TokenMetadata {
+          name: "Token".to_string(),
+          symbol: "TKN".to_string(),
+          decimals: 2,
+        }),
+        wallet_balance: Some(WalletBalance {
+          available: amount(1000),
+          allowance: amount(500),
+        }),
+        storage_balance: Some(StorageBalance {
+          available: amount(250),
+          locked_rents: amount(20),
+          locked_lets: amount(5),
+        }),
+        adjudicator_address: Some(Address::repeat_byte(4).into()),
+      }],
+      native_balance_wallet: amount(1_500_000_000_000_000_000),
+      native_balance_storage: amount(2_000_000_000_000_000),
+      pending_transactions_wallet: 1,
+      pending_transactions_storage: 2,
+      peer_id: Some(peer_id.into()),
+      address_master_record: Some(Address::repeat_byte(5).into()),
+      network: "1".to_string(),
+    }
+  }
+
+  #[test]
+  fn json_parsed_back() {
+    let peer_id = libp2p::PeerId::random();
+    let output = format_info(&info_response(peer_id), OutputFormat::Json, 4).unwrap();
+    let json: serde_json::Value = serde_json::from_str(output.as_str()).unwrap();
+
+    assert_eq!(json["peer_id"], peer_id.to_base58());
+    assert_eq!(json["network"], "1");
+    assert_eq!(json["address_wallet"], format!("0x{:x}", Address::repeat_byte(1)));
+    assert_eq!(json["address_storage"], format!("0x{:x}", Address::repeat_byte(2)));
+    assert_eq!(json["address_master_record"], format!("0x{:x}", Address::repeat_byte(5)));
+    assert_eq!(json["native_balance_wallet"], "1.500000000000000000");
+    assert_eq!(json["native_balance_storage"], "0.002000000000000000");
+    assert_eq!(json["pending_transactions_wallet"], 1);
+    assert_eq!(json["pending_transactions_storage"], 2);
+    let balance = &json["balances"][0];
+    assert_eq!(balance["token_symbol"], "TKN");
+    assert_eq!(balance["token_decimals"], 2);
+    assert_eq!(balance["available_account"], "10.00");
+    assert_eq!(balance["locked_lets"], "0.05");
+  }
+
+  #[test]
+  fn text_amounts_shown_with_the_requested_precision() {
+    let output = format_info(&info_response(libp2p::PeerId::random()), OutputFormat::Text, 3).unwrap();
+
+    assert!(output.contains("Native Balance      : 1.500 ETH"), "{}", output);
+    assert!(output.contains("Native Balance      : 0.002 ETH"), "{}", output);
+  }
+}
@@ -1,12 +1,24 @@
-use clap::Arg;
+use bigdecimal::BigDecimal;
+use clap::{Arg, ArgMatches};
+use data_encoding::BASE32_NOPAD;
+use libp2p::PeerId;
+use log::LevelFilter;
+use p2pim::proto::api::GetBalanceResponse;
+use std::error::Error;
+use std::future::Future;
 use std::str::FromStr;
+use std::time::Duration;
 
 pub mod approve;
+pub mod completions;
 pub mod daemon;
 pub mod data;
 pub mod deposit;
 pub mod info;
 pub mod swarm;
+#[cfg(test)]
+mod testutil;
+pub mod version;
 pub mod withdraw;
 
 const ARG_URL: &str = "url";
@@ -31,6 +43,20 @@ fn arg_token<'a>() -> Arg<'a> {
     .help("token to approve")
 }
 
+const ARG_NETWORK: &str = "network";
+
+fn arg_network<'a>() -> Arg<'a> {
+  Arg::new(ARG_NETWORK)
+    .long(ARG_NETWORK)
+    .takes_value(true)
+    .value_name("ID")
+    .help("id of the eth network, the one where the daemon seals leases if not present")
+}
+
+fn network_of(matches: &ArgMatches) -> String {
+  matches.value_of(ARG_NETWORK).unwrap_or_default().to_string()
+}
+
 const ARG_AMOUNT: &str = "amount";
 
 fn arg_amount<'a>() -> Arg<'a> {
@@ -40,3 +66,291 @@ fn arg_amount<'a>() -> Arg<'a> {
     .validator(bigdecimal::BigDecimal::from_str)
     .help("amount")
 }
+
+const ARG_TIMEOUT: &str = "timeout";
+const ARG_TIMEOUT_DEFAULT: &str = "10m";
+
+fn arg_timeout<'a>() -> Arg<'a> {
+  Arg::new(ARG_TIMEOUT)
+    .long(ARG_TIMEOUT)
+    .takes_value(true)
+    .value_name("DURATION")
+    .default_value(ARG_TIMEOUT_DEFAULT)
+    .validator(parse_duration::parse)
+    .help("maximum time to wait for the daemon to complete the operation")
+}
+
+fn timeout_of(matches: &ArgMatches) -> Result<Duration, Box<dyn Error>> {
+  Ok(parse_duration::parse(matches.value_of_t::<String>(ARG_TIMEOUT)?.as_str())?)
+}
+
+/// Fails with an "operation timed out" error instead of waiting forever on a daemon or peer that stopped answering.
+async fn with_timeout<T>(
+  timeout: Duration,
+  future: impl Future<Output = Result<T, Box<dyn Error>>>,
+) -> Result<T, Box<dyn Error>> {
+  match tokio::time::timeout(timeout, future).await {
+    Ok(result) => result,
+    Err(_) => Err(format!("operation timed out after {}s", timeout.as_secs_f64()).into()),
+  }
+}
+
+const ARG_DECIMALS: &str = "decimals";
+const ARG_DECIMALS_DEFAULT: &str = "4";
+
+fn arg_decimals<'a>() -> Arg<'a> {
+  Arg::new(ARG_DECIMALS)
+    .long(ARG_DECIMALS)
+    .takes_value(true)
+    .value_name("N")
+    .default_value(ARG_DECIMALS_DEFAULT)
+    .validator(str::parse::<u32>)
+    .help("fractional digits shown for token amounts in text output")
+}
+
+/// Amount with `decimals` fractional digits, the extra digits are dropped so it is never shown bigger than it is.
+fn format_amount(amount: &BigDecimal, decimals: u32) -> String {
+  amount.with_scale(decimals as i64).to_string()
+}
+
+const ARG_FORMAT: &str = "format";
+const FORMAT_TEXT: &str = "text";
+const FORMAT_JSON: &str = "json";
+
+fn arg_format<'a>() -> Arg<'a> {
+  Arg::new(ARG_FORMAT)
+    .long(ARG_FORMAT)
+    .takes_value(true)
+    .value_name("FORMAT")
+    .possible_values([FORMAT_TEXT, FORMAT_JSON])
+    .default_value(FORMAT_TEXT)
+    .help("output format")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+  Text,
+  Json,
+}
+
+impl FromStr for OutputFormat {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      FORMAT_TEXT => Ok(OutputFormat::Text),
+      FORMAT_JSON => Ok(OutputFormat::Json),
+      other => Err(format!("unsupported output format: {}", other)),
+    }
+  }
+}
+
+const ARG_PEER_ID_FORMAT: &str = "peer-id-format";
+const PEER_ID_FORMAT_BASE58: &str = "base58";
+const PEER_ID_FORMAT_BASE32: &str = "base32";
+
+fn arg_peer_id_format<'a>() -> Arg<'a> {
+  Arg::new(ARG_PEER_ID_FORMAT)
+    .long(ARG_PEER_ID_FORMAT)
+    .takes_value(true)
+    .value_name("FORMAT")
+    .possible_values([PEER_ID_FORMAT_BASE58, PEER_ID_FORMAT_BASE32])
+    .default_value(PEER_ID_FORMAT_BASE58)
+    .help("encoding of the peer ids shown, base32 is their CIDv1 form")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PeerIdFormat {
+  Base58,
+  Base32,
+}
+
+impl FromStr for PeerIdFormat {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      PEER_ID_FORMAT_BASE58 => Ok(PeerIdFormat::Base58),
+      PEER_ID_FORMAT_BASE32 => Ok(PeerIdFormat::Base32),
+      other => Err(format!("unsupported peer id format: {}", other)),
+    }
+  }
+}
+
+// The CIDv1 of a peer id is the multihash of its key prefixed with the version and the libp2p-key multicodec
+const CID_V1_LIBP2P_KEY_PREFIX: [u8; 2] = [0x01, 0x72];
+// Multibase prefix of lowercase base32 without padding
+const MULTIBASE_BASE32: &str = "b";
+
+fn format_peer_id(peer_id: &PeerId, format: PeerIdFormat) -> String {
+  match format {
+    PeerIdFormat::Base58 => peer_id.to_base58(),
+    PeerIdFormat::Base32 => {
+      let mut cid = CID_V1_LIBP2P_KEY_PREFIX.to_vec();
+      cid.append(&mut peer_id.to_bytes());
+      format!("{}{}", MULTIBASE_BASE32, BASE32_NOPAD.encode(&cid).to_lowercase())
+    }
+  }
+}
+
+/// Peer id given either in base58 or in its base32 CIDv1 form, base58 peer ids never start with the base32 prefix.
+fn parse_peer_id(value: &str) -> Result<PeerId, String> {
+  match value.strip_prefix(MULTIBASE_BASE32) {
+    Some(encoded) => {
+      let cid = BASE32_NOPAD
+        .decode(encoded.to_uppercase().as_bytes())
+        .map_err(|e| format!("invalid base32 peer id: {}", e))?;
+      let multihash = cid
+        .strip_prefix(&CID_V1_LIBP2P_KEY_PREFIX[..])
+        .ok_or("invalid base32 peer id: not the CIDv1 of a libp2p key")?;
+      PeerId::from_bytes(multihash).map_err(|e| format!("invalid peer id: {}", e))
+    }
+    None => PeerId::from_str(value).map_err(|e| format!("invalid peer id: {}", e)),
+  }
+}
+
+fn peer_id_of(matches: &ArgMatches, name: &str) -> Result<PeerId, Box<dyn Error>> {
+  Ok(parse_peer_id(matches.value_of_t::<String>(name)?.as_str())?)
+}
+
+/// Decimals of the token in a balance response, the daemon only reports them for tokens exposing ERC20 metadata.
+fn token_decimals(response: &GetBalanceResponse) -> Result<i64, String> {
+  let balance = response.balance.as_ref().ok_or("invalid response: balance empty")?;
+  let metadata = balance
+    .token_metadata
+    .as_ref()
+    .ok_or("token has no metadata, decimals are unknown")?;
+  Ok(metadata.decimals as i64)
+}
+
+const ARG_LOG_LEVEL: &str = "log-level";
+
+pub fn arg_log_level<'a>() -> Arg<'a> {
+  Arg::new(ARG_LOG_LEVEL)
+    .long(ARG_LOG_LEVEL)
+    .takes_value(true)
+    .value_name("LEVEL")
+    .possible_values(["off", "error", "warn", "info", "debug", "trace"])
+    .help("default log level, RUST_LOG takes precedence")
+}
+
+const ARG_LOG_TARGET: &str = "log-target";
+
+pub fn arg_log_target<'a>() -> Arg<'a> {
+  Arg::new(ARG_LOG_TARGET)
+    .long(ARG_LOG_TARGET)
+    .takes_value(true)
+    .multiple_occurrences(true)
+    .value_name("MODULE=LEVEL")
+    .validator(parse_log_target)
+    .help("log level for a module (e.g. p2pim::reactor=debug), RUST_LOG takes precedence")
+}
+
+fn parse_log_target(s: &str) -> Result<(String, LevelFilter), String> {
+  let (module, level) = s.split_once('=').ok_or_else(|| format!("expected MODULE=LEVEL: {}", s))?;
+  if module.is_empty() {
+    return Err(format!("empty module: {}", s));
+  }
+  let level = LevelFilter::from_str(level).map_err(|_| format!("invalid log level: {}", level))?;
+  Ok((module.to_string(), level))
+}
+
+pub fn init_logger(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+  let mut builder = logger_builder(matches)?;
+  builder.parse_env(env_logger::Env::default());
+  builder.try_init()?;
+  Ok(())
+}
+
+fn logger_builder(matches: &ArgMatches) -> Result<env_logger::Builder, Box<dyn Error>> {
+  let mut builder = env_logger::Builder::new();
+  if let Some(level) = matches.value_of(ARG_LOG_LEVEL) {
+    builder.filter_level(LevelFilter::from_str(level)?);
+  }
+  for target in matches.values_of(ARG_LOG_TARGET).into_iter().flatten() {
+    let (module, level) = parse_log_target(target)?;
+    builder.filter_module(module.as_str(), level);
+  }
+  Ok(builder)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::cmd::testutil::{FakeDaemon, TOKEN_DECIMALS};
+  use clap::Command;
+  use log::{Level, Log, Metadata};
+  use p2pim::proto::api::p2pim_client::P2pimClient;
+  use p2pim::proto::api::GetBalanceRequest;
+
+  #[test]
+  fn log_targets_mapped_to_levels() {
+    let matches = Command::new("p2pim")
+      .arg(arg_log_level())
+      .arg(arg_log_target())
+      .get_matches_from([
+        "p2pim",
+        "--log-level",
+        "warn",
+        "--log-target",
+        "p2pim::reactor=trace",
+        "--log-target",
+        "libp2p=off",
+      ]);
+
+    let logger = logger_builder(&matches).unwrap().build();
+
+    let enabled = |target, level| logger.enabled(&Metadata::builder().target(target).level(level).build());
+    assert!(enabled("p2pim::reactor", Level::Trace));
+    assert!(enabled("p2pim::reactor::lessor", Level::Debug));
+    assert!(enabled("p2pim::onchain", Level::Warn));
+    assert!(!enabled("p2pim::onchain", Level::Info));
+    assert!(!enabled("libp2p::swarm", Level::Error));
+  }
+
+  #[test]
+  fn amount_truncated_to_the_decimals() {
+    let amount = BigDecimal::from_str("1234.56789").unwrap();
+
+    assert_eq!(format_amount(&amount, 2), "1234.56");
+    assert_eq!(format_amount(&amount, 0), "1234");
+    assert_eq!(format_amount(&amount, 6), "1234.567890");
+  }
+
+  #[test]
+  fn peer_id_round_tripped_in_both_formats() {
+    let peer_id = PeerId::from(libp2p::identity::Keypair::generate_secp256k1().public());
+
+    let base58 = format_peer_id(&peer_id, PeerIdFormat::Base58);
+    let base32 = format_peer_id(&peer_id, PeerIdFormat::Base32);
+
+    assert_eq!(base58, peer_id.to_base58());
+    // CIDv1 with the libp2p-key codec of an inlined key
+    assert!(base32.starts_with("bafzaa"), "{}", base32);
+    assert_eq!(parse_peer_id(&base58).unwrap(), peer_id);
+    assert_eq!(parse_peer_id(&base32).unwrap(), peer_id);
+    assert!(parse_peer_id(&base32[..base32.len() - 2]).is_err());
+    assert!(parse_peer_id("bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi").is_err());
+  }
+
+  #[test]
+  fn malformed_log_target_rejected() {
+    assert!(parse_log_target("p2pim::reactor").is_err());
+    assert!(parse_log_target("=debug").is_err());
+    assert!(parse_log_target("p2pim=loud").is_err());
+    assert_eq!(parse_log_target("p2pim=debug"), Ok(("p2pim".to_string(), LevelFilter::Debug)));
+  }
+
+  #[tokio::test]
+  async fn decimals_read_from_the_balance_response() {
+    let url = FakeDaemon::default().serve().await;
+    let mut client = P2pimClient::connect(url).await.unwrap();
+
+    let response = client.get_balance(GetBalanceRequest::default()).await.unwrap();
+
+    assert_eq!(token_decimals(response.get_ref()), Ok(TOKEN_DECIMALS as i64));
+    let mut without_metadata = response.into_inner();
+    without_metadata.balance.as_mut().unwrap().token_metadata = None;
+    assert!(token_decimals(&without_metadata).is_err());
+  }
+}
@@ -1,10 +1,15 @@
-use crate::cmd::{arg_url, ARG_URL};
+use crate::cmd::{
+  arg_format, arg_peer_id_format, arg_url, format_peer_id, OutputFormat, PeerIdFormat, ARG_FORMAT, ARG_PEER_ID_FORMAT,
+  ARG_URL,
+};
 use clap::{ArgMatches, Command};
 use libp2p::PeerId;
 use p2pim::proto::api::swarm_client::SwarmClient;
-use p2pim::proto::api::GetConnectedPeersRequest;
+use p2pim::proto::api::{GetConnectedPeersRequest, GetPendingOperationsRequest, PendingOperationKind};
+use std::time::Duration;
 
 const CMD_PEERS: &str = "peers";
+const CMD_PENDING: &str = "pending";
 
 pub fn command<'a>() -> Command<'a> {
   Command::new("swarm")
@@ -12,44 +17,185 @@ pub fn command<'a>() -> Command<'a> {
     .subcommand_required(true)
     .arg_required_else_help(true)
     .subcommand(command_peers())
+    .subcommand(command_pending())
 }
 
 fn command_peers<'a>() -> Command<'a> {
-  Command::new(CMD_PEERS).about("lists connected peers").arg(arg_url())
+  Command::new(CMD_PEERS)
+    .about("lists connected peers")
+    .arg(arg_url())
+    .arg(arg_format())
+    .arg(arg_peer_id_format())
+}
+
+fn command_pending<'a>() -> Command<'a> {
+  Command::new(CMD_PENDING)
+    .about("lists the requests waiting for a peer response, the daemon must run with --debug-rpc")
+    .arg(arg_url())
+    .arg(arg_format())
+    .arg(arg_peer_id_format())
 }
 
 pub fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
   match matches.subcommand() {
     Some((CMD_PEERS, m)) => run_peers(m),
+    Some((CMD_PENDING, m)) => run_pending(m),
     _ => unreachable!("this should not happen if we have all the cases covered"),
   }
 }
 
 pub fn run_peers(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
   let rpc_url = matches.value_of_t(ARG_URL)?;
+  let format = matches.value_of_t(ARG_FORMAT)?;
+  let peer_id_format = matches.value_of_t(ARG_PEER_ID_FORMAT)?;
   tokio::runtime::Builder::new_multi_thread()
     .enable_all()
     .build()
     .unwrap()
-    .block_on(run_peers_async(rpc_url))
+    .block_on(run_peers_async(rpc_url, format, peer_id_format))
 }
 
-async fn run_peers_async(rpc_url: String) -> Result<(), Box<dyn std::error::Error>> {
+async fn run_peers_async(
+  rpc_url: String,
+  format: OutputFormat,
+  peer_id_format: PeerIdFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
   let mut client = SwarmClient::connect(rpc_url).await?;
   let req = GetConnectedPeersRequest {};
   let response = client.get_connected_peers(req).await?;
-  let result = response
+  let peers = response
     .get_ref()
     .peer_list
     .iter()
-    .enumerate()
-    .map(|(i, p)| PeerId::from_bytes(p.data.as_slice()).map(|c| format!("{}: {}", i, c)))
-    .collect::<Result<Vec<String>, _>>()?
-    .join("\n");
-  if result.is_empty() {
-    println!("no peers")
-  } else {
-    println!("{}", result);
+    .map(|p| PeerId::from_bytes(p.data.as_slice()))
+    .collect::<Result<Vec<PeerId>, _>>()?;
+  println!("{}", format_peers(peers.as_slice(), format, peer_id_format));
+  Ok(())
+}
+
+fn format_peers(peers: &[PeerId], format: OutputFormat, peer_id_format: PeerIdFormat) -> String {
+  match format {
+    OutputFormat::Text => {
+      let result = peers
+        .iter()
+        .enumerate()
+        .map(|(i, c)| format!("{}: {}", i, format_peer_id(c, peer_id_format)))
+        .collect::<Vec<String>>()
+        .join("\n");
+      if result.is_empty() {
+        "no peers".to_string()
+      } else {
+        result
+      }
+    }
+    OutputFormat::Json => serde_json::json!({
+      "peers": peers.iter().map(|p| format_peer_id(p, peer_id_format)).collect::<Vec<String>>(),
+    })
+    .to_string(),
+  }
+}
+
+pub fn run_pending(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+  let rpc_url = matches.value_of_t(ARG_URL)?;
+  let format = matches.value_of_t(ARG_FORMAT)?;
+  let peer_id_format = matches.value_of_t(ARG_PEER_ID_FORMAT)?;
+  tokio::runtime::Builder::new_multi_thread()
+    .enable_all()
+    .build()
+    .unwrap()
+    .block_on(run_pending_async(rpc_url, format, peer_id_format))
+}
+
+async fn run_pending_async(
+  rpc_url: String,
+  format: OutputFormat,
+  peer_id_format: PeerIdFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+  let mut client = SwarmClient::connect(rpc_url).await?;
+  let response = client
+    .get_pending_operations(GetPendingOperationsRequest {})
+    .await?
+    .into_inner();
+  let operations = response
+    .operations
+    .iter()
+    .map(|o| -> Result<_, Box<dyn std::error::Error>> {
+      let peer_id = PeerId::from_bytes(o.peer_id.as_ref().ok_or("invalid response: peer id empty")?.data.as_slice())?;
+      let kind = match PendingOperationKind::from_i32(o.kind) {
+        Some(PendingOperationKind::Challenge) => "challenge",
+        Some(PendingOperationKind::Retrieve) => "retrieve",
+        Some(PendingOperationKind::Proposal) => "proposal",
+        Some(PendingOperationKind::Quote) => "quote",
+        _ => "unknown",
+      };
+      let age = o
+        .age
+        .as_ref()
+        .map(|a| Duration::new(a.seconds as u64, a.nanos as u32))
+        .unwrap_or_default();
+      Ok((kind, format_peer_id(&peer_id, peer_id_format), o, age))
+    })
+    .collect::<Result<Vec<_>, _>>()?;
+  match format {
+    OutputFormat::Text => {
+      if operations.is_empty() {
+        println!("no pending operations");
+      }
+      for (kind, peer_id, operation, age) in operations.iter() {
+        let block = if operation.kind == PendingOperationKind::Challenge as i32 {
+          format!(" block {}", operation.block_number)
+        } else {
+          String::new()
+        };
+        println!(
+          "{} {} id {}{}: waiting {:.1}s, {} listeners",
+          kind,
+          peer_id,
+          operation.id,
+          block,
+          age.as_secs_f64(),
+          operation.listeners
+        );
+      }
+    }
+    OutputFormat::Json => {
+      let json = serde_json::json!({
+        "operations": operations
+          .iter()
+          .map(|(kind, peer_id, operation, age)| serde_json::json!({
+            "kind": kind,
+            "peer_id": peer_id,
+            "id": operation.id,
+            "block_number": operation.block_number,
+            "age_secs": age.as_secs_f64(),
+            "listeners": operation.listeners,
+          }))
+          .collect::<Vec<_>>(),
+      });
+      println!("{}", json);
+    }
   }
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::cmd::parse_peer_id;
+
+  #[test]
+  fn peers_json_parsed_back() {
+    let peers = vec![PeerId::random(), PeerId::random()];
+    for peer_id_format in [PeerIdFormat::Base58, PeerIdFormat::Base32] {
+      let output = format_peers(peers.as_slice(), OutputFormat::Json, peer_id_format);
+      let json: serde_json::Value = serde_json::from_str(output.as_str()).unwrap();
+      let parsed = json["peers"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|p| parse_peer_id(p.as_str().unwrap()).unwrap())
+        .collect::<Vec<_>>();
+      assert_eq!(parsed, peers);
+    }
+  }
+}
@@ -0,0 +1,288 @@
+//! Fake daemon serving the api on a local port, so the commands can be tested without a chain or a swarm.
+
+// Not every test uses every helper
+#![allow(dead_code)]
+// The helpers return the Status the fake handlers answer with
+#![allow(clippy::result_large_err)]
+
+use futures::StreamExt;
+use libp2p::PeerId;
+use p2pim::cryptography::{self, MerkleTree, Service as _};
+use p2pim::proto::api::balance_entry::TokenMetadata;
+use p2pim::proto::api::list_storage_rented_response::StorageRentedData;
+use p2pim::proto::api::p2pim_server::{P2pim, P2pimServer};
+use p2pim::proto::api::store_stream_request::Request as StoreStreamPart;
+use p2pim::proto::api::*;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tonic::{Request, Response, Status, Streaming};
+
+pub const TOKEN_DECIMALS: u32 = 18;
+
+// Time a retrieve stays in flight, so concurrent ones overlap
+const RETRIEVE_DELAY: Duration = Duration::from_millis(20);
+
+#[derive(Default)]
+pub struct State {
+  /// Data of the rented leases
+  pub leases: BTreeMap<(PeerId, u64), Vec<u8>>,
+  /// Data retrieve returns instead of the one of the lease
+  pub tampered: BTreeMap<(PeerId, u64), Vec<u8>>,
+  /// Retrieves never answer
+  pub stalled: bool,
+  /// Blocks served before retrieve_block starts failing
+  pub blocks_limit: Option<usize>,
+  pub blocks_served: usize,
+  /// Data chunks received by store_stream
+  pub stored_chunks: Vec<Vec<u8>>,
+  pub retrieving: usize,
+  pub max_retrieving: usize,
+}
+
+#[derive(Clone, Default)]
+pub struct FakeDaemon {
+  pub state: Arc<Mutex<State>>,
+}
+
+impl FakeDaemon {
+  /// Serves the daemon on a random local port, returns the url to connect to.
+  pub async fn serve(&self) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let incoming = futures::stream::unfold(listener, |listener| async move {
+      let stream = listener.accept().await.map(|(stream, _)| stream);
+      Some((stream, listener))
+    });
+    let server = tonic::transport::Server::builder()
+      .add_service(P2pimServer::new(self.clone()))
+      .serve_with_incoming(incoming);
+    tokio::spawn(server);
+    url
+  }
+
+  pub fn add_lease(&self, peer_id: PeerId, nonce: u64, data: Vec<u8>) {
+    self.state.lock().unwrap().leases.insert((peer_id, nonce), data);
+  }
+
+  fn lease_data(&self, peer_id: Option<&p2pim::proto::libp2p::PeerId>, nonce: u64) -> Result<Vec<u8>, Status> {
+    let peer_id = peer_id
+      .map(PeerId::try_from)
+      .transpose()
+      .map_err(|_| Status::invalid_argument("invalid peer_id"))?
+      .ok_or_else(|| Status::invalid_argument("empty peer_id"))?;
+    let state = self.state.lock().unwrap();
+    state
+      .leases
+      .get(&(peer_id, nonce))
+      .cloned()
+      .ok_or_else(|| Status::not_found("lease not found"))
+  }
+}
+
+pub fn merkle_tree(data: &[u8]) -> impl MerkleTree {
+  let mut merkle = cryptography::new_service(cryptography::BLOCK_SIZE_BYTES)
+    .unwrap()
+    .new_merkle_tree();
+  merkle.append_data(data);
+  merkle
+}
+
+#[tonic::async_trait]
+impl P2pim for FakeDaemon {
+  async fn get_info(&self, _: Request<GetInfoRequest>) -> Result<Response<GetInfoResponse>, Status> {
+    Err(Status::unimplemented("get_info"))
+  }
+
+  async fn get_balance(&self, request: Request<GetBalanceRequest>) -> Result<Response<GetBalanceResponse>, Status> {
+    Ok(Response::new(GetBalanceResponse {
+      balance: Some(BalanceEntry {
+        token_address: request.into_inner().token_address,
+        token_metadata: Some(TokenMetadata {
+          name: "Token".to_string(),
+          symbol: "TKN".to_string(),
+          decimals: TOKEN_DECIMALS,
+        }),
+        ..Default::default()
+      }),
+      network: String::new(),
+    }))
+  }
+
+  async fn approve(&self, _: Request<ApproveRequest>) -> Result<Response<ApproveResponse>, Status> {
+    Err(Status::unimplemented("approve"))
+  }
+
+  async fn deposit(&self, _: Request<DepositRequest>) -> Result<Response<DepositResponse>, Status> {
+    Err(Status::unimplemented("deposit"))
+  }
+
+  async fn withdraw(&self, _: Request<WithdrawRequest>) -> Result<Response<WithdrawResponse>, Status> {
+    Err(Status::unimplemented("withdraw"))
+  }
+
+  async fn store(&self, _: Request<StoreRequest>) -> Result<Response<StoreResponse>, Status> {
+    Err(Status::unimplemented("store"))
+  }
+
+  async fn store_stream(&self, request: Request<Streaming<StoreStreamRequest>>) -> Result<Response<StoreResponse>, Status> {
+    let mut stream = request.into_inner();
+    while let Some(part) = stream.next().await {
+      if let Some(StoreStreamPart::Data(chunk)) = part?.request {
+        self.state.lock().unwrap().stored_chunks.push(chunk);
+      }
+    }
+    Ok(Response::new(StoreResponse {
+      transaction_hash: Some(web3::types::H256::repeat_byte(1).into()),
+    }))
+  }
+
+  async fn retrieve(&self, request: Request<RetrieveRequest>) -> Result<Response<RetrieveResponse>, Status> {
+    let request = request.into_inner();
+    if self.state.lock().unwrap().stalled {
+      futures::future::pending::<()>().await;
+    }
+    let mut data = self.lease_data(request.peer_id.as_ref(), request.nonce)?;
+    {
+      let mut state = self.state.lock().unwrap();
+      state.retrieving += 1;
+      state.max_retrieving = std::cmp::max(state.max_retrieving, state.retrieving);
+    }
+    tokio::time::sleep(RETRIEVE_DELAY).await;
+    let mut state = self.state.lock().unwrap();
+    state.retrieving -= 1;
+    let peer_id = PeerId::try_from(request.peer_id.as_ref().unwrap()).unwrap();
+    if let Some(tampered) = state.tampered.get(&(peer_id, request.nonce)) {
+      data = tampered.clone();
+    }
+    Ok(Response::new(RetrieveResponse { data }))
+  }
+
+  async fn retrieve_block(&self, request: Request<RetrieveBlockRequest>) -> Result<Response<RetrieveBlockResponse>, Status> {
+    let request = request.into_inner();
+    let data = self.lease_data(request.peer_id.as_ref(), request.nonce)?;
+    {
+      let mut state = self.state.lock().unwrap();
+      if state.blocks_limit.map(|limit| state.blocks_served >= limit).unwrap_or(false) {
+        return Err(Status::unavailable("connection lost"));
+      }
+      state.blocks_served += 1;
+    }
+    let block_size = cryptography::BLOCK_SIZE_BYTES;
+    let block_start = request.block_number as usize * block_size;
+    if block_start >= data.len() {
+      return Err(Status::out_of_range("block is out of bounds"));
+    }
+    let block_end = std::cmp::min(block_start + block_size, data.len());
+    let mut merkle = merkle_tree(data.as_slice());
+    Ok(Response::new(RetrieveBlockResponse {
+      block_data: data[block_start..block_end].to_vec(),
+      proof: merkle
+        .proof(request.block_number as usize)
+        .into_iter()
+        .map(|p| web3::types::H256(p).into())
+        .collect(),
+      merkle_root: merkle.root().to_vec(),
+      size: data.len() as u64,
+    }))
+  }
+
+  async fn challenge(&self, _: Request<ChallengeRequest>) -> Result<Response<ChallengeResponse>, Status> {
+    Err(Status::unimplemented("challenge"))
+  }
+
+  async fn challenge_random(&self, _: Request<ChallengeRandomRequest>) -> Result<Response<ChallengeRandomResponse>, Status> {
+    Err(Status::unimplemented("challenge_random"))
+  }
+
+  async fn list_storage_rented(
+    &self,
+    _: Request<ListStorageRentedRequest>,
+  ) -> Result<Response<ListStorageRentedResponse>, Status> {
+    let state = self.state.lock().unwrap();
+    let storage_rented_data = state
+      .leases
+      .keys()
+      .map(|(peer_id, nonce)| StorageRentedData {
+        peer_id: Some((*peer_id).into()),
+        nonce: *nonce,
+        ..Default::default()
+      })
+      .collect();
+    Ok(Response::new(ListStorageRentedResponse { storage_rented_data }))
+  }
+
+  async fn get_lease(&self, request: Request<GetLeaseRequest>) -> Result<Response<GetLeaseResponse>, Status> {
+    let request = request.into_inner();
+    let data = self.lease_data(request.peer_id.as_ref(), request.nonce)?;
+    Ok(Response::new(GetLeaseResponse {
+      peer_id: request.peer_id,
+      nonce: request.nonce,
+      merkle_root: merkle_tree(data.as_slice()).root().to_vec(),
+      size: data.len() as u64,
+      ..Default::default()
+    }))
+  }
+
+  async fn compute_parameters(
+    &self,
+    _: Request<ComputeParametersRequest>,
+  ) -> Result<Response<ComputeParametersResponse>, Status> {
+    Err(Status::unimplemented("compute_parameters"))
+  }
+
+  async fn verify_signature(&self, _: Request<VerifySignatureRequest>) -> Result<Response<VerifySignatureResponse>, Status> {
+    Err(Status::unimplemented("verify_signature"))
+  }
+
+  async fn version(&self, _: Request<VersionRequest>) -> Result<Response<VersionResponse>, Status> {
+    Err(Status::unimplemented("version"))
+  }
+
+  async fn get_audit_log(&self, _: Request<GetAuditLogRequest>) -> Result<Response<GetAuditLogResponse>, Status> {
+    Err(Status::unimplemented("get_audit_log"))
+  }
+
+  async fn find_providers(&self, _: Request<FindProvidersRequest>) -> Result<Response<FindProvidersResponse>, Status> {
+    Err(Status::unimplemented("find_providers"))
+  }
+
+  async fn reindex(&self, _: Request<ReindexRequest>) -> Result<Response<ReindexResponse>, Status> {
+    Err(Status::unimplemented("reindex"))
+  }
+}
+
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+  pub fn new() -> Self {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+      "p2pim-cmd-test-{}-{}",
+      std::process::id(),
+      COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    std::fs::create_dir_all(&path).unwrap();
+    TempDir(path)
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.0
+  }
+}
+
+impl Drop for TempDir {
+  fn drop(&mut self) {
+    let _ = std::fs::remove_dir_all(&self.0);
+  }
+}
+
+/// Data spanning a few blocks, with a last one not full.
+pub fn sample_data(blocks: usize) -> Vec<u8> {
+  (0..blocks * cryptography::BLOCK_SIZE_BYTES - 7)
+    .map(|i| (i % 251) as u8)
+    .collect()
+}
@@ -0,0 +1,54 @@
+use crate::cmd::{arg_format, arg_url, OutputFormat, ARG_FORMAT, ARG_URL};
+use clap::{ArgMatches, Command};
+use p2pim::proto::api::p2pim_client::P2pimClient;
+use p2pim::proto::api::VersionRequest;
+use p2pim::version;
+
+pub const CMD_NAME: &str = "version";
+
+pub fn command<'a>() -> Command<'a> {
+  Command::new(CMD_NAME)
+    .about("show client and daemon versions")
+    .arg(arg_url())
+    .arg(arg_format())
+}
+
+pub fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+  let rpc_url = matches.value_of_t(ARG_URL)?;
+  let format = matches.value_of_t(ARG_FORMAT)?;
+  tokio::runtime::Builder::new_multi_thread()
+    .enable_all()
+    .build()
+    .unwrap()
+    .block_on(run_version(rpc_url, format))
+}
+
+async fn run_version(rpc_url: String, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+  let mut client = P2pimClient::connect(rpc_url).await?;
+  let response = client.version(VersionRequest {}).await?;
+  let daemon = response.get_ref();
+  match format {
+    OutputFormat::Text => {
+      println!("Client Version: {} ({})", version::VERSION, version::GIT_COMMIT);
+      println!("Daemon Version: {} ({})", daemon.version, daemon.git_commit);
+      println!("  Protocol Version: {}", daemon.protocol_version);
+      println!("  P2pim Protocol  : {}", daemon.p2pim_protocol);
+    }
+    OutputFormat::Json => {
+      let json = serde_json::json!({
+        "client": {
+          "version": version::VERSION,
+          "git_commit": version::GIT_COMMIT,
+        },
+        "daemon": {
+          "version": daemon.version,
+          "git_commit": daemon.git_commit,
+          "protocol_version": daemon.protocol_version,
+          "p2pim_protocol": daemon.p2pim_protocol,
+        },
+      });
+      println!("{}", json);
+    }
+  }
+  Ok(())
+}
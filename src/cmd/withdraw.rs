@@ -1,50 +1,64 @@
-use crate::cmd::{arg_amount, arg_token, arg_url, ARG_AMOUNT, ARG_TOKEN, ARG_URL};
+use crate::cmd::{arg_amount, arg_network, arg_token, arg_url, network_of, token_decimals, ARG_AMOUNT, ARG_TOKEN, ARG_URL};
 use bigdecimal::BigDecimal;
-use clap::{ArgMatches, Command};
+use clap::{Arg, ArgMatches, Command};
 use num_bigint::{Sign, ToBigInt};
 use p2pim::proto::api::p2pim_client::P2pimClient;
 use p2pim::proto::api::{GetBalanceRequest, WithdrawRequest};
 use std::convert::TryInto;
-use web3::types::H256;
+use std::str::FromStr;
+use web3::types::{Address, H256};
 
 pub const CMD_NAME: &str = "withdraw";
 
+const ARG_TO: &str = "to";
+
 pub fn command<'a>() -> Command<'a> {
   Command::new(CMD_NAME)
     .about("withdraw tokens from adjudicator")
     .arg(arg_url())
     .arg(arg_token())
     .arg(arg_amount())
+    .arg(arg_network())
+    .arg(arg_to())
+}
+
+fn arg_to<'a>() -> Arg<'a> {
+  Arg::new(ARG_TO)
+    .long(ARG_TO)
+    .takes_value(true)
+    .value_name("ADDRESS")
+    .validator(Address::from_str)
+    .help("address receiving the tokens, the wallet account of the daemon if not present")
 }
 
 pub fn run(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
   let rpc_url = matches.value_of_t(ARG_URL)?;
   let token_addr = matches.value_of_t(ARG_TOKEN)?;
   let amount = matches.value_of_t(ARG_AMOUNT)?;
+  let network = network_of(matches);
+  let destination = matches.value_of(ARG_TO).map(Address::from_str).transpose()?;
   tokio::runtime::Builder::new_multi_thread()
     .enable_all()
     .build()
     .unwrap()
-    .block_on(run_withdraw(rpc_url, token_addr, amount))
+    .block_on(run_withdraw(rpc_url, token_addr, amount, network, destination))
 }
 
 async fn run_withdraw(
   rpc_url: String,
   token_addr: web3::types::Address,
   amount: BigDecimal,
+  network: String,
+  destination: Option<Address>,
 ) -> Result<(), Box<dyn std::error::Error>> {
   let mut client = P2pimClient::connect(rpc_url).await?;
   let get_balance_request = GetBalanceRequest {
     token_address: Some(token_addr.into()),
+    network: network.clone(),
+    refresh: false,
   };
   let response = client.get_balance(get_balance_request).await?;
-  let decimals = response
-    .get_ref()
-    .balance
-    .as_ref()
-    .and_then(|v| v.token_metadata.as_ref())
-    .map(|v| v.decimals)
-    .ok_or("TODO: invalid response")? as i64;
+  let decimals = token_decimals(response.get_ref())?;
   let abs_amount: BigDecimal = amount * BigDecimal::new(1.into(), -decimals);
   if !abs_amount.is_integer() {
     Err("TODO(formatting): the amount has too many decimals".into())
@@ -56,6 +70,8 @@ async fn run_withdraw(
       .withdraw(WithdrawRequest {
         token_address: Some(token_addr.into()),
         amount: Some(conv_amount),
+        network,
+        destination: destination.map(Into::into),
       })
       .await?;
     let trans_hash: H256 = response
@@ -64,7 +80,10 @@ async fn run_withdraw(
       .as_ref()
       .ok_or("unexpected empty transaction hash response")?
       .into();
-    println!("Withdraw sent, transaction 0x{:x}", trans_hash);
+    match destination {
+      Some(destination) => println!("Withdraw to 0x{:x} sent, transaction 0x{:x}", destination, trans_hash),
+      None => println!("Withdraw sent, transaction 0x{:x}", trans_hash),
+    }
     Ok(())
   }
 }
@@ -2,44 +2,147 @@ use log::trace;
 use rs_merkle::{Hasher, MerkleProof};
 use sha3::digest::{FixedOutput, FixedOutputReset};
 use sha3::{Digest, Keccak256};
+use std::convert::TryInto;
+use std::fmt::{Display, Formatter};
 
+// Block size the adjudicator contract uses to verify proofs, any other size produces proofs it rejects
 pub const BLOCK_SIZE_BYTES: usize = 544;
 
+// Root of zero bytes of data, the keccak256 of the empty input (0xc5d2...a470) as the adjudicator contract expects it for a
+// tree without leaves. There is no block to prove for such data, so proofs of empty data are always rejected
+pub const EMPTY_MERKLE_ROOT: [u8; 32] = [
+  0xc5, 0xd2, 0x46, 0x01, 0x86, 0xf7, 0x23, 0x3c, 0x92, 0x7e, 0x7d, 0xb2, 0xdc, 0xc7, 0x03, 0xc0, 0xe5, 0x00, 0xb6, 0x53,
+  0xca, 0x82, 0x27, 0x3b, 0x7b, 0xfa, 0xd8, 0x04, 0x5d, 0x85, 0xa4, 0x70,
+];
+
 pub trait MerkleTree {
   fn append_data<T: AsRef<[u8]>>(&mut self, data: T);
   fn root(&mut self) -> [u8; 32];
   fn proof(&mut self, leaf_index: usize) -> Vec<[u8; 32]>;
 }
 
+#[derive(Debug)]
+pub enum VerifyError {
+  EmptyData,
+  InvalidMerkleRoot(usize),
+  LeafOutOfRange { leaf_index: usize, leaves: usize },
+  InvalidBlockSize { size: usize, expected: usize },
+  InvalidProofLength { length: usize, max: usize },
+}
+
+impl Display for VerifyError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      VerifyError::EmptyData => f.write_str("cannot verify a proof of empty data"),
+      VerifyError::InvalidMerkleRoot(length) => write!(f, "invalid merkle root length {}", length),
+      VerifyError::LeafOutOfRange { leaf_index, leaves } => {
+        write!(f, "leaf {} out of range, the tree has {} leaves", leaf_index, leaves)
+      }
+      VerifyError::InvalidBlockSize { size, expected } => {
+        write!(f, "invalid block data size {}, expected {}", size, expected)
+      }
+      VerifyError::InvalidProofLength { length, max } => {
+        write!(f, "invalid proof length {}, expected between 1 and {}", length, max)
+      }
+    }
+  }
+}
+
+impl std::error::Error for VerifyError {}
+
 pub trait Service: Send + Sync + Unpin + Clone + 'static {
   type MerkleTreeType: MerkleTree;
-  fn new_merkle_tree() -> Self::MerkleTreeType;
-  fn verify(leaf_index: usize, block_data: &[u8], proof: Vec<[u8; 32]>, merkle_root: [u8; 32], total_size: usize) -> bool;
+  fn block_size(&self) -> usize;
+  fn new_merkle_tree(&self) -> Self::MerkleTreeType;
+  fn verify(
+    &self,
+    leaf_index: usize,
+    block_data: &[u8],
+    proof: Vec<[u8; 32]>,
+    merkle_root: &[u8],
+    total_size: usize,
+  ) -> Result<bool, VerifyError>;
+}
+
+#[derive(Debug)]
+pub struct ZeroBlockSize;
+
+impl Display for ZeroBlockSize {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    f.write_str("block size must be greater than zero")
+  }
 }
 
-pub fn new_service() -> impl Service {
-  Implementation
+impl std::error::Error for ZeroBlockSize {}
+
+pub fn new_service(block_size: usize) -> Result<impl Service, ZeroBlockSize> {
+  if block_size == 0 {
+    return Err(ZeroBlockSize);
+  }
+  Ok(Implementation { block_size })
 }
 
 #[derive(Clone)]
-struct Implementation;
+struct Implementation {
+  block_size: usize,
+}
 
 impl Service for Implementation {
   type MerkleTreeType = RsMerkleTree;
 
-  fn new_merkle_tree() -> Self::MerkleTreeType {
+  fn block_size(&self) -> usize {
+    self.block_size
+  }
+
+  fn new_merkle_tree(&self) -> Self::MerkleTreeType {
     RsMerkleTree {
       inner: rs_merkle::MerkleTree::<Keccak256Hasher>::new(),
       digest: Keccak256::new(),
       current_bytes: 0,
+      block_size: self.block_size,
     }
   }
 
-  fn verify(leaf_index: usize, block_data: &[u8], proof: Vec<[u8; 32]>, merkle_root: [u8; 32], total_size: usize) -> bool {
+  fn verify(
+    &self,
+    leaf_index: usize,
+    block_data: &[u8],
+    proof: Vec<[u8; 32]>,
+    merkle_root: &[u8],
+    total_size: usize,
+  ) -> Result<bool, VerifyError> {
+    if total_size == 0 {
+      return Err(VerifyError::EmptyData);
+    }
+    let merkle_root: [u8; 32] = merkle_root
+      .try_into()
+      .map_err(|_| VerifyError::InvalidMerkleRoot(merkle_root.len()))?;
+    let total_leaves_count = total_size.div_ceil(self.block_size);
+    if leaf_index >= total_leaves_count {
+      return Err(VerifyError::LeafOutOfRange {
+        leaf_index,
+        leaves: total_leaves_count,
+      });
+    }
+    let expected_block_size = std::cmp::min(self.block_size, total_size - leaf_index * self.block_size);
+    if block_data.len() != expected_block_size {
+      return Err(VerifyError::InvalidBlockSize {
+        size: block_data.len(),
+        expected: expected_block_size,
+      });
+    }
+    // Leaves promoted in unbalanced trees have shorter proofs, but never longer than the tree depth
+    let max_proof_length = (usize::BITS - (total_leaves_count - 1).leading_zeros()) as usize;
+    if total_leaves_count > 1 && (proof.is_empty() || proof.len() > max_proof_length) {
+      return Err(VerifyError::InvalidProofLength {
+        length: proof.len(),
+        max: max_proof_length,
+      });
+    }
+
     let merkle_proof = MerkleProof::<Keccak256Hasher>::new(proof.clone());
     let indexes = [leaf_index];
     let leaf_hashes = [Keccak256Hasher::hash(block_data)];
-    let total_leaves_count = total_size / BLOCK_SIZE_BYTES + (if total_size % BLOCK_SIZE_BYTES == 0 { 0 } else { 1 });
 
     trace!(
       "verifying proof merkle_root={} proof={} leaf_hash={} block_data={} total_leaves_count={}",
@@ -49,12 +152,7 @@ impl Service for Implementation {
       hex::encode(block_data),
       total_leaves_count
     );
-    merkle_proof.verify(
-      merkle_root,
-      indexes.as_slice(),
-      leaf_hashes.as_slice(),
-      total_size / BLOCK_SIZE_BYTES + (if total_size % BLOCK_SIZE_BYTES == 0 { 0 } else { 1 }),
-    )
+    Ok(merkle_proof.verify(merkle_root, indexes.as_slice(), leaf_hashes.as_slice(), total_leaves_count))
   }
 }
 
@@ -62,6 +160,7 @@ struct RsMerkleTree {
   inner: rs_merkle::MerkleTree<Keccak256Hasher>,
   digest: Keccak256,
   current_bytes: usize,
+  block_size: usize,
 }
 
 #[derive(Clone)]
@@ -79,14 +178,14 @@ impl Hasher for Keccak256Hasher {
 
 impl MerkleTree for RsMerkleTree {
   fn append_data<T: AsRef<[u8]>>(&mut self, data: T) {
-    let remaining = BLOCK_SIZE_BYTES - self.current_bytes % BLOCK_SIZE_BYTES;
+    let remaining = self.block_size - self.current_bytes % self.block_size;
 
     let mut current = data.as_ref();
     while !current.is_empty() {
       let (left, right) = current.split_at(std::cmp::min(remaining, current.len()));
       self.digest.update(left);
 
-      if self.current_bytes % BLOCK_SIZE_BYTES == 0 {
+      if self.current_bytes % self.block_size == 0 {
         let output = self.digest.finalize_fixed_reset();
         let mut result: [u8; 32] = Default::default();
         result.copy_from_slice(output.as_slice());
@@ -100,7 +199,7 @@ impl MerkleTree for RsMerkleTree {
   }
 
   fn root(&mut self) -> [u8; 32] {
-    if self.current_bytes & BLOCK_SIZE_BYTES != 0 {
+    if self.current_bytes & self.block_size != 0 {
       let digest_clone = self.digest.clone();
       let output = digest_clone.finalize_fixed();
       let mut result: [u8; 32] = Default::default();
@@ -109,7 +208,8 @@ impl MerkleTree for RsMerkleTree {
       self.inner.insert(result);
     }
 
-    let result = self.inner.uncommitted_root().expect("error geting merkle root");
+    // Without leaves there is no root, which only happens for empty data
+    let result = self.inner.uncommitted_root().unwrap_or(EMPTY_MERKLE_ROOT);
     self.inner.abort_uncommitted();
     trace!("merkle root hash={}", hex::encode(result));
     result
@@ -117,7 +217,7 @@ impl MerkleTree for RsMerkleTree {
 
   fn proof(&mut self, leaf_index: usize) -> Vec<[u8; 32]> {
     // TODO refactorL not very efficient, same code than other
-    if self.current_bytes & BLOCK_SIZE_BYTES != 0 {
+    if self.current_bytes & self.block_size != 0 {
       let digest_clone = self.digest.clone();
       let output = digest_clone.finalize_fixed();
       let mut result: [u8; 32] = Default::default();
@@ -129,3 +229,73 @@ impl MerkleTree for RsMerkleTree {
     other.proof(&[leaf_index]).proof_hashes().to_vec()
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn merkle_root(cryptography: &impl Service, data: &[u8]) -> [u8; 32] {
+    let mut merkle_tree = cryptography.new_merkle_tree();
+    merkle_tree.append_data(data);
+    merkle_tree.root()
+  }
+
+  #[test]
+  fn zero_block_size_rejected() {
+    assert!(new_service(0).is_err());
+  }
+
+  #[test]
+  fn block_size_honored() {
+    let data = (0..100u8).collect::<Vec<_>>();
+    let small = new_service(32).unwrap();
+    let large = new_service(64).unwrap();
+    let root = merkle_root(&small, &data);
+    assert_ne!(root, merkle_root(&large, &data));
+
+    let mut merkle_tree = small.new_merkle_tree();
+    merkle_tree.append_data(&data);
+    let proof = merkle_tree.proof(1);
+    assert!(small.verify(1, &data[32..64], proof.clone(), &root, data.len()).unwrap());
+    // The second block of the large size is the rest of the data, the small block is not it
+    assert!(matches!(
+      large.verify(1, &data[32..64], proof, &root, data.len()),
+      Err(VerifyError::InvalidBlockSize { size: 32, expected: 36 })
+    ));
+  }
+
+  #[test]
+  fn proof_verified_with_the_block_size() {
+    let data = (0..100u8).collect::<Vec<_>>();
+    let large = new_service(64).unwrap();
+    let root = merkle_root(&large, &data);
+    let mut merkle_tree = large.new_merkle_tree();
+    merkle_tree.append_data(&data);
+    let proof = merkle_tree.proof(0);
+    assert!(large.verify(0, &data[..64], proof.clone(), &root, data.len()).unwrap());
+    assert!(!large.verify(0, &data[36..], proof, &root, data.len()).unwrap());
+  }
+
+  #[test]
+  fn malformed_proof_is_an_error_not_a_failure() {
+    let data = (0..100u8).collect::<Vec<_>>();
+    let cryptography = new_service(32).unwrap();
+    let root = merkle_root(&cryptography, &data);
+    let mut merkle_tree = cryptography.new_merkle_tree();
+    merkle_tree.append_data(&data);
+    let proof = merkle_tree.proof(1);
+
+    assert!(matches!(
+      cryptography.verify(1, &data[32..64], proof.clone(), &root[..31], data.len()),
+      Err(VerifyError::InvalidMerkleRoot(31))
+    ));
+    assert!(matches!(
+      cryptography.verify(1, &data[32..64], Vec::new(), &root, data.len()),
+      Err(VerifyError::InvalidProofLength { length: 0, max: 2 })
+    ));
+
+    let mut wrong_proof = proof;
+    wrong_proof[0][0] ^= 1;
+    assert!(!cryptography.verify(1, &data[32..64], wrong_proof, &root, data.len()).unwrap());
+  }
+}
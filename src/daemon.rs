@@ -1,3 +1,4 @@
+use crate::data::Compression;
 use crate::lessor::Ask;
 use crate::onchain::Service;
 use crate::types::TokenMetadata;
@@ -5,10 +6,13 @@ use crate::{onchain, p2p};
 use bigdecimal::BigDecimal;
 use futures::future::try_join_all;
 use libp2p::identity::{secp256k1, Keypair};
+use libp2p::Multiaddr;
 use log::info;
 use num_bigint::{Sign, ToBigInt};
+use secrecy::Secret;
 use std::collections::HashMap;
 use std::error::Error;
+use std::fmt::{Display, Formatter};
 use std::future::Future;
 use std::net::SocketAddr;
 use std::ops::Range;
@@ -19,14 +23,27 @@ use web3::types::{Address, U256};
 
 pub struct DaemonOpts {
   pub rpc_addr: SocketAddr,
+  pub rpc_max_upload_size: usize,
+  // Serves the rpcs exposing internal state, meant for diagnosing and not for production
+  pub debug_rpc: bool,
+  pub block_size: usize,
+  pub data_compression: Compression,
+  // Fails the startup when the data folder is readable by other users, instead of warning
+  pub data_strict_permissions: bool,
   pub eth_opts: EthOpts,
+  // Networks whose balances are served besides the one of eth_opts, where leases are sealed
+  pub eth_networks: Vec<EthOpts>,
   pub lessor_opts: LessorOpts,
+  pub connection_opts: ConnectionOpts,
   pub mdns_opts: MdnsOpts,
+  pub ping_opts: PingOpts,
+  pub reactor_opts: ReactorOpts,
   pub s3_opts: S3Opts,
 }
 
 pub struct LessorOpts {
   pub token_lease_terms: HashMap<Address, TokenLeaseAsk>,
+  pub storage_quota: Option<u64>,
 }
 
 pub struct TokenLeaseAsk {
@@ -40,6 +57,8 @@ pub struct TokenLeaseAsk {
 pub struct EthOpts {
   pub url: Url,
   pub master_addr: Option<Address>,
+  pub poll_interval: Duration,
+  pub balance_max_age: Option<Duration>,
 }
 
 pub struct S3Opts {
@@ -47,78 +66,205 @@ pub struct S3Opts {
   pub s3_addr: SocketAddr,
 }
 
+pub struct ConnectionOpts {
+  pub listen_addresses: Vec<Multiaddr>,
+  pub max_established: u32,
+  pub max_pending: u32,
+  pub max_established_per_peer: u32,
+  pub substream_idle_timeout: Duration,
+  pub max_queued_messages: usize,
+  pub challenge_timeout: Duration,
+  pub retrieve_timeout: Duration,
+  pub proposal_timeout: Duration,
+  pub retrieve_retries: u32,
+  pub network_id: Option<String>,
+  pub agent_version: String,
+  pub known_peers_capacity: usize,
+}
+
 pub struct MdnsOpts {
   pub enabled: bool,
 }
 
+pub struct PingOpts {
+  pub keep_alive: bool,
+  pub interval: Duration,
+}
+
+pub struct ReactorOpts {
+  pub integrity_check_interval: Option<Duration>,
+  pub challenge_watchdog_interval: Option<Duration>,
+  pub expiry_check_interval: Option<Duration>,
+  pub lease_keepalive_interval: Option<Duration>,
+  pub let_data_grace_period: Duration,
+  pub confirmation_depth: u64,
+  pub max_concurrent_seals: usize,
+  pub max_proposal_rate: Option<usize>,
+  pub seal_gas_precheck: bool,
+  // In ETH
+  pub min_gas_balance: BigDecimal,
+  pub max_retrieve_size: usize,
+  pub retrieve_min_interval: Duration,
+  pub withdraw_expired: bool,
+}
+
+#[derive(Debug)]
+pub enum DaemonError {
+  AskTokenNotDeployed(Address),
+  AskTokenWithoutMetadata(Address),
+  DuplicateNetwork(String),
+  BlockSizeMismatch(usize),
+}
+
+impl Display for DaemonError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      DaemonError::AskTokenNotDeployed(address) => write!(f, "lessor ask token 0x{:x} is not deployed", address),
+      DaemonError::AskTokenWithoutMetadata(address) => write!(f, "lessor ask token 0x{:x} has no metadata", address),
+      DaemonError::DuplicateNetwork(network_id) => write!(f, "eth network {} is configured more than once", network_id),
+      DaemonError::BlockSizeMismatch(size) => write!(
+        f,
+        "block size {} differs from the adjudicator block size {}, proofs would fail on chain",
+        size,
+        crate::cryptography::BLOCK_SIZE_BYTES
+      ),
+    }
+  }
+}
+
+impl std::error::Error for DaemonError {}
+
+/// Asks of the lessor in token units, every token has to be deployed with its metadata to know its decimals.
+fn lessor_asks(
+  token_lease_terms: &HashMap<Address, TokenLeaseAsk>,
+  deployed_tokens: Vec<(Address, Option<TokenMetadata>)>,
+) -> Result<Vec<(Address, Ask)>, Box<dyn Error>> {
+  let deployed_map: HashMap<Address, Option<TokenMetadata>> = deployed_tokens.into_iter().collect();
+
+  token_lease_terms
+    .iter()
+    .map(|(token_address, opts)| -> Result<(Address, Ask), Box<dyn Error>> {
+      let metadata = deployed_map
+        .get(token_address)
+        .ok_or(DaemonError::AskTokenNotDeployed(*token_address))?
+        .as_ref()
+        .ok_or(DaemonError::AskTokenWithoutMetadata(*token_address))?;
+      Ok((
+        *token_address,
+        Ask {
+          duration_range: opts.duration_range.clone(),
+          size_range: opts.size_range.clone(),
+          max_penalty_rate: opts.max_penalty_rate,
+          min_tokens_total: convert_bigdecimal(opts.min_tokens_total.clone(), metadata.decimals)?,
+          min_tokens_gb_hour: convert_bigdecimal(opts.min_tokens_gb_hour.clone(), metadata.decimals)?,
+        },
+      ))
+    })
+    .collect()
+}
+
 pub async fn listen_and_serve(opts: &DaemonOpts) -> Result<(), Box<dyn std::error::Error>> {
   info!("initializing p2pim");
 
   let secp256k1_keypair = secp256k1::Keypair::generate();
   let keypair = Keypair::Secp256k1(secp256k1_keypair.clone());
-  let p2p = p2p::create_p2p(keypair, opts.mdns_opts.enabled).await?;
+  let p2p = p2p::create_p2p(
+    keypair,
+    p2p::P2pParams {
+      listen_addresses: opts.connection_opts.listen_addresses.clone(),
+      mdns_enabled: opts.mdns_opts.enabled,
+      ping_keep_alive: opts.ping_opts.keep_alive,
+      ping_interval: opts.ping_opts.interval,
+      max_established: opts.connection_opts.max_established,
+      max_pending: opts.connection_opts.max_pending,
+      max_established_per_peer: opts.connection_opts.max_established_per_peer,
+      substream_idle_timeout: opts.connection_opts.substream_idle_timeout,
+      max_queued_messages: opts.connection_opts.max_queued_messages,
+      challenge_timeout: opts.connection_opts.challenge_timeout,
+      retrieve_timeout: opts.connection_opts.retrieve_timeout,
+      proposal_timeout: opts.connection_opts.proposal_timeout,
+      retrieve_retries: opts.connection_opts.retrieve_retries,
+      network_id: opts.connection_opts.network_id.clone(),
+      agent_version: opts.connection_opts.agent_version.clone(),
+      known_peers_capacity: opts.connection_opts.known_peers_capacity,
+    },
+  )
+  .await?;
 
   type ServeFuture = Pin<Box<dyn Future<Output = Result<(), Box<dyn Error>>>>>;
 
-  let cryptography = crate::cryptography::new_service();
-  let data = crate::data::new_service(
-    cryptography,
-    dirs::home_dir()
-      .map(|v| {
-        let mut new_path = v;
-        new_path.push(".p2pim");
-        new_path.push("datastore");
-        new_path
-      })
-      .expect("no home dir found"),
-  );
-  let private_key_raw = secp256k1_keypair.secret().to_bytes();
+  if opts.block_size != crate::cryptography::BLOCK_SIZE_BYTES {
+    return Err(DaemonError::BlockSizeMismatch(opts.block_size).into());
+  }
+  let cryptography = crate::cryptography::new_service(opts.block_size)?;
+  let p2pim_dir = dirs::home_dir()
+    .map(|v| {
+      let mut new_path = v;
+      new_path.push(".p2pim");
+      new_path
+    })
+    .expect("no home dir found");
+  let data_folder = p2pim_dir.join("datastore");
+  crate::data::check_data_folder(&data_folder, opts.data_strict_permissions).await?;
+  let data = crate::data::new_service(cryptography, data_folder, opts.data_compression);
+  let onchain_params = |eth_opts: &EthOpts| onchain::OnchainParams {
+    eth_url: eth_opts.url.clone(),
+    private_key: Secret::new(secp256k1_keypair.secret().to_bytes()),
+    master_address: eth_opts.master_addr,
+    poll_interval: eth_opts.poll_interval,
+    balance_max_age: eth_opts.balance_max_age,
+  };
 
-  let onchain = crate::onchain::new_service(onchain::OnchainParams {
-    eth_url: opts.eth_opts.url.clone(),
-    private_key: private_key_raw,
-    master_address: opts.eth_opts.master_addr,
-  })
-  .await?;
+  let onchain = crate::onchain::new_service(onchain_params(&opts.eth_opts)).await?;
+  let mut networks = HashMap::new();
+  networks.insert(onchain.network_id().to_string(), onchain.clone());
+  for eth_opts in opts.eth_networks.iter() {
+    let network = crate::onchain::new_service(onchain_params(eth_opts)).await?;
+    let network_id = network.network_id().to_string();
+    if networks.insert(network_id.clone(), network).is_some() {
+      return Err(DaemonError::DuplicateNetwork(network_id).into());
+    }
+  }
 
-  let persistence = crate::persistence::new_service();
+  let persistence = crate::persistence::new_service(p2pim_dir.join("persistence"))?;
 
-  let deployed_map: HashMap<Address, Option<TokenMetadata>> = onchain.deployed_tokens().await.into_iter().collect();
+  let asks = lessor_asks(&opts.lessor_opts.token_lease_terms, onchain.deployed_tokens().await)?;
 
-  let asks = opts
-    .lessor_opts
-    .token_lease_terms
-    .iter()
-    .map(|(token_address, opts)| {
-      deployed_map
-        .get(token_address)
-        .map(|v| {
-          v.clone()
-            .ok_or_else::<Box<dyn Error>, _>(|| "TODO: Token with no metadata".into())
-        })
-        .unwrap_or_else(|| Err("TODO: Token not deployed".into()))
-        .and_then(|v| {
-          Ok((
-            token_address.clone(),
-            Ask {
-              duration_range: opts.duration_range.clone(),
-              size_range: opts.size_range.clone(),
-              max_penalty_rate: opts.max_penalty_rate,
-              min_tokens_total: convert_bigdecimal(opts.min_tokens_total.clone(), v.decimals)?,
-              min_tokens_gb_hour: convert_bigdecimal(opts.min_tokens_gb_hour.clone(), v.decimals)?,
-            },
-          ))
-        })
-    })
-    .collect::<Result<Vec<(Address, Ask)>, _>>()?;
-
-  let lessor = crate::lessor::new_service(asks);
+  let lessor = crate::lessor::new_service(asks, opts.lessor_opts.storage_quota, persistence.clone());
 
-  let (reactor, reactor_fut) = crate::reactor::new_service(data, lessor, onchain.clone(), p2p.clone(), persistence.clone());
+  let (reactor, reactor_fut) = crate::reactor::new_service(
+    data.clone(),
+    lessor,
+    onchain.clone(),
+    p2p.clone(),
+    persistence.clone(),
+    crate::reactor::ReactorParams {
+      integrity_check_interval: opts.reactor_opts.integrity_check_interval,
+      challenge_watchdog_interval: opts.reactor_opts.challenge_watchdog_interval,
+      expiry_check_interval: opts.reactor_opts.expiry_check_interval,
+      lease_keepalive_interval: opts.reactor_opts.lease_keepalive_interval,
+      let_data_grace_period: opts.reactor_opts.let_data_grace_period,
+      confirmation_depth: opts.reactor_opts.confirmation_depth,
+      max_concurrent_seals: opts.reactor_opts.max_concurrent_seals,
+      max_proposal_rate: opts.reactor_opts.max_proposal_rate,
+      seal_gas_precheck: opts.reactor_opts.seal_gas_precheck,
+      min_gas_balance: convert_bigdecimal(opts.reactor_opts.min_gas_balance.clone(), NATIVE_DECIMALS)?,
+      max_retrieve_size: opts.reactor_opts.max_retrieve_size,
+      retrieve_min_interval: opts.reactor_opts.retrieve_min_interval,
+      withdraw_expired: opts.reactor_opts.withdraw_expired,
+    },
+  );
 
+  let grpc_params = crate::grpc::GrpcParams {
+    rpc_addr: opts.rpc_addr,
+    debug_rpc: opts.debug_rpc,
+    max_upload_size: opts.rpc_max_upload_size,
+  };
   let grpc: ServeFuture = Box::pin(crate::grpc::listen_and_serve(
-    opts.rpc_addr,
+    grpc_params,
+    data,
     onchain.clone(),
+    networks,
     p2p.clone(),
     reactor.clone(),
     persistence.clone(),
@@ -133,6 +279,8 @@ pub async fn listen_and_serve(opts: &DaemonOpts) -> Result<(), Box<dyn std::erro
   try_join_all(futures).await.map(|_| ())
 }
 
+const NATIVE_DECIMALS: u8 = 18;
+
 fn convert_bigdecimal(amount: BigDecimal, decimals: u8) -> Result<U256, Box<dyn Error>> {
   let abs_amount = amount * BigDecimal::new(1.into(), -(decimals as i64));
   if !abs_amount.is_integer() {
@@ -145,3 +293,65 @@ fn convert_bigdecimal(amount: BigDecimal, decimals: u8) -> Result<U256, Box<dyn
     Ok(web3::types::U256::from_little_endian(bytes.as_slice()))
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::str::FromStr;
+
+  fn token_lease_terms(token_address: Address) -> HashMap<Address, TokenLeaseAsk> {
+    let ask = TokenLeaseAsk {
+      duration_range: Duration::from_secs(60)..Duration::from_secs(3600),
+      size_range: 1..1024,
+      min_tokens_total: BigDecimal::from_str("0.5").unwrap(),
+      min_tokens_gb_hour: BigDecimal::from_str("0.001").unwrap(),
+      max_penalty_rate: 1.0,
+    };
+    vec![(token_address, ask)].into_iter().collect()
+  }
+
+  fn metadata() -> TokenMetadata {
+    TokenMetadata {
+      name: "Token".to_string(),
+      symbol: "TKN".to_string(),
+      decimals: 18,
+    }
+  }
+
+  #[test]
+  fn ask_converted_to_token_units() {
+    let token = Address::repeat_byte(1);
+
+    let asks = lessor_asks(&token_lease_terms(token), vec![(token, Some(metadata()))]).unwrap();
+
+    assert_eq!(asks.len(), 1);
+    assert_eq!(asks[0].0, token);
+    assert_eq!(asks[0].1.min_tokens_total, U256::exp10(17) * 5);
+  }
+
+  #[test]
+  fn ask_token_not_deployed() {
+    let token = Address::repeat_byte(1);
+
+    let error = lessor_asks(&token_lease_terms(token), vec![(Address::repeat_byte(2), Some(metadata()))]).unwrap_err();
+
+    assert!(matches!(
+      error.downcast_ref::<DaemonError>(),
+      Some(DaemonError::AskTokenNotDeployed(address)) if *address == token
+    ));
+    assert!(error.to_string().contains(&format!("{:x}", token)), "{}", error);
+  }
+
+  #[test]
+  fn ask_token_without_metadata() {
+    let token = Address::repeat_byte(1);
+
+    let error = lessor_asks(&token_lease_terms(token), vec![(token, None)]).unwrap_err();
+
+    assert!(matches!(
+      error.downcast_ref::<DaemonError>(),
+      Some(DaemonError::AskTokenWithoutMetadata(address)) if *address == token
+    ));
+    assert!(error.to_string().contains("no metadata"), "{}", error);
+  }
+}
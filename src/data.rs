@@ -1,18 +1,79 @@
 use crate::cryptography;
 use crate::cryptography::MerkleTree;
 use crate::types::DataParameters;
-use anyhow::{ensure, Context};
+use anyhow::{anyhow, ensure, Context};
 use libp2p::PeerId;
-use std::path::PathBuf;
+use log::warn;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
 use tonic::async_trait;
 
 #[async_trait]
 pub trait Service: Send + Sync + Unpin + Clone + 'static {
+  fn block_size(&self) -> usize;
   async fn parameters(&self, data: &[u8]) -> DataParameters;
-  async fn store(&self, peer_id: PeerId, nonce: u64, data: &[u8]) -> anyhow::Result<DataParameters>;
+  /// Stores `data` of the lease, `parameters` are the ones of `parameters(data)` and are not computed again.
+  async fn store(&self, peer_id: PeerId, nonce: u64, data: &[u8], parameters: &DataParameters) -> anyhow::Result<()>;
   async fn retrieve(&self, peer_id: PeerId, nonce: u64) -> anyhow::Result<Vec<u8>>;
+  async fn stored_parameters(&self, peer_id: PeerId, nonce: u64) -> anyhow::Result<DataParameters>;
   async fn proof(&self, peer_id: PeerId, nonce: u64, block_number: usize) -> anyhow::Result<(Vec<u8>, Vec<[u8; 32]>)>;
-  async fn verify(&self, params: DataParameters, block_number: u32, block_data: &[u8], proof: Vec<[u8; 32]>) -> bool;
+  async fn verify(
+    &self,
+    params: DataParameters,
+    block_number: u32,
+    block_data: &[u8],
+    proof: Vec<[u8; 32]>,
+  ) -> Result<bool, cryptography::VerifyError>;
+  async fn verify_stored(&self, peer_id: PeerId, nonce: u64) -> anyhow::Result<bool>;
+  async fn remove(&self, peer_id: PeerId, nonce: u64) -> anyhow::Result<()>;
+  /// Recomputes the parameters of every lease in the data folder, writing the missing parameters files.
+  async fn reindex(&self) -> anyhow::Result<Vec<ReindexEntry>>;
+}
+
+#[derive(Debug, Clone)]
+pub struct ReindexEntry {
+  pub peer_id: PeerId,
+  pub nonce: u64,
+  /// Parameters recomputed from the stored data, none when it could not be read
+  pub parameters: Option<DataParameters>,
+  pub outcome: ReindexOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReindexOutcome {
+  Consistent,
+  /// The parameters file was missing, it is written from the data
+  Regenerated,
+  /// The data does not match the parameters file, which is kept as it is
+  Mismatch {
+    stored: DataParameters,
+  },
+  Unreadable(String),
+}
+
+/// Codec applied to the blobs at rest, the merkle tree is always computed over the uncompressed data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+  None,
+  Zstd,
+}
+
+impl FromStr for Compression {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "none" => Ok(Compression::None),
+      "zstd" => Ok(Compression::Zstd),
+      other => Err(format!("unsupported data compression: {}", other)),
+    }
+  }
 }
 
 #[derive(Clone)]
@@ -20,30 +81,312 @@ struct Implementation<TCryptography>
 where
   TCryptography: cryptography::Service,
 {
-  _cryptography: TCryptography,
+  cryptography: TCryptography,
   data_folder: PathBuf,
+  compression: Compression,
+  // Serializes the reference counting of the shared blobs
+  blobs_lock: Arc<Mutex<()>>,
 }
 
-pub fn new_service<TCryptography>(cryptography: TCryptography, data_folder: PathBuf) -> impl Service
+pub fn new_service<TCryptography>(cryptography: TCryptography, data_folder: PathBuf, compression: Compression) -> impl Service
 where
   TCryptography: cryptography::Service,
 {
   Implementation {
-    _cryptography: cryptography,
+    cryptography,
     data_folder,
+    compression,
+    blobs_lock: Arc::new(Mutex::new(())),
   }
 }
 
+/// Creates the data folder when missing and checks it is writable, so the daemon fails at startup instead of at the
+/// first store. Stored data may be sensitive, a folder other users can read is warned about, or rejected when
+/// `strict_permissions`.
+pub async fn check_data_folder(data_folder: &Path, strict_permissions: bool) -> anyhow::Result<()> {
+  tokio::fs::create_dir_all(data_folder)
+    .await
+    .with_context(|| format!("Failed to create data folder={:?}", data_folder))?;
+  let metadata = tokio::fs::metadata(data_folder)
+    .await
+    .with_context(|| format!("Failed to read data folder={:?}", data_folder))?;
+  ensure!(metadata.is_dir(), "data folder={:?} is not a directory", data_folder);
+  // The permission bits are not enough, e.g. on read-only mounts or when owned by another user
+  let probe = data_folder.join(format!(".write-check-{}", std::process::id()));
+  tokio::fs::write(&probe, b"")
+    .await
+    .with_context(|| format!("data folder={:?} is not writable", data_folder))?;
+  tokio::fs::remove_file(&probe)
+    .await
+    .with_context(|| format!("Failed to remove file={:?}", probe))?;
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = metadata.permissions().mode();
+    if mode & 0o004 != 0 {
+      ensure!(
+        !strict_permissions,
+        "data folder={:?} is readable by other users (mode {:o})",
+        data_folder,
+        mode & 0o777
+      );
+      warn!(
+        "data folder={:?} is readable by other users (mode {:o}), consider restricting it",
+        data_folder,
+        mode & 0o777
+      );
+    }
+  }
+  #[cfg(not(unix))]
+  let _ = strict_permissions;
+  Ok(())
+}
+
 impl<TCryptography> Implementation<TCryptography>
 where
   TCryptography: cryptography::Service,
 {
+  // Leases are spread in two levels of folders named after the hex nonce, so no folder holds too many files
   fn path(&self, peer_id: PeerId, nonce: u64) -> PathBuf {
-    let mut peer_id_path = self.data_folder.clone();
-    peer_id_path.push(peer_id.to_base58());
-    peer_id_path.push(nonce.to_string());
-    peer_id_path
+    let shard = format!("{:016x}", nonce);
+    let mut path = self.data_folder.clone();
+    path.push(peer_id.to_base58());
+    path.push(&shard[0..2]);
+    path.push(&shard[2..4]);
+    path.push(nonce.to_string());
+    path
+  }
+
+  // Layout before the sharding, still read and removed so data stored by older versions keeps working
+  fn flat_path(&self, peer_id: PeerId, nonce: u64) -> PathBuf {
+    let mut path = self.data_folder.clone();
+    path.push(peer_id.to_base58());
+    path.push(nonce.to_string());
+    path
+  }
+
+  // Blobs are shared by the leases storing the same data, the parameters sidecar of each lease links to them
+  fn blob_path(&self, parameters: &DataParameters) -> PathBuf {
+    let mut path = self.data_folder.clone();
+    path.push(BLOBS_FOLDER);
+    path.push(format!("{}-{}", hex::encode(&parameters.merkle_root), parameters.size));
+    path
+  }
+
+  fn references_path(&self, parameters: &DataParameters) -> PathBuf {
+    self.blob_path(parameters).with_extension(REFERENCES_EXTENSION)
+  }
+
+  async fn read_references(&self, parameters: &DataParameters) -> anyhow::Result<u64> {
+    let path = self.references_path(parameters);
+    match tokio::fs::read(&path).await {
+      Ok(raw) => {
+        ensure!(raw.len() == 8, "invalid references length {}", raw.len());
+        let mut references: [u8; 8] = Default::default();
+        references.copy_from_slice(raw.as_slice());
+        Ok(u64::from_be_bytes(references))
+      }
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+      Err(e) => Err(e).with_context(|| format!("Failed to read references file={:?}", path)),
+    }
+  }
+
+  async fn write_references(&self, parameters: &DataParameters, references: u64) -> anyhow::Result<()> {
+    let path = self.references_path(parameters);
+    write_atomic(&path, references.to_be_bytes().as_slice())
+      .await
+      .with_context(|| format!("Failed to write references file={:?}", path))
+  }
+
+  /// Drops a reference to the blob, removing it with the last one. Called with the blobs lock held.
+  async fn release_blob(&self, parameters: &DataParameters) -> anyhow::Result<()> {
+    let references = self.read_references(parameters).await?;
+    if references > 1 {
+      self.write_references(parameters, references - 1).await
+    } else {
+      let blob_path = self.blob_path(parameters);
+      for path in [compressed_path(&blob_path), blob_path, self.references_path(parameters)] {
+        remove_if_exists(&path).await?;
+      }
+      Ok(())
+    }
+  }
+
+  fn parameters_path(&self, peer_id: PeerId, nonce: u64) -> PathBuf {
+    let mut path = self.path(peer_id, nonce);
+    path.set_extension(PARAMETERS_EXTENSION);
+    path
+  }
+
+  /// Leases with files in the data folder, in the sharded and in the flat layout.
+  async fn stored_leases(&self) -> anyhow::Result<BTreeSet<(PeerId, u64)>> {
+    let mut leases = BTreeSet::new();
+    let mut peers = match tokio::fs::read_dir(&self.data_folder).await {
+      Ok(peers) => peers,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(leases),
+      Err(e) => return Err(e).with_context(|| format!("Failed to read folder={:?}", self.data_folder)),
+    };
+    while let Some(peer_entry) = peers.next_entry().await? {
+      // The blobs folder is not named after a peer
+      let peer_id = match peer_entry.file_name().to_str().map(PeerId::from_str) {
+        Some(Ok(peer_id)) => peer_id,
+        _ => continue,
+      };
+      let mut folders = vec![(peer_entry.path(), 0)];
+      while let Some((folder, depth)) = folders.pop() {
+        let mut entries = tokio::fs::read_dir(&folder)
+          .await
+          .with_context(|| format!("Failed to read folder={:?}", folder))?;
+        while let Some(entry) = entries.next_entry().await? {
+          if entry.file_type().await?.is_dir() {
+            if depth < 2 {
+              folders.push((entry.path(), depth + 1));
+            }
+          } else if let Some(nonce) = lease_nonce(&entry.path()) {
+            leases.insert((peer_id, nonce));
+          }
+        }
+      }
+    }
+    Ok(leases)
   }
+
+  async fn reindex_lease(&self, peer_id: PeerId, nonce: u64) -> ReindexEntry {
+    let stored = self.read_parameters(peer_id, nonce).await.ok();
+    let data = match self.retrieve(peer_id, nonce).await {
+      Ok(data) => data,
+      Err(e) => {
+        return ReindexEntry {
+          peer_id,
+          nonce,
+          parameters: None,
+          outcome: ReindexOutcome::Unreadable(format!("{:#}", e)),
+        }
+      }
+    };
+    let parameters = self.parameters(data.as_slice()).await;
+    let outcome = match stored {
+      Some(stored) if stored == parameters => ReindexOutcome::Consistent,
+      Some(stored) => ReindexOutcome::Mismatch { stored },
+      None => match self.write_parameters(peer_id, nonce, &parameters).await {
+        Ok(()) => ReindexOutcome::Regenerated,
+        Err(e) => ReindexOutcome::Unreadable(format!("Failed to write parameters: {}", e)),
+      },
+    };
+    ReindexEntry {
+      peer_id,
+      nonce,
+      parameters: Some(parameters),
+      outcome,
+    }
+  }
+
+  /// Written in the sharded layout, whose folder a lease of the flat one does not have yet.
+  async fn write_parameters(&self, peer_id: PeerId, nonce: u64, parameters: &DataParameters) -> std::io::Result<()> {
+    let path = self.parameters_path(peer_id, nonce);
+    tokio::fs::create_dir_all(path.parent().expect("lease path has a parent")).await?;
+    write_atomic(&path, encode_parameters(parameters).as_slice()).await
+  }
+
+  async fn read_parameters(&self, peer_id: PeerId, nonce: u64) -> anyhow::Result<DataParameters> {
+    let path = self.parameters_path(peer_id, nonce);
+    let raw = match tokio::fs::read(&path).await {
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+        let flat_path = self.flat_path(peer_id, nonce).with_extension(PARAMETERS_EXTENSION);
+        tokio::fs::read(&flat_path).await
+      }
+      result => result,
+    }
+    .with_context(|| format!("Failed to read parameters file={:?}", path))?;
+    decode_parameters(raw.as_slice())
+  }
+}
+
+const BLOBS_FOLDER: &str = "blobs";
+const PARAMETERS_EXTENSION: &str = "params";
+const REFERENCES_EXTENSION: &str = "refs";
+const TEMPORARY_EXTENSION: &str = "tmp";
+const COMPRESSED_EXTENSION: &str = "zst";
+// Zero selects the zstd default level
+const ZSTD_LEVEL: i32 = 0;
+
+// Nonce of the lease a data or parameters file belongs to, temporary and references files belong to none
+fn lease_nonce(path: &Path) -> Option<u64> {
+  match path.extension().and_then(|e| e.to_str()) {
+    None | Some(PARAMETERS_EXTENSION) | Some(COMPRESSED_EXTENSION) => path.file_stem()?.to_str()?.parse().ok(),
+    Some(_) => None,
+  }
+}
+
+// Compressed files keep their own extension, so data stored before a compression change is still readable
+fn compressed_path(path: &Path) -> PathBuf {
+  path.with_extension(COMPRESSED_EXTENSION)
+}
+
+async fn read_maybe_compressed(path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+  let compressed_path = compressed_path(path);
+  match tokio::fs::read(&compressed_path).await {
+    Ok(compressed) => {
+      return zstd::decode_all(compressed.as_slice())
+        .map(Some)
+        .with_context(|| format!("Failed to decompress file file={:?}", compressed_path))
+    }
+    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+      return Err(e).with_context(|| format!("Failed to read file file={:?}", compressed_path))
+    }
+    _ => (),
+  }
+  match tokio::fs::read(path).await {
+    Ok(data) => Ok(Some(data)),
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+    Err(e) => Err(e).with_context(|| format!("Failed to read file file={:?}", path)),
+  }
+}
+
+async fn remove_if_exists(path: &Path) -> anyhow::Result<()> {
+  match tokio::fs::remove_file(path).await {
+    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+      Err(e).with_context(|| format!("Failed to remove file file={:?}", path))
+    }
+    _ => Ok(()),
+  }
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+  let mut file_name = path.file_name().map(OsString::from).unwrap_or_default();
+  file_name.push(".");
+  file_name.push(TEMPORARY_EXTENSION);
+  path.with_file_name(file_name)
+}
+
+async fn write_synced(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+  let mut file = tokio::fs::File::create(path).await?;
+  file.write_all(contents).await?;
+  file.sync_all().await
+}
+
+// The file only becomes visible by the rename, so a crash never leaves it partially written
+async fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+  let temporary_path = temporary_path(path);
+  write_synced(&temporary_path, contents).await?;
+  tokio::fs::rename(&temporary_path, path).await
+}
+
+// The parameters sidecar is the 32 bytes merkle root followed by the size as big endian u64
+fn encode_parameters(parameters: &DataParameters) -> Vec<u8> {
+  let mut result = parameters.merkle_root.clone();
+  result.extend_from_slice((parameters.size as u64).to_be_bytes().as_slice());
+  result
+}
+
+fn decode_parameters(raw: &[u8]) -> anyhow::Result<DataParameters> {
+  ensure!(raw.len() == 40, "invalid parameters length {}", raw.len());
+  let mut size: [u8; 8] = Default::default();
+  size.copy_from_slice(&raw[32..40]);
+  Ok(DataParameters {
+    merkle_root: raw[0..32].to_vec(),
+    size: u64::from_be_bytes(size) as usize,
+  })
 }
 
 #[async_trait]
@@ -51,8 +394,12 @@ impl<TCryptography> Service for Implementation<TCryptography>
 where
   TCryptography: cryptography::Service,
 {
+  fn block_size(&self) -> usize {
+    self.cryptography.block_size()
+  }
+
   async fn parameters(&self, data: &[u8]) -> DataParameters {
-    let mut merkle = TCryptography::new_merkle_tree();
+    let mut merkle = self.cryptography.new_merkle_tree();
     merkle.append_data(data);
     let merkle_root = merkle.root();
     DataParameters {
@@ -61,27 +408,64 @@ where
     }
   }
 
-  async fn store(&self, peer_id: PeerId, nonce: u64, data: &[u8]) -> anyhow::Result<DataParameters> {
-    let parameters = self.parameters(data).await;
-    let mut peer_id_path = self.data_folder.clone();
-    peer_id_path.push(peer_id.to_base58());
-    tokio::fs::create_dir_all(peer_id_path.clone())
+  async fn store(&self, peer_id: PeerId, nonce: u64, data: &[u8], parameters: &DataParameters) -> anyhow::Result<()> {
+    let parameters_path = self.parameters_path(peer_id, nonce);
+    tokio::fs::create_dir_all(parameters_path.parent().expect("lease path has a parent"))
       .await
       .context("error storing data from peer")?;
-    peer_id_path.push(nonce.to_string());
-    tokio::fs::write(peer_id_path, data)
+    tokio::fs::create_dir_all(self.data_folder.join(BLOBS_FOLDER))
       .await
       .context("error storing data from peer")?;
-    Ok(parameters)
+
+    let _guard = self.blobs_lock.lock().await;
+    // A lease stored again must not count twice as a reference to its blob
+    let previous = self.read_parameters(peer_id, nonce).await.ok();
+    if previous.as_ref() == Some(parameters) {
+      return Ok(());
+    }
+
+    // The blob and its reference are written before the link, a crash in between leaks the blob but never loses it
+    let references = self.read_references(parameters).await?;
+    if references == 0 {
+      let blob_path = self.blob_path(parameters);
+      let (path, contents) = match self.compression {
+        Compression::None => (blob_path, Cow::Borrowed(data)),
+        Compression::Zstd => (
+          compressed_path(&blob_path),
+          Cow::Owned(zstd::encode_all(data, ZSTD_LEVEL).context("error compressing data from peer")?),
+        ),
+      };
+      write_atomic(&path, contents.as_ref())
+        .await
+        .context("error storing data from peer")?;
+    }
+    self.write_references(parameters, references + 1).await?;
+    write_atomic(&parameters_path, encode_parameters(parameters).as_slice())
+      .await
+      .context("error storing data parameters from peer")?;
+    // Overwritten with other data, the link to the previous blob is gone
+    if let Some(previous) = previous {
+      self.release_blob(&previous).await?;
+    }
+    Ok(())
   }
 
   async fn retrieve(&self, peer_id: PeerId, nonce: u64) -> anyhow::Result<Vec<u8>> {
-    let path = self.path(peer_id, nonce);
+    if let Ok(parameters) = self.read_parameters(peer_id, nonce).await {
+      if let Some(data) = read_maybe_compressed(&self.blob_path(&parameters)).await? {
+        return Ok(data);
+      }
+    }
 
-    let data: Vec<u8> = tokio::fs::read(path.clone())
-      .await
-      .with_context(|| format!("Failed to read file file={:?}", path))?;
-    Ok(data)
+    // Data stored before the blobs were shared lives next to the parameters, which were not sharded yet
+    let path = self.flat_path(peer_id, nonce);
+    read_maybe_compressed(&path)
+      .await?
+      .ok_or_else(|| anyhow!("Failed to read file file={:?}: not found", path))
+  }
+
+  async fn stored_parameters(&self, peer_id: PeerId, nonce: u64) -> anyhow::Result<DataParameters> {
+    self.read_parameters(peer_id, nonce).await
   }
 
   async fn proof(&self, peer_id: PeerId, nonce: u64, block_number: usize) -> anyhow::Result<(Vec<u8>, Vec<[u8; 32]>)> {
@@ -90,19 +474,311 @@ where
       .await
       .with_context(|| "Error calculating proof")?;
 
-    let block_start: usize = (block_number as usize) * cryptography::BLOCK_SIZE_BYTES;
-    ensure!(data.len() >= block_start, "block is out of bounds");
-    let block_end = std::cmp::min(block_start + cryptography::BLOCK_SIZE_BYTES, data.len());
+    let block_size = self.cryptography.block_size();
+    let block_start: usize = (block_number as usize) * block_size;
+    ensure!(!data.is_empty(), "cannot prove a block of empty data");
+    ensure!(data.len() > block_start, "block is out of bounds");
+    let block_end = std::cmp::min(block_start + block_size, data.len());
     let block_data = data[block_start..block_end].to_vec();
 
-    let mut merkle = TCryptography::new_merkle_tree();
+    let mut merkle = self.cryptography.new_merkle_tree();
     merkle.append_data(data);
     Ok((block_data, merkle.proof(block_number)))
   }
 
-  async fn verify(&self, params: DataParameters, block_number: u32, block_data: &[u8], proof: Vec<[u8; 32]>) -> bool {
-    let mut merkle_root: [u8; 32] = Default::default();
-    merkle_root.copy_from_slice(params.merkle_root.as_slice());
-    TCryptography::verify(block_number as usize, block_data, proof, merkle_root, params.size)
+  async fn verify(
+    &self,
+    params: DataParameters,
+    block_number: u32,
+    block_data: &[u8],
+    proof: Vec<[u8; 32]>,
+  ) -> Result<bool, cryptography::VerifyError> {
+    self.cryptography.verify(
+      block_number as usize,
+      block_data,
+      proof,
+      params.merkle_root.as_slice(),
+      params.size,
+    )
+  }
+
+  async fn verify_stored(&self, peer_id: PeerId, nonce: u64) -> anyhow::Result<bool> {
+    let expected = self.read_parameters(peer_id, nonce).await?;
+    let data = self
+      .retrieve(peer_id, nonce)
+      .await
+      .with_context(|| "Error verifying stored data")?;
+    let current = self.parameters(data.as_slice()).await;
+    Ok(current.size == expected.size && current.merkle_root == expected.merkle_root)
+  }
+
+  async fn remove(&self, peer_id: PeerId, nonce: u64) -> anyhow::Result<()> {
+    let _guard = self.blobs_lock.lock().await;
+    let parameters = self.read_parameters(peer_id, nonce).await.ok();
+
+    // The link goes first, a crash before the reference is released leaks the blob but never loses it
+    let flat_path = self.flat_path(peer_id, nonce);
+    for path in [
+      compressed_path(&flat_path),
+      flat_path.with_extension(PARAMETERS_EXTENSION),
+      flat_path,
+      self.parameters_path(peer_id, nonce),
+    ] {
+      remove_if_exists(&path).await?;
+    }
+
+    if let Some(parameters) = parameters {
+      self.release_blob(&parameters).await?;
+    }
+    Ok(())
+  }
+
+  async fn reindex(&self) -> anyhow::Result<Vec<ReindexEntry>> {
+    let leases = self.stored_leases().await?;
+    // Serialized with the stores and removes, so a lease is not reindexed while it is being written
+    let _guard = self.blobs_lock.lock().await;
+    let mut entries = Vec::with_capacity(leases.len());
+    for (peer_id, nonce) in leases {
+      entries.push(self.reindex_lease(peer_id, nonce).await);
+    }
+    Ok(entries)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testutil::{data_service, TempDir};
+
+  fn blobs(folder: &TempDir) -> usize {
+    std::fs::read_dir(folder.path().join("data").join(BLOBS_FOLDER))
+      .unwrap()
+      .filter(|entry| entry.as_ref().unwrap().path().extension().is_none())
+      .count()
+  }
+
+  async fn store(data: &impl Service, peer_id: PeerId, nonce: u64, contents: &[u8]) {
+    let parameters = data.parameters(contents).await;
+    data.store(peer_id, nonce, contents, &parameters).await.unwrap();
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn data_folder_checked_at_startup() {
+    use std::os::unix::fs::PermissionsExt;
+    let folder = TempDir::new();
+    let set_mode = |path: &Path, mode| std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+
+    let missing = folder.path().join("missing");
+    check_data_folder(&missing, false).await.unwrap();
+    assert!(missing.is_dir());
+
+    set_mode(&missing, 0o755);
+    let error = check_data_folder(&missing, true).await.unwrap_err();
+    assert!(
+      error.to_string().contains("is readable by other users (mode 755)"),
+      "{}",
+      error
+    );
+    set_mode(&missing, 0o700);
+    check_data_folder(&missing, true).await.unwrap();
+
+    let file = folder.path().join("file");
+    std::fs::write(&file, b"").unwrap();
+    assert!(check_data_folder(&file, false).await.is_err());
+
+    set_mode(&missing, 0o500);
+    // Permissions do not stop root from writing
+    if std::fs::write(missing.join("probe"), b"").is_err() {
+      let error = check_data_folder(&missing, false).await.unwrap_err();
+      assert!(error.to_string().contains("is not writable"), "{}", error);
+    }
+    set_mode(&missing, 0o700);
+  }
+
+  #[tokio::test]
+  async fn overwritten_blob_released() {
+    let folder = TempDir::new();
+    let data = data_service(&folder);
+    let peer_id = PeerId::random();
+    store(&data, peer_id, 1, b"some data").await;
+
+    store(&data, peer_id, 1, b"other data").await;
+
+    assert_eq!(blobs(&folder), 1);
+    assert_eq!(data.retrieve(peer_id, 1).await.unwrap(), b"other data");
+  }
+
+  #[tokio::test]
+  async fn overwritten_blob_kept_while_referenced() {
+    let folder = TempDir::new();
+    let data = data_service(&folder);
+    let peer_id = PeerId::random();
+    store(&data, peer_id, 1, b"some data").await;
+    store(&data, peer_id, 2, b"some data").await;
+
+    store(&data, peer_id, 1, b"other data").await;
+    data.remove(peer_id, 2).await.unwrap();
+
+    assert_eq!(blobs(&folder), 1);
+    assert_eq!(data.retrieve(peer_id, 1).await.unwrap(), b"other data");
+  }
+
+  #[tokio::test]
+  async fn lease_stored_in_its_shard() {
+    let folder = TempDir::new();
+    let data = data_service(&folder);
+    let peer_id = PeerId::random();
+    let nonce = 0xabcd_0000_0000_0001;
+    store(&data, peer_id, nonce, b"some data").await;
+
+    let shard = folder.path().join("data").join(peer_id.to_base58()).join("ab").join("cd");
+    let files = std::fs::read_dir(shard)
+      .unwrap()
+      .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(files, vec![format!("{}.{}", nonce, PARAMETERS_EXTENSION)]);
+    assert_eq!(data.retrieve(peer_id, nonce).await.unwrap(), b"some data");
+    assert!(data.proof(peer_id, nonce, 0).await.is_ok());
+  }
+
+  #[tokio::test]
+  async fn compressed_blob_proofs_verified() {
+    let folder = TempDir::new();
+    let data = new_service(
+      cryptography::new_service(32).unwrap(),
+      folder.path().join("data"),
+      Compression::Zstd,
+    );
+    let peer_id = PeerId::random();
+    // Four blocks, the last one partial
+    let contents = b"some data ".repeat(10);
+    let parameters = data.parameters(&contents).await;
+    data.store(peer_id, 1, &contents, &parameters).await.unwrap();
+
+    let blob_path = folder.path().join("data").join(BLOBS_FOLDER).join(format!(
+      "{}-{}.{}",
+      hex::encode(&parameters.merkle_root),
+      parameters.size,
+      COMPRESSED_EXTENSION
+    ));
+    let blob = std::fs::read(blob_path).unwrap();
+    assert!(blob.len() < contents.len());
+    assert_eq!(data.stored_parameters(peer_id, 1).await.unwrap(), parameters);
+    assert_eq!(data.retrieve(peer_id, 1).await.unwrap(), contents);
+    for block_number in 0..4 {
+      let (block_data, proof) = data.proof(peer_id, 1, block_number).await.unwrap();
+      assert_eq!(
+        block_data,
+        &contents[block_number * 32..std::cmp::min((block_number + 1) * 32, 100)]
+      );
+      let verified = data.verify(parameters.clone(), block_number as u32, &block_data, proof).await;
+      assert!(verified.unwrap());
+    }
+  }
+
+  #[tokio::test]
+  async fn interrupted_store_not_visible() {
+    let folder = TempDir::new();
+    let data = Implementation {
+      cryptography: cryptography::new_service(32).unwrap(),
+      data_folder: folder.path().join("data"),
+      compression: Compression::None,
+      blobs_lock: Arc::new(Mutex::new(())),
+    };
+    let peer_id = PeerId::random();
+    let parameters = data.parameters(b"some data").await;
+    // A crash before the renames leaves only the partially written temporary files
+    let parameters_path = data.parameters_path(peer_id, 1);
+    std::fs::create_dir_all(parameters_path.parent().unwrap()).unwrap();
+    std::fs::create_dir_all(folder.path().join("data").join(BLOBS_FOLDER)).unwrap();
+    std::fs::write(temporary_path(&data.blob_path(&parameters)), b"some").unwrap();
+    std::fs::write(temporary_path(&parameters_path), &encode_parameters(&parameters)[..20]).unwrap();
+
+    assert!(!parameters_path.exists());
+    assert!(!data.blob_path(&parameters).exists());
+    assert!(data.stored_parameters(peer_id, 1).await.is_err());
+    assert!(data.retrieve(peer_id, 1).await.is_err());
+    assert!(data.reindex().await.unwrap().is_empty());
+
+    data.store(peer_id, 1, b"some data", &parameters).await.unwrap();
+    assert_eq!(data.retrieve(peer_id, 1).await.unwrap(), b"some data");
+  }
+
+  #[tokio::test]
+  async fn missing_parameters_regenerated_and_mismatches_flagged() {
+    let folder = TempDir::new();
+    let data = data_service(&folder);
+    let peer_id = PeerId::random();
+    store(&data, peer_id, 1, b"some data").await;
+    // Flat layout of older versions, a lease without its parameters and one whose parameters are of other data
+    let peer_folder = folder.path().join("data").join(peer_id.to_base58());
+    std::fs::write(peer_folder.join("2"), b"old data").unwrap();
+    std::fs::write(peer_folder.join("3"), b"more old data").unwrap();
+    let other = data.parameters(b"other data").await;
+    std::fs::write(peer_folder.join("3.params"), encode_parameters(&other)).unwrap();
+
+    let outcomes = data
+      .reindex()
+      .await
+      .unwrap()
+      .into_iter()
+      .map(|entry| (entry.nonce, entry.outcome))
+      .collect::<Vec<_>>();
+
+    assert_eq!(
+      outcomes,
+      vec![
+        (1, ReindexOutcome::Consistent),
+        (2, ReindexOutcome::Regenerated),
+        (3, ReindexOutcome::Mismatch { stored: other.clone() }),
+      ]
+    );
+    assert_eq!(
+      data.stored_parameters(peer_id, 2).await.unwrap(),
+      data.parameters(b"old data").await
+    );
+    assert_eq!(data.stored_parameters(peer_id, 3).await.unwrap(), other);
+  }
+
+  #[tokio::test]
+  async fn parameters_of_a_flat_only_folder_regenerated() {
+    let folder = TempDir::new();
+    let data = data_service(&folder);
+    let peer_id = PeerId::random();
+    let peer_folder = folder.path().join("data").join(peer_id.to_base58());
+    std::fs::create_dir_all(&peer_folder).unwrap();
+    std::fs::write(peer_folder.join("2"), b"old data").unwrap();
+
+    let entries = data.reindex().await.unwrap();
+
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].outcome, ReindexOutcome::Regenerated);
+    assert_eq!(
+      data.stored_parameters(peer_id, 2).await.unwrap(),
+      data.parameters(b"old data").await
+    );
+  }
+
+  #[tokio::test]
+  async fn empty_data_stored_but_never_proven() {
+    let folder = TempDir::new();
+    let data = data_service(&folder);
+    let peer_id = PeerId::random();
+    let parameters = data.parameters(b"").await;
+    assert_eq!(parameters.merkle_root, cryptography::EMPTY_MERKLE_ROOT.to_vec());
+    assert_eq!(
+      hex::encode(&parameters.merkle_root),
+      "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+    );
+
+    data.store(peer_id, 1, b"", &parameters).await.unwrap();
+
+    assert_eq!(data.retrieve(peer_id, 1).await.unwrap(), b"");
+    assert!(data.proof(peer_id, 1, 0).await.is_err());
+    assert!(matches!(
+      data.verify(parameters, 0, b"", Vec::new()).await,
+      Err(cryptography::VerifyError::EmptyData)
+    ));
   }
 }
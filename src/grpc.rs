@@ -1,81 +1,179 @@
+// Status is the error of every handler, the helpers they call return it as is
+#![allow(clippy::result_large_err)]
+
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::error::Error;
 use std::fmt::Debug;
+use std::future::Future;
 use std::net::SocketAddr;
 use std::time::{Duration, SystemTime};
 
 use crate::proto::api::balance_entry::{StorageBalance, TokenMetadata, WalletBalance};
+use crate::proto::api::get_audit_log_response::AuditEntry;
+use crate::proto::api::get_pending_operations_response::PendingOperation;
 use crate::proto::api::list_storage_rented_response::StorageRentedData;
 use crate::proto::api::p2pim_server::{P2pim, P2pimServer};
+use crate::proto::api::reindex_response::ReindexEntry;
+use crate::proto::api::store_stream_request;
 use crate::proto::api::swarm_server::{Swarm, SwarmServer};
 use crate::proto::api::{
-  ApproveRequest, ApproveResponse, BalanceEntry, ChallengeRequest, ChallengeResponse, DepositRequest, DepositResponse,
-  GetBalanceRequest, GetBalanceResponse, GetConnectedPeersRequest, GetConnectedPeersResponse, GetInfoRequest,
-  GetInfoResponse, ListStorageRentedRequest, ListStorageRentedResponse, RetrieveRequest, RetrieveResponse, StoreRequest,
-  StoreResponse, WithdrawRequest, WithdrawResponse,
+  ApproveRequest, ApproveResponse, AuditRole, BalanceEntry, ChallengeRandomRequest, ChallengeRandomResponse,
+  ChallengeRequest, ChallengeResponse, ComputeParametersRequest, ComputeParametersResponse, DepositRequest, DepositResponse,
+  FindProvidersRequest, FindProvidersResponse, GetAuditLogRequest, GetAuditLogResponse, GetBalanceRequest,
+  GetBalanceResponse, GetConnectedPeersRequest, GetConnectedPeersResponse, GetInfoRequest, GetInfoResponse, GetLeaseRequest,
+  GetLeaseResponse, GetPendingOperationsRequest, GetPendingOperationsResponse, LeaseState, ListStorageRentedRequest,
+  ListStorageRentedResponse, PendingOperationKind, ReindexOutcome, ReindexRequest, ReindexResponse, RetrieveBlockRequest,
+  RetrieveBlockResponse, RetrieveRequest, RetrieveResponse, StoreRequest, StoreResponse, StoreStreamRequest,
+  VerifySignatureRequest, VerifySignatureResponse, VersionRequest, VersionResponse, WithdrawRequest, WithdrawResponse,
 };
 use crate::proto::libp2p::PeerId;
-use crate::types::{Balance, ChallengeKey, LeaseTerms};
-use crate::{onchain, p2p, persistence, reactor};
-use futures::StreamExt;
+use crate::proto::solidity;
+use crate::types::{Balance, ChallengeKey, DataParameters, LeaseTerms, Signature};
+use crate::{data, onchain, p2p, persistence, reactor, types, version};
+use ethcontract::errors::ExecutionError;
+use futures::{Stream, StreamExt};
 use log::info;
+use tokio::time::Instant;
 use tonic::transport::Server;
-use tonic::{Request, Response, Status};
+use tonic::{Request, Response, Status, Streaming};
 use web3::types::Address;
 
-pub async fn listen_and_serve<TOnchain, TP2p, TPersistence, TReactor>(
-  rpc_addr: SocketAddr,
+pub struct GrpcParams {
+  pub rpc_addr: SocketAddr,
+  /// Serves the debug methods, which expose the internal state of the node.
+  pub debug_rpc: bool,
+  /// Bytes accepted on a streamed upload, whatever size its header declares.
+  pub max_upload_size: usize,
+}
+
+pub async fn listen_and_serve<TData, TOnchain, TP2p, TPersistence, TReactor>(
+  params: GrpcParams,
+  data: TData,
   onchain: TOnchain,
+  networks: HashMap<String, TOnchain>,
   p2p: TP2p,
   reactor: TReactor,
   persistence: TPersistence,
 ) -> Result<(), Box<dyn Error>>
 where
+  TData: data::Service,
   TOnchain: onchain::Service,
   TReactor: reactor::Service,
   TP2p: p2p::Service,
   TPersistence: persistence::Service,
 {
-  info!("starting gRPC server on {}", rpc_addr);
+  info!("starting gRPC server on {}", params.rpc_addr);
   let p2pim_impl = P2pimImpl {
+    local_peer_id: p2p.local_peer_id(),
+    protocol_version: p2p.protocol_version(),
+    data,
     onchain,
+    networks,
     persistence,
     reactor,
+    max_upload_size: params.max_upload_size,
+  };
+  let swarm_impl = SwarmImpl {
+    p2p,
+    debug_rpc: params.debug_rpc,
   };
-  let swarm_impl = SwarmImpl { p2p };
   Server::builder()
     .add_service(P2pimServer::new(p2pim_impl))
     .add_service(SwarmServer::new(swarm_impl))
-    .serve(rpc_addr)
+    .serve(params.rpc_addr)
     .await
     .map_err(|e| e.into())
 }
 
 #[derive(Clone, Debug)]
-struct P2pimImpl<TOnchain, TPersistence, TReactor>
+struct P2pimImpl<TData, TOnchain, TPersistence, TReactor>
 where
+  TData: data::Service,
   TOnchain: onchain::Service,
   TPersistence: persistence::Service,
   TReactor: reactor::Service,
 {
+  local_peer_id: libp2p::PeerId,
+  protocol_version: String,
+  data: TData,
+  // Network where leases are sealed
   onchain: TOnchain,
+  // Every configured network keyed by id, the one of leases included
+  networks: HashMap<String, TOnchain>,
   persistence: TPersistence,
   reactor: TReactor,
+  // Bytes accepted on a streamed upload, whatever size its header declares
+  max_upload_size: usize,
+}
+
+impl<TData, TOnchain, TPersistence, TReactor> P2pimImpl<TData, TOnchain, TPersistence, TReactor>
+where
+  TData: data::Service,
+  TOnchain: onchain::Service,
+  TPersistence: persistence::Service,
+  TReactor: reactor::Service,
+{
+  /// Network selected by a request, the one of leases when empty.
+  fn network(&self, network: &str) -> Result<&TOnchain, Status> {
+    if network.is_empty() {
+      Ok(&self.onchain)
+    } else {
+      self
+        .networks
+        .get(network)
+        .ok_or_else(|| Status::not_found(format!("network {} not configured", network)))
+    }
+  }
+
+  async fn store_lease(&self, req: StoreRequest) -> Result<Response<StoreResponse>, Status> {
+    let peer_id = req
+      .peer_id
+      .as_ref()
+      .ok_or(Status::invalid_argument("peer_id empty"))?
+      .try_into()
+      .map_err(|e| Status::invalid_argument(format!("invalid peer id: {}", e)))?;
+
+    let lease_term = LeaseTerms {
+      lease_duration: parse_lease_duration(req.lease_duration.as_ref())?,
+      token_address: parse_address(req.token_address.as_ref(), "token_address")?,
+      proposal_expiration: SystemTime::now() + Duration::from_secs(120), // TODO fixed 2 minutes, this needs to be a parameter
+      price: req.price.as_ref().ok_or(Status::invalid_argument("price empty"))?.into(),
+      penalty: req.penalty.as_ref().ok_or(Status::invalid_argument("penalty empty"))?.into(),
+    };
+
+    let result =
+      self
+        .reactor
+        .lease(peer_id, lease_term, req.data)
+        .await
+        .map_err(|e| match e.downcast_ref::<reactor::LeaseError>() {
+          Some(reactor::LeaseError::Rejected { .. }) => Status::failed_precondition(format!("Error trying to store: {}", e)),
+          Some(reactor::LeaseError::TimedOut) => Status::deadline_exceeded(format!("Error trying to store: {}", e)),
+          Some(reactor::LeaseError::SelfLease) => Status::invalid_argument(format!("Error trying to store: {}", e)),
+          None => Status::unknown(format!("Error trying to store: {}", e)),
+        })?;
+    Ok(Response::new(StoreResponse {
+      transaction_hash: Some(result.into()),
+    }))
+  }
 }
 
 #[tonic::async_trait]
-impl<TOnchain, TPersistence, TReactor> P2pim for P2pimImpl<TOnchain, TPersistence, TReactor>
+impl<TData, TOnchain, TPersistence, TReactor> P2pim for P2pimImpl<TData, TOnchain, TPersistence, TReactor>
 where
+  TData: data::Service,
   TOnchain: onchain::Service,
   TPersistence: persistence::Service,
   TReactor: reactor::Service,
 {
-  async fn get_info(&self, _: Request<GetInfoRequest>) -> Result<Response<GetInfoResponse>, Status> {
-    let balance = futures::stream::iter(self.onchain.deployed_tokens().await.iter())
+  async fn get_info(&self, request: Request<GetInfoRequest>) -> Result<Response<GetInfoResponse>, Status> {
+    let onchain = self.network(request.get_ref().network.as_str())?;
+    let refresh = request.get_ref().refresh;
+    let balance = futures::stream::iter(onchain.deployed_tokens().await.iter())
       .then(|(token_address, _)| async move {
-        self
-          .onchain
-          .balance(token_address)
+        onchain
+          .balance(token_address, refresh)
           .await
           .map(|b| convert_balance(*token_address, b))
       })
@@ -83,46 +181,62 @@ where
       .await
       .into_iter()
       .collect::<Result<Vec<BalanceEntry>, _>>()
-      .map_err(|e| Status::internal(format!("[TODO(formatting)] {}", e)))?;
+      .map_err(|e| onchain_status("error reading balances", e))?;
+
+    let account_wallet = onchain.account_wallet();
+    let account_storage = onchain.account_storage();
+    let native_balance = |address| async move {
+      onchain
+        .native_balance(address)
+        .await
+        .map_err(|e| onchain_status("error reading native balance", e))
+    };
+    let pending_transactions = |address| async move {
+      onchain
+        .pending_transactions(address)
+        .await
+        .map(|p| p.low_u64())
+        .map_err(|e| onchain_status("error reading pending transactions", e))
+    };
 
     Ok(Response::new(GetInfoResponse {
-      address_wallet: Some(From::from(&self.onchain.account_wallet())),
-      address_storage: Some(From::from(&self.onchain.account_storage())),
+      peer_id: Some(self.local_peer_id.into()),
+      address_wallet: Some(From::from(&account_wallet)),
+      address_storage: Some(From::from(&account_storage)),
+      address_master_record: Some(From::from(&onchain.master_record_address())),
+      network: onchain.network_id().to_string(),
       balance,
+      native_balance_wallet: Some(native_balance(account_wallet).await?.into()),
+      native_balance_storage: Some(native_balance(account_storage).await?.into()),
+      pending_transactions_wallet: pending_transactions(account_wallet).await?,
+      pending_transactions_storage: pending_transactions(account_storage).await?,
     }))
   }
 
   async fn get_balance(&self, request: Request<GetBalanceRequest>) -> Result<Response<GetBalanceResponse>, Status> {
-    let token_addr: web3::types::Address = request
-      .get_ref()
-      .token_address
-      .as_ref()
-      .ok_or(Status::invalid_argument("token_address empty"))?
-      .into();
+    let token_addr = parse_address(request.get_ref().token_address.as_ref(), "token_address")?;
+    let onchain = self.network(request.get_ref().network.as_str())?;
 
-    let balance = self
-      .onchain
-      .balance(&token_addr)
+    let balance = onchain
+      .balance(&token_addr, request.get_ref().refresh)
       .await
       .map(|b| convert_balance(token_addr, b))
-      .map_err(|e| Status::internal(format!("[TODO(formatting)] {}", e)))?;
+      .map_err(|e| onchain_status("error reading balance", e))?;
 
-    Ok(Response::new(GetBalanceResponse { balance: Some(balance) }))
+    Ok(Response::new(GetBalanceResponse {
+      balance: Some(balance),
+      network: onchain.network_id().to_string(),
+    }))
   }
 
   async fn approve(&self, request: Request<ApproveRequest>) -> Result<Response<ApproveResponse>, Status> {
-    let token_addr = request
-      .get_ref()
-      .token_address
-      .as_ref()
-      .ok_or(Status::invalid_argument("token_address empty"))?
-      .into();
+    let token_addr = parse_address(request.get_ref().token_address.as_ref(), "token_address")?;
+    let onchain = self.network(request.get_ref().network.as_str())?;
 
-    let result = self
-      .onchain
+    let result = onchain
       .approve(&token_addr)
       .await
-      .map_err(|e| Status::internal(format!("error sending approval transaction: {}", e)))?;
+      .map_err(|e| onchain_status("error sending approval transaction", e))?;
     Ok(Response::new(ApproveResponse {
       transaction_hash: Some(From::from(result.hash())),
     }))
@@ -130,11 +244,7 @@ where
 
   async fn deposit(&self, request: Request<DepositRequest>) -> Result<Response<DepositResponse>, Status> {
     let dep_req = request.get_ref();
-    let token_addr = dep_req
-      .token_address
-      .as_ref()
-      .ok_or(Status::invalid_argument("token_address empty"))?
-      .into();
+    let token_addr = parse_address(dep_req.token_address.as_ref(), "token_address")?;
 
     let amount = dep_req
       .amount
@@ -143,10 +253,10 @@ where
       .into();
 
     let result = self
-      .onchain
+      .network(dep_req.network.as_str())?
       .deposit(&token_addr, amount)
       .await
-      .map_err(|e| Status::internal(format!("error sending deposit transaction: {}", e)))?;
+      .map_err(|e| onchain_status("error sending deposit transaction", e))?;
     Ok(Response::new(DepositResponse {
       transaction_hash: Some(From::from(result.hash())),
     }))
@@ -154,11 +264,7 @@ where
 
   async fn withdraw(&self, request: Request<WithdrawRequest>) -> Result<Response<WithdrawResponse>, Status> {
     let dep_req = request.get_ref();
-    let token_addr = dep_req
-      .token_address
-      .as_ref()
-      .ok_or(Status::invalid_argument("token_address empty"))?
-      .into();
+    let token_addr = parse_address(dep_req.token_address.as_ref(), "token_address")?;
 
     let amount = dep_req
       .amount
@@ -166,53 +272,87 @@ where
       .ok_or(Status::invalid_argument("amount empty"))?
       .into();
 
+    let destination = dep_req
+      .destination
+      .as_ref()
+      .map(|d| parse_address(Some(d), "destination"))
+      .transpose()?;
+    // Tokens sent to the zero address are lost, it is never what the user meant
+    if destination == Some(Address::zero()) {
+      return Err(Status::invalid_argument("invalid destination: zero address"));
+    }
+
     let result = self
-      .onchain
-      .withdraw(&token_addr, amount)
+      .network(dep_req.network.as_str())?
+      .withdraw(&token_addr, amount, destination)
       .await
-      .map_err(|e| Status::internal(format!("error sending withdraw transaction: {}", e)))?;
+      .map_err(|e| onchain_status("error sending withdraw transaction", e))?;
     Ok(Response::new(WithdrawResponse {
       transaction_hash: Some(From::from(result.hash())),
     }))
   }
 
   async fn store(&self, request: Request<StoreRequest>) -> Result<Response<StoreResponse>, Status> {
+    let deadline = deadline_of(&request)?;
+    with_deadline(deadline, self.store_lease(request.into_inner())).await
+  }
+
+  async fn find_providers(&self, request: Request<FindProvidersRequest>) -> Result<Response<FindProvidersResponse>, Status> {
     let req = request.into_inner();
+    let lease_terms = LeaseTerms {
+      lease_duration: parse_lease_duration(req.lease_duration.as_ref())?,
+      token_address: parse_address(req.token_address.as_ref(), "token_address")?,
+      proposal_expiration: SystemTime::now() + Duration::from_secs(120),
+      price: req.price.as_ref().ok_or(Status::invalid_argument("price empty"))?.into(),
+      penalty: req.penalty.as_ref().ok_or(Status::invalid_argument("penalty empty"))?.into(),
+    };
+    let providers = self.reactor.find_providers(lease_terms, req.size as usize).await;
+    Ok(Response::new(FindProvidersResponse {
+      peer_list: providers.into_iter().map(PeerId::from).collect(),
+    }))
+  }
+
+  async fn store_stream(&self, request: Request<Streaming<StoreStreamRequest>>) -> Result<Response<StoreResponse>, Status> {
+    // Receiving the data counts towards the deadline too
+    let deadline = deadline_of(&request)?;
+    let mut stream = request.into_inner();
+    with_deadline(deadline, async move {
+      let (header, data) = receive_upload(&mut stream, self.max_upload_size).await?;
+      self
+        .store_lease(StoreRequest {
+          peer_id: header.peer_id,
+          token_address: header.token_address,
+          price: header.price,
+          penalty: header.penalty,
+          lease_duration: header.lease_duration,
+          data,
+        })
+        .await
+    })
+    .await
+  }
+
+  async fn retrieve(&self, request: Request<RetrieveRequest>) -> Result<Response<RetrieveResponse>, Status> {
+    let req = request.get_ref();
     let peer_id = req
       .peer_id
       .as_ref()
-      .ok_or(Status::invalid_argument("peer_id empty"))?
+      .ok_or(Status::invalid_argument("peer empty"))?
       .try_into()
       .map_err(|e| Status::invalid_argument(format!("invalid peer id: {}", e)))?;
-
-    let lease_term = LeaseTerms {
-      lease_duration: req
-        .lease_duration
-        .clone()
-        .ok_or(Status::invalid_argument("lease duration empty"))?
-        .try_into()
-        .map_err(|_| Status::invalid_argument("duration should be positive value"))?,
-      token_address: req
-        .token_address
-        .as_ref()
-        .ok_or(Status::invalid_argument("token address empty"))?
-        .into(),
-      proposal_expiration: SystemTime::now() + Duration::from_secs(120), // TODO fixed 2 minutes, this needs to be a parameter
-      price: req.price.as_ref().ok_or(Status::invalid_argument("price empty"))?.into(),
-      penalty: req.penalty.as_ref().ok_or(Status::invalid_argument("penalty empty"))?.into(),
+    let nonce = req.nonce;
+    let retrieve = async {
+      self
+        .reactor
+        .retrieve(peer_id, nonce)
+        .await
+        .map_err(|e| Status::unknown(format!("error retrieving the data: {}", e)))
     };
-
-    let result = self
-      .reactor
-      .lease(peer_id, lease_term, req.data)
-      .await
-      .map_err(|e| Status::unknown(format!("Error trying to store: {}", e)))?;
-    Ok(Response::new(StoreResponse {
-      transaction_hash: Some(result.into()),
-    }))
+    let data = with_deadline(deadline_of(&request)?, retrieve).await?;
+    Ok(Response::new(RetrieveResponse { data }))
   }
 
-  async fn retrieve(&self, request: Request<RetrieveRequest>) -> Result<Response<RetrieveResponse>, Status> {
+  async fn retrieve_block(&self, request: Request<RetrieveBlockRequest>) -> Result<Response<RetrieveBlockResponse>, Status> {
     let req = request.get_ref();
     let peer_id = req
       .peer_id
@@ -221,12 +361,30 @@ where
       .try_into()
       .map_err(|e| Status::invalid_argument(format!("invalid peer id: {}", e)))?;
     let nonce = req.nonce;
-    let data = self
-      .reactor
-      .retrieve(peer_id, nonce)
+    let parameters = self
+      .data
+      .stored_parameters(peer_id, nonce)
       .await
-      .map_err(|e| Status::unknown(format!("error retrieving the data: {}", e)))?;
-    Ok(Response::new(RetrieveResponse { data }))
+      .map_err(|e| Status::not_found(format!("lease data not found: {}", e)))?;
+    let block_size = self.data.block_size();
+    let block_count = parameters.size.div_ceil(block_size);
+    if req.block_number as usize >= block_count {
+      return Err(Status::out_of_range(format!(
+        "block {} out of range, the lease has {} blocks",
+        req.block_number, block_count
+      )));
+    }
+    let (block_data, proof) = self
+      .data
+      .proof(peer_id, nonce, req.block_number as usize)
+      .await
+      .map_err(|e| Status::unknown(format!("error reading the block: {}", e)))?;
+    Ok(Response::new(RetrieveBlockResponse {
+      block_data,
+      proof: proof.into_iter().map(|p| web3::types::H256(p).into()).collect(),
+      merkle_root: parameters.merkle_root,
+      size: parameters.size as u64,
+    }))
   }
 
   async fn challenge(&self, request: Request<ChallengeRequest>) -> Result<Response<ChallengeResponse>, Status> {
@@ -239,12 +397,48 @@ where
       .map_err(|e| Status::invalid_argument(format!("invalid peer id: {}", e)))?;
     let nonce = req.nonce;
     let block_number = req.block_number;
-    self
+    let result = self
       .reactor
       .challenge(peer_id, ChallengeKey { nonce, block_number })
       .await
       .map_err(|e| Status::unknown(format!("error challenging a lease: {}", e)))?;
-    Ok(Response::new(ChallengeResponse {}))
+    Ok(Response::new(ChallengeResponse {
+      block_data: result.proof.block_data,
+      proof: result.proof.proof.into_iter().map(|p| web3::types::H256(p).into()).collect(),
+      verified: result.verified,
+    }))
+  }
+
+  async fn challenge_random(
+    &self,
+    request: Request<ChallengeRandomRequest>,
+  ) -> Result<Response<ChallengeRandomResponse>, Status> {
+    let req = request.get_ref();
+    let peer_id = req
+      .peer_id
+      .as_ref()
+      .ok_or(Status::invalid_argument("peer empty"))?
+      .try_into()
+      .map_err(|e| Status::invalid_argument(format!("invalid peer id: {}", e)))?;
+    let random = self
+      .reactor
+      .challenge_random(peer_id, req.nonce)
+      .await
+      .map_err(|e| Status::unknown(format!("error challenging a lease: {}", e)))?;
+    Ok(Response::new(ChallengeRandomResponse {
+      block_number: random.block_number,
+      seed_block_number: random.seed_block_number,
+      seed_block_hash: Some(random.seed_block_hash.into()),
+      block_data: random.result.proof.block_data,
+      proof: random
+        .result
+        .proof
+        .proof
+        .into_iter()
+        .map(|p| web3::types::H256(p).into())
+        .collect(),
+      verified: random.result.verified,
+    }))
   }
 
   async fn list_storage_rented(
@@ -269,11 +463,357 @@ where
         .collect(),
     }))
   }
+
+  async fn get_lease(&self, request: Request<GetLeaseRequest>) -> Result<Response<GetLeaseResponse>, Status> {
+    let req = request.get_ref();
+    let peer_id = req
+      .peer_id
+      .as_ref()
+      .ok_or(Status::invalid_argument("peer empty"))?
+      .try_into()
+      .map_err(|e| Status::invalid_argument(format!("invalid peer id: {}", e)))?;
+    let lease = self
+      .persistence
+      .rent_get(peer_id, req.nonce)
+      .await
+      .ok_or(Status::not_found("lease not found"))?;
+    let state = self
+      .onchain
+      .lease_state(
+        &lease.terms.token_address,
+        lease.peer_address,
+        self.onchain.account_storage(),
+        lease.nonce,
+        lease.terms.lease_duration,
+        lease.chain_confirmation.as_ref(),
+      )
+      .await
+      .map_err(|e| Status::unknown(format!("error reading lease state: {}", e)))?;
+    Ok(Response::new(GetLeaseResponse {
+      peer_id: Some(lease.peer_id.into()),
+      nonce: lease.nonce,
+      token_address: Some(lease.terms.token_address.into()),
+      price: Some(lease.terms.price.into()),
+      penalty: Some(lease.terms.penalty.into()),
+      proposal_expiration: Some(lease.terms.proposal_expiration.into()),
+      lease_duration: Some(lease.terms.lease_duration.into()),
+      transaction_hash: lease.chain_confirmation.clone().map(|c| c.transaction_hash.into()),
+      lease_started: lease.chain_confirmation.map(|c| c.timestamp.into()),
+      merkle_root: lease.data_parameters.merkle_root,
+      size: lease.data_parameters.size as u64,
+      state: match state {
+        types::LeaseState::NotFound => LeaseState::NotFound,
+        types::LeaseState::Active => LeaseState::Active,
+        types::LeaseState::Closed => LeaseState::Closed,
+        types::LeaseState::Penalized => LeaseState::Penalized,
+      } as i32,
+    }))
+  }
+
+  async fn compute_parameters(
+    &self,
+    request: Request<ComputeParametersRequest>,
+  ) -> Result<Response<ComputeParametersResponse>, Status> {
+    let parameters = self.data.parameters(request.get_ref().data.as_slice()).await;
+    Ok(Response::new(ComputeParametersResponse {
+      merkle_root: parameters.merkle_root,
+      size: parameters.size as u64,
+    }))
+  }
+
+  async fn verify_signature(
+    &self,
+    request: Request<VerifySignatureRequest>,
+  ) -> Result<Response<VerifySignatureResponse>, Status> {
+    let req = request.into_inner();
+    let lessee_address: Address = parse_address(req.lessee_address.as_ref(), "lessee_address")?;
+    let lessor_address: Address = parse_address(req.lessor_address.as_ref(), "lessor_address")?;
+    let terms = LeaseTerms {
+      lease_duration: req
+        .lease_duration
+        .clone()
+        .ok_or(Status::invalid_argument("lease duration empty"))?
+        .try_into()
+        .map_err(|_| Status::invalid_argument("duration should be positive value"))?,
+      token_address: parse_address(req.token_address.as_ref(), "token_address")?,
+      proposal_expiration: req
+        .proposal_expiration
+        .clone()
+        .ok_or(Status::invalid_argument("proposal expiration empty"))
+        .and_then(|t| match t.seconds {
+          s if s < 0 => Err(Status::invalid_argument("proposal expiration should be after the unix epoch")),
+          _ => Ok(t),
+        })?
+        .try_into()
+        .map_err(|_| Status::invalid_argument("invalid proposal expiration"))?,
+      price: req.price.as_ref().ok_or(Status::invalid_argument("price empty"))?.into(),
+      penalty: req.penalty.as_ref().ok_or(Status::invalid_argument("penalty empty"))?.into(),
+    };
+    if req.merkle_root.len() != 32 {
+      return Err(Status::invalid_argument("merkle root should be 32 bytes"));
+    }
+    let data_parameters = DataParameters {
+      merkle_root: req.merkle_root,
+      size: req.size as usize,
+    };
+    let signature = Signature::deserialize(req.signature.as_slice())
+      .map_err(|e| Status::invalid_argument(format!("invalid signature: {}", e)))?;
+    let signer_address = onchain::recover_lease_signer(
+      &lessee_address,
+      &lessor_address,
+      req.nonce,
+      &terms,
+      &data_parameters,
+      &signature,
+    )
+    .map_err(|e| Status::invalid_argument(format!("could not recover the signer: {}", e)))?;
+    Ok(Response::new(VerifySignatureResponse {
+      signer_address: Some(signer_address.into()),
+      signed_by_lessee: signer_address == lessee_address,
+      signed_by_lessor: signer_address == lessor_address,
+    }))
+  }
+
+  async fn version(&self, _: Request<VersionRequest>) -> Result<Response<VersionResponse>, Status> {
+    Ok(Response::new(VersionResponse {
+      version: version::VERSION.to_string(),
+      git_commit: version::GIT_COMMIT.to_string(),
+      protocol_version: self.protocol_version.clone(),
+      p2pim_protocol: String::from_utf8_lossy(p2p::p2pim::P2PIM_PROTOCOL_NAME).to_string(),
+    }))
+  }
+
+  async fn get_audit_log(&self, _: Request<GetAuditLogRequest>) -> Result<Response<GetAuditLogResponse>, Status> {
+    let entries = self.persistence.audit_log().await;
+    let consistent = persistence::audit_chain_consistent(entries.as_slice());
+    // Signing the head vouches for the whole chain, each entry hash covers the previous one
+    let head_signature = match entries.last() {
+      Some(head) => self
+        .onchain
+        .sign_message(&head.hash)
+        .await
+        .map_err(|e| Status::internal(format!("failed to sign the audit log: {}", e)))?
+        .serialize()
+        .to_vec(),
+      None => Vec::new(),
+    };
+    Ok(Response::new(GetAuditLogResponse {
+      entries: entries
+        .into_iter()
+        .map(|e| AuditEntry {
+          timestamp: Some(e.timestamp.into()),
+          role: match e.role {
+            types::AuditRole::Challenger => AuditRole::Challenger,
+            types::AuditRole::Prover => AuditRole::Prover,
+          } as i32,
+          peer_id: Some(e.peer_id.into()),
+          nonce: e.nonce,
+          block_number: e.block_number,
+          proof_hash: Some(web3::types::H256(e.proof_hash).into()),
+          verified: e.verified,
+          hash: Some(web3::types::H256(e.hash).into()),
+        })
+        .collect(),
+      consistent,
+      head_signature,
+      signer_address: Some(self.onchain.account_storage().into()),
+    }))
+  }
+
+  async fn reindex(&self, _: Request<ReindexRequest>) -> Result<Response<ReindexResponse>, Status> {
+    let entries = self
+      .data
+      .reindex()
+      .await
+      .map_err(|e| Status::internal(format!("failed to reindex: {:#}", e)))?;
+    let mut response_entries = Vec::with_capacity(entries.len());
+    for entry in entries {
+      // The parameters of the lease are the ones committed, the reindex only reports when they differ
+      let committed = self
+        .persistence
+        .let_get(entry.peer_id, entry.nonce)
+        .await
+        .map(|lease| lease.data_parameters);
+      let (outcome, detail) = match (&entry.outcome, &entry.parameters, committed) {
+        (data::ReindexOutcome::Unreadable(e), _, _) => (ReindexOutcome::Unreadable, e.clone()),
+        (data::ReindexOutcome::Mismatch { stored }, _, _) => (
+          ReindexOutcome::Mismatch,
+          format!(
+            "parameters file has merkle root 0x{} and size {}",
+            hex::encode(&stored.merkle_root),
+            stored.size
+          ),
+        ),
+        (_, Some(parameters), Some(committed)) if *parameters != committed => (
+          ReindexOutcome::Mismatch,
+          format!(
+            "lease has merkle root 0x{} and size {}",
+            hex::encode(&committed.merkle_root),
+            committed.size
+          ),
+        ),
+        (data::ReindexOutcome::Regenerated, _, _) => (ReindexOutcome::Regenerated, String::new()),
+        _ => (ReindexOutcome::Consistent, String::new()),
+      };
+      let (merkle_root, size) = entry.parameters.map(|p| (p.merkle_root, p.size as u64)).unwrap_or_default();
+      response_entries.push(ReindexEntry {
+        peer_id: Some(entry.peer_id.into()),
+        nonce: entry.nonce,
+        outcome: outcome as i32,
+        merkle_root,
+        size,
+        detail,
+      });
+    }
+    Ok(Response::new(ReindexResponse {
+      entries: response_entries,
+    }))
+  }
+}
+
+/// Converts an address received from a client, which is not trusted to have the 20 bytes of an ethereum address.
+fn parse_address(address: Option<&solidity::Address>, name: &str) -> Result<Address, Status> {
+  let address = address.ok_or_else(|| Status::invalid_argument(format!("{} empty", name)))?;
+  if address.data.len() != Address::len_bytes() {
+    return Err(Status::invalid_argument(format!(
+      "invalid {}: expected {} bytes, received {}",
+      name,
+      Address::len_bytes(),
+      address.data.len()
+    )));
+  }
+  Ok(address.into())
+}
+
+/// Converts a lease duration received from a client. The contract keeps whole seconds, so it should be at least one
+/// second, and the lease end is computed adding it to the seal time, which should not overflow.
+fn parse_lease_duration(duration: Option<&prost_types::Duration>) -> Result<Duration, Status> {
+  let duration: Duration = duration
+    .cloned()
+    .ok_or(Status::invalid_argument("lease duration empty"))?
+    .try_into()
+    .map_err(|_| Status::invalid_argument("duration should be positive value"))?;
+  if duration.as_secs() == 0 {
+    return Err(Status::invalid_argument("lease duration should be at least one second"));
+  }
+  if SystemTime::now().checked_add(duration).is_none() {
+    return Err(Status::invalid_argument("lease duration too long"));
+  }
+  Ok(duration)
+}
+
+/// Header and data of a streamed upload, rejected as soon as the data goes beyond the declared size or `max_size`.
+async fn receive_upload(
+  stream: &mut (impl Stream<Item = Result<StoreStreamRequest, Status>> + Unpin),
+  max_size: usize,
+) -> Result<(store_stream_request::Header, Vec<u8>), Status> {
+  let header = match stream.next().await.transpose()?.and_then(|m| m.request) {
+    Some(store_stream_request::Request::Header(header)) => header,
+    _ => return Err(Status::invalid_argument("first message should be the header")),
+  };
+  if header.size > max_size as u64 {
+    return Err(Status::invalid_argument(format!(
+      "data too large, size={} max={}",
+      header.size, max_size
+    )));
+  }
+  // Without a declared size the data is still capped by the upload limit
+  let limit = if header.size == 0 { max_size as u64 } else { header.size };
+
+  let mut data = Vec::new();
+  while let Some(message) = stream.next().await.transpose()? {
+    match message.request {
+      Some(store_stream_request::Request::Data(mut chunk)) => {
+        if (data.len() + chunk.len()) as u64 > limit {
+          return Err(Status::invalid_argument(format!("data beyond the limit of {} bytes", limit)));
+        }
+        data.append(&mut chunk)
+      }
+      _ => return Err(Status::invalid_argument("only data chunks are expected after the header")),
+    }
+  }
+  if header.size != 0 && data.len() as u64 != header.size {
+    return Err(Status::invalid_argument(format!(
+      "incomplete data, expected={} received={}",
+      header.size,
+      data.len()
+    )));
+  }
+  Ok((header, data))
+}
+
+// Set by the client, the timeout of the request in an amount of up to 8 digits followed by its unit
+const GRPC_TIMEOUT_HEADER: &str = "grpc-timeout";
+
+/// Deadline of the request from the moment it is received, none when the client did not set a timeout.
+fn deadline_of<T>(request: &Request<T>) -> Result<Option<Instant>, Status> {
+  let value = match request.metadata().get(GRPC_TIMEOUT_HEADER) {
+    Some(value) => value,
+    None => return Ok(None),
+  };
+  let invalid = || Status::invalid_argument(format!("invalid {} header", GRPC_TIMEOUT_HEADER));
+  let value = value.to_str().map_err(|_| invalid())?;
+  if value.len() < 2 {
+    return Err(invalid());
+  }
+  let (amount, unit) = value.split_at(value.len() - 1);
+  let amount = amount.parse::<u64>().map_err(|_| invalid())?;
+  let timeout = match unit {
+    "H" => Duration::from_secs(amount.saturating_mul(3600)),
+    "M" => Duration::from_secs(amount.saturating_mul(60)),
+    "S" => Duration::from_secs(amount),
+    "m" => Duration::from_millis(amount),
+    "u" => Duration::from_micros(amount),
+    "n" => Duration::from_nanos(amount),
+    _ => return Err(invalid()),
+  };
+  Ok(Some(Instant::now() + timeout))
+}
+
+/// Drops `future` once the deadline passes, which cancels the work it was doing for a client that gave up.
+async fn with_deadline<T>(deadline: Option<Instant>, future: impl Future<Output = Result<T, Status>>) -> Result<T, Status> {
+  match deadline {
+    None => future.await,
+    Some(deadline) if deadline <= Instant::now() => Err(Status::deadline_exceeded("deadline already passed")),
+    Some(deadline) => tokio::time::timeout_at(deadline, future)
+      .await
+      .unwrap_or_else(|_| Err(Status::deadline_exceeded("deadline passed before completing the request"))),
+  }
+}
+
+/// Maps the onchain errors to the status code a client can act upon: a missing token is a precondition, a revert
+/// aborts the call and a failure reaching the node means the service is unavailable.
+fn onchain_status(context: &str, error: onchain::Error) -> Status {
+  match &error {
+    onchain::Error::TokenNotDeployed(address) => {
+      Status::failed_precondition(format!("{}: token 0x{:x} not deployed", context, address))
+    }
+    onchain::Error::MethodError(err) => match &err.inner {
+      ExecutionError::Revert(Some(reason)) => Status::aborted(format!("{}: reverted: {}", context, reason)),
+      ExecutionError::Revert(None) | ExecutionError::InvalidOpcode => Status::aborted(format!("{}: reverted", context)),
+      ExecutionError::Web3(web3_error) if is_transport_error(web3_error) => {
+        Status::unavailable(format!("{}: {}", context, web3_error))
+      }
+      _ => Status::internal(format!("{}: {}", context, error)),
+    },
+    onchain::Error::Web3Error(web3_error) if is_transport_error(web3_error) => {
+      Status::unavailable(format!("{}: {}", context, web3_error))
+    }
+    _ => Status::internal(format!("{}: {}", context, error)),
+  }
+}
+
+fn is_transport_error(error: &web3::Error) -> bool {
+  matches!(
+    error,
+    web3::Error::Transport(_) | web3::Error::Unreachable | web3::Error::Io(_)
+  )
 }
 
 fn convert_balance(token_address: Address, balance: Balance) -> BalanceEntry {
   BalanceEntry {
     token_address: Some(token_address.into()),
+    adjudicator_address: Some(balance.adjudicator_address.into()),
     token_metadata: balance.token_metadata.map(|m| TokenMetadata {
       symbol: m.symbol,
       name: m.name,
@@ -296,6 +836,7 @@ where
   TP2p: p2p::Service,
 {
   p2p: TP2p,
+  debug_rpc: bool,
 }
 
 #[tonic::async_trait]
@@ -315,4 +856,511 @@ where
       .collect();
     Ok(Response::new(GetConnectedPeersResponse { peer_list }))
   }
+
+  async fn get_pending_operations(
+    &self,
+    _: Request<GetPendingOperationsRequest>,
+  ) -> Result<Response<GetPendingOperationsResponse>, Status> {
+    if !self.debug_rpc {
+      return Err(Status::permission_denied(
+        "debug rpc disabled, start the daemon with --debug-rpc",
+      ));
+    }
+    let operations = self
+      .p2p
+      .pending_operations()
+      .into_iter()
+      .map(|operation| {
+        let (kind, block_number) = match operation.kind {
+          p2p::PendingOperationKind::Challenge { block_number } => (PendingOperationKind::Challenge, block_number),
+          p2p::PendingOperationKind::Retrieve => (PendingOperationKind::Retrieve, 0),
+          p2p::PendingOperationKind::Proposal => (PendingOperationKind::Proposal, 0),
+          p2p::PendingOperationKind::Quote => (PendingOperationKind::Quote, 0),
+        };
+        PendingOperation {
+          kind: kind as i32,
+          peer_id: Some(operation.peer_id.into()),
+          id: operation.id,
+          block_number,
+          age: Some(operation.age.into()),
+          listeners: operation.listeners as u32,
+        }
+      })
+      .collect();
+    Ok(Response::new(GetPendingOperationsResponse { operations }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::data::Service as _;
+  use crate::onchain::Service as _;
+  use crate::p2p::Service as _;
+  use crate::persistence::Service as _;
+  use crate::testutil::{
+    data_service, lease, lease_terms, onchain_params, persistence_service, reactor_params, MockEthNode, MockLessor,
+    MockOnchain, MockP2p, TempDir,
+  };
+  use crate::types::{ChallengeProof, Lease};
+  use ethcontract::errors::MethodError;
+  use futures::stream;
+  use tonic::Code;
+  use web3::types::U256;
+
+  /// Api over the given mocks, with the reactor future that has to be polled for the requests that go through it.
+  fn p2pim(
+    folder: &TempDir,
+    onchain: MockOnchain,
+    p2p: MockP2p,
+  ) -> (
+    P2pimImpl<impl data::Service, MockOnchain, impl persistence::Service, impl reactor::Service>,
+    impl Future<Output = ()>,
+  ) {
+    let data = data_service(folder);
+    let persistence = persistence_service(folder);
+    let (reactor, reactor_fut) = reactor::new_service(
+      data.clone(),
+      MockLessor::accepting(),
+      onchain.clone(),
+      p2p.clone(),
+      persistence.clone(),
+      reactor_params(),
+    );
+    let p2pim = P2pimImpl {
+      local_peer_id: p2p.local_peer_id(),
+      protocol_version: p2p.protocol_version(),
+      data,
+      onchain,
+      networks: HashMap::new(),
+      persistence,
+      reactor,
+      max_upload_size: 1024,
+    };
+    (p2pim, reactor_fut)
+  }
+
+  fn upload(size: u64, chunks: &[&[u8]]) -> impl Stream<Item = Result<StoreStreamRequest, Status>> + Unpin {
+    let header = store_stream_request::Request::Header(store_stream_request::Header {
+      size,
+      ..Default::default()
+    });
+    let data = chunks.iter().map(|chunk| store_stream_request::Request::Data(chunk.to_vec()));
+    let messages = std::iter::once(header)
+      .chain(data)
+      .map(|request| Ok(StoreStreamRequest { request: Some(request) }))
+      .collect::<Vec<_>>();
+    stream::iter(messages)
+  }
+
+  #[tokio::test]
+  async fn info_reports_the_local_peer_id() {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (p2pim, _) = p2pim(&folder, MockOnchain::new(Address::from_low_u64_be(1)), p2p.clone());
+
+    let info = p2pim
+      .get_info(Request::new(GetInfoRequest::default()))
+      .await
+      .unwrap()
+      .into_inner();
+
+    let peer_id: libp2p::PeerId = info.peer_id.as_ref().unwrap().try_into().unwrap();
+    assert_eq!(peer_id, p2p.local_peer_id());
+  }
+
+  #[tokio::test]
+  async fn challenge_answered_with_a_verified_proof() {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (peer_id, lessor) = p2p.add_peer();
+    // The lessor side of the lease, to answer with a valid proof
+    let lessor_data = data_service(&folder);
+    let parameters = lessor_data.parameters(b"some data").await;
+    lessor_data.store(peer_id, 1, b"some data", &parameters).await.unwrap();
+    let (block_data, proof) = lessor_data.proof(peer_id, 1, 0).await.unwrap();
+    p2p.state.lock().unwrap().challenge_proof = Some(ChallengeProof {
+      block_data: block_data.clone(),
+      proof: proof.clone(),
+    });
+    let (p2pim, _) = p2pim(&folder, MockOnchain::new(Address::from_low_u64_be(1)), p2p.clone());
+    p2pim
+      .persistence
+      .rent_store(Lease {
+        data_parameters: parameters,
+        ..lease(peer_id, lessor, 1)
+      })
+      .await;
+    let request = || {
+      Request::new(ChallengeRequest {
+        peer_id: Some(peer_id.into()),
+        nonce: 1,
+        block_number: 0,
+      })
+    };
+
+    let response = p2pim.challenge(request()).await.unwrap().into_inner();
+
+    assert!(response.verified);
+    assert_eq!(response.block_data, block_data);
+    let proof = proof
+      .into_iter()
+      .map(|p| web3::types::H256(p).into())
+      .collect::<Vec<solidity::H256>>();
+    assert_eq!(response.proof, proof);
+
+    p2p.state.lock().unwrap().challenge_proof.as_mut().unwrap().block_data[0] ^= 1;
+    assert!(!p2pim.challenge(request()).await.unwrap().into_inner().verified);
+  }
+
+  #[tokio::test]
+  async fn parameters_match_the_stored_ones() {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (peer_id, _) = p2p.add_peer();
+    let (p2pim, _) = p2pim(&folder, MockOnchain::new(Address::from_low_u64_be(1)), p2p);
+    let data = vec![7; 3 * p2pim.data.block_size() + 1];
+
+    let response = p2pim
+      .compute_parameters(Request::new(ComputeParametersRequest { data: data.clone() }))
+      .await
+      .unwrap()
+      .into_inner();
+
+    let parameters = p2pim.data.parameters(data.as_slice()).await;
+    p2pim.data.store(peer_id, 1, data.as_slice(), &parameters).await.unwrap();
+    let stored = p2pim.data.stored_parameters(peer_id, 1).await.unwrap();
+    assert_eq!(response.merkle_root, stored.merkle_root);
+    assert_eq!(response.size, data.len() as u64);
+  }
+
+  #[tokio::test]
+  async fn onchain_errors_mapped_to_status_codes() {
+    let folder = TempDir::new();
+    let onchain = MockOnchain::new(Address::from_low_u64_be(1));
+    let (mut p2pim, _) = p2pim(&folder, onchain.clone(), MockP2p::new());
+    p2pim.networks.insert("testnet".to_string(), onchain.clone());
+    let token_address = Some(Address::from_low_u64_be(2).into());
+    let cases: [(fn() -> onchain::Error, Code); 3] = [
+      (
+        || onchain::Error::TokenNotDeployed(Address::from_low_u64_be(2)),
+        Code::FailedPrecondition,
+      ),
+      (
+        || onchain::Error::MethodError(MethodError::from_parts("deposit".to_string(), ExecutionError::Revert(None))),
+        Code::Aborted,
+      ),
+      (|| onchain::Error::Web3Error(web3::Error::Unreachable), Code::Unavailable),
+    ];
+
+    for (failure, code) in cases {
+      onchain.state.lock().unwrap().failure = Some(failure);
+      let balance = p2pim
+        .get_balance(Request::new(GetBalanceRequest {
+          token_address: token_address.clone(),
+          network: "testnet".to_string(),
+          ..Default::default()
+        }))
+        .await;
+      assert_eq!(balance.unwrap_err().code(), code);
+      let deposit = p2pim
+        .deposit(Request::new(DepositRequest {
+          token_address: token_address.clone(),
+          amount: Some(U256::one().into()),
+          network: "testnet".to_string(),
+        }))
+        .await;
+      assert_eq!(deposit.unwrap_err().code(), code);
+    }
+  }
+
+  #[tokio::test]
+  async fn retrieved_block_verified_against_the_root() {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (peer_id, _) = p2p.add_peer();
+    let (p2pim, _) = p2pim(&folder, MockOnchain::new(Address::from_low_u64_be(1)), p2p);
+    let block_size = p2pim.data.block_size();
+    let data = (0..3 * block_size - 7).map(|i| i as u8).collect::<Vec<_>>();
+    let parameters = p2pim.data.parameters(data.as_slice()).await;
+    p2pim.data.store(peer_id, 1, data.as_slice(), &parameters).await.unwrap();
+    let request = |block_number| {
+      Request::new(RetrieveBlockRequest {
+        peer_id: Some(peer_id.into()),
+        nonce: 1,
+        block_number,
+      })
+    };
+
+    let response = p2pim.retrieve_block(request(2)).await.unwrap().into_inner();
+
+    assert_eq!(response.block_data, &data[2 * block_size..]);
+    assert_eq!(response.merkle_root, parameters.merkle_root);
+    let proof = response.proof.iter().map(|p| web3::types::H256::from(p).0).collect();
+    let verified = p2pim.data.verify(parameters, 2, response.block_data.as_slice(), proof).await;
+    assert!(verified.unwrap());
+    let out_of_range = p2pim.retrieve_block(request(3)).await.unwrap_err();
+    assert_eq!(out_of_range.code(), Code::OutOfRange);
+  }
+
+  #[tokio::test]
+  async fn lease_signature_signer_recovered() {
+    let signer = onchain::new_service(onchain_params(MockEthNode::new().serve()))
+      .await
+      .unwrap();
+    let folder = TempDir::new();
+    let (p2pim, _) = p2pim(&folder, MockOnchain::new(Address::from_low_u64_be(1)), MockP2p::new());
+    let (lessee, lessor) = (signer.account_storage(), Address::repeat_byte(3));
+    let terms = lease_terms(Address::repeat_byte(2));
+    let data_parameters = DataParameters {
+      merkle_root: vec![7; 32],
+      size: 9,
+    };
+    let signature = signer.sign_proposal(&lessor, 1, &terms, &data_parameters).await.unwrap();
+    let request = |nonce| {
+      Request::new(VerifySignatureRequest {
+        token_address: Some(terms.token_address.into()),
+        lessee_address: Some(lessee.into()),
+        lessor_address: Some(lessor.into()),
+        nonce,
+        merkle_root: data_parameters.merkle_root.clone(),
+        size: data_parameters.size as u64,
+        price: Some(terms.price.into()),
+        penalty: Some(terms.penalty.into()),
+        lease_duration: Some(terms.lease_duration.into()),
+        proposal_expiration: Some(terms.proposal_expiration.into()),
+        signature: signature.serialize(),
+      })
+    };
+
+    let response = p2pim.verify_signature(request(1)).await.unwrap().into_inner();
+
+    assert_eq!(response.signer_address, Some(lessee.into()));
+    assert!(response.signed_by_lessee);
+    assert!(!response.signed_by_lessor);
+    // Signed for another lease, some other address is recovered
+    let response = p2pim.verify_signature(request(2)).await.unwrap().into_inner();
+    assert!(!response.signed_by_lessee);
+  }
+
+  #[tokio::test]
+  async fn balances_reported_per_network() {
+    let folder = TempDir::new();
+    let onchain = MockOnchain::new(Address::from_low_u64_be(1));
+    let testnet = MockOnchain::new(Address::from_low_u64_be(1)).on_network("testnet");
+    onchain.state.lock().unwrap().token_balance = 1.into();
+    testnet.state.lock().unwrap().token_balance = 2.into();
+    let (mut p2pim, _) = p2pim(&folder, onchain, MockP2p::new());
+    p2pim.networks.insert("testnet".to_string(), testnet);
+    let balance = |network: &str| {
+      p2pim.get_balance(Request::new(GetBalanceRequest {
+        token_address: Some(Address::from_low_u64_be(2).into()),
+        network: network.to_string(),
+        ..Default::default()
+      }))
+    };
+    let available = |response: &GetBalanceResponse| {
+      let wallet = response.balance.as_ref().unwrap().wallet_balance.as_ref().unwrap();
+      U256::from(wallet.available.as_ref().unwrap())
+    };
+
+    let default = balance("").await.unwrap().into_inner();
+    let other = balance("testnet").await.unwrap().into_inner();
+    let unknown = balance("mainnet").await.unwrap_err();
+
+    assert_eq!((default.network.as_str(), available(&default)), ("test", 1.into()));
+    assert_eq!((other.network.as_str(), available(&other)), ("testnet", 2.into()));
+    assert_eq!(unknown.code(), Code::NotFound);
+  }
+
+  #[tokio::test]
+  async fn malformed_addresses_rejected() {
+    let folder = TempDir::new();
+    let onchain = MockOnchain::new(Address::from_low_u64_be(1));
+    let (mut p2pim, _) = p2pim(&folder, onchain.clone(), MockP2p::new());
+    p2pim.networks.insert("testnet".to_string(), onchain.clone());
+    let malformed = || Some(solidity::Address { data: vec![1; 19] });
+    let network = "testnet".to_string();
+
+    let balance = p2pim
+      .get_balance(Request::new(GetBalanceRequest {
+        token_address: malformed(),
+        network: network.clone(),
+        ..Default::default()
+      }))
+      .await
+      .unwrap_err();
+    let deposit = p2pim
+      .deposit(Request::new(DepositRequest {
+        token_address: None,
+        amount: Some(U256::one().into()),
+        network: network.clone(),
+      }))
+      .await
+      .unwrap_err();
+    let withdraw = p2pim
+      .withdraw(Request::new(WithdrawRequest {
+        token_address: Some(Address::from_low_u64_be(2).into()),
+        amount: Some(U256::one().into()),
+        network,
+        destination: malformed(),
+      }))
+      .await
+      .unwrap_err();
+
+    assert_eq!(balance.code(), Code::InvalidArgument);
+    assert_eq!(balance.message(), "invalid token_address: expected 20 bytes, received 19");
+    assert_eq!(deposit.code(), Code::InvalidArgument);
+    assert_eq!(deposit.message(), "token_address empty");
+    assert_eq!(withdraw.code(), Code::InvalidArgument);
+    assert_eq!(withdraw.message(), "invalid destination: expected 20 bytes, received 19");
+    assert_eq!(onchain.calls("deposit") + onchain.calls("withdraw"), 0);
+  }
+
+  #[tokio::test]
+  async fn invalid_lease_durations_rejected() {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (peer_id, _) = p2p.add_peer();
+    let (p2pim, _) = p2pim(&folder, MockOnchain::new(Address::from_low_u64_be(1)), p2p.clone());
+    let duration = |seconds, nanos| Some(prost_types::Duration { seconds, nanos });
+    let cases = [
+      (None, "lease duration empty"),
+      (duration(-1, 0), "duration should be positive value"),
+      (duration(0, 500_000_000), "lease duration should be at least one second"),
+      (duration(i64::MAX, 0), "lease duration too long"),
+    ];
+
+    for (lease_duration, message) in cases {
+      let status = p2pim
+        .store(Request::new(StoreRequest {
+          peer_id: Some(peer_id.into()),
+          token_address: Some(Address::from_low_u64_be(2).into()),
+          price: Some(U256::one().into()),
+          penalty: Some(U256::one().into()),
+          lease_duration,
+          data: b"some data".to_vec(),
+        }))
+        .await
+        .unwrap_err();
+
+      assert_eq!(status.code(), Code::InvalidArgument);
+      assert_eq!(status.message(), message);
+    }
+    assert!(p2p.state.lock().unwrap().proposals_sent.is_empty());
+  }
+
+  #[tokio::test]
+  async fn request_past_its_deadline_does_no_work() {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (peer_id, _) = p2p.add_peer();
+    let (p2pim, _) = p2pim(&folder, MockOnchain::new(Address::from_low_u64_be(1)), p2p.clone());
+    let with_timeout = |mut request: Request<StoreRequest>, timeout: &str| {
+      request.metadata_mut().insert(GRPC_TIMEOUT_HEADER, timeout.parse().unwrap());
+      request
+    };
+    let store = || {
+      Request::new(StoreRequest {
+        peer_id: Some(peer_id.into()),
+        token_address: Some(Address::from_low_u64_be(2).into()),
+        price: Some(U256::one().into()),
+        penalty: Some(U256::one().into()),
+        lease_duration: Some(prost_types::Duration { seconds: 60, nanos: 0 }),
+        data: b"some data".to_vec(),
+      })
+    };
+
+    let expired = p2pim.store(with_timeout(store(), "0n")).await.unwrap_err();
+    let malformed = p2pim.store(with_timeout(store(), "5 seconds")).await.unwrap_err();
+
+    assert_eq!(expired.code(), Code::DeadlineExceeded);
+    assert_eq!(expired.message(), "deadline already passed");
+    assert_eq!(malformed.code(), Code::InvalidArgument);
+    assert!(p2p.state.lock().unwrap().proposals_sent.is_empty());
+  }
+
+  #[tokio::test]
+  async fn version_reports_the_protocols() {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (p2pim, _) = p2pim(&folder, MockOnchain::new(Address::from_low_u64_be(1)), p2p.clone());
+
+    let version = p2pim.version(Request::new(VersionRequest {})).await.unwrap().into_inner();
+
+    assert_eq!(version.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(version.protocol_version, p2p.protocol_version());
+    assert_eq!(version.p2pim_protocol.as_bytes(), p2p::p2pim::P2PIM_PROTOCOL_NAME);
+    assert_eq!(version.p2pim_protocol, "/p2pim/protobuf/0.1.0");
+  }
+
+  #[tokio::test]
+  async fn pending_operations_reported_with_the_debug_rpc() {
+    let p2p = MockP2p::new();
+    let peer_id = libp2p::PeerId::random();
+    p2p.state.lock().unwrap().pending_operations.push(p2p::PendingOperation {
+      kind: p2p::PendingOperationKind::Retrieve,
+      peer_id,
+      id: 7,
+      age: Duration::from_secs(3),
+      listeners: 1,
+    });
+    let swarm = |debug_rpc| SwarmImpl {
+      p2p: p2p.clone(),
+      debug_rpc,
+    };
+
+    let operations = swarm(true)
+      .get_pending_operations(Request::new(GetPendingOperationsRequest {}))
+      .await
+      .unwrap()
+      .into_inner()
+      .operations;
+    let disabled = swarm(false)
+      .get_pending_operations(Request::new(GetPendingOperationsRequest {}))
+      .await
+      .unwrap_err();
+
+    assert_eq!(operations.len(), 1);
+    assert_eq!(operations[0].kind, PendingOperationKind::Retrieve as i32);
+    assert_eq!(operations[0].peer_id, Some(peer_id.into()));
+    assert_eq!(operations[0].id, 7);
+    assert_eq!(operations[0].age, Some(Duration::from_secs(3).into()));
+    assert_eq!(disabled.code(), Code::PermissionDenied);
+  }
+
+  #[tokio::test]
+  async fn upload_received() {
+    let (header, data) = receive_upload(&mut upload(9, &[b"some ", b"data"]), 16).await.unwrap();
+    assert_eq!(header.size, 9);
+    assert_eq!(data, b"some data");
+  }
+
+  #[tokio::test]
+  async fn upload_declared_above_the_limit() {
+    let result = receive_upload(&mut upload(17, &[b"some data"]), 16).await;
+    assert_eq!(result.unwrap_err().code(), tonic::Code::InvalidArgument);
+  }
+
+  #[tokio::test]
+  async fn upload_beyond_its_declared_size() {
+    // Rejected on the chunk that goes beyond, the ones after it are never read
+    let mut messages = upload(4, &[b"some", b" data"]).chain(stream::poll_fn(|_| panic!("read after the rejection")));
+    let result = receive_upload(&mut messages, 16).await;
+    assert_eq!(result.unwrap_err().code(), tonic::Code::InvalidArgument);
+  }
+
+  #[tokio::test]
+  async fn upload_of_unknown_size() {
+    let (_, data) = receive_upload(&mut upload(0, &[b"some data"]), 16).await.unwrap();
+    assert_eq!(data, b"some data");
+    let result = receive_upload(&mut upload(0, &[b"some data", b"some data"]), 16).await;
+    assert_eq!(result.unwrap_err().code(), tonic::Code::InvalidArgument);
+  }
+
+  #[tokio::test]
+  async fn upload_incomplete() {
+    let result = receive_upload(&mut upload(16, &[b"some data"]), 16).await;
+    assert_eq!(result.unwrap_err().code(), tonic::Code::InvalidArgument);
+  }
 }
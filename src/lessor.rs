@@ -1,3 +1,4 @@
+use crate::persistence;
 use crate::types::LeaseTerms;
 use bigdecimal::ToPrimitive;
 use libp2p::PeerId;
@@ -6,6 +7,7 @@ use num_bigint::{BigInt, Sign};
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::ops::Range;
+use std::sync::Arc;
 use std::time::Duration;
 use tonic::async_trait;
 use web3::types::{Address, U256};
@@ -19,6 +21,11 @@ pub enum RejectedReason {
   TotalTokensTooSmall,
   PriceRateTooSmall,
   PenaltyRateTooHigh,
+  QuotaExceeded,
+  LessorOutOfGas,
+  TooManyRequests,
+  SelfLease,
+  UnknownPeer,
 }
 
 impl Display for RejectedReason {
@@ -32,6 +39,11 @@ impl Display for RejectedReason {
       RejectedReason::TotalTokensTooSmall => f.write_str("total tokens too small"),
       RejectedReason::PriceRateTooSmall => f.write_str("price per gb per hour too small"),
       RejectedReason::PenaltyRateTooHigh => f.write_str("penalty too high"),
+      RejectedReason::QuotaExceeded => f.write_str("storage quota exceeded"),
+      RejectedReason::LessorOutOfGas => f.write_str("lessor cannot pay the gas of the seal"),
+      RejectedReason::TooManyRequests => f.write_str("too many proposals"),
+      RejectedReason::SelfLease => f.write_str("lessee and lessor are the same account"),
+      RejectedReason::UnknownPeer => f.write_str("lessee peer not identified"),
     }
   }
 }
@@ -45,25 +57,63 @@ pub struct Ask {
   pub max_penalty_rate: f32,
 }
 
+/// Price in token base units per GB per hour of a lease, none for empty data or a lease shorter than a second.
+pub fn tokens_gb_hour(price: &BigInt, lease_duration: Duration, size: usize) -> Option<BigInt> {
+  let secs_hour = BigInt::from(3600);
+  let bytes_gb = BigInt::from(1024u64 * 1024u64 * 1024u64);
+  let divisor = BigInt::from(lease_duration.as_secs()) * size;
+  if divisor == BigInt::from(0) {
+    None
+  } else {
+    Some(price * secs_hour * bytes_gb / divisor)
+  }
+}
+
 #[async_trait]
 pub trait Service: Clone + Sync + Send + 'static {
+  /// Checks the proposal against the ask of its token and the storage quota.
   async fn proposal(&self, peer_id: &PeerId, lease_terms: &LeaseTerms, size: usize) -> Result<(), RejectedReason>;
+  /// Checks the proposal as `proposal` and reserves its size in the storage quota until `release`, so proposals being
+  /// sealed together cannot exceed it.
+  async fn admit(&self, peer_id: &PeerId, lease_terms: &LeaseTerms, size: usize) -> Result<(), RejectedReason>;
+  /// Frees the size reserved by `admit`, once the lease is in persistence or failed to seal.
+  async fn release(&self, size: usize);
 }
 
 #[derive(Clone)]
-struct Implementation {
+struct Implementation<TPersistence>
+where
+  TPersistence: persistence::Service,
+{
   token_ask: HashMap<Address, Ask>,
+  storage_quota: Option<u64>,
+  persistence: TPersistence,
+  // Bytes of the admitted proposals not in persistence yet, the lock serializes the quota checks
+  admitted_bytes: Arc<tokio::sync::Mutex<u64>>,
 }
 
-pub fn new_service(token_ask: Vec<(Address, Ask)>) -> impl Service {
+/// Proposals are rejected once the let leases and the admitted proposals would store more than `storage_quota` bytes.
+pub fn new_service<TPersistence>(
+  token_ask: Vec<(Address, Ask)>,
+  storage_quota: Option<u64>,
+  persistence: TPersistence,
+) -> impl Service
+where
+  TPersistence: persistence::Service,
+{
   Implementation {
     token_ask: token_ask.into_iter().collect(),
+    storage_quota,
+    persistence,
+    admitted_bytes: Arc::new(tokio::sync::Mutex::new(0)),
   }
 }
 
-#[async_trait]
-impl Service for Implementation {
-  async fn proposal(&self, _: &PeerId, lease_terms: &LeaseTerms, size: usize) -> Result<(), RejectedReason> {
+impl<TPersistence> Implementation<TPersistence>
+where
+  TPersistence: persistence::Service,
+{
+  fn within_ask(&self, lease_terms: &LeaseTerms, size: usize) -> Result<(), RejectedReason> {
     if let Some(ask) = self.token_ask.get(&lease_terms.token_address) {
       debug!(
         "checking if proposal is within ask terms lease_terms={:?} ask={:?}",
@@ -89,14 +139,19 @@ impl Service for Implementation {
         return Err(RejectedReason::TotalTokensTooSmall);
       }
 
-      let secs_hour = BigInt::from(3600);
-      let bytes_gb = BigInt::from(1024u64 * 1024u64 * 1024u64);
       let mut buf = [0u8; 32];
       lease_terms.price.to_little_endian(buf.as_mut_slice());
       let price_bi = BigInt::from_bytes_le(Sign::Plus, buf.as_slice());
-      let tokens_gb_hour = price_bi.clone() * secs_hour * bytes_gb / lease_terms.lease_duration.as_secs() * size;
-
-      if U256::from_little_endian(tokens_gb_hour.to_bytes_le().1.as_slice()) < ask.min_tokens_gb_hour {
+      let below_min_rate = match tokens_gb_hour(&price_bi, lease_terms.lease_duration, size) {
+        // Larger than any U256 is above any ask
+        Some(rate) => {
+          rate.bits() <= 256 && U256::from_little_endian(rate.to_bytes_le().1.as_slice()) < ask.min_tokens_gb_hour
+        }
+        // Empty data takes no space, only its size range and total price are checked
+        None if size == 0 => false,
+        None => return Err(RejectedReason::DurationTooShort),
+      };
+      if below_min_rate {
         return Err(RejectedReason::PriceRateTooSmall);
       }
 
@@ -112,4 +167,149 @@ impl Service for Implementation {
       Err(RejectedReason::TokenNotAccepted)
     }
   }
+
+  async fn within_quota(&self, size: usize, reserve: bool) -> Result<(), RejectedReason> {
+    let storage_quota = match self.storage_quota {
+      Some(storage_quota) => storage_quota,
+      None => return Ok(()),
+    };
+    let mut admitted_bytes = self.admitted_bytes.lock().await;
+    // An admitted lease counts twice between its store in persistence and its release, never zero times
+    let stored_bytes = self.persistence.let_stored_bytes().await;
+    if stored_bytes + *admitted_bytes + size as u64 > storage_quota {
+      debug!(
+        "rejecting proposal over the storage quota stored_bytes={} admitted_bytes={} size={} storage_quota={}",
+        stored_bytes, *admitted_bytes, size, storage_quota
+      );
+      return Err(RejectedReason::QuotaExceeded);
+    }
+    if reserve {
+      *admitted_bytes += size as u64;
+    }
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl<TPersistence> Service for Implementation<TPersistence>
+where
+  TPersistence: persistence::Service,
+{
+  async fn proposal(&self, _: &PeerId, lease_terms: &LeaseTerms, size: usize) -> Result<(), RejectedReason> {
+    self.within_ask(lease_terms, size)?;
+    self.within_quota(size, false).await
+  }
+
+  async fn admit(&self, _: &PeerId, lease_terms: &LeaseTerms, size: usize) -> Result<(), RejectedReason> {
+    self.within_ask(lease_terms, size)?;
+    self.within_quota(size, true).await
+  }
+
+  async fn release(&self, size: usize) {
+    let mut admitted_bytes = self.admitted_bytes.lock().await;
+    *admitted_bytes = admitted_bytes.saturating_sub(size as u64);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::persistence::Service as _;
+  use crate::testutil::{lease, lease_terms, persistence_service, TempDir};
+
+  const GB: usize = 1024 * 1024 * 1024;
+
+  fn ask(min_tokens_gb_hour: u64) -> Ask {
+    Ask {
+      duration_range: Duration::from_millis(1)..Duration::from_secs(365 * 24 * 3600),
+      size_range: 0..usize::MAX,
+      min_tokens_total: U256::zero(),
+      min_tokens_gb_hour: min_tokens_gb_hour.into(),
+      max_penalty_rate: f32::MAX,
+    }
+  }
+
+  async fn proposal(min_tokens_gb_hour: u64, lease_duration: Duration, size: usize) -> Result<(), RejectedReason> {
+    let folder = TempDir::new();
+    let token = Address::repeat_byte(2);
+    let lessor = new_service(vec![(token, ask(min_tokens_gb_hour))], None, persistence_service(&folder));
+    let terms = LeaseTerms {
+      price: 100.into(),
+      penalty: 1.into(),
+      lease_duration,
+      ..lease_terms(token)
+    };
+    lessor.proposal(&PeerId::random(), &terms, size).await
+  }
+
+  #[test]
+  fn rate_divides_by_size_and_duration() {
+    let price = BigInt::from(100);
+    let hour = Duration::from_secs(3600);
+    assert_eq!(tokens_gb_hour(&price, hour, GB), Some(BigInt::from(100)));
+    assert_eq!(tokens_gb_hour(&price, hour, 2 * GB), Some(BigInt::from(50)));
+    assert_eq!(tokens_gb_hour(&price, 2 * hour, GB), Some(BigInt::from(50)));
+    assert_eq!(tokens_gb_hour(&price, hour, 0), None);
+    assert_eq!(tokens_gb_hour(&price, Duration::from_millis(500), GB), None);
+  }
+
+  #[tokio::test]
+  async fn rate_compared_with_the_ask() {
+    let hour = Duration::from_secs(3600);
+    assert!(proposal(100, hour, GB).await.is_ok());
+    assert!(matches!(
+      proposal(100, hour, 2 * GB).await,
+      Err(RejectedReason::PriceRateTooSmall)
+    ));
+  }
+
+  #[tokio::test]
+  async fn proposal_without_rate() {
+    let hour = Duration::from_secs(3600);
+    assert!(proposal(100, hour, 0).await.is_ok());
+    assert!(matches!(
+      proposal(100, Duration::from_millis(500), GB).await,
+      Err(RejectedReason::DurationTooShort)
+    ));
+  }
+
+  #[tokio::test]
+  async fn quota_reserved_by_admitted_proposals() {
+    let folder = TempDir::new();
+    let token = Address::repeat_byte(2);
+    let lessor = new_service(vec![(token, ask(0))], Some(2 * GB as u64), persistence_service(&folder));
+    let (peer_id, terms) = (PeerId::random(), lease_terms(token));
+
+    assert!(lessor.admit(&peer_id, &terms, GB).await.is_ok());
+    assert!(lessor.admit(&peer_id, &terms, GB).await.is_ok());
+
+    assert!(matches!(
+      lessor.admit(&peer_id, &terms, 1).await,
+      Err(RejectedReason::QuotaExceeded)
+    ));
+    assert!(matches!(
+      lessor.proposal(&peer_id, &terms, 1).await,
+      Err(RejectedReason::QuotaExceeded)
+    ));
+    lessor.release(GB).await;
+    assert!(lessor.admit(&peer_id, &terms, 1).await.is_ok());
+  }
+
+  #[tokio::test]
+  async fn quota_counts_the_stored_leases() {
+    let folder = TempDir::new();
+    let token = Address::repeat_byte(2);
+    let persistence = persistence_service(&folder);
+    let lessor = new_service(vec![(token, ask(0))], Some(10), persistence.clone());
+    let (peer_id, terms) = (PeerId::random(), lease_terms(token));
+    // Stored with a size of 9
+    persistence.let_store(lease(peer_id, Address::repeat_byte(1), 1)).await;
+
+    assert!(matches!(
+      lessor.proposal(&peer_id, &terms, 2).await,
+      Err(RejectedReason::QuotaExceeded)
+    ));
+    persistence.let_remove(peer_id, 1).await;
+    assert!(lessor.proposal(&peer_id, &terms, 2).await.is_ok());
+  }
 }
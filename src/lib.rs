@@ -8,6 +8,9 @@ pub mod proto {
   pub mod p2p {
     include!(concat!(env!("OUT_DIR"), "/p2p.rs"));
   }
+  pub mod persistence {
+    include!(concat!(env!("OUT_DIR"), "/persistence.rs"));
+  }
   pub mod libp2p {
     use libp2p::multihash;
     use std::convert::{TryFrom, TryInto};
@@ -141,5 +144,8 @@ pub mod p2p;
 pub mod persistence;
 pub mod reactor;
 pub mod s3;
+#[cfg(test)]
+mod testutil;
 pub mod types;
 pub mod utils;
+pub mod version;
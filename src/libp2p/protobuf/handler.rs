@@ -1,13 +1,15 @@
 use super::protocol::{DecodeError, ProtocolType};
-use futures::{SinkExt, StreamExt};
+use futures::{FutureExt, Sink, SinkExt, StreamExt};
 use libp2p::swarm::handler::{InboundUpgradeSend, OutboundUpgradeSend};
 use libp2p::swarm::{ConnectionHandler, ConnectionHandlerEvent, ConnectionHandlerUpgrErr, KeepAlive, SubstreamProtocol};
 use log::{trace, warn};
 use std::collections::VecDeque;
 use std::fmt::{Debug, Display, Formatter};
 use std::io;
+use std::pin::Pin;
 use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
+use tokio::time::Sleep;
 
 use super::protocol;
 
@@ -20,7 +22,8 @@ pub struct Config {
 
 pub struct Handler<T: prost::Message> {
   config: Config,
-  keep_alive: KeepAlive,
+  idle_deadline: Instant,
+  idle_timer: Option<Pin<Box<Sleep>>>,
   pending_messages: VecDeque<T>,
   connection: Option<ProtocolType<T>>,
   requested: bool,
@@ -33,12 +36,19 @@ impl<T: prost::Message> Handler<T> {
         idle_timeout: DEFAULT_IDLE_TIMEOUT,
         protocol_name: protocol_name.to_vec(),
       },
-      keep_alive: KeepAlive::No,
+      idle_deadline: Instant::now(),
+      idle_timer: None,
       pending_messages: VecDeque::new(),
       connection: None,
       requested: false,
     }
   }
+
+  /// Closes the substream once it stays idle for `idle_timeout`, the connection itself is left to the swarm keep-alive.
+  pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+    self.config.idle_timeout = idle_timeout;
+    self
+  }
 }
 
 #[derive(Debug)]
@@ -48,13 +58,27 @@ pub enum Event<T: prost::Message> {
 
 impl<T: prost::Message> Handler<T> {
   fn update_keep_alive(&mut self) {
-    self.keep_alive = KeepAlive::Until(Instant::now() + self.config.idle_timeout);
+    self.idle_deadline = Instant::now() + self.config.idle_timeout;
+    let deadline = tokio::time::Instant::from_std(self.idle_deadline);
+    match self.idle_timer.as_mut() {
+      Some(timer) => timer.as_mut().reset(deadline),
+      None => self.idle_timer = Some(Box::pin(tokio::time::sleep_until(deadline))),
+    }
+  }
+
+  fn is_idle(&self) -> bool {
+    self.pending_messages.is_empty() && Instant::now() >= self.idle_deadline
+  }
+
+  fn close_substream(&mut self) {
+    self.connection = None;
+    self.requested = false;
+    self.idle_timer = None;
   }
 }
 
 #[derive(Debug)]
 pub enum HandlerError {
-  InboundClosed,
   DecodeError(DecodeError),
   IOError(io::Error),
 }
@@ -97,6 +121,7 @@ impl<T: prost::Message + Default + 'static> ConnectionHandler for Handler<T> {
   ) {
     trace!("fully negotiated outbond");
     self.connection = Some(protocol);
+    self.requested = false;
     self.update_keep_alive();
   }
 
@@ -111,10 +136,16 @@ impl<T: prost::Message + Default + 'static> ConnectionHandler for Handler<T> {
     error: ConnectionHandlerUpgrErr<<Self::OutboundProtocol as OutboundUpgradeSend>::Error>,
   ) {
     warn!("dial upgrade error: {:?}", error);
+    self.requested = false;
   }
 
   fn connection_keep_alive(&self) -> KeepAlive {
-    self.keep_alive
+    keep_alive(
+      !self.pending_messages.is_empty() || self.requested,
+      self.connection.is_some(),
+      self.idle_deadline,
+      Instant::now(),
+    )
   }
 
   fn poll(
@@ -129,30 +160,12 @@ impl<T: prost::Message + Default + 'static> ConnectionHandler for Handler<T> {
       });
     }
 
-    let messages = if let Some(out) = self.connection.as_mut() {
-      let mut count = 0;
-      while let Some(message) = self.pending_messages.pop_front() {
-        if let Poll::Ready(state) = out.poll_ready_unpin(cx) {
-          match state {
-            Ok(()) => {
-              trace!("sending message {:?}", message);
-              out.start_send_unpin(message).expect("TODO");
-              count += 1;
-            }
-            Err(e) => {
-              warn!("outbound is in error estate: {:?}", e);
-              return Poll::Ready(ConnectionHandlerEvent::Close(HandlerError::IOError(e)));
-            }
-          }
-        } else {
-          warn!("outbound not ready");
-          // FIXME very bad code
-          break;
-        }
-      }
-      count
-    } else {
-      0
+    let messages = match self.connection.as_mut() {
+      Some(out) => match send_queued(out, &mut self.pending_messages, cx) {
+        Ok(count) => count,
+        Err(e) => return Poll::Ready(ConnectionHandlerEvent::Close(HandlerError::IOError(e))),
+      },
+      None => 0,
     };
     if messages > 0 {
       self.update_keep_alive();
@@ -161,7 +174,10 @@ impl<T: prost::Message + Default + 'static> ConnectionHandler for Handler<T> {
     if let Some(inbound) = self.connection.as_mut() {
       match inbound.poll_next_unpin(cx) {
         Poll::Ready(None) => {
-          return Poll::Ready(ConnectionHandlerEvent::Close(HandlerError::InboundClosed));
+          trace!("substream closed by remote");
+          self.close_substream();
+          cx.waker().wake_by_ref();
+          return Poll::Pending;
         }
         Poll::Ready(Some(Ok(message))) => {
           trace!("message received: {:?}", message);
@@ -182,6 +198,155 @@ impl<T: prost::Message + Default + 'static> ConnectionHandler for Handler<T> {
         Poll::Pending => {}
       }
     }
+
+    if self.connection.is_some() {
+      let elapsed = self.idle_timer.as_mut().map(|t| t.poll_unpin(cx).is_ready()).unwrap_or(true);
+      if elapsed && self.is_idle() {
+        trace!("closing idle substream");
+        self.close_substream();
+      }
+    }
     Poll::Pending
   }
 }
+
+/// Starts sending the queued messages in order while the sink accepts them, returns how many were sent. Messages are only
+/// dequeued once the sink accepts them, when it is not ready the waker is registered by poll_ready.
+fn send_queued<T: Debug, S: Sink<T, Error = io::Error> + Unpin>(
+  out: &mut S,
+  pending_messages: &mut VecDeque<T>,
+  cx: &mut Context<'_>,
+) -> Result<usize, io::Error> {
+  let mut count = 0;
+  while !pending_messages.is_empty() {
+    match out.poll_ready_unpin(cx) {
+      Poll::Ready(Ok(())) => {
+        let message = pending_messages.pop_front().expect("queue checked not empty");
+        trace!("sending message {:?}", message);
+        if let Err(e) = out.start_send_unpin(message) {
+          warn!("error queueing message: {:?}", e);
+          return Err(e);
+        }
+        count += 1;
+      }
+      Poll::Ready(Err(e)) => {
+        warn!("outbound is in error estate: {:?}", e);
+        return Err(e);
+      }
+      Poll::Pending => {
+        trace!("outbound not ready, {} messages queued", pending_messages.len());
+        break;
+      }
+    }
+  }
+  Ok(count)
+}
+
+/// Kept while messages wait for a substream, then while the substream is open until it stays idle past the deadline.
+fn keep_alive(busy: bool, open: bool, idle_deadline: Instant, now: Instant) -> KeepAlive {
+  if busy {
+    KeepAlive::Yes
+  } else if open && now < idle_deadline {
+    KeepAlive::Until(idle_deadline)
+  } else {
+    KeepAlive::No
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::proto::p2p::ProtocolMessage;
+  use futures::task::ArcWake;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Arc;
+
+  #[test]
+  fn idle_substream_not_kept_alive() {
+    let now = Instant::now();
+    let deadline = now + Duration::from_secs(1);
+
+    assert_eq!(keep_alive(false, true, deadline, now), KeepAlive::Until(deadline));
+    assert_eq!(keep_alive(false, true, deadline, deadline), KeepAlive::No);
+    assert_eq!(keep_alive(true, true, deadline, deadline), KeepAlive::Yes);
+    assert_eq!(keep_alive(false, false, deadline, now), KeepAlive::No);
+  }
+
+  #[derive(Default)]
+  struct CountWaker(AtomicUsize);
+
+  impl ArcWake for CountWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+      arc_self.0.fetch_add(1, Ordering::SeqCst);
+    }
+  }
+
+  /// Sink that is not ready on every other poll, waking the task right away.
+  #[derive(Default)]
+  struct FlakySink {
+    ready: bool,
+    sent: Vec<u64>,
+  }
+
+  impl Sink<u64> for FlakySink {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+      self.ready = !self.ready;
+      if self.ready {
+        Poll::Ready(Ok(()))
+      } else {
+        cx.waker().wake_by_ref();
+        Poll::Pending
+      }
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: u64) -> Result<(), io::Error> {
+      self.sent.push(item);
+      Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+      Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+      Poll::Ready(Ok(()))
+    }
+  }
+
+  #[test]
+  fn queued_messages_sent_in_order_once_ready() {
+    let mut sink = FlakySink::default();
+    let mut queue = (1..=3).collect::<VecDeque<u64>>();
+    let wakes = Arc::new(CountWaker::default());
+    let waker = futures::task::waker(wakes.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let mut sent = 0;
+    while !queue.is_empty() {
+      sent += send_queued(&mut sink, &mut queue, &mut cx).unwrap();
+    }
+
+    assert_eq!(sent, 3);
+    assert_eq!(sink.sent, vec![1, 2, 3]);
+    // Woken each time the sink was not ready, so the handler is polled again
+    assert_eq!(wakes.0.load(Ordering::SeqCst), 2);
+  }
+
+  #[tokio::test]
+  async fn queued_message_negotiates_a_substream() {
+    let mut handler = Handler::<ProtocolMessage>::new(b"/p2pim/test").with_idle_timeout(Duration::from_millis(10));
+    assert_eq!(handler.connection_keep_alive(), KeepAlive::No);
+
+    handler.inject_event(ProtocolMessage::default());
+
+    assert_eq!(handler.connection_keep_alive(), KeepAlive::Yes);
+    let waker = futures::task::noop_waker();
+    let event = handler.poll(&mut Context::from_waker(&waker));
+    assert!(matches!(
+      event,
+      Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest { .. })
+    ));
+  }
+}
@@ -5,17 +5,20 @@ use typed_arena::Arena;
 pub mod cmd;
 
 fn main() -> Result<(), Box<dyn Error>> {
-  env_logger::init();
-
   let mut buf = Arena::new();
 
-  let matches = cli(&mut buf).get_matches();
+  let mut cli = cli(&mut buf);
+  let matches = cli.get_matches_mut();
+  cmd::init_logger(&matches)?;
+
   let result = match matches.subcommand() {
     Some(("approve", m)) => cmd::approve::run(m),
+    Some((cmd::completions::CMD_NAME, m)) => cmd::completions::run(m, &mut cli),
     Some((cmd::daemon::CMD_NAME, m)) => cmd::daemon::run(m),
     Some(("deposit", m)) => cmd::deposit::run(m),
     Some(("info", m)) => cmd::info::run(m),
     Some(("swarm", m)) => cmd::swarm::run(m),
+    Some((cmd::version::CMD_NAME, m)) => cmd::version::run(m),
     Some((cmd::withdraw::CMD_NAME, m)) => cmd::withdraw::run(m),
     Some((cmd::data::DATA_CMD, m)) => cmd::data::run(m),
     _ => unreachable!("this should not happen if we have all the cases covered"),
@@ -28,11 +31,34 @@ fn cli(buf: &mut Arena<String>) -> Command {
     .about("P2pim decentralized storage")
     .subcommand_required(true)
     .arg_required_else_help(true)
+    .arg(cmd::arg_log_level())
+    .arg(cmd::arg_log_target())
     .subcommand(cmd::approve::command())
+    .subcommand(cmd::completions::command())
     .subcommand(cmd::daemon::command(buf))
     .subcommand(cmd::deposit::command())
     .subcommand(cmd::info::command())
     .subcommand(cmd::data::command())
     .subcommand(cmd::swarm::command())
+    .subcommand(cmd::version::command())
     .subcommand(cmd::withdraw::command())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use clap_complete::Shell;
+
+  #[test]
+  fn bash_completions_generated() {
+    let mut buf = Arena::new();
+    let mut out = Vec::new();
+
+    cmd::completions::generate(Shell::Bash, &mut cli(&mut buf), &mut out);
+
+    let script = String::from_utf8(out).unwrap();
+    assert!(script.contains("daemon"));
+    assert!(script.contains("retrieve"));
+    assert!(script.contains("peers"));
+  }
+}
@@ -1,40 +1,57 @@
-use crate::types::{Balance, DataParameters, LeaseTerms, Signature, StorageBalance, TokenMetadata, WalletBalance};
-use crate::utils::ethereum::IntoAddress;
-use ethcontract::errors::{EventError, MethodError};
+use crate::types::{
+  Balance, ChainConfirmation, ChallengeProof, DataParameters, LeaseState, LeaseTerms, Signature, StorageBalance,
+  TokenMetadata, WalletBalance,
+};
+use crate::utils::cache::LruCache;
+use crate::utils::ethereum::TryIntoAddress;
+use ethcontract::dyns::DynMethodBuilder;
+use ethcontract::errors::{DeployError, EventError, ExecutionError, MethodError};
 use ethcontract::transaction::TransactionResult;
 use ethcontract::{Account, Bytes, Event, EventStatus, PrivateKey};
 use futures::stream::SelectAll;
-use futures::{select, Stream, StreamExt};
+use futures::{select, Stream, StreamExt, TryStreamExt};
 use log::{debug, error, info, trace, warn};
 use p2pim_ethereum_contracts::third::openzeppelin;
 use p2pim_ethereum_contracts::{P2pimAdjudicator, P2pimMasterRecord};
 use secp256k1::Secp256k1;
+use secrecy::{ExposeSecret, Secret};
 use std::borrow::Borrow;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt::{Debug, Display, Formatter};
 use std::pin::Pin;
-use std::time;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tonic::async_trait;
 use url::Url;
 use web3::ethabi::{Token, Topic};
 use web3::signing::{Key, SecretKeyRef};
-use web3::transports::{Either, Ipc, WebSocket};
-use web3::types::{Address, Block, BlockId, H256, U256};
+use web3::transports::{Either, Http, Ipc, WebSocket};
+use web3::types::{Address, Block, BlockId, BlockNumber, H256, U256};
 
-#[derive(Clone)]
 pub struct OnchainParams {
   pub eth_url: Url,
-  // TODO Review this as could be dangerous to keep this in memory
-  pub private_key: [u8; 32],
+  pub private_key: Secret<[u8; 32]>,
   pub master_address: Option<Address>,
+  /// Interval between requests of polled event streams and, over http, of new blocks. Lower values reduce the latency
+  /// to notice a sealed lease at the cost of more requests to the JSON-RPC node.
+  pub poll_interval: Duration,
+  /// How long a balance is served from the cache. Sealed leases seen in the adjudicator events and transactions sent by
+  /// the node drop the cached balance of their token before that. Balances are always read from the chain when none.
+  pub balance_max_age: Option<Duration>,
 }
 
 #[derive(Debug)]
 pub enum Error {
   TokenNotDeployed(Address),
+  MasterRecordNotDeployed { network_id: String },
+  InvalidPrivateKey,
+  UnexpectedResponse(String),
+  ChallengeDisabled,
+  InvalidMerkleRoot,
+  SigningError(web3::signing::SigningError),
   MethodError(MethodError),
+  EventError(EventError),
   Web3Error(web3::error::Error),
 }
 
@@ -44,7 +61,18 @@ impl Display for Error {
   fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
     match self {
       Error::TokenNotDeployed(_) => f.write_str("token not deployed"),
+      Error::MasterRecordNotDeployed { network_id } => write!(
+        f,
+        "master record contract not known for network id {}, pass its address with --eth.master",
+        network_id
+      ),
+      Error::InvalidPrivateKey => f.write_str("invalid private key"),
+      Error::UnexpectedResponse(msg) => write!(f, "unexpected response: {}", msg),
+      Error::ChallengeDisabled => f.write_str("submitting challenges is disabled, build with the onchain-challenge feature"),
+      Error::InvalidMerkleRoot => f.write_str("merkle root should be 32 bytes"),
+      Error::SigningError(err) => std::fmt::Display::fmt(err, f),
       Error::MethodError(err) => std::fmt::Display::fmt(err, f),
+      Error::EventError(err) => std::fmt::Display::fmt(err, f),
       Error::Web3Error(err) => std::fmt::Display::fmt(err, f),
     }
   }
@@ -54,7 +82,14 @@ impl std::error::Error for Error {
   fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
     match self {
       Error::TokenNotDeployed(_) => None,
+      Error::MasterRecordNotDeployed { .. } => None,
+      Error::InvalidPrivateKey => None,
+      Error::UnexpectedResponse(_) => None,
+      Error::ChallengeDisabled => None,
+      Error::InvalidMerkleRoot => None,
+      Error::SigningError(err) => Some(err),
       Error::MethodError(err) => Some(err),
+      Error::EventError(err) => Some(err),
       Error::Web3Error(err) => Some(err),
     }
   }
@@ -66,6 +101,18 @@ impl From<MethodError> for Error {
   }
 }
 
+impl From<EventError> for Error {
+  fn from(value: EventError) -> Self {
+    Error::EventError(value)
+  }
+}
+
+impl From<web3::signing::SigningError> for Error {
+  fn from(value: web3::signing::SigningError) -> Self {
+    Error::SigningError(value)
+  }
+}
+
 impl From<web3::error::Error> for Error {
   fn from(value: web3::Error) -> Self {
     Error::Web3Error(value)
@@ -83,11 +130,24 @@ pub trait Service: Clone + Send + Sync + 'static {
     > + Unpin;
 
   async fn block(&self, block_id: BlockId) -> Result<Option<Block<H256>>>;
+  async fn block_number(&self) -> Result<u64>;
+  async fn block_timestamp(&self, block_hash: H256) -> Result<Option<SystemTime>>;
 
   async fn listen_adjudicator_events(&self) -> Self::StreamType;
 
+  /// Historical lease events of our own address between both blocks, inclusive.
+  async fn backfill_events(
+    &self,
+    from_block: u64,
+    to_block: u64,
+  ) -> Result<Vec<Event<EventStatus<p2pim_ethereum_contracts::adjudicator::event_data::LeaseSealed>>>>;
+
   fn account_wallet(&self) -> web3::types::Address;
   fn account_storage(&self) -> web3::types::Address;
+  fn master_record_address(&self) -> Address;
+
+  /// Id of the network reported by the JSON-RPC node.
+  fn network_id(&self) -> &str;
 
   async fn seal_lease(
     &self,
@@ -98,13 +158,26 @@ pub trait Service: Clone + Send + Sync + 'static {
     lessee_signature: Signature,
   ) -> Result<TransactionResult>;
 
+  /// Simulates `seal_lease` without sending it, returns the gas estimate or the revert reason.
+  async fn estimate_seal_gas(
+    &self,
+    lessee_address: Address,
+    nonce: u64,
+    terms: &LeaseTerms,
+    data_parameters: &DataParameters,
+    lessee_signature: &Signature,
+  ) -> Result<core::result::Result<U256, String>>;
+
   async fn sign_proposal(
     &self,
     lessor_address: &Address,
     nonce: u64,
     terms: &LeaseTerms,
     data_parameters: &DataParameters,
-  ) -> Signature;
+  ) -> Result<Signature>;
+
+  /// Signs `message` as an ethereum signed message with the storage account key, see `recover_message_signer`.
+  async fn sign_message(&self, message: &[u8]) -> Result<Signature>;
 
   async fn wait_for_seal_lease(
     &self,
@@ -114,10 +187,87 @@ pub trait Service: Clone + Send + Sync + 'static {
     until: SystemTime,
   ) -> Result<Option<ethcontract::Event<EventStatus<p2pim_ethereum_contracts::adjudicator::event_data::LeaseSealed>>>>;
 
+  async fn find_lease_sealed(
+    &self,
+    token_address: &Address,
+    lessor_address: Address,
+    lessee_address: Address,
+    nonce: u64,
+  ) -> Result<Option<ethcontract::Event<p2pim_ethereum_contracts::adjudicator::event_data::LeaseSealed>>>;
+
+  /// Checks that the transaction is mined and sealed the lease with the lessor, as a lessee.
+  async fn is_lease_sealed_in(
+    &self,
+    token_address: &Address,
+    lessor_address: Address,
+    nonce: u64,
+    transaction_hash: H256,
+  ) -> Result<bool>;
+
+  /// Whether the adjudicator penalized the lessor of the lease, `None` when the transaction that sealed it is not mined.
+  /// The events are read from the block of that transaction on.
+  async fn is_lease_penalized(
+    &self,
+    token_address: &Address,
+    lessor_address: Address,
+    lessee_address: Address,
+    nonce: u64,
+    seal_transaction_hash: H256,
+  ) -> Result<Option<bool>>;
+
+  /// State of the lease sealed by `chain_confirmation`, not found when none, the adjudicator has no getter for it. The
+  /// lease is penalized once a `LeasePenalized` event follows the seal, otherwise active until `lease_duration` after it.
+  async fn lease_state(
+    &self,
+    token_address: &Address,
+    lessor_address: Address,
+    lessee_address: Address,
+    nonce: u64,
+    lease_duration: Duration,
+    chain_confirmation: Option<&ChainConfirmation>,
+  ) -> Result<LeaseState> {
+    let confirmation = match chain_confirmation {
+      Some(confirmation) => confirmation,
+      None => return Ok(LeaseState::NotFound),
+    };
+    let penalized = self
+      .is_lease_penalized(
+        token_address,
+        lessor_address,
+        lessee_address,
+        nonce,
+        confirmation.transaction_hash,
+      )
+      .await?;
+    match penalized {
+      // The seal was dropped by a reorg, the lease is pending until reconciled
+      None => Ok(LeaseState::NotFound),
+      Some(true) => Ok(LeaseState::Penalized),
+      Some(false) if confirmation.timestamp + lease_duration > SystemTime::now() => Ok(LeaseState::Active),
+      Some(false) => Ok(LeaseState::Closed),
+    }
+  }
+
+  /// Submits a failed challenge to the adjudicator, `proof` is `None` when the lessor did not answer. Fails with
+  /// `Error::ChallengeDisabled` unless built with the `onchain-challenge` feature.
+  async fn submit_challenge(
+    &self,
+    token_address: &Address,
+    lessor_address: Address,
+    nonce: u64,
+    block_number: u32,
+    proof: Option<ChallengeProof>,
+  ) -> Result<TransactionResult>;
+
   async fn deployed_tokens(&self) -> Vec<(Address, Option<TokenMetadata>)>;
-  async fn balance(&self, token_address: &Address) -> Result<Balance>;
+  /// Balance of the token, from the cache unless `refresh` is set or the cached one is too old.
+  async fn balance(&self, token_address: &Address, refresh: bool) -> Result<Balance>;
+  async fn native_balance(&self, address: Address) -> Result<U256>;
+  async fn pending_transactions(&self, address: Address) -> Result<U256>;
+  async fn gas_price(&self) -> Result<U256>;
 
-  async fn withdraw(&self, token_address: &Address, amount: U256) -> Result<TransactionResult>;
+  /// Withdraws to `destination`, or to the wallet account when none.
+  async fn withdraw(&self, token_address: &Address, amount: U256, destination: Option<Address>) -> Result<TransactionResult>;
   async fn deposit(&self, token_address: &Address, amount: U256) -> Result<TransactionResult>;
 
   async fn approve(&self, token_address: &Address) -> Result<TransactionResult>;
@@ -126,24 +276,50 @@ pub trait Service: Clone + Send + Sync + 'static {
 #[derive(Clone)]
 struct Implementation {
   account_wallet: Address,
+  // Set when the node has no accounts, the wallet is then the storage account and transactions are signed locally
+  offline_wallet: bool,
   account_storage: Address,
-  params: OnchainParams,
   private_key: ethcontract::PrivateKey,
-  web3: web3::Web3<Either<WebSocket, Ipc>>,
+  web3: web3::Web3<Either<DuplexTransport, Http>>,
+  // Only present when the transport supports subscriptions, otherwise we fall back to polling
+  pubsub: Option<web3::Web3<DuplexTransport>>,
+  poll_interval: Duration,
+  master_record_address: Address,
+  network_id: String,
   deployments: HashMap<Address, (openzeppelin::IERC20Metadata, P2pimAdjudicator)>,
+  block_timestamps: Arc<Mutex<LruCache<H256, SystemTime>>>,
+  balance_max_age: Option<Duration>,
+  balances: BalanceCache,
 }
 
+type DuplexTransport = Either<WebSocket, Ipc>;
+
+// When each balance was read, or dropped without a balance read after it
+type BalanceCache = Arc<Mutex<HashMap<Address, (Instant, Option<Balance>)>>>;
+
+const BLOCK_TIMESTAMPS_CACHE_SIZE: usize = 256;
+
 pub async fn new_service(params: OnchainParams) -> core::result::Result<impl Service, Box<dyn std::error::Error>> {
   info!("initializing onchain subsystem");
 
   debug!("creating transport using {}", params.eth_url);
   let transport = match params.eth_url.scheme() {
-    "file" => Ok(Either::Right(web3::transports::ipc::Ipc::new(params.eth_url.path()).await?)),
-    "ws" | "wss" => Ok(Either::Left(
+    "file" => Ok(Either::Left(Either::Right(
+      web3::transports::ipc::Ipc::new(params.eth_url.path()).await?,
+    ))),
+    "ws" | "wss" => Ok(Either::Left(Either::Left(
       web3::transports::ws::WebSocket::new(params.eth_url.as_str()).await?,
-    )),
+    ))),
+    "http" | "https" => Ok(Either::Right(web3::transports::Http::new(params.eth_url.as_str())?)),
     unsupported => Err(format!("unsupported schema: {}", unsupported)),
   }?;
+  let pubsub = match &transport {
+    Either::Left(duplex) => Some(web3::Web3::new(duplex.clone())),
+    Either::Right(_) => {
+      info!("subscriptions not supported over http, polling for new blocks");
+      None
+    }
+  };
 
   debug!("creating web3");
   let web3 = web3::Web3::new(transport);
@@ -155,15 +331,15 @@ pub async fn new_service(params: OnchainParams) -> core::result::Result<impl Ser
   let instance = if let Some(addr) = params.master_address {
     Ok(P2pimMasterRecord::at(&web3, addr))
   } else {
-    P2pimMasterRecord::deployed(&web3).await
+    P2pimMasterRecord::deployed(&web3).await.map_err(|e| match e {
+      DeployError::NotFound(_) => Box::new(Error::MasterRecordNotDeployed {
+        network_id: network_id.clone(),
+      }) as Box<dyn std::error::Error>,
+      e => e.into(),
+    })
   }?;
   debug!("using master record contract on address {}", instance.address());
 
-  debug!("reading accounts");
-  let accounts = web3.eth().accounts().await?;
-  let account_wallet = accounts.get(0).map(Clone::clone).ok_or("no accounts configured")?;
-  debug!("using account for wallet {:?}", account_wallet);
-
   // TODO react to new deployments
   debug!("reading master record deployments");
   let deployments = instance
@@ -184,23 +360,79 @@ pub async fn new_service(params: OnchainParams) -> core::result::Result<impl Ser
     .collect();
   debug!("found deployments {:?}", deployments);
 
+  // The only copy of the secret kept after this point, the raw bytes are zeroed when params are dropped. The cause is
+  // not kept in the error, so nothing about the key ends up in the logs
+  let private = PrivateKey::from_slice(params.private_key.expose_secret()).map_err(|_| Error::InvalidPrivateKey)?;
   let context = Secp256k1::new();
-  let secret = secp256k1::SecretKey::from_slice(params.private_key.as_slice()).expect("this will never happen");
-  let public_key = secp256k1::PublicKey::from_secret_key(&context, &secret);
-  let account_storage = public_key.borrow().into_address();
-  let private = PrivateKey::from_raw(params.private_key).expect("TODO: this should not happen");
+  let public_key = secp256k1::PublicKey::from_secret_key(&context, &private);
+  let account_storage = public_key.borrow().try_into_address()?;
+
+  debug!("reading accounts");
+  let accounts = web3.eth().accounts().await?;
+  let (account_wallet, offline_wallet) = match accounts.get(0) {
+    Some(account) => (*account, false),
+    None => {
+      warn!("no accounts configured in the node, using the storage account as wallet");
+      (account_storage, true)
+    }
+  };
+  debug!("using account for wallet {:?}", account_wallet);
 
   Ok(Implementation {
     account_wallet,
+    offline_wallet,
     account_storage,
-    params,
     private_key: private,
     web3,
+    pubsub,
+    poll_interval: params.poll_interval,
+    master_record_address: instance.address(),
+    network_id,
     deployments,
+    block_timestamps: Arc::new(Mutex::new(LruCache::new(BLOCK_TIMESTAMPS_CACHE_SIZE))),
+    balance_max_age: params.balance_max_age,
+    balances: Arc::new(Mutex::new(HashMap::new())),
   })
 }
 
 impl Implementation {
+  /// Account sending the wallet transactions, the node signs them unless it has no accounts of its own.
+  fn wallet_account(&self) -> Account {
+    if self.offline_wallet {
+      Account::Offline(self.private_key.clone(), None)
+    } else {
+      Account::Local(self.account_wallet, None)
+    }
+  }
+
+  fn invalidate_balance(&self, token_address: &Address) {
+    invalidate_balance(&self.balances, *token_address);
+  }
+
+  async fn read_balance(&self, token_address: &Address) -> Result<Balance> {
+    let (token, adjudicator) = self.deployment(token_address)?;
+    let (available_p2pim, locked_rents, locked_lets) = adjudicator.balance(self.account_storage).call().await?;
+
+    let available_account = token.balance_of(self.account_wallet).call().await?;
+    let allowance_account = token.allowance(self.account_wallet, adjudicator.address()).call().await?;
+
+    let token_metadata = read_metadata(&token).await;
+
+    Ok(Balance {
+      adjudicator_address: adjudicator.address(),
+      token_metadata,
+      storage_balance: StorageBalance {
+        available: available_p2pim,
+        locked_rents,
+        locked_lets,
+      },
+      wallet_balance: WalletBalance {
+        available: available_account,
+        allowance: allowance_account,
+      },
+    })
+  }
+
   fn deployment(&self, address: &Address) -> Result<(openzeppelin::IERC20Metadata, P2pimAdjudicator)> {
     self
       .deployments
@@ -209,52 +441,136 @@ impl Implementation {
       .ok_or_else(|| Error::TokenNotDeployed(*address))
   }
 
-  async fn sign(
+  /// `seal_lease` of the adjudicator signed as lessor, ready to be sent or simulated.
+  async fn seal_lease_method(
     &self,
-    lessee_address: &Address,
-    lessor_address: &Address,
+    lessee_address: Address,
     nonce: u64,
     terms: &LeaseTerms,
     data_parameters: &DataParameters,
-  ) -> Signature {
-    let message = [
-      Token::Address(terms.token_address),
-      Token::Address(*lessee_address),
-      Token::Address(*lessor_address),
-      Token::Uint(nonce.into()),
-      Token::FixedBytes(data_parameters.merkle_root.clone()),
-      Token::Uint(data_parameters.size.into()),
-      Token::Uint(terms.price),
-      Token::Uint(terms.penalty),
-      Token::Uint(terms.lease_duration.as_secs().into()),
-      Token::Uint(
-        terms
-          .proposal_expiration
-          .duration_since(time::UNIX_EPOCH)
-          .unwrap()
-          .as_secs()
-          .into(),
-      ),
-    ];
-    let abi_encoded = web3::ethabi::encode(&message);
-    let message_hash = web3::signing::keccak256(abi_encoded.as_slice());
-    let eth_message_hash = web3::signing::hash_message(message_hash);
-
-    trace!(
-      "message {}, hash to sign {}, lesse: {}, lessor: {}",
-      hex::encode(abi_encoded.as_slice()),
-      hex::encode(message_hash),
+    lessee_signature: &Signature,
+  ) -> Result<DynMethodBuilder<()>> {
+    let lessor_address = self.account_storage();
+
+    let lessor_signature = self.sign(&lessee_address, &lessor_address, nonce, terms, data_parameters)?;
+
+    let merkle_root: [u8; 32] = data_parameters
+      .merkle_root
+      .clone()
+      .try_into()
+      .map_err(|_| Error::InvalidMerkleRoot)?;
+
+    let (_, adjudicator) = self.deployment(&terms.token_address)?;
+    let lease_deal = (
       lessee_address,
-      lessor_address
+      lessor_address,
+      nonce,
+      Bytes(merkle_root),
+      data_parameters.size as u64,
+      terms.price,
+      terms.penalty,
+      terms.lease_duration.as_secs().into(),
+      expiration_secs(terms).into(),
     );
-    let secret = secp256k1::SecretKey::from_slice(self.params.private_key.as_slice()).expect("this will never happen");
-
-    Signature::from(
-      SecretKeyRef::new(&secret)
-        .sign(eth_message_hash.as_bytes(), None)
-        .expect("Why can fail?"),
+    Ok(
+      adjudicator
+        .seal_lease(
+          lease_deal,
+          Bytes(lessee_signature.serialize()),
+          Bytes(lessor_signature.serialize()),
+        )
+        .from(self.wallet_account()),
     )
   }
+
+  fn sign(
+    &self,
+    lessee_address: &Address,
+    lessor_address: &Address,
+    nonce: u64,
+    terms: &LeaseTerms,
+    data_parameters: &DataParameters,
+  ) -> Result<Signature> {
+    let eth_message_hash = lease_message_hash(lessee_address, lessor_address, nonce, terms, data_parameters);
+    sign_hash(&self.private_key, eth_message_hash)
+  }
+}
+
+/// Proposal expiration as the adjudicator takes it, one before the unix epoch is expired as any other past one.
+fn expiration_secs(terms: &LeaseTerms) -> u64 {
+  terms
+    .proposal_expiration
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or(0)
+}
+
+fn sign_hash(private_key: &PrivateKey, hash: H256) -> Result<Signature> {
+  Ok(Signature::from(SecretKeyRef::new(private_key).sign(hash.as_bytes(), None)?))
+}
+
+/// Hash signed by both parties of a lease, the abi encoding of the deal as the adjudicator contract prefixed as an
+/// ethereum signed message.
+fn lease_message_hash(
+  lessee_address: &Address,
+  lessor_address: &Address,
+  nonce: u64,
+  terms: &LeaseTerms,
+  data_parameters: &DataParameters,
+) -> H256 {
+  let message = [
+    Token::Address(terms.token_address),
+    Token::Address(*lessee_address),
+    Token::Address(*lessor_address),
+    Token::Uint(nonce.into()),
+    Token::FixedBytes(data_parameters.merkle_root.clone()),
+    Token::Uint(data_parameters.size.into()),
+    Token::Uint(terms.price),
+    Token::Uint(terms.penalty),
+    Token::Uint(terms.lease_duration.as_secs().into()),
+    Token::Uint(expiration_secs(terms).into()),
+  ];
+  let abi_encoded = web3::ethabi::encode(&message);
+  let message_hash = web3::signing::keccak256(abi_encoded.as_slice());
+  let eth_message_hash = web3::signing::hash_message(message_hash);
+
+  trace!(
+    "message {}, hash to sign {}, lesse: {}, lessor: {}",
+    hex::encode(abi_encoded.as_slice()),
+    hex::encode(message_hash),
+    lessee_address,
+    lessor_address
+  );
+  eth_message_hash
+}
+
+/// Address that signed the lease, to compare with the lessee and lessor without going to the contract.
+pub fn recover_lease_signer(
+  lessee_address: &Address,
+  lessor_address: &Address,
+  nonce: u64,
+  terms: &LeaseTerms,
+  data_parameters: &DataParameters,
+  signature: &Signature,
+) -> core::result::Result<Address, web3::signing::RecoveryError> {
+  let eth_message_hash = lease_message_hash(lessee_address, lessor_address, nonce, terms, data_parameters);
+  recover_hash_signer(eth_message_hash, signature)
+}
+
+/// Address that signed the message with `Service::sign_message`.
+pub fn recover_message_signer(
+  message: &[u8],
+  signature: &Signature,
+) -> core::result::Result<Address, web3::signing::RecoveryError> {
+  recover_hash_signer(web3::signing::hash_message(message), signature)
+}
+
+fn recover_hash_signer(hash: H256, signature: &Signature) -> core::result::Result<Address, web3::signing::RecoveryError> {
+  let raw = signature.serialize();
+  // Legacy signatures carry the recovery id offset by 27
+  let v = raw[64] as i32;
+  let recovery_id = if v >= 27 { v - 27 } else { v };
+  web3::signing::recover(hash.as_bytes(), &raw[0..64], recovery_id)
 }
 
 #[async_trait]
@@ -276,6 +592,25 @@ impl Service for Implementation {
     Ok(self.web3.eth().block(block_id).await?)
   }
 
+  async fn block_number(&self) -> Result<u64> {
+    Ok(self.web3.eth().block_number().await?.as_u64())
+  }
+
+  async fn block_timestamp(&self, block_hash: H256) -> Result<Option<SystemTime>> {
+    let cached = self.block_timestamps.lock().unwrap().get(&block_hash).cloned();
+    if cached.is_some() {
+      return Ok(cached);
+    }
+    let maybe_timestamp = self
+      .block(BlockId::Hash(block_hash))
+      .await?
+      .map(|block| UNIX_EPOCH + Duration::from_secs(block.timestamp.as_u64()));
+    if let Some(timestamp) = maybe_timestamp {
+      self.block_timestamps.lock().unwrap().insert(block_hash, timestamp);
+    }
+    Ok(maybe_timestamp)
+  }
+
   async fn listen_adjudicator_events(&self) -> Self::StreamType {
     let self_address = self.account_storage();
 
@@ -283,6 +618,8 @@ impl Service for Implementation {
       adjudicator: &P2pimAdjudicator,
       lessor_address: Option<Address>,
       lessee_address: Option<Address>,
+      poll_interval: Duration,
+      invalidate_balance: impl Fn() + 'static,
     ) -> Pin<
       Box<
         dyn Stream<
@@ -300,14 +637,24 @@ impl Service for Implementation {
           .lease_sealed()
           .lessor(lessor_address.map(Topic::This).unwrap_or(Topic::Any))
           .lessee(lessee_address.map(Topic::This).unwrap_or(Topic::Any))
-          .stream(),
+          .poll_interval(poll_interval)
+          .stream()
+          .inspect(move |event| {
+            if event.is_ok() {
+              invalidate_balance();
+            }
+          }),
       )
     }
 
-    let streams = self.deployments.values().flat_map(|(_, adjudicator)| {
+    let streams = self.deployments.iter().flat_map(|(token_address, (_, adjudicator))| {
+      let invalidate = || {
+        let (balances, token_address) = (self.balances.clone(), *token_address);
+        move || invalidate_balance(&balances, token_address)
+      };
       vec![
-        event_stream(adjudicator, Some(self_address), None),
-        event_stream(adjudicator, None, Some(self_address)),
+        event_stream(adjudicator, Some(self_address), None, self.poll_interval, invalidate()),
+        event_stream(adjudicator, None, Some(self_address), self.poll_interval, invalidate()),
       ]
     });
 
@@ -318,10 +665,56 @@ impl Service for Implementation {
     self.account_wallet
   }
 
+  async fn backfill_events(
+    &self,
+    from_block: u64,
+    to_block: u64,
+  ) -> Result<Vec<Event<EventStatus<p2pim_ethereum_contracts::adjudicator::event_data::LeaseSealed>>>> {
+    let self_address = self.account_storage();
+    let mut result = Vec::new();
+    for (_, adjudicator) in self.deployments.values() {
+      let as_lessor = adjudicator
+        .events()
+        .lease_sealed()
+        .from_block(BlockNumber::Number(from_block.into()))
+        .to_block(BlockNumber::Number(to_block.into()))
+        .lessor(Topic::This(self_address))
+        .query()
+        .await?;
+      let as_lessee = adjudicator
+        .events()
+        .lease_sealed()
+        .from_block(BlockNumber::Number(from_block.into()))
+        .to_block(BlockNumber::Number(to_block.into()))
+        .lessee(Topic::This(self_address))
+        .query()
+        .await?;
+      result.extend(as_lessor.into_iter().chain(as_lessee).map(|e| Event {
+        data: EventStatus::Added(e.data),
+        meta: e.meta,
+      }));
+    }
+    debug!(
+      "backfilled events from_block={} to_block={} events={}",
+      from_block,
+      to_block,
+      result.len()
+    );
+    Ok(result)
+  }
+
   fn account_storage(&self) -> Address {
     self.account_storage
   }
 
+  fn master_record_address(&self) -> Address {
+    self.master_record_address
+  }
+
+  fn network_id(&self) -> &str {
+    self.network_id.as_str()
+  }
+
   async fn seal_lease(
     &self,
     lessee_address: Address,
@@ -330,44 +723,35 @@ impl Service for Implementation {
     data_parameters: DataParameters,
     lessee_signature: Signature,
   ) -> Result<TransactionResult> {
-    let lessor_address = self.account_storage();
-
-    let lessor_signature = self
-      .sign(&lessee_address, &lessor_address, nonce, &terms, &data_parameters)
+    let result = self
+      .seal_lease_method(lessee_address, nonce, &terms, &data_parameters, &lessee_signature)
+      .await?
+      .send()
       .await;
+    self.invalidate_balance(&terms.token_address);
+    Ok(result?)
+  }
 
-    let merkle_root: [u8; 32] = data_parameters
-      .merkle_root
-      .clone()
-      .try_into()
-      .expect("TODO this should never happen");
-
-    let (_, adjudicator) = self.deployment(&terms.token_address)?;
-    let lease_deal = (
-      lessee_address,
-      lessor_address,
-      nonce,
-      Bytes(merkle_root),
-      data_parameters.size as u64,
-      terms.price,
-      terms.penalty,
-      terms.lease_duration.as_secs().into(),
-      terms
-        .proposal_expiration
-        .duration_since(UNIX_EPOCH)
-        .expect("TODO: this should not happen")
-        .as_secs()
-        .into(),
-    );
-    let result = adjudicator
-      .seal_lease(
-        lease_deal,
-        Bytes(lessee_signature.serialize()),
-        Bytes(lessor_signature.serialize()),
-      )
-      .send()
-      .await?;
-    Ok(result)
+  async fn estimate_seal_gas(
+    &self,
+    lessee_address: Address,
+    nonce: u64,
+    terms: &LeaseTerms,
+    data_parameters: &DataParameters,
+    lessee_signature: &Signature,
+  ) -> Result<core::result::Result<U256, String>> {
+    let estimate = self
+      .seal_lease_method(lessee_address, nonce, terms, data_parameters, lessee_signature)
+      .await?
+      .tx
+      .estimate_gas()
+      .await;
+    match estimate {
+      Ok(gas) => Ok(Ok(gas)),
+      Err(ExecutionError::Revert(reason)) => Ok(Err(reason.unwrap_or_else(|| "reverted without reason".to_string()))),
+      Err(ExecutionError::InvalidOpcode) => Ok(Err("invalid opcode".to_string())),
+      Err(e) => Err(MethodError::from_parts("sealLease".to_string(), e).into()),
+    }
   }
 
   async fn sign_proposal(
@@ -376,9 +760,13 @@ impl Service for Implementation {
     nonce: u64,
     terms: &LeaseTerms,
     data_parameters: &DataParameters,
-  ) -> Signature {
+  ) -> Result<Signature> {
     let lessee_address = &self.account_storage();
-    self.sign(lessee_address, lessor_address, nonce, terms, data_parameters).await
+    self.sign(lessee_address, lessor_address, nonce, terms, data_parameters)
+  }
+
+  async fn sign_message(&self, message: &[u8]) -> Result<Signature> {
+    sign_hash(&self.private_key, web3::signing::hash_message(message))
   }
 
   async fn wait_for_seal_lease(
@@ -401,48 +789,165 @@ impl Service for Implementation {
         ))
         .lessor(Topic::This(lessor_address))
         .lessee(Topic::This(lessee_address))
-        .poll_interval(Duration::from_secs(1))
-        .stream()
-        .fuse(),
+        .poll_interval(self.poll_interval)
+        .stream(),
     );
 
-    let mut new_heads = self.web3.eth_subscribe().subscribe_new_heads().await?.fuse();
-
-    // TODO Refactor
-    let result = {
-      let mut r = None;
-      loop {
-        select! {
-          ev = event_stream.next() => match ev {
-            Some(Ok(e)) => {
-              if e.inner_data().nonce == nonce {
-                r = Some(Some(e))
-              }
-            },
-            Some(Err(e)) => warn!("TODO: error in event stream: {}", e),
-            None => unreachable!("TODO: the stream should never be closed"),
-          },
-          head = new_heads.next() => match head {
-            Some(Ok(h)) => if UNIX_EPOCH + Duration::from_secs(h.timestamp.as_u64()) > until {
-              r = Some(None);
-            },
-            Some(Err(e)) => warn!("TODO: error in heads stream: {}", e),
-            None => unreachable!("TODO: the stream should never be closed"),
-          }
-        }
-        if r.is_some() {
-          break;
-        }
+    let result = match &self.pubsub {
+      Some(pubsub) => {
+        let mut new_heads = pubsub.eth_subscribe().subscribe_new_heads().await?;
+        let heads = (&mut new_heads).map_ok(|h| UNIX_EPOCH + Duration::from_secs(h.timestamp.as_u64()));
+        let result = wait_for_nonce(&mut event_stream, heads, nonce, until).await;
+        match new_heads.unsubscribe().await {
+          Ok(true) => trace!("unsubscribed from heads"),
+          Ok(false) => warn!("unsubscribed returns false"),
+          Err(e) => error!("error while unsubscribe from heads: {}", e),
+        };
+        result
+      }
+      None => {
+        let eth = self.web3.eth();
+        let heads = self
+          .web3
+          .eth_filter()
+          .create_blocks_filter()
+          .await?
+          .stream(self.poll_interval)
+          .and_then(move |hash| {
+            let eth = eth.clone();
+            async move { eth.block(BlockId::Hash(hash)).await }
+          })
+          .try_filter_map(|block| futures::future::ok(block.map(|b| UNIX_EPOCH + Duration::from_secs(b.timestamp.as_u64()))));
+        wait_for_nonce(&mut event_stream, Box::pin(heads), nonce, until).await
       }
-      r.unwrap()
     };
+    Ok(result)
+  }
+
+  async fn find_lease_sealed(
+    &self,
+    token_address: &Address,
+    lessor_address: Address,
+    lessee_address: Address,
+    nonce: u64,
+  ) -> Result<Option<ethcontract::Event<p2pim_ethereum_contracts::adjudicator::event_data::LeaseSealed>>> {
+    let (_, adjudicator) = self.deployment(token_address)?;
+    let events = adjudicator
+      .events()
+      .lease_sealed()
+      .from_block(ethcontract::BlockNumber::Earliest)
+      .lessor(Topic::This(lessor_address))
+      .lessee(Topic::This(lessee_address))
+      .query()
+      .await?;
+    Ok(events.into_iter().find(|e| e.data.nonce == nonce))
+  }
+
+  async fn is_lease_sealed_in(
+    &self,
+    token_address: &Address,
+    lessor_address: Address,
+    nonce: u64,
+    transaction_hash: H256,
+  ) -> Result<bool> {
+    let (_, adjudicator) = self.deployment(token_address)?;
+    let receipt = self.web3.eth().transaction_receipt(transaction_hash).await?;
+    let block_number = match receipt.and_then(|r| r.block_number) {
+      Some(block_number) => block_number,
+      None => return Ok(false),
+    };
+    let events = adjudicator
+      .events()
+      .lease_sealed()
+      .from_block(ethcontract::BlockNumber::Number(block_number))
+      .to_block(ethcontract::BlockNumber::Number(block_number))
+      .lessor(Topic::This(lessor_address))
+      .lessee(Topic::This(self.account_storage()))
+      .query()
+      .await?;
+    Ok(
+      events
+        .into_iter()
+        .any(|e| e.data.nonce == nonce && e.meta.as_ref().map(|m| m.transaction_hash) == Some(transaction_hash)),
+    )
+  }
 
-    match new_heads.into_inner().unsubscribe().await {
-      Ok(true) => trace!("unsubscribed from heads"),
-      Ok(false) => warn!("unsubscribed returns false"),
-      Err(e) => error!("error while unsubscribe from heads: {}", e),
+  async fn is_lease_penalized(
+    &self,
+    token_address: &Address,
+    lessor_address: Address,
+    lessee_address: Address,
+    nonce: u64,
+    seal_transaction_hash: H256,
+  ) -> Result<Option<bool>> {
+    let (_, adjudicator) = self.deployment(token_address)?;
+    let receipt = self.web3.eth().transaction_receipt(seal_transaction_hash).await?;
+    let block_number = match receipt.and_then(|r| r.block_number) {
+      Some(block_number) => block_number,
+      None => return Ok(None),
     };
-    Ok(result)
+    let events = adjudicator
+      .events()
+      .lease_penalized()
+      .from_block(ethcontract::BlockNumber::Number(block_number))
+      .lessor(Topic::This(lessor_address))
+      .lessee(Topic::This(lessee_address))
+      .query()
+      .await?;
+    Ok(Some(events.into_iter().any(|e| e.data.nonce == nonce)))
+  }
+
+  #[cfg(not(feature = "onchain-challenge"))]
+  async fn submit_challenge(
+    &self,
+    _: &Address,
+    lessor_address: Address,
+    nonce: u64,
+    block_number: u32,
+    _: Option<ChallengeProof>,
+  ) -> Result<TransactionResult> {
+    warn!(
+      "not submitting challenge lessor={} nonce={} block_number={}, the onchain-challenge feature is disabled",
+      lessor_address, nonce, block_number
+    );
+    Err(Error::ChallengeDisabled)
+  }
+
+  #[cfg(feature = "onchain-challenge")]
+  async fn submit_challenge(
+    &self,
+    token_address: &Address,
+    lessor_address: Address,
+    nonce: u64,
+    block_number: u32,
+    proof: Option<ChallengeProof>,
+  ) -> Result<TransactionResult> {
+    let (_, adjudicator) = self.deployment(token_address)?;
+    debug!(
+      "submitting challenge lessor={} nonce={} block_number={} with_proof={}",
+      lessor_address,
+      nonce,
+      block_number,
+      proof.is_some()
+    );
+    let (block_data, merkle_proof): (Vec<u8>, Vec<Bytes<[u8; 32]>>) = proof
+      .map(|p| (p.block_data, p.proof.into_iter().map(Bytes).collect()))
+      .unwrap_or_default();
+    let result = adjudicator
+      .methods()
+      .challenge(
+        self.account_storage,
+        lessor_address,
+        nonce,
+        block_number,
+        Bytes(block_data),
+        merkle_proof,
+      )
+      .from(Account::Offline(self.private_key.clone(), None)) // TODO should we use the chain id?
+      .send()
+      .await;
+    self.invalidate_balance(token_address);
+    Ok(result?)
   }
 
   async fn deployed_tokens(&self) -> Vec<(Address, Option<TokenMetadata>)> {
@@ -452,55 +957,126 @@ impl Service for Implementation {
       .await
   }
 
-  async fn balance(&self, token_address: &Address) -> Result<Balance> {
-    let (token, adjudicator) = self.deployment(token_address)?;
-    let (available_p2pim, locked_rents, locked_lets) = adjudicator.balance(self.account_storage).call().await?;
+  async fn balance(&self, token_address: &Address, refresh: bool) -> Result<Balance> {
+    let max_age = match self.balance_max_age {
+      Some(max_age) => max_age,
+      None => return self.read_balance(token_address).await,
+    };
+    if !refresh {
+      if let Some((read_at, Some(balance))) = self.balances.lock().unwrap().get(token_address) {
+        if read_at.elapsed() < max_age {
+          return Ok(balance.clone());
+        }
+      }
+    }
+    // Taken before reading, a balance dropped while reading is not cached, it may be from before the change
+    let read_at = Instant::now();
+    let balance = self.read_balance(token_address).await?;
+    let mut balances = self.balances.lock().unwrap();
+    if balances.get(token_address).map(|(t, _)| *t <= read_at).unwrap_or(true) {
+      balances.insert(*token_address, (read_at, Some(balance.clone())));
+    }
+    Ok(balance)
+  }
 
-    let available_account = token.balance_of(self.account_wallet).call().await?;
-    let allowance_account = token.allowance(self.account_wallet, adjudicator.address()).call().await?;
+  async fn native_balance(&self, address: Address) -> Result<U256> {
+    Ok(self.web3.eth().balance(address, None).await?)
+  }
 
-    let token_metadata = read_metadata(&token).await;
+  async fn pending_transactions(&self, address: Address) -> Result<U256> {
+    let pending = self.web3.eth().transaction_count(address, Some(BlockNumber::Pending)).await?;
+    let latest = self.web3.eth().transaction_count(address, Some(BlockNumber::Latest)).await?;
+    Ok(pending.saturating_sub(latest))
+  }
 
-    Ok(Balance {
-      token_metadata,
-      storage_balance: StorageBalance {
-        available: available_p2pim,
-        locked_rents,
-        locked_lets,
-      },
-      wallet_balance: WalletBalance {
-        available: available_account,
-        allowance: allowance_account,
-      },
-    })
+  async fn gas_price(&self) -> Result<U256> {
+    Ok(self.web3.eth().gas_price().await?)
   }
 
-  async fn withdraw(&self, token_addres: &Address, amount: U256) -> Result<TransactionResult> {
+  async fn withdraw(&self, token_addres: &Address, amount: U256, destination: Option<Address>) -> Result<TransactionResult> {
     let (_, adjudicator) = self.deployment(token_addres)?;
-    Ok(
-      adjudicator
-        .methods()
-        .withdraw(amount, self.account_wallet)
-        .from(Account::Offline(self.private_key.clone(), None)) // TODO should we use the chain id?
-        .send()
-        .await?,
-    )
+    let result = adjudicator
+      .methods()
+      .withdraw(amount, destination.unwrap_or(self.account_wallet))
+      .from(Account::Offline(self.private_key.clone(), None)) // TODO should we use the chain id?
+      .send()
+      .await;
+    self.invalidate_balance(token_addres);
+    Ok(result?)
   }
 
   async fn deposit(&self, token_addres: &Address, amount: U256) -> Result<TransactionResult> {
     let (_, adjudicator) = self.deployment(token_addres)?;
-    Ok(adjudicator.methods().deposit(amount, self.account_storage).send().await?)
+    let result = adjudicator
+      .methods()
+      .deposit(amount, self.account_storage)
+      .from(self.wallet_account())
+      .send()
+      .await;
+    self.invalidate_balance(token_addres);
+    Ok(result?)
   }
 
   async fn approve(&self, token_address: &Address) -> Result<TransactionResult> {
     let (token, adjudicator) = self.deployment(token_address)?;
-    Ok(
-      token
-        .approve(adjudicator.address(), U256::max_value())
-        .confirmations(0)
-        .send()
-        .await?,
-    )
+    let result = token
+      .approve(adjudicator.address(), U256::max_value())
+      .from(self.wallet_account())
+      .confirmations(0)
+      .send()
+      .await;
+    self.invalidate_balance(token_address);
+    Ok(result?)
+  }
+}
+
+fn invalidate_balance(balances: &BalanceCache, token_address: Address) {
+  balances.lock().unwrap().insert(token_address, (Instant::now(), None));
+}
+
+async fn wait_for_nonce<E, H>(
+  events: E,
+  heads: H,
+  nonce: u64,
+  until: SystemTime,
+) -> Option<ethcontract::Event<EventStatus<p2pim_ethereum_contracts::adjudicator::event_data::LeaseSealed>>>
+where
+  E: Stream<
+      Item = core::result::Result<
+        Event<EventStatus<p2pim_ethereum_contracts::adjudicator::event_data::LeaseSealed>>,
+        EventError,
+      >,
+    > + Unpin,
+  H: Stream<Item = web3::Result<SystemTime>> + Unpin,
+{
+  let mut events = events.fuse();
+  let mut heads = heads.fuse();
+  loop {
+    select! {
+      ev = events.next() => match ev {
+        // A seal removed by a reorg is usually mined again on the new branch, so only added events count
+        Some(Ok(e)) => {
+          if e.is_added() && e.inner_data().nonce == nonce {
+            return Some(e);
+          }
+        },
+        Some(Err(e)) => warn!("TODO: error in event stream: {}", e),
+        None => {
+          warn!("adjudicator event stream closed while waiting for the seal nonce={}", nonce);
+          return None;
+        }
+      },
+      head = heads.next() => match head {
+        Some(Ok(timestamp)) => if timestamp > until {
+          return None;
+        },
+        Some(Err(e)) => warn!("TODO: error in heads stream: {}", e),
+        None => {
+          warn!("block stream closed while waiting for the seal nonce={}", nonce);
+          return None;
+        }
+      }
+    }
   }
 }
 
@@ -525,3 +1101,442 @@ async fn read_metadata(token: &openzeppelin::IERC20Metadata) -> Option<TokenMeta
     _ => None,
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testutil::{block_json, lease_removed, lease_sealed, onchain_params, MockEthNode, MockOnchain};
+  use futures::stream;
+  use std::sync::atomic::{AtomicU64, Ordering};
+
+  const LEASE_DURATION: Duration = Duration::from_secs(3600);
+
+  async fn lease_state(onchain: &MockOnchain, sealed_at: Option<SystemTime>) -> LeaseState {
+    let confirmation = sealed_at.map(|timestamp| ChainConfirmation {
+      transaction_hash: H256::repeat_byte(0xaa),
+      timestamp,
+    });
+    onchain
+      .lease_state(
+        &Address::repeat_byte(9),
+        Address::repeat_byte(2),
+        onchain.account_storage(),
+        1,
+        LEASE_DURATION,
+        confirmation.as_ref(),
+      )
+      .await
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn lease_state_without_confirmation() {
+    let onchain = MockOnchain::new(Address::repeat_byte(1));
+    assert_eq!(lease_state(&onchain, None).await, LeaseState::NotFound);
+    // Nothing to read from the chain before the lease is sealed
+    assert!(onchain.state.lock().unwrap().calls.is_empty());
+  }
+
+  #[tokio::test]
+  async fn lease_state_within_duration() {
+    let onchain = MockOnchain::new(Address::repeat_byte(1));
+    let sealed_at = SystemTime::now() - LEASE_DURATION / 2;
+    assert_eq!(lease_state(&onchain, Some(sealed_at)).await, LeaseState::Active);
+    // The seal was dropped by a reorg
+    onchain.state.lock().unwrap().penalized = None;
+    assert_eq!(lease_state(&onchain, Some(sealed_at)).await, LeaseState::NotFound);
+  }
+
+  #[tokio::test]
+  async fn lease_state_after_duration() {
+    let onchain = MockOnchain::new(Address::repeat_byte(1));
+    let sealed_at = SystemTime::now() - LEASE_DURATION * 2;
+    assert_eq!(lease_state(&onchain, Some(sealed_at)).await, LeaseState::Closed);
+  }
+
+  #[tokio::test]
+  async fn lease_state_penalized() {
+    let onchain = MockOnchain::new(Address::repeat_byte(1));
+    onchain.state.lock().unwrap().penalized = Some(true);
+    let sealed_at = SystemTime::now() - LEASE_DURATION / 2;
+    assert_eq!(lease_state(&onchain, Some(sealed_at)).await, LeaseState::Penalized);
+    let sealed_at = SystemTime::now() - LEASE_DURATION * 2;
+    assert_eq!(lease_state(&onchain, Some(sealed_at)).await, LeaseState::Penalized);
+  }
+
+  #[tokio::test]
+  async fn wait_for_nonce_skips_removed_seals() {
+    let (lessor, lessee) = (Address::repeat_byte(1), Address::repeat_byte(2));
+    let events = stream::iter(vec![
+      Ok(lease_sealed(lessor, lessee, 2, H256::zero(), H256::repeat_byte(2))),
+      Ok(lease_removed(lessor, lessee, 1, H256::zero(), H256::repeat_byte(3))),
+      Ok(lease_sealed(lessor, lessee, 1, H256::zero(), H256::repeat_byte(4))),
+    ])
+    .chain(stream::pending());
+    let until = SystemTime::now() + Duration::from_secs(60);
+
+    let event = wait_for_nonce(events, stream::pending(), 1, until).await.unwrap();
+
+    assert!(event.is_added());
+    assert_eq!(event.meta.unwrap().transaction_hash, H256::repeat_byte(4));
+  }
+
+  #[tokio::test]
+  async fn wait_for_nonce_ends_with_the_stream() {
+    let until = SystemTime::now() + Duration::from_secs(60);
+    assert!(wait_for_nonce(stream::empty(), stream::pending(), 1, until).await.is_none());
+  }
+
+  #[test]
+  fn expiration_before_the_epoch_signed_as_expired() {
+    let terms = LeaseTerms {
+      proposal_expiration: UNIX_EPOCH - Duration::from_secs(1),
+      ..crate::testutil::lease_terms(Address::repeat_byte(9))
+    };
+    assert_eq!(expiration_secs(&terms), 0);
+  }
+
+  #[test]
+  fn message_signer_recovered() {
+    let key = PrivateKey::from_raw([1; 32]).unwrap();
+    let signature = sign_hash(&key, web3::signing::hash_message(b"audit head")).unwrap();
+    assert_eq!(recover_message_signer(b"audit head", &signature), Ok(key.public_address()));
+    assert_ne!(recover_message_signer(b"other head", &signature), Ok(key.public_address()));
+  }
+
+  #[tokio::test]
+  async fn private_key_not_leaked_and_still_signing() {
+    let url = MockEthNode::new().serve();
+    let params = onchain_params(url);
+    let key = PrivateKey::from_slice(params.private_key.expose_secret()).unwrap();
+    for debug in [format!("{:?}", params.private_key), format!("{:?}", key)] {
+      assert!(!debug.contains("1, 1, 1"), "leaked {}", debug);
+      assert!(!debug.contains(&hex::encode([1u8; 32])), "leaked {}", debug);
+    }
+
+    let onchain = new_service(params).await.unwrap();
+    let signature = onchain.sign_message(b"audit head").await.unwrap();
+
+    assert_eq!(recover_message_signer(b"audit head", &signature), Ok(key.public_address()));
+  }
+
+  #[tokio::test]
+  async fn wallet_derived_from_the_key_without_node_accounts() {
+    let node = MockEthNode::new();
+    let params = onchain_params(node.serve());
+    let key_address = PrivateKey::from_slice(params.private_key.expose_secret())
+      .unwrap()
+      .public_address();
+
+    let onchain = new_service(params).await.unwrap();
+
+    assert_eq!(onchain.account_storage(), key_address);
+    assert_eq!(onchain.account_wallet(), key_address);
+
+    let node_account = Address::repeat_byte(5);
+    node.answer("eth_accounts", serde_json::json!([node_account]));
+    let onchain = new_service(onchain_params(node.serve())).await.unwrap();
+    assert_eq!(onchain.account_wallet(), node_account);
+  }
+
+  #[tokio::test]
+  async fn invalid_private_key_rejected() {
+    let url = MockEthNode::new().serve();
+    // A zero scalar and the order of the curve are both out of range
+    let mut order = [0u8; 32];
+    hex::decode_to_slice("fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141", &mut order).unwrap();
+    for key in [[0u8; 32], order] {
+      let params = OnchainParams {
+        private_key: secrecy::Secret::new(key),
+        ..onchain_params(url.clone())
+      };
+
+      let error = new_service(params).await.err().unwrap();
+
+      assert!(matches!(error.downcast_ref::<Error>(), Some(Error::InvalidPrivateKey)));
+      assert_eq!(error.to_string(), "invalid private key");
+    }
+  }
+
+  #[tokio::test]
+  async fn master_record_not_deployed_on_the_network() {
+    let node = MockEthNode::new();
+    node.answer("net_version", serde_json::json!("424242"));
+    let params = OnchainParams {
+      master_address: None,
+      ..onchain_params(node.serve())
+    };
+
+    let error = new_service(params).await.err().unwrap();
+
+    assert!(matches!(
+      error.downcast_ref::<Error>(),
+      Some(Error::MasterRecordNotDeployed { network_id }) if network_id == "424242"
+    ));
+    assert!(error.to_string().contains("--eth.master"), "{}", error);
+  }
+
+  #[tokio::test]
+  async fn native_balances_read_from_the_node() {
+    let node = MockEthNode::new();
+    node.answer("eth_getBalance", serde_json::json!("0xde0b6b3a7640000"));
+    node.answer_with("eth_getTransactionCount", |params| match params[1].as_str() {
+      Some("pending") => serde_json::json!("0x5"),
+      _ => serde_json::json!("0x3"),
+    });
+    let onchain = new_service(onchain_params(node.serve())).await.unwrap();
+    let account = onchain.account_storage();
+
+    assert_eq!(onchain.native_balance(account).await.unwrap(), U256::exp10(18));
+    assert_eq!(onchain.pending_transactions(account).await.unwrap(), 2.into());
+    assert_eq!(node.requests("eth_getBalance")[0][0], format!("{:?}", account));
+  }
+
+  #[tokio::test]
+  async fn block_timestamp_read_once() {
+    let node = MockEthNode::new();
+    let hash = H256::repeat_byte(7);
+    node.answer("eth_getBlockByHash", block_json(hash, 10, 1_600_000_000));
+    let onchain = new_service(onchain_params(node.serve())).await.unwrap();
+
+    for _ in 0..3 {
+      let timestamp = onchain.block_timestamp(hash).await.unwrap();
+      assert_eq!(timestamp, Some(UNIX_EPOCH + Duration::from_secs(1_600_000_000)));
+    }
+    assert_eq!(node.requests("eth_getBlockByHash").len(), 1);
+  }
+
+  /// Deployments of the master record, with a single one.
+  fn deployment_json(token: Address, adjudicator: Address) -> serde_json::Value {
+    serde_json::json!(format!(
+      "0x{:064x}{:064x}{:0>64}{:0>64}",
+      0x20,
+      1,
+      hex::encode(token),
+      hex::encode(adjudicator)
+    ))
+  }
+
+  fn answer_deployment(node: &MockEthNode, token: Address, adjudicator: Address) {
+    node.answer("eth_call", deployment_json(token, adjudicator));
+  }
+
+  /// Signed transactions accepted and mined in the next block.
+  fn answer_transactions(node: &MockEthNode) {
+    node.answer("eth_chainId", serde_json::json!("0x1"));
+    node.answer("eth_gasPrice", serde_json::json!("0x1"));
+    node.answer("eth_estimateGas", serde_json::json!("0x5208"));
+    node.answer("eth_getTransactionCount", serde_json::json!("0x0"));
+    // The hash of the raw transaction, which the client checks
+    node.answer_with("eth_sendRawTransaction", |params| {
+      let raw = hex::decode(params[0].as_str().unwrap().trim_start_matches("0x")).unwrap();
+      serde_json::json!(H256(web3::signing::keccak256(&raw)))
+    });
+    node.answer("eth_blockNumber", serde_json::json!("0x1"));
+    node.answer_with("eth_getTransactionReceipt", |params| {
+      serde_json::json!(web3::types::TransactionReceipt {
+        transaction_hash: serde_json::from_value(params[0].clone()).unwrap(),
+        block_number: Some(1.into()),
+        status: Some(1.into()),
+        ..Default::default()
+      })
+    });
+  }
+
+  #[tokio::test]
+  async fn balance_reports_the_adjudicator_of_the_deployment() {
+    let node = MockEthNode::new();
+    let (token, adjudicator) = (Address::repeat_byte(0x44), Address::repeat_byte(0x55));
+    let master_record = Address::repeat_byte(0x33);
+    node.answer_with("eth_call", move |params| {
+      if params[0]["to"] == format!("{:?}", master_record) {
+        deployment_json(token, adjudicator)
+      } else {
+        // Balances of the token and the adjudicator, all zero
+        serde_json::json!(format!("0x{}", "0".repeat(3 * 64)))
+      }
+    });
+    let onchain = new_service(onchain_params(node.serve())).await.unwrap();
+
+    let balance = onchain.balance(&token, false).await.unwrap();
+
+    assert_eq!(balance.adjudicator_address, adjudicator);
+    assert_eq!(onchain.master_record_address(), master_record);
+    assert!(matches!(
+      onchain.balance(&adjudicator, false).await,
+      Err(Error::TokenNotDeployed(address)) if address == adjudicator
+    ));
+  }
+
+  #[tokio::test]
+  async fn withdraw_sent_to_the_destination() {
+    let node = MockEthNode::new();
+    let (token, adjudicator) = (Address::repeat_byte(0x44), Address::repeat_byte(0x55));
+    answer_deployment(&node, token, adjudicator);
+    answer_transactions(&node);
+    let onchain = new_service(onchain_params(node.serve())).await.unwrap();
+    let destination = Address::repeat_byte(0x66);
+
+    onchain.withdraw(&token, 10.into(), Some(destination)).await.unwrap();
+    onchain.withdraw(&token, 10.into(), None).await.unwrap();
+
+    let raw = node.requests("eth_sendRawTransaction");
+    assert_eq!(raw.len(), 2);
+    let sent_to = |raw: &serde_json::Value, address: Address| raw[0].as_str().unwrap().contains(&hex::encode(address));
+    assert!(sent_to(&raw[0], adjudicator) && sent_to(&raw[0], destination));
+    assert!(sent_to(&raw[1], onchain.account_wallet()) && !sent_to(&raw[1], destination));
+  }
+
+  #[tokio::test]
+  async fn cached_balance_dropped_by_a_deposit() {
+    let node = MockEthNode::new();
+    let (token, adjudicator) = (Address::repeat_byte(0x44), Address::repeat_byte(0x55));
+    let master_record = Address::repeat_byte(0x33);
+    let available = Arc::new(AtomicU64::new(10));
+    let available_read = available.clone();
+    node.answer_with("eth_call", move |params| {
+      if params[0]["to"] == format!("{:?}", master_record) {
+        deployment_json(token, adjudicator)
+      } else {
+        let available = available_read.load(Ordering::SeqCst);
+        serde_json::json!(format!("0x{:064x}{}", available, "0".repeat(2 * 64)))
+      }
+    });
+    answer_transactions(&node);
+    let onchain = new_service(OnchainParams {
+      balance_max_age: Some(Duration::from_secs(3600)),
+      ..onchain_params(node.serve())
+    })
+    .await
+    .unwrap();
+    let available_p2pim = |balance: Balance| balance.storage_balance.available;
+
+    assert_eq!(available_p2pim(onchain.balance(&token, false).await.unwrap()), 10.into());
+    available.store(20, Ordering::SeqCst);
+    // Served from the cache until something changes it
+    assert_eq!(available_p2pim(onchain.balance(&token, false).await.unwrap()), 10.into());
+    onchain.deposit(&token, 10.into()).await.unwrap();
+
+    assert_eq!(available_p2pim(onchain.balance(&token, false).await.unwrap()), 20.into());
+  }
+
+  #[tokio::test]
+  async fn https_url_accepted() {
+    let params = onchain_params(Url::parse("https://127.0.0.1:1").unwrap());
+
+    let error = new_service(params).await.err().unwrap();
+
+    // Refused when connecting, not by the scheme
+    assert!(!error.to_string().contains("unsupported"), "{}", error);
+  }
+
+  #[tokio::test]
+  async fn seal_awaited_by_polling_over_http() {
+    let node = MockEthNode::new();
+    let (token, adjudicator) = (Address::repeat_byte(0x44), Address::repeat_byte(0x55));
+    answer_deployment(&node, token, adjudicator);
+    node.answer("eth_blockNumber", serde_json::json!("0x20"));
+    node.answer("eth_newBlockFilter", serde_json::json!("0x1"));
+    node.answer("eth_newFilter", serde_json::json!("0x2"));
+    let head = H256::repeat_byte(7);
+    node.answer_with("eth_getFilterChanges", move |params| {
+      if params[0] == "0x1" {
+        serde_json::json!([head])
+      } else {
+        serde_json::json!([])
+      }
+    });
+    node.answer("eth_getBlockByHash", block_json(head, 0x21, 2_000_000_000));
+    let onchain = new_service(OnchainParams {
+      poll_interval: Duration::from_millis(10),
+      ..onchain_params(node.serve())
+    })
+    .await
+    .unwrap();
+    let until = UNIX_EPOCH + Duration::from_secs(1_900_000_000);
+
+    let seal = onchain
+      .wait_for_seal_lease(&token, Address::repeat_byte(1), 1, until)
+      .await
+      .unwrap();
+
+    // No seal before the new head went past the deadline
+    assert!(seal.is_none());
+    assert_eq!(node.requests("eth_newBlockFilter").len(), 1);
+    assert!(node.requests("eth_subscribe").is_empty());
+  }
+
+  #[tokio::test]
+  async fn event_streams_polled_at_the_configured_interval() {
+    let node = MockEthNode::new();
+    answer_deployment(&node, Address::repeat_byte(0x44), Address::repeat_byte(0x55));
+    node.answer("eth_newFilter", serde_json::json!("0x2"));
+    node.answer("eth_getFilterChanges", serde_json::json!([]));
+    let onchain = new_service(OnchainParams {
+      poll_interval: Duration::from_millis(200),
+      ..onchain_params(node.serve())
+    })
+    .await
+    .unwrap();
+    let mut events = onchain.listen_adjudicator_events().await;
+
+    tokio::time::timeout(Duration::from_millis(700), events.next())
+      .await
+      .unwrap_err();
+
+    // The lessor and the lessee streams polled about three times each, none with the default interval
+    let polls = node.requests("eth_getFilterChanges").len();
+    assert!((2..=8).contains(&polls), "polls={}", polls);
+  }
+
+  #[tokio::test]
+  async fn penalty_events_read_from_the_seal_block() {
+    let node = MockEthNode::new();
+    let (token, adjudicator) = (Address::repeat_byte(0x44), Address::repeat_byte(0x55));
+    answer_deployment(&node, token, adjudicator);
+    let (lessor, lessee) = (Address::repeat_byte(1), Address::repeat_byte(2));
+    let seal_hash = H256::repeat_byte(7);
+    node.answer(
+      "eth_getTransactionReceipt",
+      serde_json::json!(web3::types::TransactionReceipt {
+        transaction_hash: seal_hash,
+        block_number: Some(0x10.into()),
+        status: Some(1.into()),
+        ..Default::default()
+      }),
+    );
+    let penalized = |nonce: u64| web3::types::Log {
+      address: adjudicator,
+      topics: vec![
+        H256(web3::signing::keccak256(b"LeasePenalized(address,address,uint64)")),
+        H256::from(lessor),
+        H256::from(lessee),
+      ],
+      data: web3::types::Bytes(H256::from_low_u64_be(nonce).0.to_vec()),
+      block_hash: Some(H256::repeat_byte(8)),
+      block_number: Some(0x12.into()),
+      transaction_hash: Some(H256::repeat_byte(9)),
+      transaction_index: Some(0.into()),
+      log_index: Some(nonce.into()),
+      transaction_log_index: None,
+      log_type: None,
+      removed: Some(false),
+    };
+    node.answer("eth_getLogs", serde_json::json!([penalized(3)]));
+    let onchain = new_service(onchain_params(node.serve())).await.unwrap();
+
+    let penalized = onchain
+      .is_lease_penalized(&token, lessor, lessee, 3, seal_hash)
+      .await
+      .unwrap();
+    let other_nonce = onchain
+      .is_lease_penalized(&token, lessor, lessee, 4, seal_hash)
+      .await
+      .unwrap();
+
+    assert_eq!(penalized, Some(true));
+    assert_eq!(other_nonce, Some(false));
+    let queries = node.requests("eth_getLogs");
+    assert_eq!(queries[0][0]["fromBlock"], "0x10");
+  }
+}
@@ -1,19 +1,30 @@
 use super::p2pim;
 use super::p2pim::LeaseProposal;
-use crate::types::{ChallengeKey, ChallengeProof};
+use super::{P2pParams, QuoteResponse};
+use crate::types::{ChallengeKey, ChallengeProof, LeaseTerms};
+use crate::utils::cache::LruCache;
 use libp2p::identify::{Identify, IdentifyConfig, IdentifyEvent, IdentifyInfo};
-use libp2p::identity::PublicKey;
+use libp2p::identity::{secp256k1, PublicKey};
 use libp2p::mdns::{Mdns, MdnsConfig, MdnsEvent};
 use libp2p::swarm::behaviour::toggle::Toggle;
 use libp2p::swarm::dial_opts::{DialOpts, PeerCondition};
-use libp2p::swarm::{NetworkBehaviour, NetworkBehaviourAction, NetworkBehaviourEventProcess};
-use libp2p::{ping, NetworkBehaviour, PeerId};
+use libp2p::swarm::{CloseConnection, NetworkBehaviour, NetworkBehaviourAction, NetworkBehaviourEventProcess};
+use libp2p::{ping, Multiaddr, NetworkBehaviour, PeerId};
 use log::{debug, info, trace, warn};
 use std::collections::{HashMap, VecDeque};
 use std::error::Error;
-use std::task::Poll;
+use std::sync::{Arc, RwLock};
+use std::task::{Poll, Waker};
+use std::time::{Duration, Instant};
+use web3::types::H256;
 
-const PROTOCOL_VERSION: &str = "p2pim/0.1.0";
+pub const PROTOCOL_VERSION: &str = "p2pim/0.1.0";
+pub const AGENT_VERSION: &str = "p2pim-core";
+
+const DIAL_BASE_COOLDOWN: Duration = Duration::from_secs(5);
+const DIAL_MAX_COOLDOWN: Duration = Duration::from_secs(300);
+
+pub type PublicKeys = Arc<RwLock<HashMap<PeerId, secp256k1::PublicKey>>>;
 
 #[derive(NetworkBehaviour)]
 #[behaviour(event_process = true, poll_method = "poll", out_event = "Event")]
@@ -24,10 +35,24 @@ pub struct Behaviour {
   pub p2pim: p2pim::Behaviour,
   #[behaviour(ignore)]
   actions: VecDeque<BehaviourAction>,
+  // Identified peers, the disconnected ones are kept until evicted by newer peers
+  #[behaviour(ignore)]
+  known_peers: LruCache<PeerId, IdentifiedPeer>,
   #[behaviour(ignore)]
-  known_peers: HashMap<PeerId, IdentifyInfo>,
+  public_keys: PublicKeys,
+  #[behaviour(ignore)]
+  recent_dials: HashMap<PeerId, RecentDial>,
+  // Protocol version announced by peers we disconnected from
+  #[behaviour(ignore)]
+  incompatible_peers: HashMap<PeerId, String>,
   #[behaviour(ignore)]
   events_queue: VecDeque<Event>,
+  // Announced with identify, peers announcing a different one are disconnected
+  #[behaviour(ignore)]
+  protocol_version: String,
+  // Woken when a dial is requested from outside the swarm polling
+  #[behaviour(ignore)]
+  waker: Option<Waker>,
 }
 
 #[derive(Debug)]
@@ -41,6 +66,11 @@ pub enum Event {
     nonce: u64,
     reason: String,
   },
+  ReceivedLeaseSealed {
+    peer_id: PeerId,
+    nonce: u64,
+    transaction_hash: H256,
+  },
   ReceivedChallengeRequest {
     peer_id: PeerId,
     challenge_key: ChallengeKey,
@@ -59,48 +89,175 @@ pub enum Event {
     nonce: u64,
     data: Vec<u8>,
   },
+  ReceivedRetrieveNotFound {
+    peer_id: PeerId,
+    nonce: u64,
+  },
+  ReceivedQuoteRequest {
+    peer_id: PeerId,
+    quote_id: u64,
+    lease_terms: LeaseTerms,
+    size: usize,
+  },
+  ReceivedQuoteResponse {
+    peer_id: PeerId,
+    quote_id: u64,
+    response: QuoteResponse,
+  },
+  PeerIdentified {
+    peer_id: PeerId,
+    listen_addrs: Vec<Multiaddr>,
+  },
+  PeerDisconnected {
+    peer_id: PeerId,
+  },
+}
+
+/// Protocol version announced with identify, nodes on different network ids do not connect to each other.
+pub fn protocol_version(network_id: Option<&str>) -> String {
+  match network_id {
+    Some(network_id) => format!("{}/{}", PROTOCOL_VERSION, network_id),
+    None => PROTOCOL_VERSION.to_string(),
+  }
+}
+
+fn ping_config(params: &P2pParams) -> ping::Config {
+  ping::Config::new()
+    .with_keep_alive(params.ping_keep_alive)
+    .with_interval(params.ping_interval)
 }
 
 #[derive(Debug)]
 enum BehaviourAction {
-  Dial(PeerId),
+  Dial(PeerId, Vec<Multiaddr>),
+  Disconnect(PeerId),
+}
+
+struct IdentifiedPeer {
+  info: IdentifyInfo,
+  connected: bool,
+}
+
+#[derive(Debug)]
+struct RecentDial {
+  last_dial: Instant,
+  attempts: u32,
+}
+
+impl RecentDial {
+  fn cooldown(&self) -> Duration {
+    DIAL_BASE_COOLDOWN
+      .checked_mul(2u32.saturating_pow(self.attempts.saturating_sub(1)))
+      .map(|c| std::cmp::min(c, DIAL_MAX_COOLDOWN))
+      .unwrap_or(DIAL_MAX_COOLDOWN)
+  }
 }
 
 impl Behaviour {
-  pub async fn new(local_public_key: PublicKey, mdns_enabled: bool) -> Result<Self, Box<dyn Error>> {
+  pub async fn new(local_public_key: PublicKey, params: &P2pParams, public_keys: PublicKeys) -> Result<Self, Box<dyn Error>> {
+    let protocol_version = protocol_version(params.network_id.as_deref());
     let identify = Identify::new(
-      IdentifyConfig::new(PROTOCOL_VERSION.to_string(), local_public_key).with_agent_version("p2pim-core".to_string()),
+      IdentifyConfig::new(protocol_version.clone(), local_public_key).with_agent_version(params.agent_version.clone()),
     );
-    let ping = ping::Behaviour::new(ping::Config::new().with_keep_alive(true)); // TODO This is temporary until we maintain the connection in p2pim
-    let mdns = if mdns_enabled {
+    let ping = ping::Behaviour::new(ping_config(params));
+    let mdns = if params.mdns_enabled {
       Toggle::from(Some(Mdns::new(MdnsConfig::default()).await?))
     } else {
+      debug!("mdns discovery disabled");
       Toggle::from(None)
     };
-    let p2pim = p2pim::Behaviour::new();
+    let p2pim = p2pim::Behaviour::new()
+      .with_substream_idle_timeout(params.substream_idle_timeout)
+      .with_max_queued_messages(params.max_queued_messages);
     Ok(Behaviour {
       identify,
       ping,
       mdns,
       p2pim,
       actions: VecDeque::new(),
-      known_peers: HashMap::new(),
+      known_peers: LruCache::new(params.known_peers_capacity),
+      public_keys,
+      recent_dials: HashMap::new(),
+      incompatible_peers: HashMap::new(),
       events_queue: VecDeque::new(),
+      protocol_version,
+      waker: None,
     })
   }
 
+  pub fn protocol_version(&self) -> &str {
+    &self.protocol_version
+  }
+
   pub fn peer_info(&self, peer_id: &PeerId) -> Option<&IdentifyInfo> {
-    self.known_peers.get(peer_id)
+    self.known_peers.peek(peer_id).map(|p| &p.info)
   }
 
+  /// Identified peers that are currently connected.
   pub fn known_peers(&self) -> Vec<PeerId> {
     // TODO copying the peers in memory
-    self.known_peers.keys().map(Clone::clone).collect()
+    self
+      .known_peers
+      .iter()
+      .filter(|(_, p)| p.connected)
+      .map(|(peer_id, _)| *peer_id)
+      .collect()
+  }
+
+  /// Marks the peer as disconnected, its identify info is kept until evicted.
+  pub fn forget_peer(&mut self, peer_id: &PeerId) {
+    if let Some(peer) = self.known_peers.get_mut(peer_id) {
+      peer.connected = false;
+    }
+    self.known_peers.evict_unpinned(|p| p.connected);
+    self.public_keys.write().unwrap().remove(peer_id);
+  }
+
+  fn is_connected(&self, peer_id: &PeerId) -> bool {
+    self.known_peers.peek(peer_id).map(|p| p.connected).unwrap_or(false)
+  }
+
+  /// Dials a peer unless already connected, incompatible or dialed within its cooldown. The addresses are tried along
+  /// with the ones the behaviours know, e.g. from mdns.
+  pub fn dial(&mut self, peer_id: PeerId, addresses: Vec<Multiaddr>) {
+    if self.is_connected(&peer_id) {
+      trace!("dial: peer already connected peer_id={}", peer_id);
+      return;
+    }
+    if let Some(protocol_version) = self.incompatible_peers.get(&peer_id) {
+      trace!(
+        "dial: peer has an incompatible protocol peer_id={} protocol_version={}",
+        peer_id,
+        protocol_version
+      );
+      return;
+    }
+    let now = Instant::now();
+    if let Some(recent) = self.recent_dials.get_mut(&peer_id) {
+      if now.duration_since(recent.last_dial) < recent.cooldown() {
+        trace!("dial: peer dialed recently peer_id={} attempts={}", peer_id, recent.attempts);
+        return;
+      }
+      recent.last_dial = now;
+      recent.attempts += 1;
+    } else {
+      self.recent_dials.insert(
+        peer_id,
+        RecentDial {
+          last_dial: now,
+          attempts: 1,
+        },
+      );
+    }
+    self.actions.push_back(BehaviourAction::Dial(peer_id, addresses));
+    if let Some(waker) = self.waker.take() {
+      waker.wake();
+    }
   }
 
   fn poll(
     &mut self,
-    _: &mut std::task::Context,
+    cx: &mut std::task::Context,
     _: &mut impl libp2p::swarm::PollParameters,
   ) -> std::task::Poll<
     libp2p::swarm::NetworkBehaviourAction<
@@ -110,10 +267,20 @@ impl Behaviour {
   > {
     if let Some(action) = self.actions.pop_front() {
       match action {
-        BehaviourAction::Dial(peer_id) => {
+        BehaviourAction::Dial(peer_id, addresses) => {
           return Poll::Ready(NetworkBehaviourAction::Dial {
             handler: self.new_handler(),
-            opts: DialOpts::peer_id(peer_id).condition(PeerCondition::Disconnected).build(),
+            opts: DialOpts::peer_id(peer_id)
+              .condition(PeerCondition::Disconnected)
+              .addresses(addresses)
+              .extend_addresses_through_behaviour()
+              .build(),
+          })
+        }
+        BehaviourAction::Disconnect(peer_id) => {
+          return Poll::Ready(NetworkBehaviourAction::CloseConnection {
+            peer_id,
+            connection: CloseConnection::All,
           })
         }
       }
@@ -122,6 +289,7 @@ impl Behaviour {
     if let Some(event) = self.events_queue.pop_front() {
       return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
     }
+    self.waker = Some(cx.waker().clone());
     Poll::Pending
   }
 }
@@ -131,15 +299,30 @@ impl NetworkBehaviourEventProcess<IdentifyEvent> for Behaviour {
     trace!("identify: event received: {:?}", event);
     match event {
       IdentifyEvent::Received { peer_id, info } => {
-        if info.protocol_version != PROTOCOL_VERSION {
-          debug!("received peer info for incompatible protocol: {}", info.protocol_version);
+        if info.protocol_version != self.protocol_version {
+          info!(
+            "disconnecting peer with incompatible protocol peer_id={} protocol_version={}",
+            peer_id, info.protocol_version
+          );
+          self.recent_dials.remove(&peer_id);
+          self.incompatible_peers.insert(peer_id, info.protocol_version);
+          self.actions.push_back(BehaviourAction::Disconnect(peer_id));
         } else {
           let peer_id_from_public = PeerId::from_public_key(&info.public_key);
           if peer_id_from_public != peer_id {
             warn!("peer sending wrong public key peer_id={}", peer_id);
-          } else if let libp2p::identity::PublicKey::Secp256k1(_) = info.public_key.clone() {
+          } else if let libp2p::identity::PublicKey::Secp256k1(public_key) = info.public_key.clone() {
             info!("known peer with id {}: {:?}", peer_id, info);
-            self.known_peers.insert(peer_id, info);
+            self.public_keys.write().unwrap().insert(peer_id, public_key);
+            self.recent_dials.remove(&peer_id);
+            self.incompatible_peers.remove(&peer_id);
+            self.events_queue.push_back(Event::PeerIdentified {
+              peer_id,
+              listen_addrs: info.listen_addrs.clone(),
+            });
+            self
+              .known_peers
+              .insert_unless_pinned(peer_id, IdentifiedPeer { info, connected: true }, |p| p.connected);
           } else {
             warn!("peer sending a public key not supported: {:?}", info.public_key);
           }
@@ -166,6 +349,13 @@ impl NetworkBehaviourEventProcess<p2pim::Event> for Behaviour {
       p2pim::Event::ReceivedLeaseProposalRejection(peer_id, nonce, reason) => self
         .events_queue
         .push_back(Event::ReceivedLeaseProposalRejection { peer_id, nonce, reason }),
+      p2pim::Event::ReceivedLeaseSealed(peer_id, nonce, transaction_hash) => {
+        self.events_queue.push_back(Event::ReceivedLeaseSealed {
+          peer_id,
+          nonce,
+          transaction_hash,
+        })
+      }
       p2pim::Event::ReceivedChallengeRequest(peer_id, challenge_key) => self
         .events_queue
         .push_back(Event::ReceivedChallengeRequest { peer_id, challenge_key }),
@@ -182,6 +372,25 @@ impl NetworkBehaviourEventProcess<p2pim::Event> for Behaviour {
       p2pim::Event::ReceivedRetrieveDelivery(peer_id, nonce, data) => self
         .events_queue
         .push_back(Event::ReceivedRetrieveDelivery { peer_id, nonce, data }),
+      p2pim::Event::ReceivedRetrieveNotFound(peer_id, nonce) => self
+        .events_queue
+        .push_back(Event::ReceivedRetrieveNotFound { peer_id, nonce }),
+      p2pim::Event::ReceivedQuoteRequest(peer_id, quote_id, lease_terms, size) => {
+        self.events_queue.push_back(Event::ReceivedQuoteRequest {
+          peer_id,
+          quote_id,
+          lease_terms,
+          size,
+        })
+      }
+      p2pim::Event::ReceivedQuoteResponse(peer_id, quote_id, response) => {
+        self.events_queue.push_back(Event::ReceivedQuoteResponse {
+          peer_id,
+          quote_id,
+          response,
+        })
+      }
+      p2pim::Event::PeerDisconnected(peer_id) => self.events_queue.push_back(Event::PeerDisconnected { peer_id }),
     }
   }
 }
@@ -190,10 +399,162 @@ impl NetworkBehaviourEventProcess<MdnsEvent> for Behaviour {
   fn inject_event(&mut self, event: MdnsEvent) {
     trace!("mdns: event received: {:?}", event);
     match event {
-      MdnsEvent::Discovered(addr_iter) => {
-        addr_iter.for_each(|(peer_id, _)| self.actions.push_back(BehaviourAction::Dial(peer_id)))
-      }
+      MdnsEvent::Discovered(addr_iter) => addr_iter.for_each(|(peer_id, _)| self.dial(peer_id, Vec::new())),
       MdnsEvent::Expired(_) => debug!("mdns: expired event ignored, nothing to do"),
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testutil::p2p_params;
+  use libp2p::identity::Keypair;
+  use std::collections::HashSet;
+
+  #[tokio::test]
+  async fn custom_ping_config_applied() {
+    let params = P2pParams {
+      ping_keep_alive: false,
+      ping_interval: Duration::from_secs(3),
+      ..p2p_params()
+    };
+    let config = format!("{:?}", ping_config(&params));
+    assert!(config.contains("interval: 3s"), "{}", config);
+    assert!(config.contains("keep_alive: false"), "{}", config);
+    assert!(
+      Behaviour::new(Keypair::generate_secp256k1().public(), &params, Default::default())
+        .await
+        .is_ok()
+    );
+  }
+
+  #[tokio::test]
+  async fn mdns_disabled() {
+    let params = P2pParams {
+      mdns_enabled: false,
+      ..p2p_params()
+    };
+    let behaviour = Behaviour::new(Keypair::generate_secp256k1().public(), &params, Default::default())
+      .await
+      .unwrap();
+    assert!(!behaviour.mdns.is_enabled());
+    assert!(behaviour.actions.is_empty());
+  }
+
+  #[tokio::test]
+  async fn repeated_discovery_dialed_once() {
+    let mut behaviour = Behaviour::new(Keypair::generate_secp256k1().public(), &p2p_params(), Default::default())
+      .await
+      .unwrap();
+    let peer_id = PeerId::random();
+
+    // What the mdns discovery of a peer does
+    behaviour.dial(peer_id, Vec::new());
+    behaviour.dial(peer_id, Vec::new());
+
+    assert_eq!(behaviour.actions.len(), 1);
+    assert!(matches!(behaviour.actions[0], BehaviourAction::Dial(p, _) if p == peer_id));
+  }
+
+  fn identify_info(public_key: PublicKey, protocol_version: &str) -> IdentifyInfo {
+    IdentifyInfo {
+      public_key,
+      protocol_version: protocol_version.to_string(),
+      agent_version: AGENT_VERSION.to_string(),
+      listen_addrs: Vec::new(),
+      protocols: Vec::new(),
+      observed_addr: Multiaddr::empty(),
+    }
+  }
+
+  #[tokio::test]
+  async fn incompatible_peer_disconnected() {
+    let mut behaviour = Behaviour::new(Keypair::generate_secp256k1().public(), &p2p_params(), Default::default())
+      .await
+      .unwrap();
+    let (incompatible, compatible) = (Keypair::generate_secp256k1().public(), Keypair::generate_secp256k1().public());
+    let (incompatible_id, compatible_id) = (incompatible.to_peer_id(), compatible.to_peer_id());
+
+    NetworkBehaviourEventProcess::inject_event(
+      &mut behaviour,
+      IdentifyEvent::Received {
+        peer_id: incompatible_id,
+        info: identify_info(incompatible, "p2pim/9.9.9"),
+      },
+    );
+    NetworkBehaviourEventProcess::inject_event(
+      &mut behaviour,
+      IdentifyEvent::Received {
+        peer_id: compatible_id,
+        info: identify_info(compatible, PROTOCOL_VERSION),
+      },
+    );
+
+    assert_eq!(behaviour.actions.len(), 1);
+    assert!(matches!(behaviour.actions[0], BehaviourAction::Disconnect(p) if p == incompatible_id));
+    assert!(!behaviour.known_peers.contains_key(&incompatible_id));
+    assert!(behaviour.known_peers.contains_key(&compatible_id));
+    assert_eq!(behaviour.incompatible_peers[&incompatible_id], "p2pim/9.9.9");
+    // Not dialed again
+    behaviour.dial(incompatible_id, Vec::new());
+    assert_eq!(behaviour.actions.len(), 1);
+  }
+
+  #[tokio::test]
+  async fn peers_of_other_networks_not_known() {
+    let params = P2pParams {
+      network_id: Some("testnet".to_string()),
+      ..p2p_params()
+    };
+    let mut behaviour = Behaviour::new(Keypair::generate_secp256k1().public(), &params, Default::default())
+      .await
+      .unwrap();
+    let peers = [None, Some("othernet"), Some("testnet")]
+      .iter()
+      .map(|network_id| {
+        let public_key = Keypair::generate_secp256k1().public();
+        let peer_id = public_key.to_peer_id();
+        let info = identify_info(public_key, &protocol_version(*network_id));
+        NetworkBehaviourEventProcess::inject_event(&mut behaviour, IdentifyEvent::Received { peer_id, info });
+        peer_id
+      })
+      .collect::<Vec<_>>();
+
+    assert_eq!(behaviour.known_peers(), vec![peers[2]]);
+    assert_eq!(behaviour.incompatible_peers.len(), 2);
+  }
+
+  #[tokio::test]
+  async fn oldest_disconnected_peer_evicted() {
+    let params = P2pParams {
+      known_peers_capacity: 3,
+      ..p2p_params()
+    };
+    let mut behaviour = Behaviour::new(Keypair::generate_secp256k1().public(), &params, Default::default())
+      .await
+      .unwrap();
+    let identify = |behaviour: &mut Behaviour| {
+      let public_key = Keypair::generate_secp256k1().public();
+      let peer_id = public_key.to_peer_id();
+      let info = identify_info(public_key, PROTOCOL_VERSION);
+      NetworkBehaviourEventProcess::inject_event(behaviour, IdentifyEvent::Received { peer_id, info });
+      peer_id
+    };
+    let peers = (0..3).map(|_| identify(&mut behaviour)).collect::<Vec<_>>();
+    behaviour.forget_peer(&peers[0]);
+    behaviour.forget_peer(&peers[2]);
+
+    let newer = identify(&mut behaviour);
+    assert!(behaviour.peer_info(&peers[0]).is_none());
+    assert!(behaviour.peer_info(&peers[2]).is_some());
+
+    let newest = identify(&mut behaviour);
+    assert!(behaviour.peer_info(&peers[2]).is_none());
+
+    // Connected peers are kept beyond the capacity
+    let beyond = identify(&mut behaviour);
+    let known = behaviour.known_peers().into_iter().collect::<HashSet<_>>();
+    assert_eq!(known, HashSet::from([peers[1], newer, newest, beyond]));
+  }
+}
@@ -1,30 +1,162 @@
 use crate::p2p::p2pim::LeaseProposal;
 use crate::types::{ChallengeKey, ChallengeProof, LeaseTerms, Signature};
 use crate::utils::sync::OneshotListerners;
+use futures::channel::oneshot::Canceled;
 use futures::Stream;
 use libp2p::core::Executor;
 use libp2p::identity::secp256k1::PublicKey;
 use libp2p::identity::{secp256k1, Keypair};
-use libp2p::swarm::{SwarmBuilder, SwarmEvent};
-use libp2p::{PeerId, Swarm};
-use log::{debug, trace, warn};
+use libp2p::swarm::{ConnectionLimits, SwarmBuilder, SwarmEvent};
+use libp2p::{Multiaddr, PeerId, Swarm};
+use log::{debug, info, trace, warn};
 use std::borrow::Borrow;
+use std::collections::HashMap;
 use std::error::Error;
 use std::future::Future;
 use std::ops::DerefMut;
 use std::pin::Pin;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::task::{Context, Poll};
+use std::time::Duration;
 use tonic::async_trait;
+use web3::types::H256;
 
 pub mod behaviour;
 pub mod p2pim;
 pub mod transport;
 
+pub struct P2pParams {
+  // Empty listens on DEFAULT_LISTEN_ADDRESSES
+  pub listen_addresses: Vec<Multiaddr>,
+  pub mdns_enabled: bool,
+  // When disabled, connections are kept only while the p2pim protocol keeps them alive
+  pub ping_keep_alive: bool,
+  pub ping_interval: Duration,
+  pub max_established: u32,
+  pub max_pending: u32,
+  pub max_established_per_peer: u32,
+  pub substream_idle_timeout: Duration,
+  pub max_queued_messages: usize,
+  pub challenge_timeout: Duration,
+  pub retrieve_timeout: Duration,
+  pub proposal_timeout: Duration,
+  // Requests sent again when a delivery does not arrive within the retrieve timeout
+  pub retrieve_retries: u32,
+  // Isolates private deployments, peers only identify nodes with the same network id
+  pub network_id: Option<String>,
+  pub agent_version: String,
+  // Identified peers kept in memory, connected peers are never evicted
+  pub known_peers_capacity: usize,
+}
+
+/// Any interface on both families, the IPv6 one is skipped on hosts without IPv6 support.
+pub const DEFAULT_LISTEN_ADDRESSES: [&str; 2] = ["/ip4/0.0.0.0/tcp/0", "/ip6/::/tcp/0"];
+
+// Quotes are cheap to answer, a peer taking longer is not worth waiting for
+const QUOTE_TIMEOUT: Duration = Duration::from_secs(5);
+// Deliveries waiting for room in the outbound queue, the backoff doubles on each attempt
+const DELIVERY_MAX_ATTEMPTS: u32 = 4;
+const DELIVERY_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Returned when the peer does not answer a request within the timeout configured for it.
+#[derive(Debug)]
+pub struct RequestTimeout {
+  pub request: &'static str,
+  pub timeout: Duration,
+}
+
+impl std::fmt::Display for RequestTimeout {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{} request timed out after {}s", self.request, self.timeout.as_secs_f64())
+  }
+}
+
+impl Error for RequestTimeout {}
+
+/// Returned when the last connection to the peer closes before it answers a request.
+#[derive(Debug)]
+pub struct PeerDisconnected {
+  pub request: &'static str,
+}
+
+impl std::fmt::Display for PeerDisconnected {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{} request failed, the peer disconnected", self.request)
+  }
+}
+
+impl Error for PeerDisconnected {}
+
+/// Returned when the peer answers a retrieve request telling it does not hold the lease.
+#[derive(Debug, Clone)]
+pub struct RetrieveNotFound {
+  pub nonce: u64,
+}
+
+impl std::fmt::Display for RetrieveNotFound {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "lease with nonce {} not found by the peer", self.nonce)
+  }
+}
+
+impl Error for RetrieveNotFound {}
+
+/// Answer of the lessor to a lease proposal, a sealed one is only a hint until checked on chain.
+#[derive(Debug, Clone)]
+pub enum ProposalResponse {
+  Rejected(String),
+  Sealed(H256),
+}
+
+/// Answer of a peer to a quote request, telling whether it would accept a proposal with the quoted terms.
+#[derive(Debug, Clone)]
+pub enum QuoteResponse {
+  Accepted,
+  Rejected(String),
+}
+
+/// Request sent to a peer whose response is still awaited.
+#[derive(Debug, Clone)]
+pub struct PendingOperation {
+  pub kind: PendingOperationKind,
+  pub peer_id: PeerId,
+  // Nonce of the lease, or the quote id for quotes
+  pub id: u64,
+  pub age: Duration,
+  pub listeners: usize,
+}
+
+#[derive(Debug, Clone)]
+pub enum PendingOperationKind {
+  Challenge { block_number: u32 },
+  Retrieve,
+  Proposal,
+  Quote,
+}
+
 pub enum Event {
-  ReceivedLeaseProposal { peer_id: PeerId, proposal: LeaseProposal },
-  ReceivedChallengeRequest { peer_id: PeerId, challenge_key: ChallengeKey },
-  ReceivedRetrieveRequest { peer_id: PeerId, nonce: u64 },
+  ReceivedLeaseProposal {
+    peer_id: PeerId,
+    proposal: LeaseProposal,
+  },
+  ReceivedChallengeRequest {
+    peer_id: PeerId,
+    challenge_key: ChallengeKey,
+  },
+  ReceivedRetrieveRequest {
+    peer_id: PeerId,
+    nonce: u64,
+  },
+  ReceivedQuoteRequest {
+    peer_id: PeerId,
+    quote_id: u64,
+    lease_terms: LeaseTerms,
+    size: usize,
+  },
+  PeerIdentified {
+    peer_id: PeerId,
+    listen_addrs: Vec<Multiaddr>,
+  },
 }
 
 #[async_trait]
@@ -37,13 +169,29 @@ pub trait Service: Stream<Item = Event> + Send + Sync + Clone + Unpin + 'static
     terms: LeaseTerms,
     signature: Signature,
     data: Vec<u8>,
-  ) -> String;
-  async fn send_challenge_proof(&self, peer_id: PeerId, challenge_key: ChallengeKey, challenge_proof: ChallengeProof);
-  async fn send_retrieve_delivery(&self, peer_id: PeerId, nonce: u64, data: Vec<u8>);
-  async fn send_proposal_rejection(&self, peer_id: PeerId, nonce: u64, reason: String);
+  ) -> anyhow::Result<ProposalResponse>;
+  async fn send_challenge_proof(
+    &self,
+    peer_id: PeerId,
+    challenge_key: ChallengeKey,
+    challenge_proof: ChallengeProof,
+  ) -> anyhow::Result<()>;
+  async fn send_retrieve_delivery(&self, peer_id: PeerId, nonce: u64, data: Vec<u8>) -> anyhow::Result<()>;
+  async fn send_retrieve_not_found(&self, peer_id: PeerId, nonce: u64) -> anyhow::Result<()>;
+  async fn send_proposal_rejection(&self, peer_id: PeerId, nonce: u64, reason: String) -> anyhow::Result<()>;
+  async fn send_lease_sealed(&self, peer_id: PeerId, nonce: u64, transaction_hash: H256) -> anyhow::Result<()>;
   async fn retrieve(&self, peer_id: PeerId, nonce: u64) -> anyhow::Result<Vec<u8>>;
+  async fn quote(&self, peer_id: PeerId, lease_terms: &LeaseTerms, size: usize) -> anyhow::Result<QuoteResponse>;
+  async fn send_quote_response(&self, peer_id: PeerId, quote_id: u64, response: QuoteResponse) -> anyhow::Result<()>;
   fn find_public_key(&self, peer_id: &PeerId) -> Option<secp256k1::PublicKey>;
   fn known_peers(&self) -> Vec<PeerId>;
+  fn local_peer_id(&self) -> PeerId;
+  /// Protocol version announced with identify, which includes the network id.
+  fn protocol_version(&self) -> String;
+  /// Challenges, retrieves, proposals and quotes waiting for the response of a peer, oldest first.
+  fn pending_operations(&self) -> Vec<PendingOperation>;
+  /// Dials a peer on the given addresses, skipped when connected or dialed recently.
+  fn dial(&self, peer_id: PeerId, addresses: Vec<Multiaddr>);
 }
 
 struct TokioExecutor {}
@@ -54,31 +202,160 @@ impl Executor for TokioExecutor {
   }
 }
 
-pub async fn create_p2p(keypair: Keypair, mdns_enabled: bool) -> Result<impl Service, Box<dyn Error>> {
+pub async fn create_p2p(keypair: Keypair, params: P2pParams) -> Result<impl Service, Box<dyn Error>> {
   let transport = transport::build_transport(keypair.clone())?;
-  let behaviour = behaviour::Behaviour::new(keypair.public(), mdns_enabled).await?;
+  let listen_addrs = if params.listen_addresses.is_empty() {
+    DEFAULT_LISTEN_ADDRESSES
+      .iter()
+      .map(|a| a.parse())
+      .collect::<Result<Vec<Multiaddr>, _>>()?
+  } else {
+    params.listen_addresses.clone()
+  };
+  create_p2p_with_transport(keypair, params, transport, listen_addrs).await
+}
+
+pub async fn create_p2p_with_transport(
+  keypair: Keypair,
+  params: P2pParams,
+  transport: transport::TTransport,
+  listen_addrs: Vec<Multiaddr>,
+) -> Result<impl Service, Box<dyn Error>> {
+  build_p2p(keypair, params, transport, listen_addrs).await
+}
+
+async fn build_p2p(
+  keypair: Keypair,
+  params: P2pParams,
+  transport: transport::TTransport,
+  listen_addrs: Vec<Multiaddr>,
+) -> Result<Implementation, Box<dyn Error>> {
+  let public_keys: behaviour::PublicKeys = Arc::new(RwLock::new(HashMap::new()));
+  let behaviour = behaviour::Behaviour::new(keypair.public(), &params, public_keys.clone()).await?;
   let local_peer_id = PeerId::from_public_key(keypair.public().borrow());
+  let connection_limits = ConnectionLimits::default()
+    .with_max_established(Some(params.max_established))
+    .with_max_pending_incoming(Some(params.max_pending))
+    .with_max_pending_outgoing(Some(params.max_pending))
+    .with_max_established_per_peer(Some(params.max_established_per_peer));
   let mut swarm = SwarmBuilder::new(transport, behaviour, local_peer_id)
     .executor(Box::new(TokioExecutor {}))
+    .connection_limits(connection_limits)
     .build();
   debug!("swarm build with local peer id {}", local_peer_id);
-  // TODO Make address parametrized
-  swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+  // A family missing in the host fails to bind, it is enough that one of the addresses can be listened on
+  let mut listening = false;
+  for listen_addr in listen_addrs {
+    match swarm.listen_on(listen_addr.clone()) {
+      Ok(_) => listening = true,
+      Err(e) => warn!("could not listen on {}: {}", listen_addr, e),
+    }
+  }
+  if !listening {
+    return Err("could not listen on any of the addresses".into());
+  }
 
   Ok(Implementation {
     behaviour: Arc::new(Mutex::new(swarm)),
+    timeouts: Timeouts {
+      challenge: params.challenge_timeout,
+      retrieve: params.retrieve_timeout,
+      proposal: params.proposal_timeout,
+    },
+    retrieve_retries: params.retrieve_retries,
+    public_keys,
     pending_challenges: Arc::new(Mutex::new(OneshotListerners::new())),
     pending_retrieves: Arc::new(Mutex::new(OneshotListerners::new())),
     pending_proposals: Arc::new(Mutex::new(OneshotListerners::new())),
+    pending_quotes: Arc::new(Mutex::new(OneshotListerners::new())),
   })
 }
 
-// TODO pending_* timeouts and cleanup
+#[derive(Clone, Copy)]
+struct Timeouts {
+  challenge: Duration,
+  retrieve: Duration,
+  proposal: Duration,
+}
+
 struct Implementation {
   behaviour: Arc<Mutex<Swarm<behaviour::Behaviour>>>,
+  timeouts: Timeouts,
+  retrieve_retries: u32,
+  public_keys: behaviour::PublicKeys,
   pending_challenges: Arc<Mutex<OneshotListerners<(PeerId, ChallengeKey), ChallengeProof>>>,
-  pending_retrieves: Arc<Mutex<OneshotListerners<(PeerId, u64), Vec<u8>>>>,
-  pending_proposals: Arc<Mutex<OneshotListerners<(PeerId, u64), String>>>,
+  pending_retrieves: Arc<Mutex<OneshotListerners<(PeerId, u64), Result<Vec<u8>, RetrieveNotFound>>>>,
+  pending_proposals: Arc<Mutex<OneshotListerners<(PeerId, u64), ProposalResponse>>>,
+  pending_quotes: Arc<Mutex<OneshotListerners<(PeerId, u64), QuoteResponse>>>,
+}
+
+/// Drops the listeners of `key` that stopped waiting, whether the wait timed out or its future was dropped by the caller.
+struct CanceledListenersCleanup<'a, K: std::hash::Hash + std::cmp::Eq, V: Clone> {
+  pending: &'a Arc<Mutex<OneshotListerners<K, V>>>,
+  key: K,
+}
+
+impl<'a, K: std::hash::Hash + std::cmp::Eq, V: Clone> Drop for CanceledListenersCleanup<'a, K, V> {
+  fn drop(&mut self) {
+    self.pending.lock().unwrap().remove_canceled(&self.key);
+  }
+}
+
+/// Waits for the response to a request, the listener is removed from the pending ones when `timeout` elapses or the
+/// returned future is dropped before the response arrives.
+async fn wait_response<K, V, L>(
+  pending: &Arc<Mutex<OneshotListerners<K, V>>>,
+  key: K,
+  listener: L,
+  request: &'static str,
+  timeout: Duration,
+) -> anyhow::Result<V>
+where
+  K: std::hash::Hash + std::cmp::Eq,
+  V: Clone,
+  L: Future<Output = Result<V, Canceled>>,
+{
+  let cleanup = CanceledListenersCleanup { pending, key };
+  // The listener is dropped before the cleanup, also when this future is, so its sender is seen as canceled
+  let response = tokio::time::timeout(timeout, listener).await;
+  drop(cleanup);
+  match response {
+    Ok(Ok(value)) => Ok(value),
+    Ok(Err(Canceled)) => Err(PeerDisconnected { request }.into()),
+    Err(_) => Err(RequestTimeout { request, timeout }.into()),
+  }
+}
+
+fn pending_of<K, V>(
+  pending: &Arc<Mutex<OneshotListerners<(PeerId, K), V>>>,
+  kind: impl Fn(&K) -> (PendingOperationKind, u64),
+) -> Vec<PendingOperation>
+where
+  K: std::hash::Hash + std::cmp::Eq,
+  V: Clone,
+{
+  let pending = pending.lock().unwrap();
+  pending
+    .pending()
+    .map(|((peer_id, key), age, listeners)| {
+      let (kind, id) = kind(key);
+      PendingOperation {
+        kind,
+        peer_id: *peer_id,
+        id,
+        age,
+        listeners,
+      }
+    })
+    .collect()
+}
+
+/// Fails the listeners waiting on a response from `peer_id`.
+fn cancel_peer<K: std::hash::Hash + std::cmp::Eq, V: Clone>(
+  pending: &Arc<Mutex<OneshotListerners<(PeerId, K), V>>>,
+  peer_id: &PeerId,
+) -> usize {
+  pending.lock().unwrap().cancel_matching(|(p, _)| p == peer_id)
 }
 
 trait Notify<K, V> {
@@ -92,14 +369,14 @@ impl<K: std::hash::Hash + std::cmp::Eq, V: Clone> Notify<K, V> for Arc<Mutex<One
 }
 
 trait Listeners<K, V> {
-  type FutureType: Future<Output = V>;
+  type FutureType: Future<Output = Result<V, Canceled>>;
   fn new_listener(&self, key: K) -> Self::FutureType;
 }
 
 impl<K: std::hash::Hash + std::cmp::Eq + 'static, V: Clone + Send + 'static> Listeners<K, V>
   for Arc<Mutex<OneshotListerners<K, V>>>
 {
-  type FutureType = Box<dyn Future<Output = V> + Send + Sync + Unpin + 'static>;
+  type FutureType = Box<dyn Future<Output = Result<V, Canceled>> + Send + Sync + Unpin + 'static>;
 
   fn new_listener(&self, key: K) -> Self::FutureType {
     Box::new(self.lock().unwrap().new_listener(key))
@@ -110,9 +387,13 @@ impl Clone for Implementation {
   fn clone(&self) -> Self {
     Implementation {
       behaviour: Arc::clone(&self.behaviour),
+      timeouts: self.timeouts,
+      retrieve_retries: self.retrieve_retries,
+      public_keys: Arc::clone(&self.public_keys),
       pending_challenges: Arc::clone(&self.pending_challenges),
       pending_retrieves: Arc::clone(&self.pending_retrieves),
       pending_proposals: Arc::clone(&self.pending_proposals),
+      pending_quotes: Arc::clone(&self.pending_quotes),
     }
   }
 }
@@ -121,7 +402,8 @@ impl Stream for Implementation {
   type Item = Event;
 
   fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-    while let Poll::Ready(e) = futures::stream::StreamExt::poll_next_unpin(self.behaviour.lock().unwrap().deref_mut(), cx) {
+    let mut guard = self.behaviour.lock().unwrap();
+    while let Poll::Ready(e) = futures::stream::StreamExt::poll_next_unpin(guard.deref_mut(), cx) {
       match e {
         Some(SwarmEvent::Behaviour(be)) => match be {
           behaviour::Event::ReceivedLeaseProposal { peer_id, proposal } => {
@@ -149,13 +431,38 @@ impl Stream for Implementation {
             return Poll::Ready(Some(Event::ReceivedRetrieveRequest { peer_id, nonce }));
           }
           behaviour::Event::ReceivedRetrieveDelivery { peer_id, nonce, data } => {
-            let count = self.pending_retrieves.notify(&(peer_id, nonce), data);
+            let count = self.pending_retrieves.notify(&(peer_id, nonce), Ok(data));
             if count == 0 {
               warn!("received retrieve delivery not expected peer_id={} nonce={}", peer_id, nonce);
             }
           }
+          behaviour::Event::ReceivedRetrieveNotFound { peer_id, nonce } => {
+            let count = self
+              .pending_retrieves
+              .notify(&(peer_id, nonce), Err(RetrieveNotFound { nonce }));
+            if count == 0 {
+              warn!("received retrieve not found not expected peer_id={} nonce={}", peer_id, nonce);
+            }
+          }
+          behaviour::Event::ReceivedLeaseSealed {
+            peer_id,
+            nonce,
+            transaction_hash,
+          } => {
+            let count = self
+              .pending_proposals
+              .notify(&(peer_id, nonce), ProposalResponse::Sealed(transaction_hash));
+            if count == 0 {
+              warn!(
+                "received a lease sealed not expected peer_id={} nonce={} transaction_hash={:?}",
+                peer_id, nonce, transaction_hash
+              );
+            }
+          }
           behaviour::Event::ReceivedLeaseProposalRejection { peer_id, nonce, reason } => {
-            let count = self.pending_proposals.notify(&(peer_id, nonce), reason.clone());
+            let count = self
+              .pending_proposals
+              .notify(&(peer_id, nonce), ProposalResponse::Rejected(reason.clone()));
             if count == 0 {
               warn!(
                 "received a proposal rejection not expected peer_id={} nonce={} reason={}",
@@ -163,7 +470,61 @@ impl Stream for Implementation {
               );
             }
           }
+          behaviour::Event::ReceivedQuoteRequest {
+            peer_id,
+            quote_id,
+            lease_terms,
+            size,
+          } => {
+            return Poll::Ready(Some(Event::ReceivedQuoteRequest {
+              peer_id,
+              quote_id,
+              lease_terms,
+              size,
+            }));
+          }
+          behaviour::Event::ReceivedQuoteResponse {
+            peer_id,
+            quote_id,
+            response,
+          } => {
+            let count = self.pending_quotes.notify(&(peer_id, quote_id), response);
+            if count == 0 {
+              warn!(
+                "received a quote response not expected peer_id={} quote_id={}",
+                peer_id, quote_id
+              );
+            }
+          }
+          behaviour::Event::PeerIdentified { peer_id, listen_addrs } => {
+            return Poll::Ready(Some(Event::PeerIdentified { peer_id, listen_addrs }));
+          }
+          behaviour::Event::PeerDisconnected { peer_id } => {
+            let count = cancel_peer(&self.pending_challenges, &peer_id)
+              + cancel_peer(&self.pending_retrieves, &peer_id)
+              + cancel_peer(&self.pending_proposals, &peer_id)
+              + cancel_peer(&self.pending_quotes, &peer_id);
+            if count > 0 {
+              debug!(
+                "failed pending requests of disconnected peer peer_id={} count={}",
+                peer_id, count
+              );
+            }
+          }
         },
+        Some(SwarmEvent::NewListenAddr { address, .. }) => {
+          info!("listening on {}", address);
+        }
+        Some(SwarmEvent::ConnectionClosed {
+          peer_id,
+          num_established,
+          ..
+        }) => {
+          trace!("connection closed peer_id={} num_established={}", peer_id, num_established);
+          if num_established == 0 {
+            guard.behaviour_mut().forget_peer(&peer_id);
+          }
+        }
         Some(other) => {
           trace!("TODO: swarm: {:?}", other);
         }
@@ -179,15 +540,20 @@ impl Stream for Implementation {
 #[async_trait]
 impl Service for Implementation {
   async fn challenge(&self, peer_id: PeerId, challenge_key: ChallengeKey) -> anyhow::Result<ChallengeProof> {
-    let listener = self.pending_challenges.new_listener((peer_id, challenge_key.clone()));
-    self
-      .behaviour
-      .lock()
-      .unwrap()
-      .behaviour_mut()
-      .p2pim
-      .send_challenge(peer_id, challenge_key);
-    Ok(listener.await)
+    // The listener is registered while holding the swarm, so the response cannot be processed before it exists
+    let listener = {
+      let mut guard = self.behaviour.lock().unwrap();
+      guard.behaviour_mut().p2pim.send_challenge(peer_id, challenge_key.clone())?;
+      self.pending_challenges.new_listener((peer_id, challenge_key.clone()))
+    };
+    wait_response(
+      &self.pending_challenges,
+      (peer_id, challenge_key),
+      listener,
+      "challenge",
+      self.timeouts.challenge,
+    )
+    .await
   }
 
   async fn send_proposal(
@@ -197,64 +563,516 @@ impl Service for Implementation {
     terms: LeaseTerms,
     signature: Signature,
     data: Vec<u8>,
-  ) -> String {
-    let listener = self.pending_proposals.new_listener((peer_id, nonce));
-    self.behaviour.lock().unwrap().behaviour_mut().p2pim.send_proposal(
-      peer_id,
-      p2pim::LeaseProposal {
-        nonce,
-        lease_terms: terms,
-        signature,
-        data,
-      },
-    );
-    listener.await
+  ) -> anyhow::Result<ProposalResponse> {
+    let listener = {
+      let mut guard = self.behaviour.lock().unwrap();
+      guard.behaviour_mut().p2pim.send_proposal(
+        peer_id,
+        p2pim::LeaseProposal {
+          nonce,
+          lease_terms: terms,
+          signature,
+          data,
+        },
+      )?;
+      self.pending_proposals.new_listener((peer_id, nonce))
+    };
+    wait_response(
+      &self.pending_proposals,
+      (peer_id, nonce),
+      listener,
+      "proposal",
+      self.timeouts.proposal,
+    )
+    .await
   }
 
-  async fn send_challenge_proof(&self, peer_id: PeerId, challenge_key: ChallengeKey, challenge_proof: ChallengeProof) {
+  async fn send_challenge_proof(
+    &self,
+    peer_id: PeerId,
+    challenge_key: ChallengeKey,
+    challenge_proof: ChallengeProof,
+  ) -> anyhow::Result<()> {
     let mut guard = self.behaviour.lock().unwrap();
     guard
       .behaviour_mut()
       .p2pim
-      .send_challenge_proof(peer_id, challenge_key, challenge_proof);
+      .send_challenge_proof(peer_id, challenge_key, challenge_proof)?;
+    Ok(())
   }
 
-  async fn send_retrieve_delivery(&self, peer_id: PeerId, nonce: u64, data: Vec<u8>) {
+  async fn send_retrieve_delivery(&self, peer_id: PeerId, nonce: u64, data: Vec<u8>) -> anyhow::Result<()> {
+    let mut backoff = DELIVERY_RETRY_BACKOFF;
+    for attempt in 1..DELIVERY_MAX_ATTEMPTS {
+      {
+        let mut guard = self.behaviour.lock().unwrap();
+        let p2pim = &mut guard.behaviour_mut().p2pim;
+        if !p2pim.is_queue_full() {
+          p2pim.send_retrieve_delivery(peer_id, nonce, data)?;
+          return Ok(());
+        }
+      }
+      warn!(
+        "outbound queue full, retrying delivery peer_id={} nonce={} attempt={} backoff={:?}",
+        peer_id, nonce, attempt, backoff
+      );
+      tokio::time::sleep(backoff).await;
+      backoff *= 2;
+    }
     let mut guard = self.behaviour.lock().unwrap();
-    guard.behaviour_mut().p2pim.send_retrieve_delivery(peer_id, nonce, data);
+    guard.behaviour_mut().p2pim.send_retrieve_delivery(peer_id, nonce, data)?;
+    Ok(())
   }
 
-  async fn send_proposal_rejection(&self, peer_id: PeerId, nonce: u64, reason: String) {
+  async fn send_retrieve_not_found(&self, peer_id: PeerId, nonce: u64) -> anyhow::Result<()> {
     let mut guard = self.behaviour.lock().unwrap();
-    guard.behaviour_mut().p2pim.send_proposal_rejection(peer_id, nonce, reason);
+    guard.behaviour_mut().p2pim.send_retrieve_not_found(peer_id, nonce)?;
+    Ok(())
   }
 
-  async fn retrieve(&self, peer_id: PeerId, nonce: u64) -> anyhow::Result<Vec<u8>> {
-    let listener = self.pending_retrieves.new_listener((peer_id, nonce));
-    self
-      .behaviour
-      .lock()
-      .unwrap()
+  async fn send_proposal_rejection(&self, peer_id: PeerId, nonce: u64, reason: String) -> anyhow::Result<()> {
+    let mut guard = self.behaviour.lock().unwrap();
+    guard.behaviour_mut().p2pim.send_proposal_rejection(peer_id, nonce, reason)?;
+    Ok(())
+  }
+
+  async fn send_lease_sealed(&self, peer_id: PeerId, nonce: u64, transaction_hash: H256) -> anyhow::Result<()> {
+    let mut guard = self.behaviour.lock().unwrap();
+    guard
       .behaviour_mut()
       .p2pim
-      .send_retrieve_request(peer_id, nonce);
-    let data = listener.await;
-    Ok(data)
+      .send_lease_sealed(peer_id, nonce, transaction_hash)?;
+    Ok(())
+  }
+
+  async fn retrieve(&self, peer_id: PeerId, nonce: u64) -> anyhow::Result<Vec<u8>> {
+    let mut retries = 0;
+    loop {
+      let listener = {
+        let mut guard = self.behaviour.lock().unwrap();
+        guard.behaviour_mut().p2pim.send_retrieve_request(peer_id, nonce)?;
+        self.pending_retrieves.new_listener((peer_id, nonce))
+      };
+      let result = wait_response(
+        &self.pending_retrieves,
+        (peer_id, nonce),
+        listener,
+        "retrieve",
+        self.timeouts.retrieve,
+      )
+      .await;
+      match result {
+        Ok(delivery) => return delivery.map_err(anyhow::Error::from),
+        Err(e) if e.is::<RequestTimeout>() && retries < self.retrieve_retries => {
+          retries += 1;
+          warn!(
+            "delivery not received, requesting again peer_id={} nonce={} retry={}/{}",
+            peer_id, nonce, retries, self.retrieve_retries
+          );
+        }
+        Err(e) => return Err(e),
+      }
+    }
+  }
+
+  async fn quote(&self, peer_id: PeerId, lease_terms: &LeaseTerms, size: usize) -> anyhow::Result<QuoteResponse> {
+    let quote_id = rand::random();
+    let listener = {
+      let mut guard = self.behaviour.lock().unwrap();
+      guard
+        .behaviour_mut()
+        .p2pim
+        .send_quote_request(peer_id, quote_id, lease_terms, size)?;
+      self.pending_quotes.new_listener((peer_id, quote_id))
+    };
+    wait_response(&self.pending_quotes, (peer_id, quote_id), listener, "quote", QUOTE_TIMEOUT).await
+  }
+
+  async fn send_quote_response(&self, peer_id: PeerId, quote_id: u64, response: QuoteResponse) -> anyhow::Result<()> {
+    let mut guard = self.behaviour.lock().unwrap();
+    guard.behaviour_mut().p2pim.send_quote_response(peer_id, quote_id, response)?;
+    Ok(())
   }
 
   fn find_public_key(&self, peer_id: &PeerId) -> Option<PublicKey> {
-    let guard = self.behaviour.lock().unwrap();
-    guard.behaviour().peer_info(peer_id).and_then(|i| {
-      if let libp2p::identity::PublicKey::Secp256k1(p) = i.public_key.clone() {
-        Some(p)
-      } else {
-        None
+    self.public_keys.read().unwrap().get(peer_id).cloned()
+  }
+
+  fn known_peers(&self) -> Vec<PeerId> {
+    self.public_keys.read().unwrap().keys().cloned().collect()
+  }
+
+  fn local_peer_id(&self) -> PeerId {
+    *self.behaviour.lock().unwrap().local_peer_id()
+  }
+
+  fn protocol_version(&self) -> String {
+    self.behaviour.lock().unwrap().behaviour().protocol_version().to_string()
+  }
+
+  fn pending_operations(&self) -> Vec<PendingOperation> {
+    let mut operations = pending_of(&self.pending_challenges, |key| {
+      let kind = PendingOperationKind::Challenge {
+        block_number: key.block_number,
+      };
+      (kind, key.nonce)
+    });
+    operations.extend(pending_of(&self.pending_retrieves, |nonce| {
+      (PendingOperationKind::Retrieve, *nonce)
+    }));
+    operations.extend(pending_of(&self.pending_proposals, |nonce| {
+      (PendingOperationKind::Proposal, *nonce)
+    }));
+    operations.extend(pending_of(&self.pending_quotes, |quote_id| {
+      (PendingOperationKind::Quote, *quote_id)
+    }));
+    operations.sort_by_key(|o| std::cmp::Reverse(o.age));
+    operations
+  }
+
+  fn dial(&self, peer_id: PeerId, addresses: Vec<Multiaddr>) {
+    self.behaviour.lock().unwrap().behaviour_mut().dial(peer_id, addresses);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testutil::{lease_terms, memory_address, p2p_params, signature};
+  use futures::channel::mpsc;
+  use futures::StreamExt;
+  use web3::types::Address;
+
+  struct Node {
+    p2p: Implementation,
+    peer_id: PeerId,
+    address: Multiaddr,
+    events: mpsc::UnboundedReceiver<Event>,
+  }
+
+  /// Node on the in-memory transport, its swarm polled in the background.
+  async fn node(params: P2pParams) -> Node {
+    let keypair = Keypair::generate_secp256k1();
+    let peer_id = PeerId::from_public_key(&keypair.public());
+    let address = memory_address();
+    let transport = transport::build_memory_transport(keypair.clone()).unwrap();
+    let p2p = build_p2p(keypair, params, transport, vec![address.clone()]).await.unwrap();
+    let (sender, events) = mpsc::unbounded();
+    let mut stream = p2p.clone();
+    tokio::spawn(async move {
+      while let Some(event) = stream.next().await {
+        if sender.unbounded_send(event).is_err() {
+          break;
+        }
+      }
+    });
+    Node {
+      p2p,
+      peer_id,
+      address,
+      events,
+    }
+  }
+
+  async fn next_event(node: &mut Node) -> Event {
+    tokio::time::timeout(Duration::from_secs(5), node.events.next())
+      .await
+      .expect("no event received")
+      .unwrap()
+  }
+
+  async fn identified(node: &mut Node, peer_id: PeerId) {
+    loop {
+      if let Event::PeerIdentified { peer_id: p, .. } = next_event(node).await {
+        if p == peer_id {
+          return;
+        }
       }
+    }
+  }
+
+  /// Two nodes identified by each other.
+  async fn connected_nodes(params_a: P2pParams, params_b: P2pParams) -> (Node, Node) {
+    let mut a = node(params_a).await;
+    let mut b = node(params_b).await;
+    a.p2p.dial(b.peer_id, vec![b.address.clone()]);
+    identified(&mut a, b.peer_id).await;
+    identified(&mut b, a.peer_id).await;
+    (a, b)
+  }
+
+  #[tokio::test]
+  async fn public_key_found_without_the_swarm() {
+    let (a, b) = connected_nodes(p2p_params(), p2p_params()).await;
+
+    // Held as while the swarm is polled, the lookups would block on it otherwise
+    let _swarm = a.p2p.behaviour.lock().unwrap();
+    let public_key = a.p2p.find_public_key(&b.peer_id).unwrap();
+    assert_eq!(
+      PeerId::from_public_key(&libp2p::identity::PublicKey::Secp256k1(public_key)),
+      b.peer_id
+    );
+    assert_eq!(a.p2p.known_peers(), vec![b.peer_id]);
+  }
+
+  #[tokio::test]
+  async fn local_peer_id_derived_from_the_keypair() {
+    let keypair = Keypair::generate_secp256k1();
+    let transport = transport::build_memory_transport(keypair.clone()).unwrap();
+
+    let p2p = build_p2p(keypair.clone(), p2p_params(), transport, vec![memory_address()])
+      .await
+      .unwrap();
+
+    assert_eq!(p2p.local_peer_id(), PeerId::from_public_key(&keypair.public()));
+  }
+
+  #[tokio::test]
+  async fn protocol_version_includes_the_network() {
+    let default = node(p2p_params()).await;
+    let network = node(P2pParams {
+      network_id: Some("testnet".to_string()),
+      ..p2p_params()
     })
+    .await;
+
+    assert_eq!(default.p2p.protocol_version(), behaviour::PROTOCOL_VERSION);
+    assert_eq!(
+      network.p2p.protocol_version(),
+      format!("{}/testnet", behaviour::PROTOCOL_VERSION)
+    );
   }
 
-  fn known_peers(&self) -> Vec<PeerId> {
-    let guard = self.behaviour.lock().unwrap();
-    guard.behaviour().known_peers()
+  #[tokio::test]
+  async fn full_queue_signals_back_pressure() {
+    let keypair = Keypair::generate_secp256k1();
+    let transport = transport::build_memory_transport(keypair.clone()).unwrap();
+    let params = P2pParams {
+      max_queued_messages: 2,
+      ..p2p_params()
+    };
+    // The swarm is never polled, so the queue is not drained
+    let p2p = build_p2p(keypair, params, transport, vec![memory_address()]).await.unwrap();
+    let peer_id = PeerId::random();
+
+    for nonce in 1..=2 {
+      p2p.send_proposal_rejection(peer_id, nonce, "busy".to_string()).await.unwrap();
+    }
+    let error = p2p.send_proposal_rejection(peer_id, 3, "busy".to_string()).await.unwrap_err();
+
+    let full = error.downcast_ref::<p2pim::QueueFullError>().unwrap();
+    assert_eq!(full.max_queued_messages, 2);
+  }
+
+  #[tokio::test]
+  async fn lease_proposal_sent_over_memory() {
+    let (a, mut b) = connected_nodes(p2p_params(), p2p_params()).await;
+    let terms = lease_terms(Address::repeat_byte(9));
+    let response = tokio::spawn({
+      let (a, peer_id) = (a.p2p.clone(), b.peer_id);
+      async move { a.send_proposal(peer_id, 1, terms, signature(), b"some data".to_vec()).await }
+    });
+
+    let proposal = loop {
+      if let Event::ReceivedLeaseProposal { peer_id, proposal } = next_event(&mut b).await {
+        assert_eq!(peer_id, a.peer_id);
+        break proposal;
+      }
+    };
+    assert_eq!(proposal.nonce, 1);
+    assert_eq!(proposal.lease_terms.token_address, Address::repeat_byte(9));
+    assert_eq!(proposal.data, b"some data");
+    b.p2p.send_lease_sealed(a.peer_id, 1, H256::repeat_byte(7)).await.unwrap();
+
+    assert!(matches!(
+      response.await.unwrap().unwrap(),
+      ProposalResponse::Sealed(hash) if hash == H256::repeat_byte(7)
+    ));
+  }
+
+  #[tokio::test]
+  async fn connections_beyond_the_limit_refused() {
+    let limited = P2pParams {
+      max_established: 1,
+      ..p2p_params()
+    };
+    let (a, _b) = connected_nodes(limited, p2p_params()).await;
+    let mut c = node(p2p_params()).await;
+
+    c.p2p.dial(a.peer_id, vec![a.address.clone()]);
+
+    let identified_a = tokio::time::timeout(Duration::from_secs(1), identified(&mut c, a.peer_id)).await;
+    assert!(identified_a.is_err());
+    assert!(c.p2p.find_public_key(&a.peer_id).is_none());
+    assert_eq!(a.p2p.known_peers().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn challenge_timed_out_with_its_own_timeout() {
+    let params = P2pParams {
+      challenge_timeout: Duration::from_millis(200),
+      retrieve_timeout: Duration::from_millis(400),
+      ..p2p_params()
+    };
+    // The other node has no reactor, nothing answers the requests
+    let (a, b) = connected_nodes(params, p2p_params()).await;
+    let timeout_of = |error: anyhow::Error| error.downcast_ref::<RequestTimeout>().unwrap().timeout;
+
+    let challenge = a.p2p.challenge(
+      b.peer_id,
+      ChallengeKey {
+        nonce: 1,
+        block_number: 0,
+      },
+    );
+    let started = std::time::Instant::now();
+    let challenge_error = challenge.await.unwrap_err();
+    assert_eq!(timeout_of(challenge_error), Duration::from_millis(200));
+    assert!(started.elapsed() < Duration::from_millis(400));
+
+    let retrieve_error = a.p2p.retrieve(b.peer_id, 1).await.unwrap_err();
+    assert_eq!(timeout_of(retrieve_error), Duration::from_millis(400));
+  }
+
+  #[tokio::test]
+  async fn retrieve_of_an_unknown_lease_failed_before_the_timeout() {
+    let params = P2pParams {
+      retrieve_timeout: Duration::from_secs(30),
+      ..p2p_params()
+    };
+    let (a, mut b) = connected_nodes(params, p2p_params()).await;
+    let started = std::time::Instant::now();
+    let retrieve = tokio::spawn({
+      let (a, peer_id) = (a.p2p.clone(), b.peer_id);
+      async move { a.retrieve(peer_id, 9).await }
+    });
+    while !matches!(next_event(&mut b).await, Event::ReceivedRetrieveRequest { .. }) {}
+
+    b.p2p.send_retrieve_not_found(a.peer_id, 9).await.unwrap();
+
+    let error = retrieve.await.unwrap().unwrap_err();
+    assert!(
+      matches!(error.downcast_ref::<RetrieveNotFound>(), Some(RetrieveNotFound { nonce: 9 })),
+      "{}",
+      error
+    );
+    assert!(started.elapsed() < Duration::from_secs(5));
+  }
+
+  #[tokio::test]
+  async fn pending_retrieve_reported() {
+    let (a, mut b) = connected_nodes(p2p_params(), p2p_params()).await;
+    let retrieve = tokio::spawn({
+      let (a, peer_id) = (a.p2p.clone(), b.peer_id);
+      async move { a.retrieve(peer_id, 7).await }
+    });
+    while !matches!(next_event(&mut b).await, Event::ReceivedRetrieveRequest { .. }) {}
+
+    let operations = a.p2p.pending_operations();
+
+    assert_eq!(operations.len(), 1);
+    assert!(matches!(operations[0].kind, PendingOperationKind::Retrieve));
+    assert_eq!((operations[0].peer_id, operations[0].id), (b.peer_id, 7));
+    assert_eq!(operations[0].listeners, 1);
+    retrieve.abort();
+  }
+
+  #[tokio::test]
+  async fn delivery_requested_again_when_lost() {
+    let params = P2pParams {
+      retrieve_timeout: Duration::from_millis(300),
+      retrieve_retries: 1,
+      ..p2p_params()
+    };
+    let (a, mut b) = connected_nodes(params, p2p_params()).await;
+    let retrieve = tokio::spawn({
+      let (a, peer_id) = (a.p2p.clone(), b.peer_id);
+      async move { a.retrieve(peer_id, 1).await }
+    });
+
+    // The first delivery is lost, only the request sent again is answered
+    for attempt in 0..2 {
+      loop {
+        if let Event::ReceivedRetrieveRequest { peer_id, nonce } = next_event(&mut b).await {
+          assert_eq!((peer_id, nonce), (a.peer_id, 1));
+          break;
+        }
+      }
+      if attempt == 1 {
+        b.p2p
+          .send_retrieve_delivery(a.peer_id, 1, b"some data".to_vec())
+          .await
+          .unwrap();
+      }
+    }
+
+    assert_eq!(retrieve.await.unwrap().unwrap(), b"some data");
+  }
+
+  #[tokio::test]
+  async fn abandoned_proposal_listener_removed() {
+    let (a, mut b) = connected_nodes(p2p_params(), p2p_params()).await;
+    let proposal = tokio::spawn({
+      let (a, peer_id) = (a.p2p.clone(), b.peer_id);
+      let terms = lease_terms(Address::repeat_byte(9));
+      async move { a.send_proposal(peer_id, 1, terms, signature(), b"some data".to_vec()).await }
+    });
+    while !matches!(next_event(&mut b).await, Event::ReceivedLeaseProposal { .. }) {}
+    assert_eq!(a.p2p.pending_operations().len(), 1);
+
+    proposal.abort();
+    assert!(proposal.await.unwrap_err().is_cancelled());
+
+    assert!(a.p2p.pending_operations().is_empty());
+  }
+
+  #[tokio::test]
+  async fn pending_proposal_failed_when_the_peer_disconnects() {
+    let (a, mut b) = connected_nodes(p2p_params(), p2p_params()).await;
+    let proposal = tokio::spawn({
+      let (a, peer_id) = (a.p2p.clone(), b.peer_id);
+      let terms = lease_terms(Address::repeat_byte(9));
+      async move { a.send_proposal(peer_id, 1, terms, signature(), b"some data".to_vec()).await }
+    });
+    while !matches!(next_event(&mut b).await, Event::ReceivedLeaseProposal { .. }) {}
+
+    b.p2p.behaviour.lock().unwrap().disconnect_peer_id(a.peer_id).unwrap();
+
+    let error = tokio::time::timeout(Duration::from_secs(5), proposal)
+      .await
+      .unwrap()
+      .unwrap()
+      .unwrap_err();
+    assert!(error.downcast_ref::<PeerDisconnected>().is_some(), "{}", error);
+    assert!(a.p2p.pending_operations().is_empty());
+  }
+
+  #[tokio::test]
+  async fn ipv6_listener_reported() {
+    // Hosts without IPv6 cannot run this test
+    if std::net::TcpListener::bind("[::1]:0").is_err() {
+      return;
+    }
+    let keypair = Keypair::generate_secp256k1();
+    let transport = transport::build_transport(keypair.clone()).unwrap();
+    let address: Multiaddr = "/ip6/::1/tcp/0".parse().unwrap();
+    let p2p = build_p2p(keypair, p2p_params(), transport, vec![address]).await.unwrap();
+    let mut stream = p2p.clone();
+    tokio::spawn(async move { while stream.next().await.is_some() {} });
+
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    loop {
+      let listening = p2p
+        .behaviour
+        .lock()
+        .unwrap()
+        .listeners()
+        .any(|a| matches!(a.iter().next(), Some(libp2p::multiaddr::Protocol::Ip6(ip)) if ip.is_loopback()));
+      if listening {
+        break;
+      }
+      assert!(std::time::Instant::now() < deadline, "no IPv6 listener reported");
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
   }
 }
@@ -1,9 +1,11 @@
 use crate::libp2p::protobuf;
 use crate::libp2p::protobuf::handler;
+use crate::p2p;
 use crate::proto;
 use crate::proto::p2p::protocol_message::Message;
 use crate::proto::p2p::{
-  protocol_message, ChallengeRequest, ChallengeResponse, LeaseRejection, RetrieveDelivery, RetrieveRequest,
+  protocol_message, ChallengeRequest, ChallengeResponse, LeaseRejection, LeaseSealed, QuoteRequest, QuoteResponse,
+  RetrieveDelivery, RetrieveNotFound, RetrieveRequest,
 };
 use crate::types::{ChallengeKey, ChallengeProof, LeaseTerms, Signature};
 use libp2p::core::connection::ConnectionId;
@@ -12,20 +14,39 @@ use libp2p::swarm::{
   ConnectionHandler, IntoConnectionHandler, NetworkBehaviour, NetworkBehaviourAction, NotifyHandler, PollParameters,
 };
 use libp2p::{Multiaddr, PeerId};
-use log::warn;
+use log::{debug, warn};
 use std::collections::VecDeque;
 use std::convert::{TryFrom, TryInto};
+use std::fmt::{Display, Formatter};
 use std::task::{Context, Poll, Waker};
+use std::time::Duration;
 use web3::types::H256;
 
-const P2PIM_PROTOCOL_NAME: &[u8] = b"/p2pim/protobuf/0.1.0";
+pub const P2PIM_PROTOCOL_NAME: &[u8] = b"/p2pim/protobuf/0.1.0";
+
+const DEFAULT_MAX_QUEUED_MESSAGES: usize = 1024;
 
 pub struct Behaviour {
   message_queue: VecDeque<(PeerId, protocol_message::Message)>,
   event_queue: VecDeque<Event>,
   waker: Option<Waker>,
+  substream_idle_timeout: Option<Duration>,
+  max_queued_messages: usize,
 }
 
+#[derive(Debug)]
+pub struct QueueFullError {
+  pub max_queued_messages: usize,
+}
+
+impl Display for QueueFullError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "p2pim message queue is full ({} messages)", self.max_queued_messages)
+  }
+}
+
+impl std::error::Error for QueueFullError {}
+
 impl Default for Behaviour {
   fn default() -> Self {
     Behaviour::new()
@@ -38,29 +59,42 @@ impl Behaviour {
       message_queue: VecDeque::new(),
       event_queue: VecDeque::new(),
       waker: None,
+      substream_idle_timeout: None,
+      max_queued_messages: DEFAULT_MAX_QUEUED_MESSAGES,
     }
   }
 
-  pub fn send_proposal(&mut self, peer_id: PeerId, lease_proposal: LeaseProposal) {
+  pub fn with_substream_idle_timeout(mut self, substream_idle_timeout: Duration) -> Self {
+    self.substream_idle_timeout = Some(substream_idle_timeout);
     self
-      .message_queue
-      .push_back((peer_id, Message::LeaseProposal(lease_proposal.into())));
-    self.wake()
   }
 
-  pub fn send_challenge(&mut self, peer_id: PeerId, challenge_key: ChallengeKey) {
-    self.message_queue.push_back((
+  pub fn with_max_queued_messages(mut self, max_queued_messages: usize) -> Self {
+    self.max_queued_messages = max_queued_messages;
+    self
+  }
+
+  pub fn send_proposal(&mut self, peer_id: PeerId, lease_proposal: LeaseProposal) -> Result<(), QueueFullError> {
+    self.enqueue(peer_id, Message::LeaseProposal(lease_proposal.into()))
+  }
+
+  pub fn send_challenge(&mut self, peer_id: PeerId, challenge_key: ChallengeKey) -> Result<(), QueueFullError> {
+    self.enqueue(
       peer_id,
       Message::ChallengeRequest(ChallengeRequest {
         nonce: challenge_key.nonce,
         block_number: challenge_key.block_number,
       }),
-    ));
-    self.wake()
+    )
   }
 
-  pub fn send_challenge_proof(&mut self, peer_id: PeerId, challenge_key: ChallengeKey, challenge_proof: ChallengeProof) {
-    self.message_queue.push_back((
+  pub fn send_challenge_proof(
+    &mut self,
+    peer_id: PeerId,
+    challenge_key: ChallengeKey,
+    challenge_proof: ChallengeProof,
+  ) -> Result<(), QueueFullError> {
+    self.enqueue(
       peer_id,
       Message::ChallengeResponse(ChallengeResponse {
         nonce: challenge_key.nonce,
@@ -68,29 +102,86 @@ impl Behaviour {
         block_data: challenge_proof.block_data,
         proof: challenge_proof.proof.into_iter().map(|p| H256(p).into()).collect(),
       }),
-    ));
-    self.wake()
+    )
   }
 
-  pub fn send_retrieve_request(&mut self, peer_id: PeerId, nonce: u64) {
-    self
-      .message_queue
-      .push_back((peer_id, Message::RetrieveRequest(RetrieveRequest { nonce })));
-    self.wake()
+  pub fn send_retrieve_request(&mut self, peer_id: PeerId, nonce: u64) -> Result<(), QueueFullError> {
+    self.enqueue(peer_id, Message::RetrieveRequest(RetrieveRequest { nonce }))
   }
 
-  pub fn send_retrieve_delivery(&mut self, peer_id: PeerId, nonce: u64, data: Vec<u8>) {
-    self
-      .message_queue
-      .push_back((peer_id, Message::RetrieveDelivery(RetrieveDelivery { nonce, data })));
-    self.wake()
+  pub fn send_retrieve_delivery(&mut self, peer_id: PeerId, nonce: u64, data: Vec<u8>) -> Result<(), QueueFullError> {
+    self.enqueue(peer_id, Message::RetrieveDelivery(RetrieveDelivery { nonce, data }))
   }
 
-  pub fn send_proposal_rejection(&mut self, peer_id: PeerId, nonce: u64, reason: String) {
-    self
-      .message_queue
-      .push_back((peer_id, Message::LeaseRejection(LeaseRejection { nonce, reason })));
-    self.wake()
+  pub fn send_retrieve_not_found(&mut self, peer_id: PeerId, nonce: u64) -> Result<(), QueueFullError> {
+    self.enqueue(peer_id, Message::RetrieveNotFound(RetrieveNotFound { nonce }))
+  }
+
+  pub fn send_proposal_rejection(&mut self, peer_id: PeerId, nonce: u64, reason: String) -> Result<(), QueueFullError> {
+    self.enqueue(peer_id, Message::LeaseRejection(LeaseRejection { nonce, reason }))
+  }
+
+  pub fn send_lease_sealed(&mut self, peer_id: PeerId, nonce: u64, transaction_hash: H256) -> Result<(), QueueFullError> {
+    self.enqueue(
+      peer_id,
+      Message::LeaseSealed(LeaseSealed {
+        nonce,
+        transaction_hash: Some(transaction_hash.into()),
+      }),
+    )
+  }
+
+  pub fn send_quote_request(
+    &mut self,
+    peer_id: PeerId,
+    quote_id: u64,
+    lease_terms: &LeaseTerms,
+    size: usize,
+  ) -> Result<(), QueueFullError> {
+    self.enqueue(
+      peer_id,
+      Message::QuoteRequest(QuoteRequest {
+        quote_id,
+        lease_terms: Some(lease_terms.into()),
+        size: size as u64,
+      }),
+    )
+  }
+
+  pub fn send_quote_response(
+    &mut self,
+    peer_id: PeerId,
+    quote_id: u64,
+    response: p2p::QuoteResponse,
+  ) -> Result<(), QueueFullError> {
+    let (accepted, reason) = match response {
+      p2p::QuoteResponse::Accepted => (true, String::new()),
+      p2p::QuoteResponse::Rejected(reason) => (false, reason),
+    };
+    self.enqueue(
+      peer_id,
+      Message::QuoteResponse(QuoteResponse {
+        quote_id,
+        accepted,
+        reason,
+      }),
+    )
+  }
+
+  /// Whether a new message would be rejected with [`QueueFullError`].
+  pub fn is_queue_full(&self) -> bool {
+    self.message_queue.len() >= self.max_queued_messages
+  }
+
+  fn enqueue(&mut self, peer_id: PeerId, message: protocol_message::Message) -> Result<(), QueueFullError> {
+    if self.is_queue_full() {
+      return Err(QueueFullError {
+        max_queued_messages: self.max_queued_messages,
+      });
+    }
+    self.message_queue.push_back((peer_id, message));
+    self.wake();
+    Ok(())
   }
 
   fn wake(&mut self) {
@@ -104,10 +195,15 @@ impl Behaviour {
 pub enum Event {
   ReceivedLeaseProposal(PeerId, LeaseProposal),
   ReceivedLeaseProposalRejection(PeerId, u64, String),
+  ReceivedLeaseSealed(PeerId, u64, H256),
   ReceivedChallengeRequest(PeerId, ChallengeKey),
   ReceivedChallengeResponse(PeerId, ChallengeKey, ChallengeProof),
   ReceivedRetrieveRequest(PeerId, u64),
   ReceivedRetrieveDelivery(PeerId, u64, Vec<u8>),
+  ReceivedRetrieveNotFound(PeerId, u64),
+  ReceivedQuoteRequest(PeerId, u64, LeaseTerms, usize),
+  ReceivedQuoteResponse(PeerId, u64, p2p::QuoteResponse),
+  PeerDisconnected(PeerId),
 }
 
 #[derive(Debug)]
@@ -122,26 +218,9 @@ impl TryFrom<proto::p2p::LeaseProposal> for LeaseProposal {
   type Error = String;
 
   fn try_from(value: proto::p2p::LeaseProposal) -> Result<Self, Self::Error> {
-    let lease_terms = value.lease_terms.as_ref().ok_or("lease_terms empty")?;
     Ok(LeaseProposal {
       nonce: value.nonce,
-      lease_terms: LeaseTerms {
-        token_address: lease_terms.token_address.as_ref().ok_or("token_address empty")?.into(),
-        price: lease_terms.price.as_ref().ok_or("price empty")?.into(),
-        penalty: lease_terms.penalty.as_ref().ok_or("penalty empty")?.into(),
-        proposal_expiration: lease_terms
-          .proposal_expiration
-          .clone()
-          .ok_or("proposal_expiration empty")?
-          .try_into()
-          .map_err(|e| format!("{}", e))?,
-        lease_duration: lease_terms
-          .lease_duration
-          .clone()
-          .ok_or("lease_duration empty")?
-          .try_into()
-          .map_err(|_| "lease_duration should be positive")?,
-      },
+      lease_terms: value.lease_terms.as_ref().ok_or("lease_terms empty")?.try_into()?,
       signature: Signature::deserialize(value.signature.as_slice()).map_err(|e| format!("{}", e))?,
       data: value.data,
     })
@@ -150,22 +229,51 @@ impl TryFrom<proto::p2p::LeaseProposal> for LeaseProposal {
 
 impl From<LeaseProposal> for proto::p2p::LeaseProposal {
   fn from(value: LeaseProposal) -> Self {
-    let lease_terms = &value.lease_terms;
     proto::p2p::LeaseProposal {
       nonce: value.nonce,
-      lease_terms: Some(proto::p2p::lease_proposal::LeaseTerms {
-        token_address: Some((&lease_terms.token_address).into()),
-        price: Some((&lease_terms.price).into()),
-        penalty: Some((&lease_terms.penalty).into()),
-        proposal_expiration: Some(lease_terms.proposal_expiration.into()),
-        lease_duration: Some(lease_terms.lease_duration.into()),
-      }),
+      lease_terms: Some((&value.lease_terms).into()),
       signature: value.signature.serialize(),
       data: value.data,
     }
   }
 }
 
+impl TryFrom<&proto::p2p::lease_proposal::LeaseTerms> for LeaseTerms {
+  type Error = String;
+
+  fn try_from(lease_terms: &proto::p2p::lease_proposal::LeaseTerms) -> Result<Self, Self::Error> {
+    Ok(LeaseTerms {
+      token_address: lease_terms.token_address.as_ref().ok_or("token_address empty")?.into(),
+      price: lease_terms.price.as_ref().ok_or("price empty")?.into(),
+      penalty: lease_terms.penalty.as_ref().ok_or("penalty empty")?.into(),
+      proposal_expiration: lease_terms
+        .proposal_expiration
+        .clone()
+        .ok_or("proposal_expiration empty")?
+        .try_into()
+        .map_err(|e| format!("{}", e))?,
+      lease_duration: lease_terms
+        .lease_duration
+        .clone()
+        .ok_or("lease_duration empty")?
+        .try_into()
+        .map_err(|_| "lease_duration should be positive")?,
+    })
+  }
+}
+
+impl From<&LeaseTerms> for proto::p2p::lease_proposal::LeaseTerms {
+  fn from(lease_terms: &LeaseTerms) -> Self {
+    proto::p2p::lease_proposal::LeaseTerms {
+      token_address: Some((&lease_terms.token_address).into()),
+      price: Some((&lease_terms.price).into()),
+      penalty: Some((&lease_terms.penalty).into()),
+      proposal_expiration: Some(lease_terms.proposal_expiration.into()),
+      lease_duration: Some(lease_terms.lease_duration.into()),
+    }
+  }
+}
+
 #[derive(Debug)]
 pub enum SourceData {
   Data(Vec<u8>),
@@ -176,7 +284,11 @@ impl NetworkBehaviour for Behaviour {
   type OutEvent = Event;
 
   fn new_handler(&mut self) -> Self::ConnectionHandler {
-    protobuf::handler::Handler::new(P2PIM_PROTOCOL_NAME)
+    let handler = protobuf::handler::Handler::new(P2PIM_PROTOCOL_NAME);
+    match self.substream_idle_timeout {
+      Some(idle_timeout) => handler.with_idle_timeout(idle_timeout),
+      None => handler,
+    }
   }
 
   fn inject_connection_established(
@@ -191,12 +303,27 @@ impl NetworkBehaviour for Behaviour {
 
   fn inject_connection_closed(
     &mut self,
-    _peer_id: &PeerId,
+    peer_id: &PeerId,
     _connection_id: &ConnectionId,
     _endpoint: &ConnectedPoint,
     _handler: <Self::ConnectionHandler as IntoConnectionHandler>::Handler,
-    _remaining_established: usize,
+    remaining_established: usize,
   ) {
+    if remaining_established > 0 {
+      return;
+    }
+    // Messages queued for the peer would be sent to a handler that no longer exists
+    let queued = self.message_queue.len();
+    self.message_queue.retain(|(p, _)| p != peer_id);
+    let dropped = queued - self.message_queue.len();
+    if dropped > 0 {
+      debug!(
+        "dropped queued messages of disconnected peer peer_id={} count={}",
+        peer_id, dropped
+      );
+    }
+    self.event_queue.push_back(Event::PeerDisconnected(*peer_id));
+    self.wake();
   }
 
   fn inject_event(
@@ -236,6 +363,14 @@ impl NetworkBehaviour for Behaviour {
           lease_rejection.nonce,
           lease_rejection.reason,
         )),
+        Some(Message::LeaseSealed(lease_sealed)) => match lease_sealed.transaction_hash.as_ref() {
+          Some(transaction_hash) => self.event_queue.push_back(Event::ReceivedLeaseSealed(
+            peer_id,
+            lease_sealed.nonce,
+            transaction_hash.into(),
+          )),
+          None => warn!("invalid lease sealed received from peer {}: transaction_hash empty", peer_id),
+        },
         Some(Message::RetrieveRequest(retrieve_request)) => self
           .event_queue
           .push_back(Event::ReceivedRetrieveRequest(peer_id, retrieve_request.nonce)),
@@ -244,6 +379,33 @@ impl NetworkBehaviour for Behaviour {
           retrieve_delivery.nonce,
           retrieve_delivery.data,
         )),
+        Some(Message::RetrieveNotFound(retrieve_not_found)) => self
+          .event_queue
+          .push_back(Event::ReceivedRetrieveNotFound(peer_id, retrieve_not_found.nonce)),
+        Some(Message::QuoteRequest(quote_request)) => match quote_request
+          .lease_terms
+          .as_ref()
+          .ok_or_else(|| "lease_terms empty".to_string())
+          .and_then(LeaseTerms::try_from)
+        {
+          Ok(lease_terms) => self.event_queue.push_back(Event::ReceivedQuoteRequest(
+            peer_id,
+            quote_request.quote_id,
+            lease_terms,
+            quote_request.size as usize,
+          )),
+          Err(e) => warn!("invalid quote request received from peer {}: {}", peer_id, e),
+        },
+        Some(Message::QuoteResponse(quote_response)) => {
+          let response = if quote_response.accepted {
+            p2p::QuoteResponse::Accepted
+          } else {
+            p2p::QuoteResponse::Rejected(quote_response.reason)
+          };
+          self
+            .event_queue
+            .push_back(Event::ReceivedQuoteResponse(peer_id, quote_response.quote_id, response))
+        }
         None => warn!("invalid message received from peer {}: no inner message", peer_id),
       },
     };
@@ -281,3 +443,35 @@ impl NetworkBehaviour for Behaviour {
     Poll::Pending
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use libp2p::core::Endpoint;
+
+  #[test]
+  fn messages_of_a_disconnected_peer_dropped() {
+    let mut behaviour = Behaviour::new();
+    let (gone, other) = (PeerId::random(), PeerId::random());
+    behaviour.send_proposal_rejection(gone, 1, "busy".to_string()).unwrap();
+    behaviour.send_proposal_rejection(other, 2, "busy".to_string()).unwrap();
+    let endpoint = ConnectedPoint::Dialer {
+      address: Multiaddr::empty(),
+      role_override: Endpoint::Dialer,
+    };
+    let close = |behaviour: &mut Behaviour, remaining_established| {
+      let handler = behaviour.new_handler();
+      behaviour.inject_connection_closed(&gone, &ConnectionId::new(1), &endpoint, handler, remaining_established);
+    };
+
+    // Still connected over another connection
+    close(&mut behaviour, 1);
+    assert_eq!(behaviour.message_queue.len(), 2);
+    assert!(behaviour.event_queue.is_empty());
+
+    close(&mut behaviour, 0);
+    let queued = behaviour.message_queue.iter().map(|(p, _)| *p).collect::<Vec<_>>();
+    assert_eq!(queued, vec![other]);
+    assert!(matches!(behaviour.event_queue.pop_front(), Some(Event::PeerDisconnected(p)) if p == gone));
+  }
+}
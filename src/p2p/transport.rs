@@ -1,5 +1,5 @@
 use libp2p::core::muxing::StreamMuxerBox;
-use libp2p::core::transport::Boxed;
+use libp2p::core::transport::{Boxed, MemoryTransport};
 use libp2p::core::upgrade::{SelectUpgrade, Version};
 use libp2p::dns::TokioDnsConfig;
 use libp2p::mplex::MplexConfig;
@@ -28,3 +28,20 @@ pub fn build_transport(keypair: identity::Keypair) -> io::Result<TTransport> {
       .boxed(),
   )
 }
+
+/// Transport over in-process channels, listening on `/memory/<port>` addresses. Intended to wire swarms in tests.
+pub fn build_memory_transport(keypair: identity::Keypair) -> io::Result<TTransport> {
+  let xx_keypair = noise::Keypair::<noise::X25519Spec>::new().into_authentic(&keypair).unwrap();
+  let noise_config = NoiseConfig::xx(xx_keypair).into_authenticated();
+
+  Ok(
+    MemoryTransport
+      .upgrade(Version::V1)
+      .authenticate(noise_config)
+      .multiplex(SelectUpgrade::new(YamuxConfig::default(), MplexConfig::new()))
+      .timeout(Duration::from_secs(20))
+      .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+      .map_err(Error::other)
+      .boxed(),
+  )
+}
@@ -1,11 +1,37 @@
-use crate::types::{ChainConfirmation, Lease};
-use libp2p::PeerId;
+use crate::proto;
+use crate::types::{
+  AuditEntry, AuditRole, ChainConfirmation, ChallengeKey, ChallengeOutcome, ChallengeRecord, DataParameters, KnownPeer,
+  Lease, LeaseTerms,
+};
+use crate::utils::cache::LruCache;
+use libp2p::{Multiaddr, PeerId};
+use log::{debug, error};
+use prost::Message;
+use sha3::{Digest, Keccak256};
 use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tonic::async_trait;
-use web3::types::Address;
+use web3::types::{Address, H256};
+
+const TREE_LEASES_RENT: &str = "leases_rent";
+const TREE_LEASES_LET: &str = "leases_let";
+const TREE_META: &str = "meta";
+const TREE_AUDIT: &str = "audit";
+const TREE_PEERS: &str = "peers";
+
+const KEY_LAST_PROCESSED_BLOCK: &[u8] = b"last_processed_block";
+const KEY_SCHEMA_VERSION: &[u8] = b"schema_version";
+
+// Layout of the trees, every bump needs a migration from the previous version in `migrate`
+const SCHEMA_VERSION: u32 = 1;
+
+// Peers kept to dial on startup, the least recently seen ones are evicted first
+const KNOWN_PEERS_CAPACITY: usize = 256;
 
 #[derive(Debug)]
 pub enum UpdateError {
@@ -22,6 +48,27 @@ impl Display for UpdateError {
 
 impl Error for UpdateError {}
 
+#[derive(Debug)]
+pub enum SchemaError {
+  MalformedVersion,
+  UnsupportedVersion { found: u32, supported: u32 },
+}
+
+impl Display for SchemaError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      SchemaError::MalformedVersion => f.write_str("malformed persistence schema version"),
+      SchemaError::UnsupportedVersion { found, supported } => write!(
+        f,
+        "persistence schema version {} is not supported, this version of p2pim supports up to {}",
+        found, supported
+      ),
+    }
+  }
+}
+
+impl Error for SchemaError {}
+
 #[async_trait]
 pub trait Service: Clone + Sync + Send + 'static {
   async fn rent_store(&self, lease: Lease);
@@ -33,23 +80,244 @@ pub trait Service: Clone + Sync + Send + 'static {
   ) -> Result<(), UpdateError>;
   async fn rent_list(&self) -> Vec<Lease>;
   async fn rent_get(&self, peer_id: PeerId, nonce: u64) -> Option<Lease>;
+  async fn rent_record_challenge(&self, peer_id: PeerId, nonce: u64, outcome: ChallengeOutcome) -> ChallengeRecord;
+  /// Outcomes of the watchdog challenges of the lease since the node started.
+  async fn rent_challenges(&self, peer_id: PeerId, nonce: u64) -> ChallengeRecord;
+  async fn rent_complete(&self, peer_id: PeerId, nonce: u64) -> Result<(), UpdateError>;
+  async fn rent_remove(&self, peer_id: PeerId, nonce: u64);
+  async fn let_store(&self, lease: Lease);
+  async fn let_update_chain(
+    &self,
+    peer_address: Address,
+    nonce: u64,
+    chain_confirmation: Option<ChainConfirmation>,
+  ) -> Result<(), UpdateError>;
+  async fn let_complete(&self, peer_id: PeerId, nonce: u64) -> Result<(), UpdateError>;
+  async fn let_remove(&self, peer_id: PeerId, nonce: u64);
+  async fn let_list(&self) -> Vec<Lease>;
+  async fn let_get(&self, peer_id: PeerId, nonce: u64) -> Option<Lease>;
+  /// Bytes of the let leases whose data is still kept, completed ones count until removed.
+  async fn let_stored_bytes(&self) -> u64;
+  async fn last_processed_block(&self) -> Option<u64>;
+  async fn set_last_processed_block(&self, block_number: u64);
+  /// Appends a handled challenge to the audit log, chaining its hash to the last entry.
+  async fn audit_append(
+    &self,
+    role: AuditRole,
+    peer_id: PeerId,
+    challenge_key: ChallengeKey,
+    proof_hash: [u8; 32],
+    verified: bool,
+  ) -> AuditEntry;
+  async fn audit_log(&self) -> Vec<AuditEntry>;
+  /// Records a peer as seen now, evicting the least recently seen one when the cache is full.
+  async fn peers_store(&self, peer_id: PeerId, addresses: Vec<Multiaddr>);
+  async fn peers_list(&self) -> Vec<KnownPeer>;
 }
 
 struct Implementation {
   leases_rent: HashMap<Key, Lease>,
+  leases_let: HashMap<Key, Lease>,
+  // Data size of `leases_let`, kept along so the lessor does not sum it on every proposal
+  let_bytes: u64,
+  challenges_rent: HashMap<Key, ChallengeRecord>,
+  tree_rent: sled::Tree,
+  tree_let: sled::Tree,
+  tree_meta: sled::Tree,
+  tree_audit: sled::Tree,
+  tree_peers: sled::Tree,
+  known_peers: LruCache<PeerId, KnownPeer>,
+  audit_last: Option<AuditHead>,
 }
 
-pub fn new_service() -> impl Service {
+/// Sequence number and hash of the last audit entry.
+type AuditHead = (u64, [u8; 32]);
+
+pub fn new_service<P: AsRef<Path>>(path: P) -> Result<impl Service, Box<dyn Error>> {
+  debug!("opening persistence database on {:?}", path.as_ref());
+  let db = sled::open(path)?;
+  let tree_rent = db.open_tree(TREE_LEASES_RENT)?;
+  let tree_let = db.open_tree(TREE_LEASES_LET)?;
+  let tree_meta = db.open_tree(TREE_META)?;
+  let tree_audit = db.open_tree(TREE_AUDIT)?;
+  let tree_peers = db.open_tree(TREE_PEERS)?;
+  migrate(&db, &tree_meta)?;
+  let audit_last = load_audit_last(&tree_audit)?;
+  let leases_rent = load_leases(&tree_rent)?;
+  let leases_let = load_leases(&tree_let)?;
+  debug!(
+    "loaded leases from persistence rents={} lets={}",
+    leases_rent.len(),
+    leases_let.len()
+  );
+  let known_peers = load_known_peers(&tree_peers)?;
+  let let_bytes = leases_let.values().map(|lease| lease.data_parameters.size as u64).sum();
+
   // TODO Make it RwLock
-  Arc::new(Mutex::new(Implementation {
-    leases_rent: HashMap::new(),
-  }))
+  Ok(Arc::new(Mutex::new(Implementation {
+    leases_rent,
+    leases_let,
+    let_bytes,
+    challenges_rent: HashMap::new(),
+    tree_rent,
+    tree_let,
+    tree_meta,
+    tree_audit,
+    tree_peers,
+    known_peers,
+    audit_last,
+  })))
+}
+
+/// Records `SCHEMA_VERSION` in a store without one, stores written by a newer version are refused.
+fn migrate(db: &sled::Db, tree_meta: &sled::Tree) -> Result<(), Box<dyn Error>> {
+  let version = match tree_meta.get(KEY_SCHEMA_VERSION)? {
+    Some(raw) => u32::from_be_bytes(raw.as_ref().try_into().map_err(|_| SchemaError::MalformedVersion)?),
+    // Stores written before the version was recorded have the layout of version 1
+    None => {
+      tree_meta.insert(KEY_SCHEMA_VERSION, SCHEMA_VERSION.to_be_bytes().as_slice())?;
+      SCHEMA_VERSION
+    }
+  };
+  if version == 0 {
+    return Err(SchemaError::MalformedVersion.into());
+  }
+  if version > SCHEMA_VERSION {
+    return Err(
+      SchemaError::UnsupportedVersion {
+        found: version,
+        supported: SCHEMA_VERSION,
+      }
+      .into(),
+    );
+  }
+  db.flush()?;
+  Ok(())
+}
+
+fn load_leases(tree: &sled::Tree) -> Result<HashMap<Key, Lease>, Box<dyn Error>> {
+  tree
+    .iter()
+    .values()
+    .map(|value| {
+      let proto_lease = proto::persistence::Lease::decode(value?.as_ref())?;
+      let lease: Lease = proto_lease.try_into()?;
+      Ok((key(&lease), lease))
+    })
+    .collect()
+}
+
+/// Loads the peers from the least to the most recently seen, dropping from the store the ones beyond the capacity.
+fn load_known_peers(tree: &sled::Tree) -> Result<LruCache<PeerId, KnownPeer>, Box<dyn Error>> {
+  let mut peers = Vec::new();
+  for value in tree.iter().values() {
+    let peer: KnownPeer = proto::persistence::KnownPeer::decode(value?.as_ref())?.try_into()?;
+    peers.push(peer);
+  }
+  peers.sort_by_key(|p| p.last_seen);
+  let mut known_peers = LruCache::new(KNOWN_PEERS_CAPACITY);
+  for peer in peers {
+    if let Some(evicted) = insert_known_peer(&mut known_peers, peer) {
+      tree.remove(evicted.to_bytes())?;
+    }
+  }
+  Ok(known_peers)
+}
+
+/// Returns the peer evicted to make room for a new one.
+fn insert_known_peer(known_peers: &mut LruCache<PeerId, KnownPeer>, peer: KnownPeer) -> Option<PeerId> {
+  let evicted = if !known_peers.contains_key(&peer.peer_id) && known_peers.len() >= known_peers.capacity() {
+    known_peers.least_recently_used().cloned()
+  } else {
+    None
+  };
+  known_peers.insert(peer.peer_id, peer);
+  evicted
+}
+
+fn load_audit_last(tree: &sled::Tree) -> Result<Option<AuditHead>, Box<dyn Error>> {
+  match tree.last()? {
+    Some((key, value)) => {
+      let sequence = u64::from_be_bytes(key.as_ref().try_into()?);
+      let entry: AuditEntry = proto::persistence::AuditEntry::decode(value.as_ref())?.try_into()?;
+      Ok(Some((sequence, entry.hash)))
+    }
+    None => Ok(None),
+  }
+}
+
+/// Keccak256 of the previous entry hash followed by the entry fields, the first entry chains to zeroes.
+pub fn audit_hash(previous_hash: &[u8; 32], entry: &AuditEntry) -> [u8; 32] {
+  let timestamp = entry.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+  let mut hasher = Keccak256::new();
+  hasher.update(previous_hash);
+  hasher.update(timestamp.as_secs().to_be_bytes());
+  hasher.update(timestamp.subsec_nanos().to_be_bytes());
+  hasher.update([match entry.role {
+    AuditRole::Challenger => 1u8,
+    AuditRole::Prover => 2u8,
+  }]);
+  hasher.update(entry.peer_id.to_bytes());
+  hasher.update(entry.nonce.to_be_bytes());
+  hasher.update(entry.block_number.to_be_bytes());
+  hasher.update(entry.proof_hash);
+  hasher.update([entry.verified as u8]);
+  let mut result: [u8; 32] = Default::default();
+  result.copy_from_slice(hasher.finalize().as_slice());
+  result
+}
+
+/// Whether every entry hash matches the chain recomputed from the first entry.
+pub fn audit_chain_consistent(entries: &[AuditEntry]) -> bool {
+  let mut previous_hash = [0u8; 32];
+  for entry in entries {
+    if audit_hash(&previous_hash, entry) != entry.hash {
+      return false;
+    }
+    previous_hash = entry.hash;
+  }
+  true
+}
+
+fn write_lease(tree: &sled::Tree, lease: &Lease) {
+  let value = proto::persistence::Lease::from(lease).encode_to_vec();
+  if let Err(e) = tree.insert(key(lease).to_bytes(), value) {
+    error!(
+      "error writing lease to persistence peer_id={} nonce={}: {}",
+      lease.peer_id, lease.nonce, e
+    );
+  }
+}
+
+fn update_chain(
+  leases: &mut HashMap<Key, Lease>,
+  tree: &sled::Tree,
+  peer_address: Address,
+  nonce: u64,
+  chain_confirmation: Option<ChainConfirmation>,
+) -> Result<(), UpdateError> {
+  // TODO unfortunately, we do not have it indexed by peer_address
+  let lease = leases
+    .values_mut()
+    .find(|value| value.peer_address == peer_address && value.nonce == nonce)
+    .ok_or(UpdateError::LeaseNotFound)?;
+  lease.chain_confirmation = chain_confirmation;
+  write_lease(tree, lease);
+  Ok(())
+}
+
+fn complete(leases: &mut HashMap<Key, Lease>, tree: &sled::Tree, peer_id: PeerId, nonce: u64) -> Result<(), UpdateError> {
+  let lease = leases.get_mut(&Key { peer_id, nonce }).ok_or(UpdateError::LeaseNotFound)?;
+  lease.completed = true;
+  write_lease(tree, lease);
+  Ok(())
 }
 
 #[async_trait]
 impl Service for Arc<Mutex<Implementation>> {
   async fn rent_store(&self, lease: Lease) {
     let mut guard = self.lock().unwrap();
+    write_lease(&guard.tree_rent, &lease);
     let key = key(&lease);
     guard.leases_rent.insert(key, lease);
   }
@@ -61,21 +329,10 @@ impl Service for Arc<Mutex<Implementation>> {
     chain_confirmation: Option<ChainConfirmation>,
   ) -> Result<(), UpdateError> {
     let mut guard = self.lock().unwrap();
-
-    // TODO unfortunately, we do not have it indexed by peer_address
-    let maybe_key = guard
-      .leases_rent
-      .iter()
-      .find(|(_, value)| value.peer_address == peer_address && value.nonce == nonce)
-      .map(|(key, value)| (key.clone(), value.clone()));
-    match maybe_key {
-      None => Err(UpdateError::LeaseNotFound),
-      Some((key, mut lease)) => {
-        lease.chain_confirmation = chain_confirmation;
-        guard.leases_rent.insert(key, lease);
-        Ok(())
-      }
-    }
+    let Implementation {
+      leases_rent, tree_rent, ..
+    } = &mut *guard;
+    update_chain(leases_rent, tree_rent, peer_address, nonce, chain_confirmation)
   }
 
   async fn rent_list(&self) -> Vec<Lease> {
@@ -88,6 +345,204 @@ impl Service for Arc<Mutex<Implementation>> {
     let guard = self.lock().unwrap();
     guard.leases_rent.get(&Key { peer_id, nonce }).cloned()
   }
+
+  async fn rent_record_challenge(&self, peer_id: PeerId, nonce: u64, outcome: ChallengeOutcome) -> ChallengeRecord {
+    let mut guard = self.lock().unwrap();
+    let record = guard.challenges_rent.entry(Key { peer_id, nonce }).or_default();
+    record.record(outcome);
+    record.clone()
+  }
+
+  async fn rent_challenges(&self, peer_id: PeerId, nonce: u64) -> ChallengeRecord {
+    let guard = self.lock().unwrap();
+    guard
+      .challenges_rent
+      .get(&Key { peer_id, nonce })
+      .cloned()
+      .unwrap_or_default()
+  }
+
+  async fn rent_complete(&self, peer_id: PeerId, nonce: u64) -> Result<(), UpdateError> {
+    let mut guard = self.lock().unwrap();
+    let Implementation {
+      leases_rent, tree_rent, ..
+    } = &mut *guard;
+    complete(leases_rent, tree_rent, peer_id, nonce)
+  }
+
+  async fn rent_remove(&self, peer_id: PeerId, nonce: u64) {
+    let mut guard = self.lock().unwrap();
+    let key = Key { peer_id, nonce };
+    if let Err(e) = guard.tree_rent.remove(key.to_bytes()) {
+      error!(
+        "error removing lease from persistence peer_id={} nonce={}: {}",
+        peer_id, nonce, e
+      );
+    }
+    guard.leases_rent.remove(&key);
+    guard.challenges_rent.remove(&key);
+  }
+
+  async fn let_store(&self, lease: Lease) {
+    let mut guard = self.lock().unwrap();
+    write_lease(&guard.tree_let, &lease);
+    let key = key(&lease);
+    guard.let_bytes += lease.data_parameters.size as u64;
+    if let Some(replaced) = guard.leases_let.insert(key, lease) {
+      guard.let_bytes -= replaced.data_parameters.size as u64;
+    }
+  }
+
+  async fn let_update_chain(
+    &self,
+    peer_address: Address,
+    nonce: u64,
+    chain_confirmation: Option<ChainConfirmation>,
+  ) -> Result<(), UpdateError> {
+    let mut guard = self.lock().unwrap();
+    let Implementation {
+      leases_let, tree_let, ..
+    } = &mut *guard;
+    update_chain(leases_let, tree_let, peer_address, nonce, chain_confirmation)
+  }
+
+  async fn let_complete(&self, peer_id: PeerId, nonce: u64) -> Result<(), UpdateError> {
+    let mut guard = self.lock().unwrap();
+    let Implementation {
+      leases_let, tree_let, ..
+    } = &mut *guard;
+    complete(leases_let, tree_let, peer_id, nonce)
+  }
+
+  async fn let_remove(&self, peer_id: PeerId, nonce: u64) {
+    let mut guard = self.lock().unwrap();
+    let key = Key { peer_id, nonce };
+    if let Err(e) = guard.tree_let.remove(key.to_bytes()) {
+      error!(
+        "error removing lease from persistence peer_id={} nonce={}: {}",
+        peer_id, nonce, e
+      );
+    }
+    if let Some(removed) = guard.leases_let.remove(&key) {
+      guard.let_bytes -= removed.data_parameters.size as u64;
+    }
+  }
+
+  async fn let_list(&self) -> Vec<Lease> {
+    let guard = self.lock().unwrap();
+    guard.leases_let.values().cloned().collect()
+  }
+
+  async fn let_get(&self, peer_id: PeerId, nonce: u64) -> Option<Lease> {
+    let guard = self.lock().unwrap();
+    guard.leases_let.get(&Key { peer_id, nonce }).cloned()
+  }
+
+  async fn let_stored_bytes(&self) -> u64 {
+    self.lock().unwrap().let_bytes
+  }
+
+  async fn last_processed_block(&self) -> Option<u64> {
+    let guard = self.lock().unwrap();
+    match guard.tree_meta.get(KEY_LAST_PROCESSED_BLOCK) {
+      Ok(value) => value.and_then(|v| v.as_ref().try_into().ok()).map(u64::from_be_bytes),
+      Err(e) => {
+        error!("error reading last processed block from persistence: {}", e);
+        None
+      }
+    }
+  }
+
+  async fn audit_append(
+    &self,
+    role: AuditRole,
+    peer_id: PeerId,
+    challenge_key: ChallengeKey,
+    proof_hash: [u8; 32],
+    verified: bool,
+  ) -> AuditEntry {
+    let mut guard = self.lock().unwrap();
+    let (sequence, previous_hash) = match guard.audit_last {
+      Some((sequence, hash)) => (sequence + 1, hash),
+      None => (0, [0u8; 32]),
+    };
+    let mut entry = AuditEntry {
+      timestamp: SystemTime::now(),
+      role,
+      peer_id,
+      nonce: challenge_key.nonce,
+      block_number: challenge_key.block_number,
+      proof_hash,
+      verified,
+      hash: Default::default(),
+    };
+    entry.hash = audit_hash(&previous_hash, &entry);
+    let value = proto::persistence::AuditEntry::from(&entry).encode_to_vec();
+    if let Err(e) = guard.tree_audit.insert(sequence.to_be_bytes(), value) {
+      error!(
+        "error writing audit entry to persistence peer_id={} nonce={}: {}",
+        peer_id, challenge_key.nonce, e
+      );
+    } else {
+      guard.audit_last = Some((sequence, entry.hash));
+    }
+    entry
+  }
+
+  async fn audit_log(&self) -> Vec<AuditEntry> {
+    let guard = self.lock().unwrap();
+    guard
+      .tree_audit
+      .iter()
+      .values()
+      .filter_map(|value| {
+        let entry = value
+          .map_err(|e| format!("{}", e))
+          .and_then(|v| proto::persistence::AuditEntry::decode(v.as_ref()).map_err(|e| format!("{}", e)))
+          .and_then(AuditEntry::try_from);
+        match entry {
+          Ok(entry) => Some(entry),
+          Err(e) => {
+            error!("error reading audit entry from persistence: {}", e);
+            None
+          }
+        }
+      })
+      .collect()
+  }
+
+  async fn peers_store(&self, peer_id: PeerId, addresses: Vec<Multiaddr>) {
+    let mut guard = self.lock().unwrap();
+    let peer = KnownPeer {
+      peer_id,
+      addresses,
+      last_seen: SystemTime::now(),
+    };
+    let value = proto::persistence::KnownPeer::from(&peer).encode_to_vec();
+    if let Err(e) = guard.tree_peers.insert(peer_id.to_bytes(), value) {
+      error!("error writing known peer to persistence peer_id={}: {}", peer_id, e);
+    }
+    if let Some(evicted) = insert_known_peer(&mut guard.known_peers, peer) {
+      if let Err(e) = guard.tree_peers.remove(evicted.to_bytes()) {
+        error!("error removing known peer from persistence peer_id={}: {}", evicted, e);
+      }
+    }
+  }
+
+  async fn peers_list(&self) -> Vec<KnownPeer> {
+    let guard = self.lock().unwrap();
+    guard.known_peers.values().cloned().collect()
+  }
+
+  async fn set_last_processed_block(&self, block_number: u64) {
+    let guard = self.lock().unwrap();
+    if let Err(e) = guard
+      .tree_meta
+      .insert(KEY_LAST_PROCESSED_BLOCK, block_number.to_be_bytes().as_slice())
+    {
+      error!("error writing last processed block to persistence: {}", e);
+    }
+  }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -96,9 +551,292 @@ pub struct Key {
   pub nonce: u64,
 }
 
+impl Key {
+  fn to_bytes(&self) -> Vec<u8> {
+    let mut result = self.peer_id.to_bytes();
+    result.extend_from_slice(self.nonce.to_be_bytes().as_slice());
+    result
+  }
+}
+
 fn key(lease: &Lease) -> Key {
   Key {
     peer_id: lease.peer_id,
     nonce: lease.nonce,
   }
 }
+
+impl From<&Lease> for proto::persistence::Lease {
+  fn from(value: &Lease) -> Self {
+    let terms = &value.terms;
+    proto::persistence::Lease {
+      peer_id: Some(value.peer_id.into()),
+      peer_address: Some(value.peer_address.into()),
+      nonce: value.nonce,
+      terms: Some(proto::persistence::lease::LeaseTerms {
+        token_address: Some(terms.token_address.into()),
+        price: Some(terms.price.into()),
+        penalty: Some(terms.penalty.into()),
+        proposal_expiration: Some(terms.proposal_expiration.into()),
+        lease_duration: Some(terms.lease_duration.into()),
+      }),
+      data_parameters: Some(proto::persistence::lease::DataParameters {
+        merkle_root: value.data_parameters.merkle_root.clone(),
+        size: value.data_parameters.size as u64,
+      }),
+      chain_confirmation: value
+        .chain_confirmation
+        .as_ref()
+        .map(|c| proto::persistence::lease::ChainConfirmation {
+          transaction_hash: Some(c.transaction_hash.into()),
+          timestamp: Some(c.timestamp.into()),
+        }),
+      completed: value.completed,
+    }
+  }
+}
+
+impl From<&AuditEntry> for proto::persistence::AuditEntry {
+  fn from(value: &AuditEntry) -> Self {
+    proto::persistence::AuditEntry {
+      timestamp: Some(value.timestamp.into()),
+      role: match value.role {
+        AuditRole::Challenger => proto::persistence::AuditRole::Challenger,
+        AuditRole::Prover => proto::persistence::AuditRole::Prover,
+      } as i32,
+      peer_id: Some(value.peer_id.into()),
+      nonce: value.nonce,
+      block_number: value.block_number,
+      proof_hash: Some(H256(value.proof_hash).into()),
+      verified: value.verified,
+      hash: Some(H256(value.hash).into()),
+    }
+  }
+}
+
+impl From<&KnownPeer> for proto::persistence::KnownPeer {
+  fn from(value: &KnownPeer) -> Self {
+    proto::persistence::KnownPeer {
+      peer_id: Some(value.peer_id.into()),
+      addresses: value.addresses.iter().map(Multiaddr::to_vec).collect(),
+      last_seen: Some(value.last_seen.into()),
+    }
+  }
+}
+
+impl TryFrom<proto::persistence::KnownPeer> for KnownPeer {
+  type Error = String;
+
+  fn try_from(value: proto::persistence::KnownPeer) -> Result<Self, Self::Error> {
+    Ok(KnownPeer {
+      peer_id: value
+        .peer_id
+        .as_ref()
+        .ok_or("peer_id empty")?
+        .try_into()
+        .map_err(|e| format!("{}", e))?,
+      addresses: value
+        .addresses
+        .into_iter()
+        .map(|a| Multiaddr::try_from(a).map_err(|e| format!("{}", e)))
+        .collect::<Result<Vec<Multiaddr>, String>>()?,
+      last_seen: value
+        .last_seen
+        .ok_or("last_seen empty")?
+        .try_into()
+        .map_err(|e| format!("{}", e))?,
+    })
+  }
+}
+
+impl TryFrom<proto::persistence::AuditEntry> for AuditEntry {
+  type Error = String;
+
+  fn try_from(value: proto::persistence::AuditEntry) -> Result<Self, Self::Error> {
+    Ok(AuditEntry {
+      timestamp: value
+        .timestamp
+        .clone()
+        .ok_or("timestamp empty")?
+        .try_into()
+        .map_err(|e| format!("{}", e))?,
+      role: match proto::persistence::AuditRole::from_i32(value.role) {
+        Some(proto::persistence::AuditRole::Challenger) => AuditRole::Challenger,
+        Some(proto::persistence::AuditRole::Prover) => AuditRole::Prover,
+        _ => return Err(format!("invalid audit role {}", value.role)),
+      },
+      peer_id: value
+        .peer_id
+        .as_ref()
+        .ok_or("peer_id empty")?
+        .try_into()
+        .map_err(|e| format!("{}", e))?,
+      nonce: value.nonce,
+      block_number: value.block_number,
+      proof_hash: H256::from(value.proof_hash.as_ref().ok_or("proof_hash empty")?).0,
+      verified: value.verified,
+      hash: H256::from(value.hash.as_ref().ok_or("hash empty")?).0,
+    })
+  }
+}
+
+impl TryFrom<proto::persistence::Lease> for Lease {
+  type Error = String;
+
+  fn try_from(value: proto::persistence::Lease) -> Result<Self, Self::Error> {
+    let terms = value.terms.as_ref().ok_or("terms empty")?;
+    let data_parameters = value.data_parameters.as_ref().ok_or("data_parameters empty")?;
+    Ok(Lease {
+      peer_id: value
+        .peer_id
+        .as_ref()
+        .ok_or("peer_id empty")?
+        .try_into()
+        .map_err(|e| format!("{}", e))?,
+      peer_address: value.peer_address.as_ref().ok_or("peer_address empty")?.into(),
+      nonce: value.nonce,
+      terms: LeaseTerms {
+        token_address: terms.token_address.as_ref().ok_or("token_address empty")?.into(),
+        price: terms.price.as_ref().ok_or("price empty")?.into(),
+        penalty: terms.penalty.as_ref().ok_or("penalty empty")?.into(),
+        proposal_expiration: terms
+          .proposal_expiration
+          .clone()
+          .ok_or("proposal_expiration empty")?
+          .try_into()
+          .map_err(|e| format!("{}", e))?,
+        lease_duration: terms
+          .lease_duration
+          .clone()
+          .ok_or("lease_duration empty")?
+          .try_into()
+          .map_err(|_| "lease_duration should be positive")?,
+      },
+      data_parameters: DataParameters {
+        merkle_root: data_parameters.merkle_root.clone(),
+        size: data_parameters.size as usize,
+      },
+      chain_confirmation: value
+        .chain_confirmation
+        .as_ref()
+        .map(|c| {
+          Ok::<_, String>(ChainConfirmation {
+            transaction_hash: c.transaction_hash.as_ref().ok_or("transaction_hash empty")?.into(),
+            timestamp: c
+              .timestamp
+              .clone()
+              .ok_or("timestamp empty")?
+              .try_into()
+              .map_err(|e| format!("{}", e))?,
+          })
+        })
+        .transpose()?,
+      completed: value.completed,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testutil::{lease, unlocked, TempDir};
+
+  fn schema_version(path: &Path) -> Option<u32> {
+    let db = unlocked(|| sled::open(path)).unwrap();
+    let raw = db.open_tree(TREE_META).unwrap().get(KEY_SCHEMA_VERSION).unwrap()?;
+    Some(u32::from_be_bytes(raw.as_ref().try_into().unwrap()))
+  }
+
+  #[tokio::test]
+  async fn unversioned_store_loaded() {
+    let folder = TempDir::new();
+    let path = folder.path().join("db");
+    let peer_id = PeerId::random();
+    {
+      // Stores written before the schema version was recorded
+      let db = sled::open(&path).unwrap();
+      write_lease(
+        &db.open_tree(TREE_LEASES_LET).unwrap(),
+        &lease(peer_id, Address::repeat_byte(1), 1),
+      );
+      db.flush().unwrap();
+    }
+    assert_eq!(schema_version(&path), None);
+
+    let persistence = unlocked(|| new_service(&path)).unwrap();
+
+    assert!(persistence.let_get(peer_id, 1).await.is_some());
+    drop(persistence);
+    assert_eq!(schema_version(&path), Some(SCHEMA_VERSION));
+  }
+
+  #[test]
+  fn future_version_refused() {
+    let folder = TempDir::new();
+    let path = folder.path().join("db");
+    {
+      let db = sled::open(&path).unwrap();
+      let version = SCHEMA_VERSION + 1;
+      db.open_tree(TREE_META)
+        .unwrap()
+        .insert(KEY_SCHEMA_VERSION, version.to_be_bytes().as_slice())
+        .unwrap();
+      db.flush().unwrap();
+    }
+
+    let error = unlocked(|| new_service(&path)).err().unwrap();
+
+    assert!(matches!(
+      error.downcast_ref::<SchemaError>(),
+      Some(SchemaError::UnsupportedVersion { found, supported }) if *found == SCHEMA_VERSION + 1 && *supported == SCHEMA_VERSION
+    ));
+  }
+
+  #[tokio::test]
+  async fn audit_entries_chained() {
+    let folder = TempDir::new();
+    let path = folder.path().join("db");
+    let persistence = new_service(&path).unwrap();
+    let peer_id = PeerId::random();
+    for (nonce, role) in [(1, AuditRole::Challenger), (2, AuditRole::Prover)] {
+      let key = ChallengeKey { nonce, block_number: 0 };
+      persistence.audit_append(role, peer_id, key, [nonce as u8; 32], true).await;
+    }
+    drop(persistence);
+
+    // Read back after a restart, the next entry keeps chaining to the last stored one
+    let persistence = unlocked(|| new_service(&path)).unwrap();
+    let key = ChallengeKey {
+      nonce: 3,
+      block_number: 1,
+    };
+    persistence
+      .audit_append(AuditRole::Prover, peer_id, key, [3; 32], false)
+      .await;
+    let mut entries = persistence.audit_log().await;
+
+    assert_eq!(entries.iter().map(|e| e.nonce).collect::<Vec<_>>(), vec![1, 2, 3]);
+    assert!(audit_chain_consistent(&entries));
+    entries[1].verified = false;
+    assert!(!audit_chain_consistent(&entries));
+  }
+
+  #[tokio::test]
+  async fn least_recently_seen_peer_evicted() {
+    let folder = TempDir::new();
+    let path = folder.path().join("db");
+    let persistence = new_service(&path).unwrap();
+    let peers = (0..=KNOWN_PEERS_CAPACITY).map(|_| PeerId::random()).collect::<Vec<_>>();
+    for peer_id in peers.iter() {
+      persistence.peers_store(*peer_id, Vec::new()).await;
+    }
+    drop(persistence);
+
+    let persistence = unlocked(|| new_service(&path)).unwrap();
+    let known = persistence.peers_list().await;
+
+    assert_eq!(known.len(), KNOWN_PEERS_CAPACITY);
+    assert!(!known.iter().any(|p| p.peer_id == peers[0]));
+    assert!(known.iter().any(|p| p.peer_id == peers[KNOWN_PEERS_CAPACITY]));
+  }
+}
@@ -1,28 +1,153 @@
 use crate::p2p::p2pim::LeaseProposal;
-use crate::p2p::Event;
-use crate::types::{ChainConfirmation, ChallengeKey, ChallengeProof, Lease, LeaseTerms};
-use crate::utils::ethereum::IntoAddress;
-use crate::{cryptography, data, lessor, onchain, p2p, persistence};
+use crate::p2p::{Event, ProposalResponse, QuoteResponse};
+use crate::types::{
+  AuditRole, ChainConfirmation, ChallengeKey, ChallengeOutcome, ChallengeProof, ChallengeResult, Lease, LeaseTerms, Signature,
+};
+use crate::utils::cache::LruCache;
+use crate::utils::ethereum::{KeyError, TryIntoAddress};
+use crate::{data, lessor, onchain, p2p, persistence};
 use anyhow::anyhow;
 use ethcontract::transaction::TransactionResult;
 use ethcontract::{EventMetadata, EventStatus};
 use futures::future::join_all;
 use futures::{select, FutureExt, StreamExt};
 use libp2p::PeerId;
-use log::{error, info, trace, warn};
+use log::{debug, error, info, trace, warn};
+use rand::Rng;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
+use tokio::sync::{broadcast, Semaphore};
+use tokio::time::Instant;
 use tonic::async_trait;
-use web3::types::{BlockId, H256};
+use web3::signing::keccak256;
+use web3::types::{Address, BlockId, BlockNumber, H256, U256};
+
+/// Outcome of a lease that did not get sealed, returned boxed by `Service::lease`.
+#[derive(Debug)]
+pub enum LeaseError {
+  Rejected { reason: String },
+  TimedOut,
+  // The peer is this node or resolves to its storage address
+  SelfLease,
+}
+
+impl Display for LeaseError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      LeaseError::Rejected { reason } => write!(
+        f,
+        "lease rejected with reason: {}, note that the lease can still be processed on chain",
+        reason
+      ),
+      LeaseError::TimedOut => f.write_str("lease timed out"),
+      LeaseError::SelfLease => f.write_str("cannot lease to this same node"),
+    }
+  }
+}
+
+impl std::error::Error for LeaseError {}
+
+/// Significant transitions of the reactor, broadcast to the receivers of `Service::subscribe`.
+#[derive(Debug, Clone)]
+pub enum ReactorEvent {
+  /// A lease we proposed got sealed by the lessor.
+  LeaseSealed {
+    peer_id: PeerId,
+    nonce: u64,
+    transaction_hash: H256,
+  },
+  /// A lease we proposed got rejected by the lessor.
+  LeaseRejected { peer_id: PeerId, nonce: u64, reason: String },
+  /// We sealed the proposal of the lessee.
+  ProposalSealed {
+    peer_id: PeerId,
+    nonce: u64,
+    transaction_hash: H256,
+  },
+  /// We rejected the proposal of the lessee.
+  ProposalRejected { peer_id: PeerId, nonce: u64, reason: String },
+  /// The lessor answered a challenge with an invalid proof or did not answer, it is submitted on chain.
+  ChallengeFailed { peer_id: PeerId, nonce: u64, block_number: u32 },
+  /// A rented or let lease reached its end.
+  LeaseExpired { peer_id: PeerId, nonce: u64 },
+  /// The stored data of a let lease no longer matches its merkle root, or could not be read.
+  IntegrityCheckFailed { peer_id: PeerId, nonce: u64 },
+}
+
+/// Challenge of a block chosen from the hash of a chain block, see [`random_block_number`].
+#[derive(Debug, Clone)]
+pub struct RandomChallengeResult {
+  pub seed_block_number: u64,
+  pub seed_block_hash: H256,
+  pub block_number: u32,
+  pub result: ChallengeResult,
+}
 
 #[async_trait]
 pub trait Service: Clone + Send + Sync + 'static {
   async fn lease(&self, peer_id: PeerId, terms: LeaseTerms, data: Vec<u8>) -> Result<H256, Box<dyn Error>>;
-  async fn challenge(&self, peer_id: PeerId, challenge_key: ChallengeKey) -> Result<(), Box<dyn Error>>;
+  async fn challenge(&self, peer_id: PeerId, challenge_key: ChallengeKey) -> Result<ChallengeResult, Box<dyn Error>>;
+  /// Challenges a block the lessor cannot predict, derived from the hash of the latest confirmed chain block.
+  async fn challenge_random(&self, peer_id: PeerId, nonce: u64) -> Result<RandomChallengeResult, Box<dyn Error>>;
   async fn retrieve(&self, peer_id: PeerId, nonce: u64) -> anyhow::Result<Vec<u8>>;
+  /// Known peers that would accept a proposal with these terms, peers not answering within a few seconds are skipped.
+  async fn find_providers(&self, terms: LeaseTerms, size: usize) -> Vec<PeerId>;
+  /// Receives the events from now on, a receiver lagging too far behind misses the oldest ones.
+  fn subscribe(&self) -> broadcast::Receiver<ReactorEvent>;
+}
+
+#[derive(Clone)]
+pub struct ReactorParams {
+  pub integrity_check_interval: Option<Duration>,
+  pub challenge_watchdog_interval: Option<Duration>,
+  pub expiry_check_interval: Option<Duration>,
+  /// Interval between dials of the peers of active leases that are not connected, so challenges and retrieves reach them.
+  pub lease_keepalive_interval: Option<Duration>,
+  pub let_data_grace_period: Duration,
+  pub confirmation_depth: u64,
+  pub max_concurrent_seals: usize,
+  /// Lease proposals received from a peer within a minute, the ones beyond are rejected before any other check.
+  pub max_proposal_rate: Option<usize>,
+  /// Simulates each seal before sending it, so proposals that would revert are rejected without spending gas.
+  pub seal_gas_precheck: bool,
+  /// Native balance in wei the wallet keeps to seal, proposals are rejected below it or below the cost of the seal.
+  pub min_gas_balance: U256,
+  pub max_retrieve_size: usize,
+  pub retrieve_min_interval: Duration,
+  /// Withdraws the storage balance of a token to the wallet once some of its leases expire.
+  pub withdraw_expired: bool,
+}
+
+const WATCHDOG_MAX_CONSECUTIVE_FAILURES: u32 = 3;
+const TENTATIVE_CONFIRMATIONS_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// Removals of a reorg arrive in a burst, the affected leases are reconciled once no event arrived for this long
+const REORG_RECONCILE_DELAY: Duration = Duration::from_secs(5);
+// Peers tracked by the retrieve rate limiter, the least recently served ones are forgotten first
+const RETRIEVE_RATE_LIMIT_PEERS: usize = 1024;
+// Sliding window of the proposal rate limiter, and the peers it tracks
+const PROPOSAL_RATE_WINDOW: Duration = Duration::from_secs(60);
+const PROPOSAL_RATE_LIMIT_PEERS: usize = 1024;
+// Events buffered for each receiver of the reactor events
+const REACTOR_EVENTS_CAPACITY: usize = 256;
+// Gas of a seal when it is not estimated, above what the adjudicator usually takes
+const SEAL_GAS_FALLBACK: u64 = 300_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct TentativeKey {
+  lessor: Address,
+  lessee: Address,
+  nonce: u64,
+}
+
+#[derive(Debug, Clone)]
+struct TentativeConfirmation {
+  block_number: u64,
+  chain_confirmation: ChainConfirmation,
 }
 
 #[derive(Clone)]
@@ -39,6 +164,43 @@ where
   onchain: TOnchain,
   p2p: TP2p,
   persistence: TPersistence,
+  params: ReactorParams,
+  tentative_confirmations: Arc<Mutex<HashMap<TentativeKey, TentativeConfirmation>>>,
+  seal_permits: Arc<Semaphore>,
+  proposals_in_flight: Arc<Mutex<HashSet<(PeerId, u64)>>>,
+  retrieves_served: Arc<Mutex<LruCache<PeerId, Instant>>>,
+  proposals_received: Arc<Mutex<RequestWindows>>,
+  quotes_answered: Arc<Mutex<RequestWindows>>,
+  events: broadcast::Sender<ReactorEvent>,
+}
+
+type RequestWindows = LruCache<PeerId, VecDeque<Instant>>;
+
+/// Whether the peer already made `max_rate` requests in the sliding window, dropping the ones that left it.
+fn rate_limited(windows: &mut RequestWindows, peer_id: PeerId, max_rate: usize) -> bool {
+  let now = Instant::now();
+  match windows.get_mut(&peer_id) {
+    Some(requests) => {
+      while requests
+        .front()
+        .map(|t| now.duration_since(*t) >= PROPOSAL_RATE_WINDOW)
+        .unwrap_or(false)
+      {
+        requests.pop_front();
+      }
+      requests.len() >= max_rate
+    }
+    None => false,
+  }
+}
+
+fn record_request(windows: &mut RequestWindows, peer_id: PeerId) {
+  match windows.get_mut(&peer_id) {
+    Some(requests) => requests.push_back(Instant::now()),
+    None => {
+      windows.insert(peer_id, VecDeque::from(vec![Instant::now()]));
+    }
+  }
 }
 
 pub fn new_service<TData, TLessor, TOnchain, TP2p, TPersistence>(
@@ -47,6 +209,7 @@ pub fn new_service<TData, TLessor, TOnchain, TP2p, TPersistence>(
   onchain: TOnchain,
   p2p: TP2p,
   persistence: TPersistence,
+  params: ReactorParams,
 ) -> (impl Service, impl Future<Output = ()>)
 where
   TData: data::Service,
@@ -61,20 +224,59 @@ where
     onchain,
     p2p,
     persistence,
+    tentative_confirmations: Arc::new(Mutex::new(HashMap::new())),
+    seal_permits: Arc::new(Semaphore::new(params.max_concurrent_seals)),
+    proposals_in_flight: Arc::new(Mutex::new(HashSet::new())),
+    retrieves_served: Arc::new(Mutex::new(LruCache::new(RETRIEVE_RATE_LIMIT_PEERS))),
+    proposals_received: Arc::new(Mutex::new(LruCache::new(PROPOSAL_RATE_LIMIT_PEERS))),
+    quotes_answered: Arc::new(Mutex::new(LruCache::new(PROPOSAL_RATE_LIMIT_PEERS))),
+    events: broadcast::channel(REACTOR_EVENTS_CAPACITY).0,
+    params,
   };
 
   type ReactorFuture = Pin<Box<dyn Future<Output = ()>>>;
 
   let p2p_fut: ReactorFuture = Box::pin(implementation.clone().process_p2p_events());
   let onchain_fut: ReactorFuture = Box::pin(implementation.clone().process_onchain_events());
-  let futures = vec![p2p_fut, onchain_fut];
+  let integrity_fut: Option<ReactorFuture> = implementation
+    .params
+    .integrity_check_interval
+    .map(|interval| Box::pin(implementation.clone().process_integrity_checks(interval)) as ReactorFuture);
+  let watchdog_fut: Option<ReactorFuture> = implementation
+    .params
+    .challenge_watchdog_interval
+    .map(|interval| Box::pin(implementation.clone().process_challenge_watchdog(interval)) as ReactorFuture);
+  let expiry_fut: Option<ReactorFuture> = implementation
+    .params
+    .expiry_check_interval
+    .map(|interval| Box::pin(implementation.clone().process_expired_leases(interval)) as ReactorFuture);
+  let keepalive_fut: Option<ReactorFuture> = implementation
+    .params
+    .lease_keepalive_interval
+    .map(|interval| Box::pin(implementation.clone().process_lease_keepalive(interval)) as ReactorFuture);
+  let tentative_fut: Option<ReactorFuture> = (implementation.params.confirmation_depth > 0)
+    .then(|| Box::pin(implementation.clone().process_tentative_confirmations()) as ReactorFuture);
+  let futures: Vec<ReactorFuture> = vec![
+    Some(p2p_fut),
+    Some(onchain_fut),
+    integrity_fut,
+    watchdog_fut,
+    expiry_fut,
+    keepalive_fut,
+    tentative_fut,
+  ]
+  .into_iter()
+  .flatten()
+  .collect();
   (implementation, join_all(futures).map(|_| ()))
 }
 
 enum ProcessProposalError {
   Rejected(lessor::RejectedReason),
+  SealReverted(String),
   OnchainError(onchain::Error),
   DataError(anyhow::Error),
+  KeyError(KeyError),
 }
 
 impl From<onchain::Error> for ProcessProposalError {
@@ -83,6 +285,12 @@ impl From<onchain::Error> for ProcessProposalError {
   }
 }
 
+impl From<KeyError> for ProcessProposalError {
+  fn from(value: KeyError) -> Self {
+    ProcessProposalError::KeyError(value)
+  }
+}
+
 impl From<anyhow::Error> for ProcessProposalError {
   fn from(value: anyhow::Error) -> Self {
     ProcessProposalError::DataError(value)
@@ -93,12 +301,16 @@ impl Display for ProcessProposalError {
   fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
     match self {
       ProcessProposalError::Rejected(reason) => write!(f, "proposal rejected: {}", reason),
+      ProcessProposalError::SealReverted(reason) => write!(f, "seal would revert: {}", reason),
       ProcessProposalError::OnchainError(err) => {
         write!(f, "onchain error: {}", err)
       }
       ProcessProposalError::DataError(err) => {
         write!(f, "data error: {}", err)
       }
+      ProcessProposalError::KeyError(err) => {
+        write!(f, "peer key error: {}", err)
+      }
     }
   }
 }
@@ -111,26 +323,121 @@ where
   TP2p: p2p::Service,
   TPersistence: persistence::Service,
 {
+  fn emit(&self, event: ReactorEvent) {
+    // Sending fails only when nobody is subscribed
+    let _ = self.events.send(event);
+  }
+
   async fn process_p2p_events(mut self) {
+    // Peers from previous runs are dialed right away instead of waiting to discover them again
+    for peer in self.persistence.peers_list().await {
+      debug!("dialing known peer peer_id={} addresses={:?}", peer.peer_id, peer.addresses);
+      self.p2p.dial(peer.peer_id, peer.addresses);
+    }
     while let Some(ev) = self.p2p.next().await {
       match ev {
         Event::ReceivedLeaseProposal { peer_id, proposal } => {
+          if self.proposal_rate_limited(peer_id) {
+            let reason = lessor::RejectedReason::TooManyRequests;
+            warn!(
+              "rejecting lease proposal, rate limited peer_id={} nonce={}",
+              peer_id, proposal.nonce
+            );
+            self.emit(ReactorEvent::ProposalRejected {
+              peer_id,
+              nonce: proposal.nonce,
+              reason: reason.to_string(),
+            });
+            if let Err(e) = self
+              .p2p
+              .send_proposal_rejection(peer_id, proposal.nonce, reason.to_string())
+              .await
+            {
+              warn!("could not send proposal rejection peer_id={} error={}", peer_id, e);
+            }
+            continue;
+          }
+          let proposal_key = (peer_id, proposal.nonce);
+          // Retransmitted proposals are ignored, the first one is already sealed or being sealed
+          if self.persistence.let_get(peer_id, proposal.nonce).await.is_some()
+            || !self.proposals_in_flight.lock().unwrap().insert(proposal_key)
+          {
+            debug!(
+              "ignoring duplicated lease proposal peer_id={} nonce={}",
+              peer_id, proposal.nonce
+            );
+            continue;
+          }
+          let permit = match self.seal_permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+              warn!(
+                "rejecting lease proposal, too many concurrent seals peer_id={} nonce={}",
+                peer_id, proposal.nonce
+              );
+              self.proposals_in_flight.lock().unwrap().remove(&proposal_key);
+              self.emit(ReactorEvent::ProposalRejected {
+                peer_id,
+                nonce: proposal.nonce,
+                reason: "busy".to_string(),
+              });
+              if let Err(e) = self
+                .p2p
+                .send_proposal_rejection(peer_id, proposal.nonce, "busy".to_string())
+                .await
+              {
+                warn!("could not send proposal rejection peer_id={} error={}", peer_id, e);
+              }
+              continue;
+            }
+          };
           let self_clone = self.clone();
           tokio::task::spawn(async move {
+            let _permit = permit;
             let nonce = proposal.nonce;
             match self_clone.process_proposal_received(peer_id, proposal).await {
-              Ok(TransactionResult::Hash(hash)) => info!("lease sealed transaction_hash={}", hash),
-              Ok(TransactionResult::Receipt(receipt)) => info!("lease sealed transaction_hash={}", receipt.transaction_hash),
+              Ok(result) => {
+                let transaction_hash = result.hash();
+                info!("lease sealed transaction_hash={}", transaction_hash);
+                self_clone.emit(ReactorEvent::ProposalSealed {
+                  peer_id,
+                  nonce,
+                  transaction_hash,
+                });
+                if let Err(e) = self_clone.p2p.send_lease_sealed(peer_id, nonce, transaction_hash).await {
+                  warn!("could not send lease sealed peer_id={} error={}", peer_id, e);
+                }
+              }
               Err(ProcessProposalError::Rejected(reason)) => {
-                self_clone
+                self_clone.emit(ReactorEvent::ProposalRejected {
+                  peer_id,
+                  nonce,
+                  reason: reason.to_string(),
+                });
+                if let Err(e) = self_clone
                   .p2p
                   .send_proposal_rejection(peer_id, nonce, reason.to_string())
-                  .await;
+                  .await
+                {
+                  warn!("could not send proposal rejection peer_id={} error={}", peer_id, e);
+                }
+              }
+              Err(err @ ProcessProposalError::SealReverted(_)) => {
+                warn!("rejecting lease proposal peer_id={} nonce={}: {}", peer_id, nonce, err);
+                self_clone.emit(ReactorEvent::ProposalRejected {
+                  peer_id,
+                  nonce,
+                  reason: err.to_string(),
+                });
+                if let Err(e) = self_clone.p2p.send_proposal_rejection(peer_id, nonce, err.to_string()).await {
+                  warn!("could not send proposal rejection peer_id={} error={}", peer_id, e);
+                }
               }
               Err(err) => {
                 error!("unexpected error while processing lease proposal: {}", err);
               }
             }
+            self_clone.proposals_in_flight.lock().unwrap().remove(&proposal_key);
           });
         }
         Event::ReceivedChallengeRequest { peer_id, challenge_key } => {
@@ -143,6 +450,10 @@ where
           });
         }
         Event::ReceivedRetrieveRequest { peer_id, nonce } => {
+          if self.retrieve_rate_limited(peer_id) {
+            warn!("ignoring retrieve request, rate limited peer_id={} nonce={}", peer_id, nonce);
+            continue;
+          }
           let self_clone = self.clone();
           tokio::task::spawn(async move {
             let result = self_clone.send_retrieve_delivery(peer_id, nonce).await;
@@ -151,20 +462,78 @@ where
             }
           });
         }
+        Event::ReceivedQuoteRequest {
+          peer_id,
+          quote_id,
+          lease_terms,
+          size,
+        } => {
+          let self_clone = self.clone();
+          tokio::task::spawn(async move {
+            let response = if self_clone.quote_rate_limited(peer_id) {
+              debug!(
+                "rejecting quote request, rate limited peer_id={} quote_id={}",
+                peer_id, quote_id
+              );
+              QuoteResponse::Rejected(lessor::RejectedReason::TooManyRequests.to_string())
+            } else {
+              match self_clone.lessor.proposal(&peer_id, &lease_terms, size).await {
+                Ok(()) => QuoteResponse::Accepted,
+                Err(reason) => QuoteResponse::Rejected(reason.to_string()),
+              }
+            };
+            if let Err(e) = self_clone.p2p.send_quote_response(peer_id, quote_id, response).await {
+              warn!("could not send quote response peer_id={} error={}", peer_id, e);
+            }
+          });
+        }
+        Event::PeerIdentified { peer_id, listen_addrs } => {
+          self.persistence.peers_store(peer_id, listen_addrs).await;
+        }
       }
     }
   }
 
   async fn process_onchain_events(self) {
     let mut events_stream = self.onchain.listen_adjudicator_events().await;
-    while let Some(ev) = events_stream.next().await {
+    // Leases sealed while we were not running would never be confirmed by the stream
+    if let Err(e) = self.backfill_events().await {
+      error!("reactor: error backfilling onchain events: {}", e);
+    }
+    self.reconcile_pending_leases().await;
+    let mut reorged = HashSet::new();
+    loop {
+      let ev = if reorged.is_empty() {
+        events_stream.next().await
+      } else {
+        match tokio::time::timeout(REORG_RECONCILE_DELAY, events_stream.next()).await {
+          Ok(ev) => ev,
+          Err(_) => {
+            self.reconcile_reorged_leases(std::mem::take(&mut reorged)).await;
+            continue;
+          }
+        }
+      };
+      let ev = match ev {
+        Some(ev) => ev,
+        None => break,
+      };
       match ev {
         Err(e) => error!("TODO: reactor: error receiving onchain events: {}", e),
         Ok(ethcontract::Event { data, meta: Some(meta) }) => {
+          let block_number = meta.block_number;
+          if let EventStatus::Removed(ev) = &data {
+            reorged.insert(TentativeKey {
+              lessor: ev.lessor,
+              lessee: ev.lessee,
+              nonce: ev.nonce,
+            });
+          }
           let result = self.process_onchain_event(data, meta).await;
           if let Err(e) = result {
             error!("reactor: error processing onchain event: {}", e)
           }
+          self.persistence.set_last_processed_block(block_number).await;
         }
         Ok(ethcontract::Event { meta: None, .. }) => {
           unreachable!("we are not looking for not confirmed events")
@@ -173,6 +542,420 @@ where
     }
   }
 
+  async fn backfill_events(&self) -> Result<(), Box<dyn Error>> {
+    let last_processed = match self.persistence.last_processed_block().await {
+      Some(block_number) => block_number,
+      None => {
+        // Nothing to backfill on a fresh start, pending leases are reconciled one by one
+        let current_block = self.onchain.block_number().await?;
+        self.persistence.set_last_processed_block(current_block).await;
+        return Ok(());
+      }
+    };
+    // Tentative confirmations are not persisted, so blocks inside the confirmation depth are processed again
+    let from_block = last_processed.saturating_sub(self.params.confirmation_depth) + 1;
+    let to_block = self.onchain.block_number().await?;
+    if from_block > to_block {
+      return Ok(());
+    }
+    info!("backfilling onchain events from_block={} to_block={}", from_block, to_block);
+    for event in self.onchain.backfill_events(from_block, to_block).await? {
+      match event {
+        ethcontract::Event { data, meta: Some(meta) } => {
+          if let Err(e) = self.process_onchain_event(data, meta).await {
+            error!("reactor: error processing backfilled onchain event: {}", e)
+          }
+        }
+        ethcontract::Event { meta: None, .. } => warn!("skipping backfilled onchain event without block"),
+      }
+    }
+    self.persistence.set_last_processed_block(to_block).await;
+    Ok(())
+  }
+
+  async fn reconcile_pending_leases(&self) {
+    let pending = self
+      .persistence
+      .rent_list()
+      .await
+      .into_iter()
+      .filter(|l| l.chain_confirmation.is_none())
+      .collect::<Vec<_>>();
+    if pending.is_empty() {
+      return;
+    }
+    info!("reconciling pending leases leases={}", pending.len());
+    for lease in pending {
+      if let Err(e) = self.reconcile_pending_lease(&lease).await {
+        error!(
+          "error reconciling pending lease peer_id={} nonce={}: {}",
+          lease.peer_id, lease.nonce, e
+        );
+      }
+    }
+  }
+
+  async fn reconcile_pending_lease(&self, lease: &Lease) -> Result<(), Box<dyn Error>> {
+    let event = self
+      .onchain
+      .find_lease_sealed(
+        &lease.terms.token_address,
+        lease.peer_address,
+        self.onchain.account_storage(),
+        lease.nonce,
+      )
+      .await?;
+    match event {
+      Some(ethcontract::Event { meta: Some(meta), .. }) => {
+        let timestamp = self
+          .onchain
+          .block_timestamp(meta.block_hash)
+          .await?
+          .ok_or("block not found")?;
+        info!(
+          "pending lease sealed peer_id={} nonce={} transaction_hash={}",
+          lease.peer_id, lease.nonce, meta.transaction_hash
+        );
+        self
+          .record_confirmation(
+            TentativeKey {
+              lessor: lease.peer_address,
+              lessee: self.onchain.account_storage(),
+              nonce: lease.nonce,
+            },
+            TentativeConfirmation {
+              block_number: meta.block_number,
+              chain_confirmation: ChainConfirmation {
+                transaction_hash: meta.transaction_hash,
+                timestamp,
+              },
+            },
+          )
+          .await?;
+      }
+      Some(ethcontract::Event { meta: None, .. }) => warn!(
+        "sealed event without block, lease left pending peer_id={} nonce={}",
+        lease.peer_id, lease.nonce
+      ),
+      None => trace!("lease still pending peer_id={} nonce={}", lease.peer_id, lease.nonce),
+    }
+    Ok(())
+  }
+
+  /// Queries again the leases whose events were removed by a reorg, the removals and the events added back on the new
+  /// branch arrive in no particular order, so the persisted confirmation is corrected from the chain state.
+  async fn reconcile_reorged_leases(&self, keys: HashSet<TentativeKey>) {
+    info!("reconciling leases after a reorg leases={}", keys.len());
+    for key in keys {
+      if let Err(e) = self.reconcile_reorged_lease(&key).await {
+        error!("error reconciling lease after a reorg {:?}: {}", key, e);
+      }
+    }
+  }
+
+  async fn reconcile_reorged_lease(&self, key: &TentativeKey) -> Result<(), Box<dyn Error>> {
+    // Tentative confirmations are settled later by the confirmation depth
+    if self.tentative_confirmations.lock().unwrap().contains_key(key) {
+      return Ok(());
+    }
+    let (leases, peer_address) = if key.lessee == self.onchain.account_storage() {
+      (self.persistence.rent_list().await, key.lessor)
+    } else {
+      (self.persistence.let_list().await, key.lessee)
+    };
+    let lease = leases
+      .into_iter()
+      .find(|l| l.peer_address == peer_address && l.nonce == key.nonce)
+      .ok_or("lease not found")?;
+
+    // The sealed event is the state of the lease on chain, it is gone when the reorg dropped the seal
+    let chain_confirmation = match self
+      .onchain
+      .find_lease_sealed(&lease.terms.token_address, key.lessor, key.lessee, key.nonce)
+      .await?
+    {
+      Some(ethcontract::Event { meta: Some(meta), .. }) => Some(ChainConfirmation {
+        transaction_hash: meta.transaction_hash,
+        timestamp: self
+          .onchain
+          .block_timestamp(meta.block_hash)
+          .await?
+          .ok_or("block not found")?,
+      }),
+      Some(ethcontract::Event { meta: None, .. }) => return Err("sealed event without block".into()),
+      None => None,
+    };
+
+    let transaction_hash = |c: &Option<ChainConfirmation>| c.as_ref().map(|c| c.transaction_hash);
+    if transaction_hash(&lease.chain_confirmation) != transaction_hash(&chain_confirmation) {
+      info!(
+        "lease corrected after a reorg peer_id={} nonce={} transaction_hash={:?}",
+        lease.peer_id,
+        lease.nonce,
+        transaction_hash(&chain_confirmation)
+      );
+      self.update_chain(key, chain_confirmation).await?;
+    }
+    Ok(())
+  }
+
+  async fn process_integrity_checks(self, interval: Duration) {
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+      ticker.tick().await;
+      let leases = self.persistence.let_list().await;
+      trace!("checking integrity of stored data leases={}", leases.len());
+      let mut failures = 0;
+      for lease in leases {
+        match self.data.verify_stored(lease.peer_id, lease.nonce).await {
+          Ok(true) => {
+            trace!("stored data is intact peer_id={} nonce={}", lease.peer_id, lease.nonce);
+            continue;
+          }
+          Ok(false) => error!(
+            "stored data does not match the merkle root peer_id={} nonce={}",
+            lease.peer_id, lease.nonce
+          ),
+          Err(e) => error!(
+            "error checking stored data integrity peer_id={} nonce={}: {}",
+            lease.peer_id, lease.nonce, e
+          ),
+        }
+        failures += 1;
+        self.emit(ReactorEvent::IntegrityCheckFailed {
+          peer_id: lease.peer_id,
+          nonce: lease.nonce,
+        });
+      }
+      if failures > 0 {
+        warn!("integrity check finished with failures={}", failures);
+      }
+    }
+  }
+
+  async fn process_challenge_watchdog(self, interval: Duration) {
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+      ticker.tick().await;
+      let now = SystemTime::now();
+      let leases = self
+        .persistence
+        .rent_list()
+        .await
+        .into_iter()
+        .filter(|l| {
+          l.chain_confirmation
+            .as_ref()
+            .map(|c| c.timestamp + l.terms.lease_duration > now)
+            .unwrap_or(false)
+        })
+        // Empty data has no block to prove
+        .filter(|l| l.data_parameters.size > 0)
+        .collect::<Vec<_>>();
+      trace!("watchdog challenging active leases={}", leases.len());
+      for lease in leases {
+        let block_size = self.data.block_size();
+        let blocks = lease.data_parameters.size.div_ceil(block_size);
+        let block_number = rand::thread_rng().gen_range(0..blocks) as u32;
+        let challenge_key = ChallengeKey {
+          nonce: lease.nonce,
+          block_number,
+        };
+        // The challenge times out after the p2p challenge timeout, independently of the other requests
+        let outcome = match self.challenge(lease.peer_id, challenge_key).await {
+          Ok(result) if result.verified => ChallengeOutcome::Honored,
+          Ok(_) => {
+            warn!(
+              "watchdog challenge proof not valid peer_id={} nonce={} block_number={}",
+              lease.peer_id, lease.nonce, block_number
+            );
+            ChallengeOutcome::Failed
+          }
+          Err(e) if e.is::<p2p::RequestTimeout>() => ChallengeOutcome::Timeout,
+          Err(e) => {
+            warn!(
+              "watchdog challenge failed peer_id={} nonce={} block_number={}: {}",
+              lease.peer_id, lease.nonce, block_number, e
+            );
+            ChallengeOutcome::Failed
+          }
+        };
+        let record = self
+          .persistence
+          .rent_record_challenge(lease.peer_id, lease.nonce, outcome)
+          .await;
+        trace!(
+          "watchdog challenge peer_id={} nonce={} outcome={:?} record={:?}",
+          lease.peer_id,
+          lease.nonce,
+          outcome,
+          record
+        );
+        if record.consecutive_failures >= WATCHDOG_MAX_CONSECUTIVE_FAILURES {
+          warn!(
+            "lease failed repeated challenges peer_id={} nonce={} consecutive_failures={}",
+            lease.peer_id, lease.nonce, record.consecutive_failures
+          );
+        }
+      }
+    }
+  }
+
+  async fn process_expired_leases(self, interval: Duration) {
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+      ticker.tick().await;
+      let now = SystemTime::now();
+      // Tokens whose locked funds the expired leases freed
+      let mut freed_tokens = HashSet::new();
+
+      for lease in self.persistence.rent_list().await {
+        if self.never_sealed(&lease, now) {
+          info!(
+            "removing rent lease never sealed peer_id={} nonce={}",
+            lease.peer_id, lease.nonce
+          );
+          self.persistence.rent_remove(lease.peer_id, lease.nonce).await;
+          continue;
+        }
+        if !lease.completed && lease_ends(&lease).map(|ends| ends <= now).unwrap_or(false) {
+          info!("rent lease expired peer_id={} nonce={}", lease.peer_id, lease.nonce);
+          self.emit(ReactorEvent::LeaseExpired {
+            peer_id: lease.peer_id,
+            nonce: lease.nonce,
+          });
+          match self.persistence.rent_complete(lease.peer_id, lease.nonce).await {
+            Ok(()) => {
+              freed_tokens.insert(lease.terms.token_address);
+            }
+            Err(e) => error!(
+              "error completing rent lease peer_id={} nonce={}: {}",
+              lease.peer_id, lease.nonce, e
+            ),
+          }
+        }
+      }
+
+      for lease in self.persistence.let_list().await {
+        if self.never_sealed(&lease, now) {
+          info!(
+            "removing let lease never sealed peer_id={} nonce={}",
+            lease.peer_id, lease.nonce
+          );
+          self.remove_let(&lease).await;
+          continue;
+        }
+        let ends = match lease_ends(&lease) {
+          Some(ends) => ends,
+          None => continue,
+        };
+        if !lease.completed && ends <= now {
+          info!("let lease expired peer_id={} nonce={}", lease.peer_id, lease.nonce);
+          self.emit(ReactorEvent::LeaseExpired {
+            peer_id: lease.peer_id,
+            nonce: lease.nonce,
+          });
+          match self.persistence.let_complete(lease.peer_id, lease.nonce).await {
+            Ok(()) => {
+              freed_tokens.insert(lease.terms.token_address);
+            }
+            Err(e) => error!(
+              "error completing let lease peer_id={} nonce={}: {}",
+              lease.peer_id, lease.nonce, e
+            ),
+          }
+        }
+        if ends + self.params.let_data_grace_period <= now {
+          self.remove_let(&lease).await;
+        }
+      }
+
+      if self.params.withdraw_expired {
+        for token_address in freed_tokens {
+          self.withdraw_freed(token_address).await;
+        }
+      }
+    }
+  }
+
+  /// Whether the lease was never confirmed and can no longer be, its proposal expired more than a grace period ago.
+  fn never_sealed(&self, lease: &Lease, now: SystemTime) -> bool {
+    lease.chain_confirmation.is_none() && lease.terms.proposal_expiration + self.params.let_data_grace_period <= now
+  }
+
+  /// Removes the data of a let lease, and the lease once its data is gone.
+  async fn remove_let(&self, lease: &Lease) {
+    match self.data.remove(lease.peer_id, lease.nonce).await {
+      Ok(()) => {
+        info!(
+          "removed data of expired lease peer_id={} nonce={}",
+          lease.peer_id, lease.nonce
+        );
+        self.persistence.let_remove(lease.peer_id, lease.nonce).await;
+      }
+      Err(e) => error!(
+        "error removing data of expired lease peer_id={} nonce={}: {}",
+        lease.peer_id, lease.nonce, e
+      ),
+    }
+  }
+
+  /// Withdraws the storage balance of the token available once its expired leases unlocked their funds.
+  async fn withdraw_freed(&self, token_address: Address) {
+    let available = match self.onchain.balance(&token_address, true).await {
+      Ok(balance) => balance.storage_balance.available,
+      Err(e) => {
+        error!("error reading the balance to withdraw token={:?}: {}", token_address, e);
+        return;
+      }
+    };
+    if available.is_zero() {
+      return;
+    }
+    match self.onchain.withdraw(&token_address, available, None).await {
+      Ok(_) => info!(
+        "withdrew funds freed by expired leases token={:?} amount={}",
+        token_address, available
+      ),
+      Err(e) => error!(
+        "error withdrawing funds freed by expired leases token={:?}: {}",
+        token_address, e
+      ),
+    }
+  }
+
+  async fn process_lease_keepalive(self, interval: Duration) {
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+      ticker.tick().await;
+      let now = SystemTime::now();
+      let rents = self.persistence.rent_list().await;
+      let lets = self.persistence.let_list().await;
+      let lease_peers = rents
+        .iter()
+        .chain(lets.iter())
+        .filter(|l| !l.completed && lease_ends(l).map(|ends| ends > now).unwrap_or(false))
+        .map(|l| l.peer_id)
+        .collect::<HashSet<_>>();
+      if lease_peers.is_empty() {
+        continue;
+      }
+      let connected = self.p2p.known_peers().into_iter().collect::<HashSet<_>>();
+      let addresses = self
+        .persistence
+        .peers_list()
+        .await
+        .into_iter()
+        .map(|p| (p.peer_id, p.addresses))
+        .collect::<HashMap<_, _>>();
+      for peer_id in lease_peers.difference(&connected) {
+        // Dials within the cooldown of the previous one are skipped by the p2p service
+        debug!("dialing disconnected peer with active leases peer_id={}", peer_id);
+        self.p2p.dial(*peer_id, addresses.get(peer_id).cloned().unwrap_or_default());
+      }
+    }
+  }
+
   async fn process_proposal_received(
     &self,
     peer_id: PeerId,
@@ -183,52 +966,231 @@ where
     TOnchain: onchain::Service,
     TP2p: p2p::Service,
   {
-    if let Err(e) = self
-      .lessor
-      .proposal(&peer_id, &proposal.lease_terms, proposal.data.len())
-      .await
-    {
+    let size = proposal.data.len();
+    if let Err(e) = self.lessor.admit(&peer_id, &proposal.lease_terms, size).await {
       return Err(ProcessProposalError::Rejected(e));
     }
+    let result = self.seal_admitted_proposal(peer_id, proposal).await;
+    // Counted by the persistence once sealed, so the reservation is not needed either way
+    self.lessor.release(size).await;
+    result
+  }
 
-    let lessee_address = self
-      .p2p
-      .find_public_key(&peer_id)
-      .as_ref()
-      .map(IntoAddress::into_address)
-      .expect("peer id should be identified already");
-    // TODO check if the nonce is duplicated
-    let data_parameters = self.data.store(peer_id, proposal.nonce, proposal.data.as_slice()).await?;
+  async fn seal_admitted_proposal(
+    &self,
+    peer_id: PeerId,
+    proposal: LeaseProposal,
+  ) -> Result<TransactionResult, ProcessProposalError>
+  where
+    TData: data::Service,
+    TOnchain: onchain::Service,
+    TP2p: p2p::Service,
+  {
+    // Proposals come over a connection, the peer is identified unless it disconnected since
+    let lessee_address = match self.p2p.find_public_key(&peer_id) {
+      Some(public_key) => public_key.try_into_address()?,
+      None => return Err(ProcessProposalError::Rejected(lessor::RejectedReason::UnknownPeer)),
+    };
+    // A lease with ourselves is never sealed, whichever peer sent the proposal
+    if lessee_address == self.onchain.account_storage() {
+      return Err(ProcessProposalError::Rejected(lessor::RejectedReason::SelfLease));
+    }
+
+    let data_parameters = self.data.parameters(proposal.data.as_slice()).await;
+    let seal_gas = if self.params.seal_gas_precheck {
+      let estimate = self
+        .onchain
+        .estimate_seal_gas(
+          lessee_address,
+          proposal.nonce,
+          &proposal.lease_terms,
+          &data_parameters,
+          &proposal.signature,
+        )
+        .await?;
+      match estimate {
+        Ok(gas) => {
+          debug!("seal gas estimated peer_id={} nonce={} gas={}", peer_id, proposal.nonce, gas);
+          gas
+        }
+        Err(reason) => return Err(ProcessProposalError::SealReverted(reason)),
+      }
+    } else {
+      SEAL_GAS_FALLBACK.into()
+    };
+    self.check_gas_balance(peer_id, proposal.nonce, seal_gas).await?;
+
+    self
+      .data
+      .store(peer_id, proposal.nonce, proposal.data.as_slice(), &data_parameters)
+      .await?;
 
     let result = self
       .onchain
       .seal_lease(
         lessee_address,
         proposal.nonce,
-        proposal.lease_terms,
-        data_parameters,
+        proposal.lease_terms.clone(),
+        data_parameters.clone(),
         proposal.signature,
       )
       .await?;
     info!("lease sealed peer_id={} transaction_result={:?}", peer_id, result);
+
+    self
+      .persistence
+      .let_store(Lease {
+        peer_id,
+        peer_address: lessee_address,
+        nonce: proposal.nonce,
+        terms: proposal.lease_terms,
+        data_parameters,
+        chain_confirmation: None,
+        completed: false,
+      })
+      .await;
     Ok(result)
   }
 
+  /// Rejects the proposal when the wallet, which sends the seal, cannot pay it, instead of the seal failing on chain.
+  async fn check_gas_balance(&self, peer_id: PeerId, nonce: u64, seal_gas: U256) -> Result<(), ProcessProposalError> {
+    let gas_price = self.onchain.gas_price().await?;
+    let required = std::cmp::max(seal_gas.saturating_mul(gas_price), self.params.min_gas_balance);
+    let balance = self.onchain.native_balance(self.onchain.account_wallet()).await?;
+    if balance < required {
+      warn!(
+        "wallet balance too low to seal peer_id={} nonce={} balance={} required={}",
+        peer_id, nonce, balance, required
+      );
+      return Err(ProcessProposalError::Rejected(lessor::RejectedReason::LessorOutOfGas));
+    }
+    Ok(())
+  }
+
   async fn send_proof(&self, peer_id: PeerId, challenge_key: ChallengeKey) -> Result<(), Box<dyn Error>> {
     let (block_data, proof) = self
       .data
       .proof(peer_id, challenge_key.nonce, challenge_key.block_number as usize)
       .await?;
+    let challenge_proof = ChallengeProof { block_data, proof };
+    // The proof is checked against our own parameters, so the audit log tells whether we answered correctly
+    let verified = match self.data.stored_parameters(peer_id, challenge_key.nonce).await {
+      Ok(parameters) => self
+        .data
+        .verify(
+          parameters,
+          challenge_key.block_number,
+          challenge_proof.block_data.as_slice(),
+          challenge_proof.proof.clone(),
+        )
+        .await
+        .unwrap_or(false),
+      Err(_) => false,
+    };
     self
-      .p2p
-      .send_challenge_proof(peer_id, challenge_key, ChallengeProof { block_data, proof })
+      .persistence
+      .audit_append(
+        AuditRole::Prover,
+        peer_id,
+        challenge_key.clone(),
+        challenge_proof.hash(),
+        verified,
+      )
       .await;
+    self.p2p.send_challenge_proof(peer_id, challenge_key, challenge_proof).await?;
     Ok(())
   }
 
+  async fn submit_failed_challenge(&self, lease: &Lease, block_number: u32, proof: Option<ChallengeProof>) {
+    self.emit(ReactorEvent::ChallengeFailed {
+      peer_id: lease.peer_id,
+      nonce: lease.nonce,
+      block_number,
+    });
+    let result = self
+      .onchain
+      .submit_challenge(
+        &lease.terms.token_address,
+        lease.peer_address,
+        lease.nonce,
+        block_number,
+        proof,
+      )
+      .await;
+    match result {
+      Ok(TransactionResult::Hash(hash)) => info!(
+        "challenge submitted peer_id={} nonce={} transaction_hash={}",
+        lease.peer_id, lease.nonce, hash
+      ),
+      Ok(TransactionResult::Receipt(receipt)) => info!(
+        "challenge submitted peer_id={} nonce={} transaction_hash={}",
+        lease.peer_id, lease.nonce, receipt.transaction_hash
+      ),
+      Err(e) => error!(
+        "error submitting challenge peer_id={} nonce={}: {}",
+        lease.peer_id, lease.nonce, e
+      ),
+    }
+  }
+
+  fn retrieve_rate_limited(&self, peer_id: PeerId) -> bool {
+    let now = Instant::now();
+    let mut retrieves_served = self.retrieves_served.lock().unwrap();
+    if let Some(last_served) = retrieves_served.get(&peer_id) {
+      if now.duration_since(*last_served) < self.params.retrieve_min_interval {
+        return true;
+      }
+    }
+    retrieves_served.insert(peer_id, now);
+    false
+  }
+
+  /// Whether the peer already sent `max_proposal_rate` proposals in the sliding window, otherwise the proposal is
+  /// counted whatever its outcome, so proposals failing the later checks are limited too.
+  fn proposal_rate_limited(&self, peer_id: PeerId) -> bool {
+    let max_rate = match self.params.max_proposal_rate {
+      Some(max_rate) => max_rate,
+      None => return false,
+    };
+    let mut proposals_received = self.proposals_received.lock().unwrap();
+    if rate_limited(&mut proposals_received, peer_id, max_rate) {
+      return true;
+    }
+    record_request(&mut proposals_received, peer_id);
+    false
+  }
+
+  /// Quotes share the rate of the proposals, every answered one counts as evaluating it costs the same.
+  fn quote_rate_limited(&self, peer_id: PeerId) -> bool {
+    let max_rate = match self.params.max_proposal_rate {
+      Some(max_rate) => max_rate,
+      None => return false,
+    };
+    let mut quotes_answered = self.quotes_answered.lock().unwrap();
+    if rate_limited(&mut quotes_answered, peer_id, max_rate) {
+      return true;
+    }
+    record_request(&mut quotes_answered, peer_id);
+    false
+  }
+
   async fn send_retrieve_delivery(&self, peer_id: PeerId, nonce: u64) -> anyhow::Result<()> {
+    // Answered right away, otherwise the peer waits for a delivery until its retrieve timeout
+    if self.persistence.let_get(peer_id, nonce).await.is_none() {
+      debug!("retrieve request for an unknown lease peer_id={} nonce={}", peer_id, nonce);
+      self.p2p.send_retrieve_not_found(peer_id, nonce).await?;
+      return Ok(());
+    }
+    let parameters = self.data.stored_parameters(peer_id, nonce).await?;
+    if parameters.size > self.params.max_retrieve_size {
+      warn!(
+        "ignoring retrieve request exceeding the size limit peer_id={} nonce={} size={} max={}",
+        peer_id, nonce, parameters.size, self.params.max_retrieve_size
+      );
+      return Ok(());
+    }
     let data = self.data.retrieve(peer_id, nonce).await?;
-    self.p2p.send_retrieve_delivery(peer_id, nonce, data).await;
+    self.p2p.send_retrieve_delivery(peer_id, nonce, data).await?;
 
     Ok(())
   }
@@ -238,61 +1200,196 @@ where
     event: EventStatus<p2pim_ethereum_contracts::adjudicator::event_data::LeaseSealed>,
     meta: EventMetadata,
   ) -> Result<(), Box<dyn Error>> {
-    let own_address = self.onchain.account_storage();
-    let block = self
-      .onchain
-      .block(BlockId::Hash(meta.block_hash))
-      .await?
-      .ok_or("block not found")?;
-    //let block = self.onchain
     match event {
-      EventStatus::Removed(ev) if ev.lessee == own_address => self
-        .persistence
-        .rent_update_chain(ev.lessor, ev.nonce, None)
-        .await
-        .map_err(|_| "lease not found")?,
-      EventStatus::Removed(ev) if ev.lessor == own_address => warn!("TODO: handle lets events"),
-      EventStatus::Added(ev) if ev.lessee == own_address => self
-        .persistence
-        .rent_update_chain(
-          ev.lessor,
-          ev.nonce,
-          Some(ChainConfirmation {
-            transaction_hash: meta.transaction_hash,
-            timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(block.timestamp.as_u64()),
-          }),
-        )
-        .await
-        .unwrap_or_else(|err| error!("reactor: error processing a onchain event: {}: {:?}", err, ev)),
-      EventStatus::Added(ev) if ev.lessor == own_address => warn!("TODO: handle lets events"),
-      _ => error!("received event does not belong to us: {:?}", event),
-    };
-    Ok(())
+      EventStatus::Removed(ev) => {
+        let key = TentativeKey {
+          lessor: ev.lessor,
+          lessee: ev.lessee,
+          nonce: ev.nonce,
+        };
+        if self.tentative_confirmations.lock().unwrap().remove(&key).is_some() {
+          debug!("tentative confirmation removed before being final: {:?}", key);
+          Ok(())
+        } else {
+          self.update_chain(&key, None).await
+        }
+      }
+      EventStatus::Added(ev) => {
+        let block_timestamp = self
+          .onchain
+          .block_timestamp(meta.block_hash)
+          .await?
+          .ok_or("block not found")?;
+        self
+          .record_confirmation(
+            TentativeKey {
+              lessor: ev.lessor,
+              lessee: ev.lessee,
+              nonce: ev.nonce,
+            },
+            TentativeConfirmation {
+              block_number: meta.block_number,
+              chain_confirmation: ChainConfirmation {
+                transaction_hash: meta.transaction_hash,
+                timestamp: block_timestamp,
+              },
+            },
+          )
+          .await
+      }
+    }
   }
-}
 
-#[async_trait]
-impl<TData, TLessor, TOnchain, TP2p, TPersistence> Service for Implementation<TData, TLessor, TOnchain, TP2p, TPersistence>
-where
-  TData: data::Service,
-  TLessor: lessor::Service,
-  TOnchain: onchain::Service,
-  TP2p: p2p::Service,
-  TPersistence: persistence::Service,
-{
-  async fn lease(&self, peer_id: PeerId, terms: LeaseTerms, data: Vec<u8>) -> Result<H256, Box<dyn Error>> {
-    let nonce = rand::random(); // TODO Is this ok?
-    let data_parameters = self.data.parameters(data.as_slice()).await;
-    let lessor_address = self
+  async fn record_confirmation(&self, key: TentativeKey, tentative: TentativeConfirmation) -> Result<(), Box<dyn Error>> {
+    if self.params.confirmation_depth == 0 {
+      self.update_chain(&key, Some(tentative.chain_confirmation)).await
+    } else {
+      debug!("tentative confirmation at block_number={}: {:?}", tentative.block_number, key);
+      self.tentative_confirmations.lock().unwrap().insert(key, tentative);
+      Ok(())
+    }
+  }
+
+  async fn process_tentative_confirmations(self) {
+    let mut ticker = tokio::time::interval(TENTATIVE_CONFIRMATIONS_CHECK_INTERVAL);
+    loop {
+      ticker.tick().await;
+      let current_block = match self.onchain.block_number().await {
+        Ok(block_number) => block_number,
+        Err(e) => {
+          error!("error reading current block number: {}", e);
+          continue;
+        }
+      };
+      let depth = self.params.confirmation_depth;
+      let finals = {
+        let mut guard = self.tentative_confirmations.lock().unwrap();
+        let keys = guard
+          .iter()
+          .filter(|(_, t)| t.block_number + depth <= current_block)
+          .map(|(k, _)| k.clone())
+          .collect::<Vec<_>>();
+        keys
+          .into_iter()
+          .filter_map(|k| guard.remove(&k).map(|t| (k, t)))
+          .collect::<Vec<_>>()
+      };
+      for (key, tentative) in finals {
+        trace!("confirmation is final current_block={}: {:?}", current_block, key);
+        if let Err(e) = self.update_chain(&key, Some(tentative.chain_confirmation)).await {
+          error!("reactor: error processing a onchain event: {}: {:?}", e, key);
+        }
+      }
+    }
+  }
+
+  async fn update_chain(
+    &self,
+    key: &TentativeKey,
+    chain_confirmation: Option<ChainConfirmation>,
+  ) -> Result<(), Box<dyn Error>> {
+    let own_address = self.onchain.account_storage();
+    if key.lessee == own_address {
+      self
+        .persistence
+        .rent_update_chain(key.lessor, key.nonce, chain_confirmation)
+        .await?
+    } else if key.lessor == own_address {
+      self
+        .persistence
+        .let_update_chain(key.lessee, key.nonce, chain_confirmation)
+        .await?
+    } else {
+      return Err(format!("received event does not belong to us: {:?}", key).into());
+    }
+    Ok(())
+  }
+
+  /// Sends the proposal and waits for the lease to be sealed, the caller owns the pending record of the lease.
+  async fn propose_and_wait_seal(
+    &self,
+    peer_id: PeerId,
+    lessor_address: Address,
+    nonce: u64,
+    terms: LeaseTerms,
+    signature: Signature,
+    data: Vec<u8>,
+  ) -> Result<H256, Box<dyn Error>> {
+    let expiration = terms.proposal_expiration;
+    let token_address = terms.token_address;
+    let mut p2p_future = self.p2p.send_proposal(peer_id, nonce, terms, signature, data).fuse();
+
+    let mut seal_lease_future = self
+      .onchain
+      .wait_for_seal_lease(&token_address, lessor_address, nonce, expiration)
+      .fuse();
+
+    let response = select! {
+      response = p2p_future => response,
+      e = seal_lease_future => return sealed_transaction_hash(e),
+    };
+    let response = match response {
+      Ok(response) => response,
+      // The lessor can still seal the lease until the proposal expires
+      Err(e) if e.is::<p2p::RequestTimeout>() => {
+        warn!("no answer to the lease proposal peer_id={} nonce={}: {}", peer_id, nonce, e);
+        return sealed_transaction_hash(seal_lease_future.await);
+      }
+      Err(e) => return Err(e.into()),
+    };
+    let hinted_transaction_hash = match response {
+      ProposalResponse::Rejected(reason) => return Err(LeaseError::Rejected { reason }.into()),
+      ProposalResponse::Sealed(transaction_hash) => transaction_hash,
+    };
+
+    // The lessor notification saves waiting for the event stream, but it is trusted only once found on chain
+    match self
+      .onchain
+      .is_lease_sealed_in(&token_address, lessor_address, nonce, hinted_transaction_hash)
+      .await
+    {
+      Ok(true) => return Ok(hinted_transaction_hash),
+      Ok(false) => warn!(
+        "lease sealed notification not found on chain peer_id={} nonce={} transaction_hash={:?}",
+        peer_id, nonce, hinted_transaction_hash
+      ),
+      Err(e) => warn!(
+        "error checking lease sealed notification peer_id={} nonce={}: {}",
+        peer_id, nonce, e
+      ),
+    }
+    sealed_transaction_hash(seal_lease_future.await)
+  }
+}
+
+#[async_trait]
+impl<TData, TLessor, TOnchain, TP2p, TPersistence> Service for Implementation<TData, TLessor, TOnchain, TP2p, TPersistence>
+where
+  TData: data::Service,
+  TLessor: lessor::Service,
+  TOnchain: onchain::Service,
+  TP2p: p2p::Service,
+  TPersistence: persistence::Service,
+{
+  async fn lease(&self, peer_id: PeerId, terms: LeaseTerms, data: Vec<u8>) -> Result<H256, Box<dyn Error>> {
+    if peer_id == self.p2p.local_peer_id() {
+      return Err(LeaseError::SelfLease.into());
+    }
+    let nonce = rand::random(); // TODO Is this ok?
+    let data_parameters = self.data.parameters(data.as_slice()).await;
+    let lessor_address = self
       .p2p
       .find_public_key(&peer_id)
       .as_ref()
-      .map(IntoAddress::into_address)
-      .ok_or("peer id not found")?;
+      .map(TryIntoAddress::try_into_address)
+      .ok_or("peer id not found")??;
+    if lessor_address == self.onchain.account_storage() {
+      return Err(LeaseError::SelfLease.into());
+    }
     let signature = self
       .onchain
       .sign_proposal(&lessor_address, nonce, &terms, &data_parameters)
-      .await;
+      .await?;
 
     let expiration = terms.proposal_expiration;
     let token_address = terms.token_address;
@@ -306,59 +1403,125 @@ where
         terms: terms.clone(),
         data_parameters: data_parameters.clone(),
         chain_confirmation: None,
+        completed: false,
       })
       .await;
 
-    let mut p2p_future = self.p2p.send_proposal(peer_id, nonce, terms, signature, data).fuse();
-
-    let mut seal_lease_future = self
-      .onchain
-      .wait_for_seal_lease(&token_address, lessor_address, nonce, expiration)
-      .fuse();
-
-    select! {
-      reason = p2p_future => Err(format!("lease rejected with reason: {}, note that the lease can still be processed on chain", reason).into()),
-      e = seal_lease_future =>  {
-        match e {
-          Ok(Some(ev)) => {
-            if ev.is_removed() {
-              todo!()
-            } else {
-              Ok(ev.meta.expect("we not look for transactions not confirmed").transaction_hash)
-            }
-          }
-          Ok(None) => Err("lease timed out".into()),
-          Err(e) => Err(e.into()),
+    let mut abandoned_cleanup = AbandonedLeaseCleanup {
+      armed: true,
+      onchain: self.onchain.clone(),
+      persistence: self.persistence.clone(),
+      token_address,
+      lessor_address,
+      peer_id,
+      nonce,
+      expiration,
+    };
+    let result = self
+      .propose_and_wait_seal(peer_id, lessor_address, nonce, terms, signature, data)
+      .await;
+    abandoned_cleanup.disarm();
+    match &result {
+      Ok(transaction_hash) => self.emit(ReactorEvent::LeaseSealed {
+        peer_id,
+        nonce,
+        transaction_hash: *transaction_hash,
+      }),
+      Err(e) => {
+        if let Some(LeaseError::Rejected { reason }) = e.downcast_ref::<LeaseError>() {
+          self.emit(ReactorEvent::LeaseRejected {
+            peer_id,
+            nonce,
+            reason: reason.clone(),
+          });
         }
       }
     }
+    result
   }
 
-  async fn challenge(&self, peer_id: PeerId, challenge_key: ChallengeKey) -> Result<(), Box<dyn Error>> {
+  async fn challenge(&self, peer_id: PeerId, challenge_key: ChallengeKey) -> Result<ChallengeResult, Box<dyn Error>> {
     let ChallengeKey { nonce, block_number } = challenge_key;
     let lease = self.persistence.rent_get(peer_id, nonce).await.ok_or("lease not found")?;
-    if lease.data_parameters.size < (block_number as usize) * cryptography::BLOCK_SIZE_BYTES {
+    if lease.data_parameters.size == 0 {
+      return Err("cannot challenge a lease of empty data".into());
+    }
+    if lease.data_parameters.size <= (block_number as usize) * self.data.block_size() {
       return Err("block number is out of bounds".into());
     }
 
-    // TODO timeout
-    let challenge_proof = self.p2p.challenge(peer_id, challenge_key.clone()).await?;
+    let challenge_proof = match self.p2p.challenge(peer_id, challenge_key.clone()).await {
+      Ok(challenge_proof) => challenge_proof,
+      Err(e) => {
+        // A lessor that does not answer fails the challenge as much as one sending a wrong proof
+        if e.is::<p2p::RequestTimeout>() {
+          self.submit_failed_challenge(&lease, block_number, None).await;
+        }
+        return Err(p2p_error(e));
+      }
+    };
     trace!("proof received peer={}", peer_id);
 
-    let valid = self
+    let verification = self
       .data
       .verify(
-        lease.data_parameters,
+        lease.data_parameters.clone(),
         block_number,
         challenge_proof.block_data.as_slice(),
-        challenge_proof.proof,
+        challenge_proof.proof.clone(),
       )
       .await;
-    if valid {
-      Ok(())
-    } else {
-      Err("proof not valid".into())
+    // A malformed proof fails the challenge the same as a proof that does not match the merkle root
+    let valid = verification.unwrap_or_else(|e| {
+      warn!("malformed proof received peer_id={} nonce={}: {}", peer_id, nonce, e);
+      false
+    });
+    self
+      .persistence
+      .audit_append(AuditRole::Challenger, peer_id, challenge_key, challenge_proof.hash(), valid)
+      .await;
+    if !valid {
+      self
+        .submit_failed_challenge(&lease, block_number, Some(challenge_proof.clone()))
+        .await;
+    }
+    Ok(ChallengeResult {
+      proof: challenge_proof,
+      verified: valid,
+    })
+  }
+
+  async fn challenge_random(&self, peer_id: PeerId, nonce: u64) -> Result<RandomChallengeResult, Box<dyn Error>> {
+    let lease = self.persistence.rent_get(peer_id, nonce).await.ok_or("lease not found")?;
+    if lease.data_parameters.size == 0 {
+      return Err("cannot challenge a lease of empty data".into());
     }
+    // A confirmed block, so the seed does not change with a reorg and anyone can derive the block again
+    let seed_block_number = self
+      .onchain
+      .block_number()
+      .await?
+      .saturating_sub(self.params.confirmation_depth);
+    let seed_block_hash = self
+      .onchain
+      .block(BlockId::Number(BlockNumber::Number(seed_block_number.into())))
+      .await?
+      .and_then(|b| b.hash)
+      .ok_or_else(|| format!("seed block {} not found", seed_block_number))?;
+    let block_size = self.data.block_size();
+    let blocks = lease.data_parameters.size.div_ceil(block_size);
+    let block_number = random_block_number(&seed_block_hash, &peer_id, nonce, blocks);
+    debug!(
+      "random challenge peer_id={} nonce={} block_number={} seed_block_number={} seed_block_hash={:?}",
+      peer_id, nonce, block_number, seed_block_number, seed_block_hash
+    );
+    let result = self.challenge(peer_id, ChallengeKey { nonce, block_number }).await?;
+    Ok(RandomChallengeResult {
+      seed_block_number,
+      seed_block_hash,
+      block_number,
+      result,
+    })
   }
 
   async fn retrieve(&self, peer_id: PeerId, nonce: u64) -> anyhow::Result<Vec<u8>> {
@@ -381,4 +1544,1413 @@ where
       Ok(data)
     }
   }
+
+  async fn find_providers(&self, terms: LeaseTerms, size: usize) -> Vec<PeerId> {
+    let local_peer_id = self.p2p.local_peer_id();
+    let quotes = self
+      .p2p
+      .known_peers()
+      .into_iter()
+      .filter(|peer_id| *peer_id != local_peer_id)
+      .map(|peer_id| {
+        let terms = &terms;
+        async move {
+          match self.p2p.quote(peer_id, terms, size).await {
+            Ok(QuoteResponse::Accepted) => Some(peer_id),
+            Ok(QuoteResponse::Rejected(reason)) => {
+              debug!("quote rejected peer_id={} reason={}", peer_id, reason);
+              None
+            }
+            Err(e) if e.is::<p2p::RequestTimeout>() => {
+              debug!("quote timed out peer_id={}", peer_id);
+              None
+            }
+            Err(e) => {
+              warn!("could not request quote peer_id={} error={}", peer_id, e);
+              None
+            }
+          }
+        }
+      });
+    join_all(quotes).await.into_iter().flatten().collect()
+  }
+
+  fn subscribe(&self) -> broadcast::Receiver<ReactorEvent> {
+    self.events.subscribe()
+  }
+}
+
+fn lease_ends(lease: &Lease) -> Option<SystemTime> {
+  lease
+    .chain_confirmation
+    .as_ref()
+    .map(|c| c.timestamp + lease.terms.lease_duration)
+}
+
+/// Block of a lease of `blocks` blocks selected by the keccak256 of the seed block hash, the peer id and the nonce,
+/// so the same seed always selects the same block and every lease gets a different one.
+pub fn random_block_number(seed_block_hash: &H256, peer_id: &PeerId, nonce: u64, blocks: usize) -> u32 {
+  let mut input = seed_block_hash.as_bytes().to_vec();
+  input.extend_from_slice(peer_id.to_bytes().as_slice());
+  input.extend_from_slice(&nonce.to_be_bytes());
+  let hash = U256::from_big_endian(&keccak256(input.as_slice()));
+  (hash % U256::from(blocks)).as_u32()
+}
+
+// Boxing the anyhow error directly would hide p2p::RequestTimeout from downcasts
+fn p2p_error(error: anyhow::Error) -> Box<dyn Error> {
+  match error.downcast::<p2p::RequestTimeout>() {
+    Ok(timeout) => Box::new(timeout),
+    Err(error) => error.into(),
+  }
+}
+
+/// Removes the pending record of a lease whose `Service::lease` future is dropped before finishing. The lessor can still
+/// seal it until the proposal expires, so the record is only removed once the proposal expires without a seal.
+struct AbandonedLeaseCleanup<TOnchain: onchain::Service, TPersistence: persistence::Service> {
+  armed: bool,
+  onchain: TOnchain,
+  persistence: TPersistence,
+  token_address: Address,
+  lessor_address: Address,
+  peer_id: PeerId,
+  nonce: u64,
+  expiration: SystemTime,
+}
+
+impl<TOnchain: onchain::Service, TPersistence: persistence::Service> AbandonedLeaseCleanup<TOnchain, TPersistence> {
+  fn disarm(&mut self) {
+    self.armed = false;
+  }
+}
+
+impl<TOnchain: onchain::Service, TPersistence: persistence::Service> Drop for AbandonedLeaseCleanup<TOnchain, TPersistence> {
+  fn drop(&mut self) {
+    if !self.armed {
+      return;
+    }
+    let onchain = self.onchain.clone();
+    let persistence = self.persistence.clone();
+    let (token_address, lessor_address, peer_id, nonce, expiration) = (
+      self.token_address,
+      self.lessor_address,
+      self.peer_id,
+      self.nonce,
+      self.expiration,
+    );
+    info!("lease abandoned before being sealed peer_id={} nonce={}", peer_id, nonce);
+    tokio::task::spawn(async move {
+      match onchain
+        .wait_for_seal_lease(&token_address, lessor_address, nonce, expiration)
+        .await
+      {
+        Ok(None) => persistence.rent_remove(peer_id, nonce).await,
+        Ok(Some(_)) => debug!("abandoned lease has been sealed peer_id={} nonce={}", peer_id, nonce),
+        Err(e) => warn!(
+          "error waiting for an abandoned lease, keeping it peer_id={} nonce={}: {}",
+          peer_id, nonce, e
+        ),
+      }
+    });
+  }
+}
+
+fn sealed_transaction_hash(
+  result: onchain::Result<
+    Option<ethcontract::Event<EventStatus<p2pim_ethereum_contracts::adjudicator::event_data::LeaseSealed>>>,
+  >,
+) -> Result<H256, Box<dyn Error>> {
+  match result {
+    // Removed seals are skipped while waiting, whatever comes back else is not a confirmed seal
+    Ok(Some(ethcontract::Event {
+      data: EventStatus::Removed(_),
+      ..
+    })) => Err("lease seal removed by a reorg".into()),
+    Ok(Some(ethcontract::Event { meta: Some(meta), .. })) => Ok(meta.transaction_hash),
+    Ok(Some(ethcontract::Event { meta: None, .. })) => Err("lease sealed event without block".into()),
+    Ok(None) => Err(LeaseError::TimedOut.into()),
+    Err(e) => Err(e.into()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::data::Service as _;
+  use crate::p2p::Service as _;
+  use crate::persistence::Service as _;
+  use crate::testutil::{
+    data_service, lease, lease_proposal, lease_removed, lease_sealed, lease_terms, memory_address, persistence_service,
+    reactor_params, MockLessor, MockOnchain, MockP2p, TempDir,
+  };
+
+  /// Waits for the spawned processing, the data is written outside of the runtime so paused time does not wait for it.
+  async fn until(condition: impl Fn() -> bool) {
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while !condition() {
+      assert!(std::time::Instant::now() < deadline, "condition not met in time");
+      // Sleeping would move the paused clock
+      tokio::task::yield_now().await;
+    }
+  }
+
+  async fn until_async<F: Future<Output = bool>>(condition: impl Fn() -> F) {
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while !condition().await {
+      assert!(std::time::Instant::now() < deadline, "condition not met in time");
+      tokio::task::yield_now().await;
+    }
+  }
+
+  /// Runs the reactor background processing long enough to go through its startup.
+  async fn run_reactor(reactor: impl Future<Output = ()>) {
+    let _ = tokio::time::timeout(Duration::from_millis(100), reactor).await;
+  }
+
+  #[test]
+  fn random_block_derived_from_the_seed() {
+    let peer_id = PeerId::random();
+    let seed = H256::repeat_byte(3);
+    let block = random_block_number(&seed, &peer_id, 1, 10);
+
+    assert_eq!(random_block_number(&seed, &peer_id, 1, 10), block);
+    let blocks = (0..100u64)
+      .map(|i| random_block_number(&H256::from_low_u64_be(i), &peer_id, 1, 10))
+      .collect::<HashSet<_>>();
+    assert!(blocks.iter().all(|b| *b < 10));
+    // Every block gets picked by some seed
+    assert_eq!(blocks.len(), 10);
+    assert_eq!(random_block_number(&seed, &peer_id, 1, 1), 0);
+  }
+
+  #[tokio::test]
+  async fn lease_sealed_by_the_lessor() {
+    let folder = TempDir::new();
+    let onchain = MockOnchain::new(Address::from_low_u64_be(1));
+    let p2p = MockP2p::new();
+    let (peer_id, lessor_address) = p2p.add_peer();
+    let transaction_hash = H256::repeat_byte(7);
+    p2p.state.lock().unwrap().proposal_response = Some(ProposalResponse::Sealed(transaction_hash));
+    let persistence = persistence_service(&folder);
+    let (reactor, _) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      onchain.clone(),
+      p2p.clone(),
+      persistence.clone(),
+      reactor_params(),
+    );
+    let mut events = reactor.subscribe();
+
+    let result = reactor
+      .lease(peer_id, lease_terms(Address::from_low_u64_be(2)), b"some data".to_vec())
+      .await
+      .unwrap();
+
+    assert_eq!(result, transaction_hash);
+    assert_eq!(onchain.calls("sign_proposal"), 1);
+    assert_eq!(onchain.calls("is_lease_sealed_in"), 1);
+    let proposals_sent = p2p.state.lock().unwrap().proposals_sent.clone();
+    assert_eq!(proposals_sent.len(), 1);
+    let (_, nonce) = proposals_sent[0];
+    let lease = persistence.rent_get(peer_id, nonce).await.unwrap();
+    assert_eq!(lease.peer_address, lessor_address);
+    assert_eq!(lease.data_parameters.size, 9);
+    assert!(matches!(
+      events.try_recv(),
+      Ok(ReactorEvent::LeaseSealed { transaction_hash: hash, .. }) if hash == transaction_hash
+    ));
+  }
+
+  #[tokio::test]
+  async fn lease_rejected_by_the_lessor() {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (peer_id, _) = p2p.add_peer();
+    p2p.state.lock().unwrap().proposal_response = Some(ProposalResponse::Rejected("no space".to_string()));
+    let (reactor, _) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      MockOnchain::new(Address::from_low_u64_be(1)),
+      p2p,
+      persistence_service(&folder),
+      reactor_params(),
+    );
+
+    // The seal is never found, only the rejection ends the lease
+    let lease = reactor.lease(peer_id, lease_terms(Address::from_low_u64_be(2)), b"some data".to_vec());
+    let error = tokio::time::timeout(Duration::from_secs(1), lease)
+      .await
+      .expect("lease not returned on the rejection")
+      .unwrap_err();
+
+    assert!(matches!(
+      error.downcast_ref::<LeaseError>(),
+      Some(LeaseError::Rejected { reason }) if reason == "no space"
+    ));
+  }
+
+  #[tokio::test]
+  async fn lease_to_this_same_node_refused() {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (peer_id, address) = p2p.add_peer();
+    // The peer resolves to the storage address of this node
+    let onchain = MockOnchain::new(address);
+    let (reactor, _) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      onchain.clone(),
+      p2p.clone(),
+      persistence_service(&folder),
+      reactor_params(),
+    );
+
+    for peer_id in [p2p.local_peer_id(), peer_id] {
+      let error = reactor
+        .lease(peer_id, lease_terms(Address::from_low_u64_be(2)), b"some data".to_vec())
+        .await
+        .unwrap_err();
+
+      assert!(matches!(error.downcast_ref::<LeaseError>(), Some(LeaseError::SelfLease)));
+    }
+    assert_eq!(onchain.calls("sign_proposal"), 0);
+    assert!(p2p.state.lock().unwrap().proposals_sent.is_empty());
+  }
+
+  #[tokio::test]
+  async fn proposal_from_this_same_node_rejected() {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (peer_id, address) = p2p.add_peer();
+    let onchain = MockOnchain::new(address);
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      onchain.clone(),
+      p2p.clone(),
+      persistence_service(&folder),
+      reactor_params(),
+    );
+    p2p.emit(Event::ReceivedLeaseProposal {
+      peer_id,
+      proposal: lease_proposal(1),
+    });
+
+    run_reactor(reactor_fut).await;
+
+    assert_eq!(onchain.calls("seal_lease"), 0);
+    assert_eq!(
+      p2p.state.lock().unwrap().rejections_sent,
+      vec![(peer_id, 1, lessor::RejectedReason::SelfLease.to_string())]
+    );
+  }
+
+  #[tokio::test]
+  async fn providers_accepting_the_quote_found() {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (accepting, _) = p2p.add_peer();
+    let (rejecting, _) = p2p.add_peer();
+    // The third peer never answers
+    p2p.add_peer();
+    {
+      let mut state = p2p.state.lock().unwrap();
+      state.quote_responses.insert(accepting, QuoteResponse::Accepted);
+      state
+        .quote_responses
+        .insert(rejecting, QuoteResponse::Rejected("no space".to_string()));
+    }
+    let (reactor, _) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      MockOnchain::new(Address::from_low_u64_be(1)),
+      p2p,
+      persistence_service(&folder),
+      reactor_params(),
+    );
+
+    let providers = reactor.find_providers(lease_terms(Address::from_low_u64_be(2)), 9).await;
+
+    assert_eq!(providers, vec![accepting]);
+  }
+
+  #[tokio::test]
+  async fn abandoned_lease_removed_once_expired() {
+    let folder = TempDir::new();
+    let onchain = MockOnchain::new(Address::from_low_u64_be(1));
+    let p2p = MockP2p::new();
+    let (peer_id, _) = p2p.add_peer();
+    let persistence = persistence_service(&folder);
+    let (reactor, _) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      onchain.clone(),
+      p2p,
+      persistence.clone(),
+      reactor_params(),
+    );
+    // No answer to the proposal, the lease waits for its seal
+    let lease = tokio::spawn(async move {
+      let terms = lease_terms(Address::from_low_u64_be(2));
+      reactor
+        .lease(peer_id, terms, b"some data".to_vec())
+        .await
+        .map_err(|e| e.to_string())
+    });
+    until(|| onchain.calls("wait_for_seal_lease") == 1).await;
+    assert_eq!(persistence.rent_list().await.len(), 1);
+
+    // Not sealed until the proposal expired
+    onchain.state.lock().unwrap().wait_for_seal = Some(None);
+    lease.abort();
+
+    until_async(|| async { persistence.rent_list().await.is_empty() }).await;
+    assert_eq!(onchain.calls("wait_for_seal_lease"), 2);
+  }
+
+  #[tokio::test]
+  async fn pending_lease_reconciled_on_restart() {
+    let folder = TempDir::new();
+    let lessee = Address::from_low_u64_be(1);
+    let onchain = MockOnchain::new(lessee);
+    let p2p = MockP2p::new();
+    let (peer_id, lessor) = p2p.add_peer();
+    let persistence = persistence_service(&folder);
+    persistence.rent_store(lease(peer_id, lessor, 1)).await;
+    persistence.rent_store(lease(peer_id, lessor, 2)).await;
+    let transaction_hash = H256::repeat_byte(7);
+    onchain
+      .state
+      .lock()
+      .unwrap()
+      .sealed_events
+      .push(lease_sealed(lessor, lessee, 1, H256::zero(), transaction_hash));
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      onchain,
+      p2p,
+      persistence.clone(),
+      reactor_params(),
+    );
+
+    run_reactor(reactor_fut).await;
+
+    let sealed = persistence.rent_get(peer_id, 1).await.unwrap();
+    assert_eq!(sealed.chain_confirmation.unwrap().transaction_hash, transaction_hash);
+    let pending = persistence.rent_get(peer_id, 2).await.unwrap();
+    assert!(pending.chain_confirmation.is_none());
+  }
+
+  #[tokio::test]
+  async fn known_peers_dialed_on_restart() {
+    let folder = TempDir::new();
+    let peer_id = PeerId::random();
+    let address = memory_address();
+    {
+      let persistence = persistence_service(&folder);
+      persistence.peers_store(peer_id, vec![address.clone()]).await;
+    }
+    let p2p = MockP2p::new();
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      MockOnchain::new(Address::from_low_u64_be(1)),
+      p2p.clone(),
+      persistence_service(&folder),
+      reactor_params(),
+    );
+
+    run_reactor(reactor_fut).await;
+
+    assert_eq!(p2p.state.lock().unwrap().dials, vec![(peer_id, vec![address])]);
+  }
+
+  #[tokio::test]
+  async fn disconnected_peer_with_an_active_lease_dialed() {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (peer_id, lessee) = p2p.add_peer();
+    let address = memory_address();
+    let persistence = persistence_service(&folder);
+    persistence.peers_store(peer_id, vec![address.clone()]).await;
+    persistence
+      .let_store(Lease {
+        chain_confirmation: Some(ChainConfirmation {
+          transaction_hash: H256::repeat_byte(7),
+          timestamp: SystemTime::now(),
+        }),
+        ..lease(peer_id, lessee, 1)
+      })
+      .await;
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      MockOnchain::new(Address::from_low_u64_be(1)),
+      p2p.clone(),
+      persistence,
+      ReactorParams {
+        lease_keepalive_interval: Some(Duration::from_millis(10)),
+        ..reactor_params()
+      },
+    );
+    let dials = || p2p.state.lock().unwrap().dials.len();
+    let connection_dropped = async {
+      // Only dialed on startup while connected
+      tokio::time::sleep(Duration::from_millis(50)).await;
+      assert_eq!(dials(), 1);
+      p2p.state.lock().unwrap().public_keys.remove(&peer_id);
+      until(|| dials() == 2).await;
+    };
+
+    tokio::select! {
+      _ = reactor_fut => {}
+      _ = connection_dropped => {}
+    }
+
+    assert_eq!(p2p.state.lock().unwrap().dials[1], (peer_id, vec![address]));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn lease_corrected_after_a_reorg() {
+    let folder = TempDir::new();
+    let lessee = Address::from_low_u64_be(1);
+    let onchain = MockOnchain::new(lessee);
+    let p2p = MockP2p::new();
+    let (peer_id, lessor) = p2p.add_peer();
+    let persistence = persistence_service(&folder);
+    persistence.set_last_processed_block(1).await;
+    let old_hash = H256::repeat_byte(7);
+    let new_hash = H256::repeat_byte(8);
+    persistence
+      .rent_store(Lease {
+        chain_confirmation: Some(ChainConfirmation {
+          transaction_hash: old_hash,
+          timestamp: SystemTime::now(),
+        }),
+        ..lease(peer_id, lessor, 1)
+      })
+      .await;
+    let sealed_again = lease_sealed(lessor, lessee, 1, H256::repeat_byte(2), new_hash);
+    onchain.state.lock().unwrap().sealed_events.push(sealed_again.clone());
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      onchain.clone(),
+      p2p,
+      persistence.clone(),
+      reactor_params(),
+    );
+    // The seal on the new branch arrives before the removal of the old one
+    onchain.emit(sealed_again);
+    onchain.emit(lease_removed(lessor, lessee, 1, H256::repeat_byte(1), old_hash));
+
+    tokio::time::timeout(REORG_RECONCILE_DELAY * 2, reactor_fut)
+      .await
+      .unwrap_err();
+
+    let lease = persistence.rent_get(peer_id, 1).await.unwrap();
+    assert_eq!(lease.chain_confirmation.unwrap().transaction_hash, new_hash);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn confirmation_removed_within_the_depth_never_final() {
+    let folder = TempDir::new();
+    let lessee = Address::from_low_u64_be(1);
+    let onchain = MockOnchain::new(lessee);
+    let p2p = MockP2p::new();
+    let (peer_id, lessor) = p2p.add_peer();
+    let persistence = persistence_service(&folder);
+    persistence.rent_store(lease(peer_id, lessor, 1)).await;
+    persistence.rent_store(lease(peer_id, lessor, 2)).await;
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      onchain.clone(),
+      p2p,
+      persistence.clone(),
+      ReactorParams {
+        confirmation_depth: 3,
+        ..reactor_params()
+      },
+    );
+    let confirmed = |nonce| {
+      let persistence = persistence.clone();
+      async move {
+        persistence
+          .rent_get(peer_id, nonce)
+          .await
+          .unwrap()
+          .chain_confirmation
+          .is_some()
+      }
+    };
+    let scenario = async {
+      onchain.emit(lease_sealed(lessor, lessee, 1, H256::repeat_byte(1), H256::repeat_byte(7)));
+      onchain.emit(lease_sealed(lessor, lessee, 2, H256::repeat_byte(1), H256::repeat_byte(8)));
+      onchain.emit(lease_removed(lessor, lessee, 1, H256::repeat_byte(1), H256::repeat_byte(7)));
+      tokio::time::sleep(TENTATIVE_CONFIRMATIONS_CHECK_INTERVAL * 2).await;
+      assert!(!confirmed(2).await, "confirmed before the depth");
+      onchain.state.lock().unwrap().block_number = 4;
+      tokio::time::sleep(TENTATIVE_CONFIRMATIONS_CHECK_INTERVAL * 2).await;
+      until_async(|| confirmed(2)).await;
+    };
+
+    tokio::select! {
+      _ = reactor_fut => {}
+      _ = scenario => {}
+    }
+
+    assert!(!confirmed(1).await);
+  }
+
+  #[tokio::test]
+  async fn events_backfilled_on_restart() {
+    let folder = TempDir::new();
+    let lessor = Address::from_low_u64_be(1);
+    let onchain = MockOnchain::new(lessor);
+    onchain.state.lock().unwrap().block_number = 5;
+    let p2p = MockP2p::new();
+    let (peer_id, lessee) = p2p.add_peer();
+    let persistence = persistence_service(&folder);
+    persistence.set_last_processed_block(1).await;
+    persistence.let_store(lease(peer_id, lessee, 1)).await;
+    let transaction_hash = H256::repeat_byte(7);
+    let mut unconfirmed = lease_sealed(lessor, lessee, 2, H256::zero(), H256::zero());
+    unconfirmed.meta = None;
+    onchain.state.lock().unwrap().sealed_events =
+      vec![unconfirmed, lease_sealed(lessor, lessee, 1, H256::zero(), transaction_hash)];
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      onchain,
+      p2p,
+      persistence.clone(),
+      reactor_params(),
+    );
+
+    run_reactor(reactor_fut).await;
+
+    let lease = persistence.let_get(peer_id, 1).await.unwrap();
+    assert_eq!(lease.chain_confirmation.unwrap().transaction_hash, transaction_hash);
+    assert_eq!(persistence.last_processed_block().await, Some(5));
+  }
+
+  #[tokio::test]
+  async fn lessee_notified_once_sealed() {
+    let folder = TempDir::new();
+    let onchain = MockOnchain::new(Address::from_low_u64_be(1));
+    let p2p = MockP2p::new();
+    let (peer_id, lessee) = p2p.add_peer();
+    let persistence = persistence_service(&folder);
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      onchain.clone(),
+      p2p.clone(),
+      persistence.clone(),
+      reactor_params(),
+    );
+    p2p.emit(Event::ReceivedLeaseProposal {
+      peer_id,
+      proposal: lease_proposal(1),
+    });
+
+    run_reactor(reactor_fut).await;
+
+    let transaction_hash = onchain.state.lock().unwrap().seal_transaction_hash;
+    assert_eq!(onchain.calls("seal_lease"), 1);
+    assert_eq!(
+      p2p.state.lock().unwrap().lease_sealed_sent,
+      vec![(peer_id, 1, transaction_hash)]
+    );
+    assert_eq!(persistence.let_get(peer_id, 1).await.unwrap().peer_address, lessee);
+  }
+
+  #[tokio::test]
+  async fn proposal_rejected_when_the_seal_would_revert() {
+    let folder = TempDir::new();
+    let onchain = MockOnchain::new(Address::from_low_u64_be(1));
+    onchain.state.lock().unwrap().seal_gas = Err("invalid signature".to_string());
+    let p2p = MockP2p::new();
+    let (peer_id, _) = p2p.add_peer();
+    let persistence = persistence_service(&folder);
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      onchain.clone(),
+      p2p.clone(),
+      persistence.clone(),
+      ReactorParams {
+        seal_gas_precheck: true,
+        ..reactor_params()
+      },
+    );
+    p2p.emit(Event::ReceivedLeaseProposal {
+      peer_id,
+      proposal: lease_proposal(1),
+    });
+
+    run_reactor(reactor_fut).await;
+
+    assert_eq!(onchain.calls("estimate_seal_gas"), 1);
+    assert_eq!(onchain.calls("seal_lease"), 0);
+    assert_eq!(
+      p2p.state.lock().unwrap().rejections_sent,
+      vec![(peer_id, 1, "seal would revert: invalid signature".to_string())]
+    );
+    assert!(persistence.let_get(peer_id, 1).await.is_none());
+  }
+
+  #[tokio::test]
+  async fn proposal_rejected_without_gas_to_seal() {
+    let folder = TempDir::new();
+    let onchain = MockOnchain::new(Address::from_low_u64_be(1));
+    onchain.state.lock().unwrap().native_balance = 999.into();
+    let p2p = MockP2p::new();
+    let (peer_id, _) = p2p.add_peer();
+    let persistence = persistence_service(&folder);
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      onchain.clone(),
+      p2p.clone(),
+      persistence.clone(),
+      ReactorParams {
+        min_gas_balance: 1000.into(),
+        ..reactor_params()
+      },
+    );
+    p2p.emit(Event::ReceivedLeaseProposal {
+      peer_id,
+      proposal: lease_proposal(1),
+    });
+
+    run_reactor(reactor_fut).await;
+
+    assert_eq!(onchain.calls("seal_lease"), 0);
+    assert_eq!(
+      p2p.state.lock().unwrap().rejections_sent,
+      vec![(peer_id, 1, lessor::RejectedReason::LessorOutOfGas.to_string())]
+    );
+    assert!(persistence.let_get(peer_id, 1).await.is_none());
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn proposals_rate_limited_within_the_window() {
+    let folder = TempDir::new();
+    let onchain = MockOnchain::new(Address::from_low_u64_be(1));
+    let p2p = MockP2p::new();
+    let (peer_id, _) = p2p.add_peer();
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      onchain.clone(),
+      p2p.clone(),
+      persistence_service(&folder),
+      ReactorParams {
+        max_proposal_rate: Some(1),
+        ..reactor_params()
+      },
+    );
+    let propose = |nonce| {
+      p2p.emit(Event::ReceivedLeaseProposal {
+        peer_id,
+        proposal: lease_proposal(nonce),
+      })
+    };
+    let proposals = async {
+      propose(1);
+      until(|| onchain.calls("seal_lease") == 1).await;
+      propose(2);
+      until(|| p2p.state.lock().unwrap().rejections_sent.len() == 1).await;
+      tokio::time::sleep(PROPOSAL_RATE_WINDOW).await;
+      propose(3);
+      until(|| onchain.calls("seal_lease") == 2).await;
+    };
+
+    tokio::select! {
+      _ = reactor_fut => {}
+      _ = proposals => {}
+    }
+
+    assert_eq!(onchain.calls("seal_lease"), 2);
+    assert_eq!(
+      p2p.state.lock().unwrap().rejections_sent,
+      vec![(peer_id, 2, lessor::RejectedReason::TooManyRequests.to_string())]
+    );
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn flooding_peer_does_not_limit_the_others() {
+    let folder = TempDir::new();
+    let onchain = MockOnchain::new(Address::from_low_u64_be(1));
+    let p2p = MockP2p::new();
+    let (flooder, _) = p2p.add_peer();
+    let (other, _) = p2p.add_peer();
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      onchain.clone(),
+      p2p.clone(),
+      persistence_service(&folder),
+      ReactorParams {
+        max_proposal_rate: Some(2),
+        ..reactor_params()
+      },
+    );
+    let propose = |peer_id, nonce| {
+      p2p.emit(Event::ReceivedLeaseProposal {
+        peer_id,
+        proposal: lease_proposal(nonce),
+      })
+    };
+    let proposals = async {
+      for nonce in 1..=10 {
+        propose(flooder, nonce);
+      }
+      until(|| p2p.state.lock().unwrap().rejections_sent.len() == 8).await;
+      propose(other, 11);
+      until(|| onchain.calls("seal_lease") == 3).await;
+    };
+
+    tokio::select! {
+      _ = reactor_fut => {}
+      _ = proposals => {}
+    }
+
+    let rejections = p2p.state.lock().unwrap().rejections_sent.clone();
+    let too_many = lessor::RejectedReason::TooManyRequests.to_string();
+    assert!(rejections
+      .iter()
+      .all(|(peer_id, _, reason)| *peer_id == flooder && *reason == too_many));
+    assert_eq!(onchain.calls("seal_lease"), 3);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn concurrent_seals_limited() {
+    let folder = TempDir::new();
+    let onchain = MockOnchain::new(Address::from_low_u64_be(1));
+    onchain.state.lock().unwrap().seal_delay = Some(Duration::from_secs(1));
+    let p2p = MockP2p::new();
+    let (peer_id, _) = p2p.add_peer();
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      onchain.clone(),
+      p2p.clone(),
+      persistence_service(&folder),
+      ReactorParams {
+        max_concurrent_seals: 2,
+        ..reactor_params()
+      },
+    );
+    for nonce in 1..=5 {
+      p2p.emit(Event::ReceivedLeaseProposal {
+        peer_id,
+        proposal: lease_proposal(nonce),
+      });
+    }
+    let proposals = async {
+      until(|| p2p.state.lock().unwrap().rejections_sent.len() == 3).await;
+      until(|| onchain.calls("seal_lease") == 2).await;
+      tokio::time::sleep(Duration::from_secs(2)).await;
+    };
+
+    tokio::select! {
+      _ = reactor_fut => {}
+      _ = proposals => {}
+    }
+
+    assert_eq!(onchain.state.lock().unwrap().max_sealing, 2);
+    let rejections = p2p.state.lock().unwrap().rejections_sent.clone();
+    assert!(rejections.iter().all(|(_, _, reason)| reason == "busy"));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn retransmitted_proposal_sealed_once() {
+    let folder = TempDir::new();
+    let onchain = MockOnchain::new(Address::from_low_u64_be(1));
+    onchain.state.lock().unwrap().seal_delay = Some(Duration::from_secs(1));
+    let p2p = MockP2p::new();
+    let (peer_id, _) = p2p.add_peer();
+    let persistence = persistence_service(&folder);
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      onchain.clone(),
+      p2p.clone(),
+      persistence.clone(),
+      reactor_params(),
+    );
+    let propose = || {
+      p2p.emit(Event::ReceivedLeaseProposal {
+        peer_id,
+        proposal: lease_proposal(1),
+      })
+    };
+    let proposals = async {
+      // Once while the first one is being sealed, once after it is stored
+      propose();
+      until(|| onchain.calls("seal_lease") == 1).await;
+      propose();
+      tokio::time::sleep(Duration::from_secs(2)).await;
+      until_async(|| async { persistence.let_get(peer_id, 1).await.is_some() }).await;
+      propose();
+      tokio::time::sleep(Duration::from_secs(2)).await;
+    };
+
+    tokio::select! {
+      _ = reactor_fut => {}
+      _ = proposals => {}
+    }
+
+    assert_eq!(onchain.calls("seal_lease"), 1);
+    assert!(p2p.state.lock().unwrap().rejections_sent.is_empty());
+  }
+
+  #[tokio::test]
+  async fn quotes_rate_limited() {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (peer_id, _) = p2p.add_peer();
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      MockOnchain::new(Address::from_low_u64_be(1)),
+      p2p.clone(),
+      persistence_service(&folder),
+      ReactorParams {
+        max_proposal_rate: Some(1),
+        ..reactor_params()
+      },
+    );
+    for quote_id in 1..=2 {
+      p2p.emit(Event::ReceivedQuoteRequest {
+        peer_id,
+        quote_id,
+        lease_terms: lease_terms(Address::from_low_u64_be(2)),
+        size: 9,
+      });
+    }
+
+    run_reactor(reactor_fut).await;
+
+    let responses = p2p.state.lock().unwrap().quote_responses_sent.clone();
+    assert_eq!(responses.len(), 2);
+    let too_many = lessor::RejectedReason::TooManyRequests.to_string();
+    let rejected = responses
+      .iter()
+      .filter(|(_, _, response)| matches!(response, QuoteResponse::Rejected(reason) if *reason == too_many))
+      .count();
+    assert_eq!(rejected, 1);
+  }
+
+  #[tokio::test]
+  async fn rejected_proposals_rate_limited() {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (peer_id, _) = p2p.add_peer();
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::rejecting(|| lessor::RejectedReason::PriceRateTooSmall),
+      MockOnchain::new(Address::from_low_u64_be(1)),
+      p2p.clone(),
+      persistence_service(&folder),
+      ReactorParams {
+        max_proposal_rate: Some(2),
+        ..reactor_params()
+      },
+    );
+    let proposals = async {
+      for nonce in 1..=5 {
+        p2p.emit(Event::ReceivedLeaseProposal {
+          peer_id,
+          proposal: lease_proposal(nonce),
+        });
+      }
+      until(|| p2p.state.lock().unwrap().rejections_sent.len() == 5).await;
+    };
+
+    tokio::select! {
+      _ = reactor_fut => {}
+      _ = proposals => {}
+    }
+
+    let mut rejections = p2p.state.lock().unwrap().rejections_sent.clone();
+    rejections.sort_by_key(|(_, nonce, _)| *nonce);
+    let reasons = rejections.into_iter().map(|(_, _, reason)| reason).collect::<Vec<_>>();
+    let (price, too_many) = (
+      lessor::RejectedReason::PriceRateTooSmall.to_string(),
+      lessor::RejectedReason::TooManyRequests.to_string(),
+    );
+    assert_eq!(
+      reasons,
+      vec![price.clone(), price, too_many.clone(), too_many.clone(), too_many]
+    );
+  }
+
+  #[tokio::test]
+  async fn proposal_of_an_unidentified_peer_rejected() {
+    let folder = TempDir::new();
+    let onchain = MockOnchain::new(Address::from_low_u64_be(1));
+    let p2p = MockP2p::new();
+    let peer_id = PeerId::random();
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      onchain.clone(),
+      p2p.clone(),
+      persistence_service(&folder),
+      reactor_params(),
+    );
+    p2p.emit(Event::ReceivedLeaseProposal {
+      peer_id,
+      proposal: lease_proposal(1),
+    });
+
+    run_reactor(reactor_fut).await;
+
+    assert_eq!(onchain.calls("seal_lease"), 0);
+    assert_eq!(
+      p2p.state.lock().unwrap().rejections_sent,
+      vec![(peer_id, 1, lessor::RejectedReason::UnknownPeer.to_string())]
+    );
+  }
+
+  /// Lets a lease of 9 bytes with nonce 1 and one of 15 with nonce 2, then asks for the given nonces.
+  async fn retrieves_delivered(params: ReactorParams, nonces: &[u64]) -> Vec<u64> {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (peer_id, lessee) = p2p.add_peer();
+    let data = data_service(&folder);
+    let persistence = persistence_service(&folder);
+    for (nonce, contents) in [(1, "some data"), (2, "some more data.")] {
+      let parameters = data.parameters(contents.as_bytes()).await;
+      data.store(peer_id, nonce, contents.as_bytes(), &parameters).await.unwrap();
+      persistence
+        .let_store(Lease {
+          data_parameters: parameters,
+          ..lease(peer_id, lessee, nonce)
+        })
+        .await;
+    }
+    let (_, reactor_fut) = new_service(
+      data,
+      MockLessor::accepting(),
+      MockOnchain::new(Address::from_low_u64_be(1)),
+      p2p.clone(),
+      persistence,
+      params,
+    );
+    for nonce in nonces {
+      p2p.emit(Event::ReceivedRetrieveRequest { peer_id, nonce: *nonce });
+    }
+
+    run_reactor(reactor_fut).await;
+
+    let deliveries = p2p.state.lock().unwrap().deliveries_sent.clone();
+    deliveries.into_iter().map(|(_, nonce)| nonce).collect()
+  }
+
+  #[tokio::test]
+  async fn retrieve_of_an_unknown_lease_answered_not_found() {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (peer_id, _) = p2p.add_peer();
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      MockOnchain::new(Address::from_low_u64_be(1)),
+      p2p.clone(),
+      persistence_service(&folder),
+      reactor_params(),
+    );
+    p2p.emit(Event::ReceivedRetrieveRequest { peer_id, nonce: 9 });
+
+    run_reactor(reactor_fut).await;
+
+    let state = p2p.state.lock().unwrap();
+    assert_eq!(state.not_found_sent, vec![(peer_id, 9)]);
+    assert!(state.deliveries_sent.is_empty());
+  }
+
+  #[tokio::test]
+  async fn retrieve_above_the_size_limit_refused() {
+    let params = ReactorParams {
+      max_retrieve_size: 9,
+      ..reactor_params()
+    };
+    assert_eq!(retrieves_delivered(params, &[2]).await, Vec::<u64>::new());
+    assert_eq!(retrieves_delivered(reactor_params(), &[2]).await, vec![2]);
+  }
+
+  #[tokio::test]
+  async fn repeated_retrieves_throttled() {
+    let params = ReactorParams {
+      retrieve_min_interval: Duration::from_secs(60),
+      ..reactor_params()
+    };
+    assert_eq!(retrieves_delivered(params, &[1, 1, 1]).await, vec![1]);
+    assert_eq!(retrieves_delivered(reactor_params(), &[1, 1]).await, vec![1, 1]);
+  }
+
+  #[tokio::test]
+  async fn corrupted_data_flagged_by_the_integrity_check() {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (peer_id, lessee) = p2p.add_peer();
+    let data = data_service(&folder);
+    let persistence = persistence_service(&folder);
+    for nonce in 1..=2 {
+      let contents = format!("some data {}", nonce);
+      let parameters = data.parameters(contents.as_bytes()).await;
+      data.store(peer_id, nonce, contents.as_bytes(), &parameters).await.unwrap();
+      persistence
+        .let_store(Lease {
+          data_parameters: parameters,
+          ..lease(peer_id, lessee, nonce)
+        })
+        .await;
+    }
+    let corrupted = data.parameters(b"some data 2").await;
+    let blob = folder
+      .path()
+      .join("data/blobs")
+      .join(format!("{}-{}", hex::encode(&corrupted.merkle_root), corrupted.size));
+    std::fs::write(blob, b"some data 3").unwrap();
+    let (reactor, reactor_fut) = new_service(
+      data,
+      MockLessor::accepting(),
+      MockOnchain::new(Address::from_low_u64_be(1)),
+      p2p,
+      persistence,
+      ReactorParams {
+        integrity_check_interval: Some(Duration::from_millis(10)),
+        ..reactor_params()
+      },
+    );
+    let mut events = reactor.subscribe();
+
+    run_reactor(reactor_fut).await;
+
+    assert!(matches!(
+      events.try_recv(),
+      Ok(ReactorEvent::IntegrityCheckFailed { nonce: 2, .. })
+    ));
+  }
+
+  #[tokio::test]
+  async fn watchdog_records_the_challenge_outcomes() {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (peer_id, lessor) = p2p.add_peer();
+    // The lessor side of the lease, to answer with a valid proof
+    let lessor_data = data_service(&folder);
+    let parameters = lessor_data.parameters(b"some data").await;
+    lessor_data.store(peer_id, 1, b"some data", &parameters).await.unwrap();
+    let (block_data, proof) = lessor_data.proof(peer_id, 1, 0).await.unwrap();
+    p2p.state.lock().unwrap().challenge_proof = Some(ChallengeProof { block_data, proof });
+    let persistence = persistence_service(&folder);
+    persistence
+      .rent_store(Lease {
+        data_parameters: parameters,
+        chain_confirmation: Some(ChainConfirmation {
+          transaction_hash: H256::repeat_byte(7),
+          timestamp: SystemTime::now(),
+        }),
+        ..lease(peer_id, lessor, 1)
+      })
+      .await;
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      MockOnchain::new(Address::from_low_u64_be(1)),
+      p2p.clone(),
+      persistence.clone(),
+      ReactorParams {
+        challenge_watchdog_interval: Some(Duration::from_millis(10)),
+        ..reactor_params()
+      },
+    );
+    let challenges = async {
+      until_async(|| async { persistence.rent_challenges(peer_id, 1).await.honored > 0 }).await;
+      // The lessor stops answering
+      p2p.state.lock().unwrap().challenge_proof = None;
+      until_async(|| async { persistence.rent_challenges(peer_id, 1).await.timeout > 0 }).await;
+    };
+
+    tokio::select! {
+      _ = reactor_fut => {}
+      _ = challenges => {}
+    }
+
+    let record = persistence.rent_challenges(peer_id, 1).await;
+    assert_eq!(record.failed, 0);
+    assert_eq!(record.consecutive_failures, record.timeout);
+  }
+
+  #[tokio::test]
+  async fn malformed_proof_fails_the_challenge() {
+    let folder = TempDir::new();
+    let lessee = Address::from_low_u64_be(1);
+    let onchain = MockOnchain::new(lessee);
+    let p2p = MockP2p::new();
+    let (peer_id, lessor) = p2p.add_peer();
+    // No merkle path at all, the proof cannot even be verified
+    p2p.state.lock().unwrap().challenge_proof = Some(ChallengeProof {
+      block_data: b"some data".to_vec(),
+      proof: Vec::new(),
+    });
+    let persistence = persistence_service(&folder);
+    persistence.rent_store(lease(peer_id, lessor, 1)).await;
+    let (reactor, _) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      onchain.clone(),
+      p2p,
+      persistence,
+      reactor_params(),
+    );
+    let mut events = reactor.subscribe();
+
+    let result = reactor
+      .challenge(
+        peer_id,
+        ChallengeKey {
+          nonce: 1,
+          block_number: 0,
+        },
+      )
+      .await
+      .unwrap();
+
+    assert!(!result.verified);
+    let submitted = onchain.state.lock().unwrap().submitted_challenges.clone();
+    assert_eq!(submitted.len(), 1);
+    assert_eq!((submitted[0].0, submitted[0].1, submitted[0].2), (lessor, 1, 0));
+    assert_eq!(submitted[0].3.as_ref().map(ChallengeProof::hash), Some(result.proof.hash()));
+    assert!(matches!(
+      events.try_recv(),
+      Ok(ReactorEvent::ChallengeFailed { nonce: 1, .. })
+    ));
+  }
+
+  #[tokio::test]
+  async fn timed_out_challenge_submitted_without_proof() {
+    let folder = TempDir::new();
+    let onchain = MockOnchain::new(Address::from_low_u64_be(1));
+    let p2p = MockP2p::new();
+    let (peer_id, lessor) = p2p.add_peer();
+    let persistence = persistence_service(&folder);
+    persistence
+      .rent_store(Lease {
+        chain_confirmation: Some(ChainConfirmation {
+          transaction_hash: H256::repeat_byte(7),
+          timestamp: SystemTime::now(),
+        }),
+        ..lease(peer_id, lessor, 1)
+      })
+      .await;
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      onchain.clone(),
+      p2p,
+      persistence,
+      ReactorParams {
+        challenge_watchdog_interval: Some(Duration::from_millis(10)),
+        ..reactor_params()
+      },
+    );
+
+    tokio::select! {
+      _ = reactor_fut => {}
+      _ = until(|| !onchain.state.lock().unwrap().submitted_challenges.is_empty()) => {}
+    }
+
+    let submitted = onchain.state.lock().unwrap().submitted_challenges.clone();
+    assert_eq!((submitted[0].0, submitted[0].1, submitted[0].2), (lessor, 1, 0));
+    assert!(submitted[0].3.is_none());
+  }
+
+  #[tokio::test]
+  async fn unanswered_challenge_submitted_without_proof() {
+    let folder = TempDir::new();
+    let onchain = MockOnchain::new(Address::from_low_u64_be(1));
+    let p2p = MockP2p::new();
+    let (peer_id, lessor) = p2p.add_peer();
+    let persistence = persistence_service(&folder);
+    persistence.rent_store(lease(peer_id, lessor, 1)).await;
+    let (reactor, _) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      onchain.clone(),
+      p2p,
+      persistence,
+      reactor_params(),
+    );
+
+    let error = reactor
+      .challenge(
+        peer_id,
+        ChallengeKey {
+          nonce: 1,
+          block_number: 0,
+        },
+      )
+      .await
+      .unwrap_err();
+
+    assert!(error.is::<p2p::RequestTimeout>());
+    let submitted = onchain.state.lock().unwrap().submitted_challenges.clone();
+    assert_eq!(submitted.len(), 1);
+    assert_eq!((submitted[0].0, submitted[0].1, submitted[0].2), (lessor, 1, 0));
+    assert!(submitted[0].3.is_none());
+  }
+
+  #[tokio::test]
+  async fn expired_leases_collected() {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (peer_id, peer_address) = p2p.add_peer();
+    let expired = Some(ChainConfirmation {
+      transaction_hash: H256::repeat_byte(7),
+      timestamp: SystemTime::now() - Duration::from_secs(7200),
+    });
+    let persistence = persistence_service(&folder);
+    persistence
+      .rent_store(Lease {
+        chain_confirmation: expired.clone(),
+        ..lease(peer_id, peer_address, 1)
+      })
+      .await;
+    let data = data_service(&folder);
+    let parameters = data.parameters(b"some data").await;
+    data.store(peer_id, 2, b"some data", &parameters).await.unwrap();
+    persistence
+      .let_store(Lease {
+        data_parameters: parameters,
+        chain_confirmation: expired,
+        ..lease(peer_id, peer_address, 2)
+      })
+      .await;
+    let (reactor, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      MockOnchain::new(Address::from_low_u64_be(1)),
+      p2p,
+      persistence.clone(),
+      ReactorParams {
+        expiry_check_interval: Some(Duration::from_millis(10)),
+        ..reactor_params()
+      },
+    );
+    let mut events = reactor.subscribe();
+    let collected = until_async(|| async {
+      persistence.rent_get(peer_id, 1).await.map(|l| l.completed).unwrap_or(false)
+        && persistence.let_get(peer_id, 2).await.is_none()
+    });
+
+    tokio::select! {
+      _ = reactor_fut => {}
+      _ = collected => {}
+    }
+
+    assert!(data.retrieve(peer_id, 2).await.is_err());
+    let mut expired_nonces = vec![];
+    while let Ok(event) = events.try_recv() {
+      if let ReactorEvent::LeaseExpired { nonce, .. } = event {
+        expired_nonces.push(nonce);
+      }
+    }
+    assert_eq!(expired_nonces, vec![1, 2]);
+  }
+
+  #[tokio::test]
+  async fn freed_funds_withdrawn_once_leases_expire() {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (peer_id, peer_address) = p2p.add_peer();
+    let onchain = MockOnchain::new(Address::from_low_u64_be(1));
+    onchain.state.lock().unwrap().storage_balance = 500.into();
+    let persistence = persistence_service(&folder);
+    persistence
+      .rent_store(Lease {
+        chain_confirmation: Some(ChainConfirmation {
+          transaction_hash: H256::repeat_byte(7),
+          timestamp: SystemTime::now() - Duration::from_secs(7200),
+        }),
+        ..lease(peer_id, peer_address, 1)
+      })
+      .await;
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      onchain.clone(),
+      p2p,
+      persistence,
+      ReactorParams {
+        expiry_check_interval: Some(Duration::from_millis(10)),
+        withdraw_expired: true,
+        ..reactor_params()
+      },
+    );
+
+    tokio::select! {
+      _ = reactor_fut => {}
+      _ = until(|| onchain.calls("withdraw") == 1) => {}
+    }
+
+    let withdrawals = onchain.state.lock().unwrap().withdrawals.clone();
+    assert_eq!(withdrawals, vec![(Address::from_low_u64_be(2), 500.into(), None)]);
+  }
+
+  #[tokio::test]
+  async fn leases_never_sealed_collected() {
+    let folder = TempDir::new();
+    let p2p = MockP2p::new();
+    let (peer_id, peer_address) = p2p.add_peer();
+    let expired_proposal = |nonce| {
+      let mut lease = lease(peer_id, peer_address, nonce);
+      lease.terms.proposal_expiration = SystemTime::now() - Duration::from_secs(60);
+      lease
+    };
+    let persistence = persistence_service(&folder);
+    persistence.rent_store(expired_proposal(1)).await;
+    // Its proposal can still be sealed
+    persistence.rent_store(lease(peer_id, peer_address, 2)).await;
+    let data = data_service(&folder);
+    let parameters = data.parameters(b"some data").await;
+    data.store(peer_id, 3, b"some data", &parameters).await.unwrap();
+    persistence
+      .let_store(Lease {
+        data_parameters: parameters,
+        ..expired_proposal(3)
+      })
+      .await;
+    let (_, reactor_fut) = new_service(
+      data_service(&folder),
+      MockLessor::accepting(),
+      MockOnchain::new(Address::from_low_u64_be(1)),
+      p2p,
+      persistence.clone(),
+      ReactorParams {
+        expiry_check_interval: Some(Duration::from_millis(10)),
+        ..reactor_params()
+      },
+    );
+    let collected = until_async(|| async {
+      persistence.rent_get(peer_id, 1).await.is_none() && persistence.let_get(peer_id, 3).await.is_none()
+    });
+
+    tokio::select! {
+      _ = reactor_fut => {}
+      _ = collected => {}
+    }
+
+    assert!(persistence.rent_get(peer_id, 2).await.is_some());
+    assert!(data.retrieve(peer_id, 3).await.is_err());
+  }
 }
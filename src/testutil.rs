@@ -0,0 +1,848 @@
+//! Mock services and temporary backends, so the reactor and the gRPC layer can be tested without a chain or a swarm.
+
+// Not every test uses every mock and helper
+#![allow(dead_code)]
+
+use crate::lessor::RejectedReason;
+use crate::p2p::p2pim::LeaseProposal;
+use crate::p2p::{Event, PendingOperation, ProposalResponse, QuoteResponse, RequestTimeout};
+use crate::types::{
+  Balance, ChallengeKey, ChallengeProof, DataParameters, Lease, LeaseTerms, Signature, StorageBalance, TokenMetadata,
+  WalletBalance,
+};
+use crate::utils::ethereum::TryIntoAddress;
+use crate::{cryptography, data, lessor, onchain, p2p, persistence, reactor};
+use ethcontract::errors::EventError;
+use ethcontract::transaction::TransactionResult;
+use ethcontract::EventStatus;
+use futures::channel::mpsc;
+use futures::Stream;
+use libp2p::identity::{secp256k1, PublicKey};
+use libp2p::{Multiaddr, PeerId};
+use p2pim_ethereum_contracts::adjudicator::event_data::LeaseSealed;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use tonic::async_trait;
+use web3::types::{Address, Block, BlockId, H256, U256};
+
+pub const BLOCK_SIZE: usize = 32;
+
+pub type LeaseSealedEvent = ethcontract::Event<EventStatus<LeaseSealed>>;
+
+/// Folder removed with all its contents when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+  pub fn new() -> Self {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+      "p2pim-test-{}-{}",
+      std::process::id(),
+      COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    std::fs::create_dir_all(&path).unwrap();
+    TempDir(path)
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.0
+  }
+}
+
+impl Drop for TempDir {
+  fn drop(&mut self) {
+    let _ = std::fs::remove_dir_all(&self.0);
+  }
+}
+
+pub fn data_service(folder: &TempDir) -> impl data::Service {
+  data::new_service(
+    cryptography::new_service(BLOCK_SIZE).unwrap(),
+    folder.path().join("data"),
+    data::Compression::None,
+  )
+}
+
+pub fn persistence_service(folder: &TempDir) -> impl persistence::Service {
+  unlocked(|| persistence::new_service(folder.path().join("db"))).unwrap()
+}
+
+/// Retries while the store is locked, the flusher thread of a dropped store still holds the lock for a moment.
+pub fn unlocked<T, E: std::fmt::Display>(open: impl Fn() -> Result<T, E>) -> Result<T, E> {
+  let deadline = std::time::Instant::now() + Duration::from_secs(5);
+  loop {
+    match open() {
+      Err(e) if e.to_string().contains("could not acquire lock") && std::time::Instant::now() < deadline => {
+        std::thread::sleep(Duration::from_millis(10))
+      }
+      result => return result,
+    }
+  }
+}
+
+pub fn reactor_params() -> reactor::ReactorParams {
+  reactor::ReactorParams {
+    integrity_check_interval: None,
+    challenge_watchdog_interval: None,
+    expiry_check_interval: None,
+    lease_keepalive_interval: None,
+    let_data_grace_period: Duration::from_secs(0),
+    confirmation_depth: 0,
+    max_concurrent_seals: 4,
+    max_proposal_rate: None,
+    seal_gas_precheck: false,
+    min_gas_balance: U256::zero(),
+    max_retrieve_size: usize::MAX,
+    retrieve_min_interval: Duration::from_secs(0),
+    withdraw_expired: false,
+  }
+}
+
+pub fn lease_terms(token_address: Address) -> LeaseTerms {
+  LeaseTerms {
+    token_address,
+    price: 1000.into(),
+    penalty: 100.into(),
+    proposal_expiration: SystemTime::now() + Duration::from_secs(60),
+    lease_duration: Duration::from_secs(3600),
+  }
+}
+
+/// Lease of some data with the given peer, not sealed yet.
+pub fn lease(peer_id: PeerId, peer_address: Address, nonce: u64) -> Lease {
+  Lease {
+    peer_id,
+    peer_address,
+    nonce,
+    terms: lease_terms(Address::from_low_u64_be(2)),
+    data_parameters: DataParameters {
+      merkle_root: vec![0; 32],
+      size: 9,
+    },
+    chain_confirmation: None,
+    completed: false,
+  }
+}
+
+/// Proposal received from a lessee to store some data.
+pub fn lease_proposal(nonce: u64) -> LeaseProposal {
+  LeaseProposal {
+    nonce,
+    lease_terms: lease_terms(Address::from_low_u64_be(2)),
+    signature: signature(),
+    data: b"some data".to_vec(),
+  }
+}
+
+pub fn signature() -> Signature {
+  Signature::from(web3::signing::Signature {
+    r: H256::repeat_byte(1),
+    s: H256::repeat_byte(2),
+    v: 27,
+  })
+}
+
+/// Sealed event of a lease, confirmed in a block with the given hash.
+// Only the fields read by the reactor are set, whatever else the adjudicator emits is left to its default
+#[allow(clippy::needless_update)]
+pub fn lease_sealed(
+  lessor: Address,
+  lessee: Address,
+  nonce: u64,
+  block_hash: H256,
+  transaction_hash: H256,
+) -> LeaseSealedEvent {
+  ethcontract::Event {
+    data: EventStatus::Added(LeaseSealed {
+      lessor,
+      lessee,
+      nonce,
+      ..Default::default()
+    }),
+    meta: Some(ethcontract::EventMetadata {
+      block_hash,
+      block_number: 1,
+      transaction_hash,
+      transaction_index: 0,
+      log_index: 0,
+      transaction_log_index: None,
+      log_type: None,
+    }),
+  }
+}
+
+/// Removal by a reorg of the sealed event confirmed in a block with the given hash.
+pub fn lease_removed(
+  lessor: Address,
+  lessee: Address,
+  nonce: u64,
+  block_hash: H256,
+  transaction_hash: H256,
+) -> LeaseSealedEvent {
+  let event = lease_sealed(lessor, lessee, nonce, block_hash, transaction_hash);
+  match event.data {
+    EventStatus::Added(data) => ethcontract::Event {
+      data: EventStatus::Removed(data),
+      meta: event.meta,
+    },
+    EventStatus::Removed(_) => unreachable!(),
+  }
+}
+
+/// State of [`MockOnchain`], tests set the canned results and read the recorded calls.
+pub struct MockOnchainState {
+  pub calls: Vec<&'static str>,
+  pub block_number: u64,
+  pub block_timestamp: Option<SystemTime>,
+  pub native_balance: U256,
+  /// Tokens available in the wallet, reported by `balance`.
+  pub token_balance: U256,
+  /// Tokens available in the adjudicator, reported by `balance`.
+  pub storage_balance: U256,
+  pub gas_price: U256,
+  /// Error `balance` and `deposit` fail with, they succeed when none.
+  pub failure: Option<fn() -> onchain::Error>,
+  pub seal_gas: Result<U256, String>,
+  pub seal_transaction_hash: H256,
+  pub sealed_in: bool,
+  /// Returned by `is_lease_penalized`.
+  pub penalized: Option<bool>,
+  /// Time `seal_lease` takes, so concurrent seals overlap
+  pub seal_delay: Option<Duration>,
+  pub sealing: usize,
+  pub max_sealing: usize,
+  /// Events returned by `find_lease_sealed` and `backfill_events`.
+  pub sealed_events: Vec<LeaseSealedEvent>,
+  /// Returned by `wait_for_seal_lease`, which never finishes when none.
+  pub wait_for_seal: Option<Option<LeaseSealedEvent>>,
+  pub submitted_challenges: Vec<(Address, u64, u32, Option<ChallengeProof>)>,
+  /// Token, amount and destination of each withdraw.
+  pub withdrawals: Vec<(Address, U256, Option<Address>)>,
+  events: Option<mpsc::UnboundedReceiver<Result<LeaseSealedEvent, EventError>>>,
+}
+
+#[derive(Clone)]
+pub struct MockOnchain {
+  account_storage: Address,
+  network_id: &'static str,
+  pub state: Arc<Mutex<MockOnchainState>>,
+  events: mpsc::UnboundedSender<Result<LeaseSealedEvent, EventError>>,
+}
+
+impl MockOnchain {
+  pub fn new(account_storage: Address) -> Self {
+    let (events, receiver) = mpsc::unbounded();
+    MockOnchain {
+      account_storage,
+      network_id: "test",
+      state: Arc::new(Mutex::new(MockOnchainState {
+        calls: Vec::new(),
+        block_number: 1,
+        block_timestamp: Some(SystemTime::now()),
+        native_balance: U256::exp10(18),
+        token_balance: U256::zero(),
+        storage_balance: U256::zero(),
+        gas_price: 1.into(),
+        failure: None,
+        seal_gas: Ok(100_000.into()),
+        seal_transaction_hash: H256::repeat_byte(0xaa),
+        sealed_in: true,
+        penalized: Some(false),
+        seal_delay: None,
+        sealing: 0,
+        max_sealing: 0,
+        sealed_events: Vec::new(),
+        wait_for_seal: None,
+        submitted_challenges: Vec::new(),
+        withdrawals: Vec::new(),
+        events: Some(receiver),
+      })),
+      events,
+    }
+  }
+
+  pub fn on_network(self, network_id: &'static str) -> Self {
+    MockOnchain { network_id, ..self }
+  }
+
+  /// Sends an event to the stream of `listen_adjudicator_events`.
+  pub fn emit(&self, event: LeaseSealedEvent) {
+    self.events.unbounded_send(Ok(event)).unwrap();
+  }
+
+  pub fn calls(&self, name: &str) -> usize {
+    self.state.lock().unwrap().calls.iter().filter(|c| **c == name).count()
+  }
+
+  fn record(&self, name: &'static str) {
+    self.state.lock().unwrap().calls.push(name);
+  }
+}
+
+#[async_trait]
+impl onchain::Service for MockOnchain {
+  type StreamType = mpsc::UnboundedReceiver<Result<LeaseSealedEvent, EventError>>;
+
+  async fn block(&self, _: BlockId) -> onchain::Result<Option<Block<H256>>> {
+    let block_number = self.state.lock().unwrap().block_number;
+    Ok(Some(Block {
+      hash: Some(H256::from_low_u64_be(block_number)),
+      number: Some(block_number.into()),
+      ..Default::default()
+    }))
+  }
+
+  async fn block_number(&self) -> onchain::Result<u64> {
+    Ok(self.state.lock().unwrap().block_number)
+  }
+
+  async fn block_timestamp(&self, _: H256) -> onchain::Result<Option<SystemTime>> {
+    Ok(self.state.lock().unwrap().block_timestamp)
+  }
+
+  async fn listen_adjudicator_events(&self) -> Self::StreamType {
+    match self.state.lock().unwrap().events.take() {
+      Some(events) => events,
+      None => mpsc::unbounded().1,
+    }
+  }
+
+  async fn backfill_events(&self, _: u64, _: u64) -> onchain::Result<Vec<LeaseSealedEvent>> {
+    self.record("backfill_events");
+    Ok(self.state.lock().unwrap().sealed_events.clone())
+  }
+
+  fn account_wallet(&self) -> Address {
+    self.account_storage
+  }
+
+  fn account_storage(&self) -> Address {
+    self.account_storage
+  }
+
+  fn master_record_address(&self) -> Address {
+    Address::zero()
+  }
+
+  fn network_id(&self) -> &str {
+    self.network_id
+  }
+
+  async fn seal_lease(
+    &self,
+    _: Address,
+    _: u64,
+    _: LeaseTerms,
+    _: DataParameters,
+    _: Signature,
+  ) -> onchain::Result<TransactionResult> {
+    self.record("seal_lease");
+    let delay = {
+      let mut state = self.state.lock().unwrap();
+      state.sealing += 1;
+      state.max_sealing = std::cmp::max(state.max_sealing, state.sealing);
+      state.seal_delay
+    };
+    if let Some(delay) = delay {
+      tokio::time::sleep(delay).await;
+    }
+    let mut state = self.state.lock().unwrap();
+    state.sealing -= 1;
+    Ok(TransactionResult::Hash(state.seal_transaction_hash))
+  }
+
+  async fn estimate_seal_gas(
+    &self,
+    _: Address,
+    _: u64,
+    _: &LeaseTerms,
+    _: &DataParameters,
+    _: &Signature,
+  ) -> onchain::Result<Result<U256, String>> {
+    self.record("estimate_seal_gas");
+    Ok(self.state.lock().unwrap().seal_gas.clone())
+  }
+
+  async fn sign_proposal(&self, _: &Address, _: u64, _: &LeaseTerms, _: &DataParameters) -> onchain::Result<Signature> {
+    self.record("sign_proposal");
+    Ok(signature())
+  }
+
+  async fn sign_message(&self, _: &[u8]) -> onchain::Result<Signature> {
+    self.record("sign_message");
+    Ok(signature())
+  }
+
+  async fn wait_for_seal_lease(
+    &self,
+    _: &Address,
+    _: Address,
+    _: u64,
+    _: SystemTime,
+  ) -> onchain::Result<Option<LeaseSealedEvent>> {
+    self.record("wait_for_seal_lease");
+    let result = self.state.lock().unwrap().wait_for_seal.clone();
+    match result {
+      Some(result) => Ok(result),
+      None => futures::future::pending().await,
+    }
+  }
+
+  async fn find_lease_sealed(
+    &self,
+    _: &Address,
+    lessor_address: Address,
+    lessee_address: Address,
+    nonce: u64,
+  ) -> onchain::Result<Option<ethcontract::Event<LeaseSealed>>> {
+    self.record("find_lease_sealed");
+    let events = self.state.lock().unwrap().sealed_events.clone();
+    Ok(events.into_iter().find_map(|e| match e.data {
+      EventStatus::Added(data) if data.lessor == lessor_address && data.lessee == lessee_address && data.nonce == nonce => {
+        Some(ethcontract::Event { data, meta: e.meta })
+      }
+      _ => None,
+    }))
+  }
+
+  async fn is_lease_sealed_in(&self, _: &Address, _: Address, _: u64, _: H256) -> onchain::Result<bool> {
+    self.record("is_lease_sealed_in");
+    Ok(self.state.lock().unwrap().sealed_in)
+  }
+
+  async fn is_lease_penalized(&self, _: &Address, _: Address, _: Address, _: u64, _: H256) -> onchain::Result<Option<bool>> {
+    self.record("is_lease_penalized");
+    Ok(self.state.lock().unwrap().penalized)
+  }
+
+  async fn submit_challenge(
+    &self,
+    _: &Address,
+    lessor_address: Address,
+    nonce: u64,
+    block_number: u32,
+    proof: Option<ChallengeProof>,
+  ) -> onchain::Result<TransactionResult> {
+    self.record("submit_challenge");
+    let mut state = self.state.lock().unwrap();
+    state.submitted_challenges.push((lessor_address, nonce, block_number, proof));
+    Ok(TransactionResult::Hash(H256::repeat_byte(0xcc)))
+  }
+
+  async fn deployed_tokens(&self) -> Vec<(Address, Option<TokenMetadata>)> {
+    Vec::new()
+  }
+
+  async fn balance(&self, _: &Address, _: bool) -> onchain::Result<Balance> {
+    let state = self.state.lock().unwrap();
+    if let Some(failure) = state.failure {
+      return Err(failure());
+    }
+    Ok(Balance {
+      adjudicator_address: Address::zero(),
+      token_metadata: None,
+      storage_balance: StorageBalance {
+        available: state.storage_balance,
+        locked_rents: U256::zero(),
+        locked_lets: U256::zero(),
+      },
+      wallet_balance: WalletBalance {
+        available: state.token_balance,
+        allowance: U256::zero(),
+      },
+    })
+  }
+
+  async fn native_balance(&self, _: Address) -> onchain::Result<U256> {
+    Ok(self.state.lock().unwrap().native_balance)
+  }
+
+  async fn pending_transactions(&self, _: Address) -> onchain::Result<U256> {
+    Ok(U256::zero())
+  }
+
+  async fn gas_price(&self) -> onchain::Result<U256> {
+    Ok(self.state.lock().unwrap().gas_price)
+  }
+
+  async fn withdraw(
+    &self,
+    token_address: &Address,
+    amount: U256,
+    destination: Option<Address>,
+  ) -> onchain::Result<TransactionResult> {
+    self.record("withdraw");
+    let withdrawal = (*token_address, amount, destination);
+    self.state.lock().unwrap().withdrawals.push(withdrawal);
+    Ok(TransactionResult::Hash(H256::zero()))
+  }
+
+  async fn deposit(&self, _: &Address, _: U256) -> onchain::Result<TransactionResult> {
+    self.record("deposit");
+    if let Some(failure) = self.state.lock().unwrap().failure {
+      return Err(failure());
+    }
+    Ok(TransactionResult::Hash(H256::zero()))
+  }
+
+  async fn approve(&self, _: &Address) -> onchain::Result<TransactionResult> {
+    self.record("approve");
+    Ok(TransactionResult::Hash(H256::zero()))
+  }
+}
+
+/// State of [`MockP2p`], tests set the canned responses and read the messages sent.
+pub struct MockP2pState {
+  pub public_keys: HashMap<PeerId, secp256k1::PublicKey>,
+  /// Answer to the proposals, a request timeout when none.
+  pub proposal_response: Option<ProposalResponse>,
+  /// Answer to the challenges, a request timeout when none.
+  pub challenge_proof: Option<ChallengeProof>,
+  /// Answer of each peer to the quotes, a request timeout for the peers without one.
+  pub quote_responses: HashMap<PeerId, QuoteResponse>,
+  pub proposals_sent: Vec<(PeerId, u64)>,
+  pub rejections_sent: Vec<(PeerId, u64, String)>,
+  pub lease_sealed_sent: Vec<(PeerId, u64, H256)>,
+  pub quote_responses_sent: Vec<(PeerId, u64, QuoteResponse)>,
+  pub deliveries_sent: Vec<(PeerId, u64)>,
+  pub not_found_sent: Vec<(PeerId, u64)>,
+  pub pending_operations: Vec<PendingOperation>,
+  pub dials: Vec<(PeerId, Vec<Multiaddr>)>,
+}
+
+#[derive(Clone)]
+pub struct MockP2p {
+  local_peer_id: PeerId,
+  pub state: Arc<Mutex<MockP2pState>>,
+  events: mpsc::UnboundedSender<Event>,
+  receiver: Arc<Mutex<mpsc::UnboundedReceiver<Event>>>,
+}
+
+impl MockP2p {
+  pub fn new() -> Self {
+    let (events, receiver) = mpsc::unbounded();
+    MockP2p {
+      local_peer_id: PeerId::random(),
+      state: Arc::new(Mutex::new(MockP2pState {
+        public_keys: HashMap::new(),
+        proposal_response: None,
+        challenge_proof: None,
+        quote_responses: HashMap::new(),
+        proposals_sent: Vec::new(),
+        rejections_sent: Vec::new(),
+        lease_sealed_sent: Vec::new(),
+        quote_responses_sent: Vec::new(),
+        deliveries_sent: Vec::new(),
+        not_found_sent: Vec::new(),
+        pending_operations: Vec::new(),
+        dials: Vec::new(),
+      })),
+      events,
+      receiver: Arc::new(Mutex::new(receiver)),
+    }
+  }
+
+  /// Identifies a new peer, returning its id and its storage address.
+  pub fn add_peer(&self) -> (PeerId, Address) {
+    let keypair = secp256k1::Keypair::generate();
+    let peer_id = PublicKey::Secp256k1(keypair.public().clone()).to_peer_id();
+    let address = keypair.public().try_into_address().unwrap();
+    self
+      .state
+      .lock()
+      .unwrap()
+      .public_keys
+      .insert(peer_id, keypair.public().clone());
+    (peer_id, address)
+  }
+
+  /// Delivers an event to the reactor, as if received from the swarm.
+  pub fn emit(&self, event: Event) {
+    self.events.unbounded_send(event).unwrap();
+  }
+}
+
+impl Stream for MockP2p {
+  type Item = Event;
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    Pin::new(&mut *self.receiver.lock().unwrap()).poll_next(cx)
+  }
+}
+
+fn timeout(request: &'static str) -> anyhow::Error {
+  RequestTimeout {
+    request,
+    timeout: Duration::from_secs(1),
+  }
+  .into()
+}
+
+#[async_trait]
+impl p2p::Service for MockP2p {
+  async fn challenge(&self, _: PeerId, _: ChallengeKey) -> anyhow::Result<ChallengeProof> {
+    self
+      .state
+      .lock()
+      .unwrap()
+      .challenge_proof
+      .clone()
+      .ok_or_else(|| timeout("challenge"))
+  }
+
+  async fn send_proposal(
+    &self,
+    peer_id: PeerId,
+    nonce: u64,
+    _: LeaseTerms,
+    _: Signature,
+    _: Vec<u8>,
+  ) -> anyhow::Result<ProposalResponse> {
+    let mut state = self.state.lock().unwrap();
+    state.proposals_sent.push((peer_id, nonce));
+    state.proposal_response.clone().ok_or_else(|| timeout("proposal"))
+  }
+
+  async fn send_challenge_proof(&self, _: PeerId, _: ChallengeKey, _: ChallengeProof) -> anyhow::Result<()> {
+    Ok(())
+  }
+
+  async fn send_retrieve_delivery(&self, peer_id: PeerId, nonce: u64, _: Vec<u8>) -> anyhow::Result<()> {
+    self.state.lock().unwrap().deliveries_sent.push((peer_id, nonce));
+    Ok(())
+  }
+
+  async fn send_retrieve_not_found(&self, peer_id: PeerId, nonce: u64) -> anyhow::Result<()> {
+    self.state.lock().unwrap().not_found_sent.push((peer_id, nonce));
+    Ok(())
+  }
+
+  async fn send_proposal_rejection(&self, peer_id: PeerId, nonce: u64, reason: String) -> anyhow::Result<()> {
+    self.state.lock().unwrap().rejections_sent.push((peer_id, nonce, reason));
+    Ok(())
+  }
+
+  async fn send_lease_sealed(&self, peer_id: PeerId, nonce: u64, transaction_hash: H256) -> anyhow::Result<()> {
+    let mut state = self.state.lock().unwrap();
+    state.lease_sealed_sent.push((peer_id, nonce, transaction_hash));
+    Ok(())
+  }
+
+  async fn retrieve(&self, _: PeerId, _: u64) -> anyhow::Result<Vec<u8>> {
+    Err(timeout("retrieve"))
+  }
+
+  async fn quote(&self, peer_id: PeerId, _: &LeaseTerms, _: usize) -> anyhow::Result<QuoteResponse> {
+    let state = self.state.lock().unwrap();
+    state.quote_responses.get(&peer_id).cloned().ok_or_else(|| timeout("quote"))
+  }
+
+  async fn send_quote_response(&self, peer_id: PeerId, quote_id: u64, response: QuoteResponse) -> anyhow::Result<()> {
+    let mut state = self.state.lock().unwrap();
+    state.quote_responses_sent.push((peer_id, quote_id, response));
+    Ok(())
+  }
+
+  fn find_public_key(&self, peer_id: &PeerId) -> Option<secp256k1::PublicKey> {
+    self.state.lock().unwrap().public_keys.get(peer_id).cloned()
+  }
+
+  fn known_peers(&self) -> Vec<PeerId> {
+    self.state.lock().unwrap().public_keys.keys().cloned().collect()
+  }
+
+  fn local_peer_id(&self) -> PeerId {
+    self.local_peer_id
+  }
+
+  fn protocol_version(&self) -> String {
+    "/p2pim/test".to_string()
+  }
+
+  fn pending_operations(&self) -> Vec<PendingOperation> {
+    self.state.lock().unwrap().pending_operations.clone()
+  }
+
+  fn dial(&self, peer_id: PeerId, addresses: Vec<Multiaddr>) {
+    self.state.lock().unwrap().dials.push((peer_id, addresses));
+  }
+}
+
+/// Lessor accepting every proposal, or rejecting all of them with the reason returned by `reject`.
+#[derive(Clone)]
+pub struct MockLessor {
+  reject: Option<fn() -> RejectedReason>,
+  pub proposals: Arc<Mutex<usize>>,
+}
+
+impl MockLessor {
+  pub fn accepting() -> Self {
+    MockLessor {
+      reject: None,
+      proposals: Arc::new(Mutex::new(0)),
+    }
+  }
+
+  pub fn rejecting(reject: fn() -> RejectedReason) -> Self {
+    MockLessor {
+      reject: Some(reject),
+      proposals: Arc::new(Mutex::new(0)),
+    }
+  }
+}
+
+#[async_trait]
+impl lessor::Service for MockLessor {
+  async fn proposal(&self, _: &PeerId, _: &LeaseTerms, _: usize) -> Result<(), RejectedReason> {
+    *self.proposals.lock().unwrap() += 1;
+    match self.reject {
+      Some(reject) => Err(reject()),
+      None => Ok(()),
+    }
+  }
+
+  async fn admit(&self, peer_id: &PeerId, lease_terms: &LeaseTerms, size: usize) -> Result<(), RejectedReason> {
+    self.proposal(peer_id, lease_terms, size).await
+  }
+
+  async fn release(&self, _: usize) {}
+}
+
+type RpcAnswer = Arc<dyn Fn(&serde_json::Value) -> serde_json::Value + Send + Sync>;
+
+/// JSON-RPC node over http answering each method with a canned result, so the onchain implementation runs on a known
+/// chain. Methods without an answer fail with a JSON-RPC error.
+#[derive(Clone)]
+pub struct MockEthNode {
+  answers: Arc<Mutex<HashMap<String, RpcAnswer>>>,
+  /// Method and params of every request received.
+  pub requests: Arc<Mutex<Vec<(String, serde_json::Value)>>>,
+}
+
+impl MockEthNode {
+  /// Node of network 1 without accounts, whose master record has no deployments.
+  pub fn new() -> Self {
+    let node = MockEthNode {
+      answers: Default::default(),
+      requests: Default::default(),
+    };
+    node.answer("net_version", serde_json::json!("1"));
+    node.answer("eth_accounts", serde_json::json!([]));
+    // An empty array of (token, adjudicator)
+    node.answer("eth_call", serde_json::json!(format!("0x{:064x}{:064x}", 0x20, 0)));
+    node
+  }
+
+  pub fn answer(&self, method: &str, result: serde_json::Value) {
+    self.answer_with(method, move |_| result.clone());
+  }
+
+  /// Answers with the result computed from the params of each request.
+  pub fn answer_with(&self, method: &str, answer: impl Fn(&serde_json::Value) -> serde_json::Value + Send + Sync + 'static) {
+    self.answers.lock().unwrap().insert(method.to_string(), Arc::new(answer));
+  }
+
+  /// Params of the requests received for a method.
+  pub fn requests(&self, method: &str) -> Vec<serde_json::Value> {
+    let requests = self.requests.lock().unwrap();
+    requests.iter().filter(|(m, _)| m == method).map(|(_, p)| p.clone()).collect()
+  }
+
+  /// Serves on a random local port, returns the url to connect to.
+  pub fn serve(&self) -> url::Url {
+    use warp::Filter;
+
+    let node = self.clone();
+    let route = warp::post()
+      .and(warp::body::json())
+      .map(move |body: serde_json::Value| match body {
+        serde_json::Value::Array(calls) => warp::reply::json(&calls.iter().map(|c| node.call(c)).collect::<Vec<_>>()),
+        call => warp::reply::json(&node.call(&call)),
+      });
+    let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    url::Url::parse(format!("http://{}", address).as_str()).unwrap()
+  }
+
+  fn call(&self, call: &serde_json::Value) -> serde_json::Value {
+    let method = call["method"].as_str().unwrap_or_default().to_string();
+    let params = call["params"].clone();
+    self.requests.lock().unwrap().push((method.clone(), params.clone()));
+    let answer = self.answers.lock().unwrap().get(&method).cloned();
+    match answer {
+      Some(answer) => serde_json::json!({ "jsonrpc": "2.0", "id": call["id"], "result": answer(&params) }),
+      None => serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": call["id"],
+        "error": { "code": -32601, "message": format!("method {} not found", method) },
+      }),
+    }
+  }
+}
+
+pub fn onchain_params(eth_url: url::Url) -> onchain::OnchainParams {
+  onchain::OnchainParams {
+    eth_url,
+    private_key: secrecy::Secret::new([1u8; 32]),
+    master_address: Some(Address::repeat_byte(0x33)),
+    poll_interval: Duration::from_secs(1),
+    balance_max_age: None,
+  }
+}
+
+/// Block as a JSON-RPC node returns it, without transactions.
+pub fn block_json(hash: H256, number: u64, timestamp: u64) -> serde_json::Value {
+  let zero = format!("{:?}", H256::zero());
+  serde_json::json!({
+    "hash": format!("{:?}", hash),
+    "parentHash": zero,
+    "sha3Uncles": zero,
+    "miner": format!("{:?}", Address::zero()),
+    "stateRoot": zero,
+    "transactionsRoot": zero,
+    "receiptsRoot": zero,
+    "number": format!("{:#x}", number),
+    "gasUsed": "0x0",
+    "gasLimit": "0x0",
+    "extraData": "0x",
+    "logsBloom": format!("0x{}", "0".repeat(512)),
+    "timestamp": format!("{:#x}", timestamp),
+    "difficulty": "0x0",
+    "uncles": [],
+    "transactions": [],
+    "size": "0x0",
+  })
+}
+
+/// Parameters of a swarm without mdns, with short request timeouts.
+pub fn p2p_params() -> p2p::P2pParams {
+  p2p::P2pParams {
+    listen_addresses: Vec::new(),
+    mdns_enabled: false,
+    ping_keep_alive: true,
+    ping_interval: Duration::from_secs(15),
+    max_established: 16,
+    max_pending: 16,
+    max_established_per_peer: 2,
+    substream_idle_timeout: Duration::from_secs(10),
+    max_queued_messages: 16,
+    challenge_timeout: Duration::from_secs(2),
+    retrieve_timeout: Duration::from_secs(2),
+    proposal_timeout: Duration::from_secs(2),
+    retrieve_retries: 0,
+    network_id: None,
+    agent_version: "p2pim-test".to_string(),
+    known_peers_capacity: 16,
+  }
+}
+
+/// An in-memory transport address no other test listens on.
+pub fn memory_address() -> Multiaddr {
+  let port = rand::random::<u64>().saturating_add(1);
+  format!("/memory/{}", port).parse().unwrap()
+}
@@ -1,5 +1,6 @@
+use sha3::{Digest, Keccak256};
 use std::error::Error;
-use std::fmt::{Debug, Formatter};
+use std::fmt::{Debug, Display, Formatter};
 use std::time::{Duration, SystemTime};
 use web3::types::H256;
 
@@ -7,7 +8,18 @@ pub struct Signature(web3::signing::Signature);
 
 impl Debug for Signature {
   fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-    f.write_str("TODO Signature")
+    f.debug_struct("Signature")
+      .field("r", &format_args!("0x{:x}", self.0.r))
+      .field("s", &format_args!("0x{:x}", self.0.s))
+      .field("v", &self.0.v)
+      .finish()
+  }
+}
+
+/// The serialized signature in hex, as sent on chain.
+impl Display for Signature {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "0x{}", hex::encode(self.serialize()))
   }
 }
 
@@ -44,44 +56,57 @@ impl Signature {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Lease {
+  #[cfg_attr(feature = "serde", serde(with = "serde_fields::peer_id"))]
   pub peer_id: libp2p::PeerId,
   pub peer_address: web3::types::Address,
   pub nonce: u64,
   pub terms: LeaseTerms,
   pub data_parameters: DataParameters,
   pub chain_confirmation: Option<ChainConfirmation>,
+  pub completed: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DataParameters {
+  #[cfg_attr(feature = "serde", serde(with = "serde_fields::hex_bytes"))]
   pub merkle_root: Vec<u8>,
   pub size: usize,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LeaseTerms {
   pub token_address: web3::types::Address,
   pub price: web3::types::U256,
   pub penalty: web3::types::U256,
+  #[cfg_attr(feature = "serde", serde(with = "serde_fields::unix_secs"))]
   pub proposal_expiration: SystemTime,
+  #[cfg_attr(feature = "serde", serde(with = "serde_fields::secs"))]
   pub lease_duration: Duration,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ChainConfirmation {
   pub transaction_hash: web3::types::H256,
+  #[cfg_attr(feature = "serde", serde(with = "serde_fields::unix_secs"))]
   pub timestamp: SystemTime,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Balance {
+  pub adjudicator_address: web3::types::Address,
   pub token_metadata: Option<TokenMetadata>,
   pub storage_balance: StorageBalance,
   pub wallet_balance: WalletBalance,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StorageBalance {
   pub available: web3::types::U256,
   pub locked_rents: web3::types::U256,
@@ -89,12 +114,14 @@ pub struct StorageBalance {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct WalletBalance {
   pub available: web3::types::U256,
   pub allowance: web3::types::U256,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TokenMetadata {
   pub name: String,
   pub symbol: String,
@@ -102,13 +129,368 @@ pub struct TokenMetadata {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ChallengeKey {
   pub nonce: u64,
   pub block_number: u32,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ChallengeProof {
+  #[cfg_attr(feature = "serde", serde(with = "serde_fields::hex_bytes"))]
   pub block_data: Vec<u8>,
+  #[cfg_attr(feature = "serde", serde(with = "serde_fields::hex_bytes_list"))]
   pub proof: Vec<[u8; 32]>,
 }
+
+impl ChallengeProof {
+  /// Keccak256 of the block data followed by the proof hashes, identifies the proof in the audit log.
+  pub fn hash(&self) -> [u8; 32] {
+    let mut hasher = Keccak256::new();
+    hasher.update(self.block_data.as_slice());
+    self.proof.iter().for_each(|p| hasher.update(p));
+    let mut result: [u8; 32] = Default::default();
+    result.copy_from_slice(hasher.finalize().as_slice());
+    result
+  }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChallengeResult {
+  pub proof: ChallengeProof,
+  pub verified: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum AuditRole {
+  // We challenged the peer and verified its proof
+  Challenger,
+  // The peer challenged us and we produced the proof
+  Prover,
+}
+
+/// Peer identified in a previous run, dialed again on startup.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KnownPeer {
+  #[cfg_attr(feature = "serde", serde(with = "serde_fields::peer_id"))]
+  pub peer_id: libp2p::PeerId,
+  #[cfg_attr(feature = "serde", serde(with = "serde_fields::multiaddrs"))]
+  pub addresses: Vec<libp2p::Multiaddr>,
+  #[cfg_attr(feature = "serde", serde(with = "serde_fields::unix_secs"))]
+  pub last_seen: SystemTime,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AuditEntry {
+  #[cfg_attr(feature = "serde", serde(with = "serde_fields::unix_secs"))]
+  pub timestamp: SystemTime,
+  pub role: AuditRole,
+  #[cfg_attr(feature = "serde", serde(with = "serde_fields::peer_id"))]
+  pub peer_id: libp2p::PeerId,
+  pub nonce: u64,
+  pub block_number: u32,
+  #[cfg_attr(feature = "serde", serde(with = "serde_fields::hex_bytes"))]
+  pub proof_hash: [u8; 32],
+  pub verified: bool,
+  #[cfg_attr(feature = "serde", serde(with = "serde_fields::hex_bytes"))]
+  pub hash: [u8; 32],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ChallengeOutcome {
+  Honored,
+  Failed,
+  Timeout,
+}
+
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChallengeRecord {
+  pub honored: u32,
+  pub failed: u32,
+  pub timeout: u32,
+  pub consecutive_failures: u32,
+}
+
+impl ChallengeRecord {
+  pub fn record(&mut self, outcome: ChallengeOutcome) {
+    match outcome {
+      ChallengeOutcome::Honored => {
+        self.honored += 1;
+        self.consecutive_failures = 0;
+      }
+      ChallengeOutcome::Failed => {
+        self.failed += 1;
+        self.consecutive_failures += 1;
+      }
+      ChallengeOutcome::Timeout => {
+        self.timeout += 1;
+        self.consecutive_failures += 1;
+      }
+    }
+  }
+}
+
+/// Status of a lease as derived from its sealed event on chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum LeaseState {
+  NotFound,
+  Active,
+  Closed,
+  Penalized,
+}
+
+/// Serialized as its hex form, the same as [`Display`].
+#[cfg(feature = "serde")]
+impl serde::Serialize for Signature {
+  fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Signature {
+  fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let bytes = serde_fields::hex_bytes::deserialize::<D, Vec<u8>>(deserializer)?;
+    Signature::deserialize(bytes.as_slice()).map_err(serde::de::Error::custom)
+  }
+}
+
+/// Field representations meant to be read by people: bytes in hex prefixed by 0x, peer ids and addresses as their
+/// string forms and times in whole seconds, the precision stored on chain. The web3 types already serialize as hex.
+#[cfg(feature = "serde")]
+mod serde_fields {
+  use serde::de::Error;
+  use serde::{Deserialize, Deserializer, Serializer};
+
+  pub mod peer_id {
+    use super::*;
+    use libp2p::PeerId;
+
+    pub fn serialize<S: Serializer>(peer_id: &PeerId, serializer: S) -> Result<S::Ok, S::Error> {
+      serializer.serialize_str(peer_id.to_base58().as_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PeerId, D::Error> {
+      let value = String::deserialize(deserializer)?;
+      value.parse().map_err(D::Error::custom)
+    }
+  }
+
+  pub mod multiaddrs {
+    use super::*;
+    use libp2p::Multiaddr;
+
+    pub fn serialize<S: Serializer>(addresses: &[Multiaddr], serializer: S) -> Result<S::Ok, S::Error> {
+      serializer.collect_seq(addresses.iter().map(Multiaddr::to_string))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Multiaddr>, D::Error> {
+      let values = Vec::<String>::deserialize(deserializer)?;
+      values.iter().map(|v| v.parse().map_err(D::Error::custom)).collect()
+    }
+  }
+
+  pub mod hex_bytes {
+    use super::*;
+    use std::convert::TryFrom;
+
+    pub fn serialize<S: Serializer>(bytes: impl AsRef<[u8]>, serializer: S) -> Result<S::Ok, S::Error> {
+      serializer.serialize_str(format!("0x{}", hex::encode(bytes)).as_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, T: TryFrom<Vec<u8>>>(deserializer: D) -> Result<T, D::Error> {
+      let value = String::deserialize(deserializer)?;
+      let bytes = hex::decode(value.strip_prefix("0x").unwrap_or(&value)).map_err(D::Error::custom)?;
+      let length = bytes.len();
+      T::try_from(bytes).map_err(|_| D::Error::invalid_length(length, &"the length of the field"))
+    }
+  }
+
+  pub mod hex_bytes_list {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(values: &[[u8; 32]], serializer: S) -> Result<S::Ok, S::Error> {
+      serializer.collect_seq(values.iter().map(|v| format!("0x{}", hex::encode(v))))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<[u8; 32]>, D::Error> {
+      let values = Vec::<String>::deserialize(deserializer)?;
+      values
+        .iter()
+        .map(|value| {
+          let mut result = [0u8; 32];
+          hex::decode_to_slice(value.strip_prefix("0x").unwrap_or(value), &mut result).map_err(D::Error::custom)?;
+          Ok(result)
+        })
+        .collect()
+    }
+  }
+
+  pub mod secs {
+    use super::*;
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+      serializer.serialize_u64(duration.as_secs())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+      u64::deserialize(deserializer).map(Duration::from_secs)
+    }
+  }
+
+  pub mod unix_secs {
+    use super::*;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+      let since_epoch = time
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| serde::ser::Error::custom("time before the unix epoch"))?;
+      serializer.serialize_u64(since_epoch.as_secs())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+      u64::deserialize(deserializer).map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn signature_formatted_with_its_components() {
+    let signature = Signature::from(web3::signing::Signature {
+      r: H256::repeat_byte(0xab),
+      s: H256::repeat_byte(0xcd),
+      v: 28,
+    });
+
+    let debug = format!("{:?}", signature);
+    let display = signature.to_string();
+
+    assert!(debug.contains(&format!("r: 0x{}", "ab".repeat(32))), "{}", debug);
+    assert!(debug.contains(&format!("s: 0x{}", "cd".repeat(32))), "{}", debug);
+    assert!(debug.contains("v: 28"), "{}", debug);
+    assert_eq!(display, format!("0x{}{}1c", "ab".repeat(32), "cd".repeat(32)));
+  }
+
+  #[cfg(feature = "serde")]
+  fn round_trip<T: serde::Serialize + serde::de::DeserializeOwned>(value: &T) -> serde_json::Value {
+    let json = serde_json::to_value(value).unwrap();
+    let read: T = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(serde_json::to_value(&read).unwrap(), json);
+    json
+  }
+
+  #[cfg(feature = "serde")]
+  #[test]
+  fn types_round_tripped_through_json() {
+    let peer_id = libp2p::PeerId::random();
+    let timestamp = std::time::UNIX_EPOCH + Duration::from_secs(1_650_000_000);
+    let lease = Lease {
+      peer_id,
+      peer_address: web3::types::Address::repeat_byte(1),
+      nonce: 7,
+      terms: LeaseTerms {
+        token_address: web3::types::Address::repeat_byte(2),
+        price: 255.into(),
+        penalty: 16.into(),
+        proposal_expiration: timestamp,
+        lease_duration: Duration::from_secs(3600),
+      },
+      data_parameters: DataParameters {
+        merkle_root: vec![0xab; 32],
+        size: 9,
+      },
+      chain_confirmation: Some(ChainConfirmation {
+        transaction_hash: H256::repeat_byte(3),
+        timestamp,
+      }),
+      completed: false,
+    };
+
+    let json = round_trip(&lease);
+    assert_eq!(json["peer_id"], peer_id.to_base58());
+    assert_eq!(json["peer_address"], format!("0x{}", "01".repeat(20)));
+    assert_eq!(json["terms"]["price"], "0xff");
+    assert_eq!(json["terms"]["proposal_expiration"], 1_650_000_000);
+    assert_eq!(json["terms"]["lease_duration"], 3600);
+    assert_eq!(json["data_parameters"]["merkle_root"], format!("0x{}", "ab".repeat(32)));
+    assert_eq!(
+      json["chain_confirmation"]["transaction_hash"],
+      format!("0x{}", "03".repeat(32))
+    );
+
+    let signature = Signature::from(web3::signing::Signature {
+      r: H256::repeat_byte(1),
+      s: H256::repeat_byte(2),
+      v: 27,
+    });
+    assert_eq!(round_trip(&signature), signature.to_string());
+    round_trip(&ChallengeResult {
+      proof: ChallengeProof {
+        block_data: b"some data".to_vec(),
+        proof: vec![[4; 32], [5; 32]],
+      },
+      verified: true,
+    });
+    round_trip(&ChallengeKey {
+      nonce: 7,
+      block_number: 2,
+    });
+    let known_peer = KnownPeer {
+      peer_id,
+      addresses: vec!["/ip4/127.0.0.1/tcp/4001".parse().unwrap()],
+      last_seen: timestamp,
+    };
+    assert_eq!(round_trip(&known_peer)["addresses"][0], "/ip4/127.0.0.1/tcp/4001");
+    round_trip(&AuditEntry {
+      timestamp,
+      role: AuditRole::Prover,
+      peer_id,
+      nonce: 7,
+      block_number: 2,
+      proof_hash: [6; 32],
+      verified: true,
+      hash: [7; 32],
+    });
+    round_trip(&Balance {
+      adjudicator_address: web3::types::Address::repeat_byte(8),
+      token_metadata: Some(TokenMetadata {
+        name: "Token".to_string(),
+        symbol: "TKN".to_string(),
+        decimals: 18,
+      }),
+      storage_balance: StorageBalance {
+        available: 1.into(),
+        locked_rents: 2.into(),
+        locked_lets: 3.into(),
+      },
+      wallet_balance: WalletBalance {
+        available: 4.into(),
+        allowance: 5.into(),
+      },
+    });
+    round_trip(&ChallengeRecord {
+      honored: 1,
+      ..Default::default()
+    });
+    assert_eq!(round_trip(&LeaseState::Active), "active");
+    assert_eq!(round_trip(&LeaseState::Penalized), "penalized");
+    assert_eq!(round_trip(&ChallengeOutcome::Timeout), "timeout");
+  }
+}
@@ -0,0 +1,130 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+pub struct LruCache<K: Hash + Eq + Clone, V> {
+  capacity: usize,
+  entries: HashMap<K, V>,
+  // Keys from the least to the most recently used
+  order: VecDeque<K>,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+  pub fn new(capacity: usize) -> Self {
+    LruCache {
+      capacity,
+      entries: HashMap::new(),
+      order: VecDeque::new(),
+    }
+  }
+
+  pub fn get(&mut self, key: &K) -> Option<&V> {
+    if self.entries.contains_key(key) {
+      self.touch(key);
+    }
+    self.entries.get(key)
+  }
+
+  pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+    let previous = self.entries.insert(key.clone(), value);
+    if previous.is_some() {
+      self.touch(&key);
+    } else {
+      self.order.push_back(key);
+      while self.order.len() > self.capacity {
+        if let Some(evicted) = self.order.pop_front() {
+          self.entries.remove(&evicted);
+        }
+      }
+    }
+    previous
+  }
+
+  /// Inserts like [`LruCache::insert`], but never evicts the entries `pinned` accepts, going over capacity when every
+  /// entry is pinned.
+  pub fn insert_unless_pinned(&mut self, key: K, value: V, pinned: impl Fn(&V) -> bool) -> Option<V> {
+    let previous = self.entries.insert(key.clone(), value);
+    if previous.is_some() {
+      self.touch(&key);
+    } else {
+      self.order.push_back(key);
+      self.evict_unpinned(pinned);
+    }
+    previous
+  }
+
+  /// Evicts the least recently used entries that are not pinned until the cache is within its capacity.
+  pub fn evict_unpinned(&mut self, pinned: impl Fn(&V) -> bool) {
+    while self.order.len() > self.capacity {
+      let entries = &self.entries;
+      match self
+        .order
+        .iter()
+        .position(|k| entries.get(k).map(|v| !pinned(v)).unwrap_or(true))
+      {
+        Some(position) => {
+          if let Some(evicted) = self.order.remove(position) {
+            self.entries.remove(&evicted);
+          }
+        }
+        None => break,
+      }
+    }
+  }
+
+  pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+    if self.entries.contains_key(key) {
+      self.touch(key);
+    }
+    self.entries.get_mut(key)
+  }
+
+  /// Looks up an entry without marking it as used.
+  pub fn peek(&self, key: &K) -> Option<&V> {
+    self.entries.get(key)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+    self.entries.iter()
+  }
+
+  pub fn remove(&mut self, key: &K) -> Option<V> {
+    let removed = self.entries.remove(key);
+    if removed.is_some() {
+      self.order.retain(|k| k != key);
+    }
+    removed
+  }
+
+  pub fn contains_key(&self, key: &K) -> bool {
+    self.entries.contains_key(key)
+  }
+
+  /// Key evicted by the next insert of a new key when the cache is full.
+  pub fn least_recently_used(&self) -> Option<&K> {
+    self.order.front()
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.capacity
+  }
+
+  pub fn values(&self) -> impl Iterator<Item = &V> {
+    self.entries.values()
+  }
+
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  fn touch(&mut self, key: &K) {
+    if let Some(position) = self.order.iter().position(|k| k == key) {
+      if let Some(k) = self.order.remove(position) {
+        self.order.push_back(k);
+      }
+    }
+  }
+}
@@ -1,26 +1,78 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
 use web3::signing::keccak256;
 use web3::types::Address;
 
-pub trait IntoAddress {
-  fn into_address(self) -> Address;
+const UNCOMPRESSED_KEY_LENGTH: usize = 65;
+const UNCOMPRESSED_KEY_PREFIX: u8 = 0x04;
+
+#[derive(Debug)]
+pub enum KeyError {
+  InvalidLength(usize),
+  InvalidPrefix(u8),
+}
+
+impl Display for KeyError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      KeyError::InvalidLength(length) => write!(f, "invalid uncompressed key length: {}", length),
+      KeyError::InvalidPrefix(prefix) => write!(f, "invalid uncompressed key prefix: 0x{:02x}", prefix),
+    }
+  }
 }
 
-impl IntoAddress for &libp2p::identity::secp256k1::PublicKey {
-  fn into_address(self) -> Address {
+impl Error for KeyError {}
+
+pub trait TryIntoAddress {
+  fn try_into_address(self) -> Result<Address, KeyError>;
+}
+
+impl TryIntoAddress for &libp2p::identity::secp256k1::PublicKey {
+  fn try_into_address(self) -> Result<Address, KeyError> {
     let public_key = self.encode_uncompressed();
-    as_address(public_key)
+    as_address(public_key.as_slice())
   }
 }
 
-impl IntoAddress for &secp256k1::PublicKey {
-  fn into_address(self) -> Address {
+impl TryIntoAddress for &secp256k1::PublicKey {
+  fn try_into_address(self) -> Result<Address, KeyError> {
     let public_key = self.serialize_uncompressed();
-    as_address(public_key)
+    as_address(public_key.as_slice())
   }
 }
 
-fn as_address(raw: [u8; 65]) -> Address {
-  debug_assert_eq!(raw[0], 0x04);
+fn as_address(raw: &[u8]) -> Result<Address, KeyError> {
+  if raw.len() != UNCOMPRESSED_KEY_LENGTH {
+    return Err(KeyError::InvalidLength(raw.len()));
+  }
+  if raw[0] != UNCOMPRESSED_KEY_PREFIX {
+    return Err(KeyError::InvalidPrefix(raw[0]));
+  }
   let hash = keccak256(&raw[1..]);
-  Address::from_slice(&hash[12..])
+  Ok(Address::from_slice(&hash[12..]))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::str::FromStr;
+
+  #[test]
+  fn address_of_a_well_formed_key() {
+    let secret = secp256k1::SecretKey::from_slice(&[1u8; 32]).unwrap();
+    let public_key = secp256k1::PublicKey::from_secret_key(&secp256k1::Secp256k1::new(), &secret);
+    let expected = Address::from_str("1a642f0e3c3af545e7acbd38b07251b3990914f1").unwrap();
+    assert_eq!((&public_key).try_into_address().unwrap(), expected);
+
+    let libp2p_key = libp2p::identity::secp256k1::PublicKey::decode(&public_key.serialize()).unwrap();
+    assert_eq!((&libp2p_key).try_into_address().unwrap(), expected);
+  }
+
+  #[test]
+  fn malformed_key_rejected() {
+    let mut raw = [0u8; UNCOMPRESSED_KEY_LENGTH];
+    assert!(matches!(as_address(&raw[..33]), Err(KeyError::InvalidLength(33))));
+    raw[0] = 0x02;
+    assert!(matches!(as_address(&raw), Err(KeyError::InvalidPrefix(0x02))));
+  }
 }
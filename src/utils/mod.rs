@@ -1,2 +1,3 @@
+pub mod cache;
 pub mod ethereum;
 pub mod sync;
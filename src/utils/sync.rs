@@ -1,11 +1,13 @@
-use futures::FutureExt;
+use futures::channel::oneshot::Canceled;
 use log::error;
 use std::collections::HashMap;
 use std::future::Future;
 use std::hash::Hash;
+use std::time::{Duration, Instant};
 
 pub struct OneshotListerners<K: Hash + Eq, V: Clone> {
-  inner: HashMap<K, Vec<futures::channel::oneshot::Sender<V>>>,
+  // Along with the senders, when the first listener of the key started waiting
+  inner: HashMap<K, (Instant, Vec<futures::channel::oneshot::Sender<V>>)>,
 }
 
 impl<K: Hash + Eq, V: Clone> OneshotListerners<K, V> {
@@ -13,14 +15,44 @@ impl<K: Hash + Eq, V: Clone> OneshotListerners<K, V> {
     OneshotListerners { inner: HashMap::new() }
   }
 
-  pub fn new_listener(&mut self, key: K) -> impl Future<Output = V> {
+  /// The listener resolves to [`Canceled`] when its sender is dropped with [`OneshotListerners::cancel_matching`].
+  pub fn new_listener(&mut self, key: K) -> impl Future<Output = Result<V, Canceled>> {
     let (sender, receiver) = futures::channel::oneshot::channel();
-    self.inner.entry(key).or_default().push(sender);
-    receiver.map(|r| r.expect("we never cancel the sender"))
+    self
+      .inner
+      .entry(key)
+      .or_insert_with(|| (Instant::now(), Vec::new()))
+      .1
+      .push(sender);
+    receiver
+  }
+
+  /// Drops the senders of every key matching `predicate`, failing their listeners, returns how many were dropped.
+  pub fn cancel_matching(&mut self, predicate: impl Fn(&K) -> bool) -> usize {
+    let mut count = 0;
+    self.inner.retain(|key, (_, senders)| {
+      if predicate(key) {
+        count += senders.len();
+        false
+      } else {
+        true
+      }
+    });
+    count
+  }
+
+  /// Drops the senders of listeners that stopped waiting, e.g. after timing out.
+  pub fn remove_canceled(&mut self, key: &K) {
+    if let Some((_, senders)) = self.inner.get_mut(key) {
+      senders.retain(|sender| !sender.is_canceled());
+      if senders.is_empty() {
+        self.inner.remove(key);
+      }
+    }
   }
 
   pub fn notify(&mut self, key: &K, value: V) -> usize {
-    let senders = self.inner.remove(key).unwrap_or_else(Vec::new);
+    let senders = self.inner.remove(key).map(|(_, senders)| senders).unwrap_or_else(Vec::new);
     let res = senders.len();
     senders.into_iter().for_each(|sender| {
       if sender.send(value.clone()).is_err() {
@@ -29,4 +61,12 @@ impl<K: Hash + Eq, V: Clone> OneshotListerners<K, V> {
     });
     res
   }
+
+  /// Keys waited on, with how long they have been waited and the number of listeners.
+  pub fn pending(&self) -> impl Iterator<Item = (&K, Duration, usize)> {
+    self
+      .inner
+      .iter()
+      .map(|(key, (since, senders))| (key, since.elapsed(), senders.len()))
+  }
 }
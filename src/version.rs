@@ -0,0 +1,3 @@
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub const GIT_COMMIT: &str = env!("P2PIM_GIT_COMMIT");